@@ -0,0 +1,305 @@
+//! Local approval of remote requests
+//!
+//! With `[approval]` enabled, the server forwards every remote instance creation
+//! and the first attach of each web session as an `ApprovalRequest`. The person
+//! at the machine answers on the agent's console (`prompt`) or through a
+//! notification command (`command`); no answer within `timeout_secs` denies the
+//! request. A session approved once is not asked again for attaches.
+//!
+//! The command runs through the shell with the request in its environment:
+//!
+//! ```text
+//! TUNNEL_APPROVAL_ACTION     create_instance | attach
+//! TUNNEL_APPROVAL_REQUESTER  account name or role of the web user
+//! TUNNEL_APPROVAL_CLIENT_IP  client IP address of the web user
+//! TUNNEL_APPROVAL_INSTANCE   instance ID
+//! TUNNEL_APPROVAL_SESSION    web session ID
+//! TUNNEL_APPROVAL_CWD        working directory (create_instance only)
+//! ```
+//!
+//! Exit status 0 approves, anything else denies.
+
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use common::{ApprovalAction, ApprovalConfig, ApprovalMode};
+
+/// Remote request waiting for approval
+#[derive(Debug, Clone)]
+pub struct ApprovalRequest {
+    /// Action to approve
+    pub action: ApprovalAction,
+    /// Instance to create or attach to
+    pub instance_id: Uuid,
+    /// Requesting web session
+    pub session_id: Uuid,
+    /// Account name or role of the requester
+    pub requester: String,
+    /// Client IP address of the requester
+    pub client_ip: String,
+    /// Working directory of a new instance
+    pub cwd: Option<String>,
+}
+
+impl ApprovalRequest {
+    /// One-line description shown to the person at the machine
+    fn describe(&self) -> String {
+        match (&self.action, &self.cwd) {
+            (ApprovalAction::CreateInstance, Some(cwd)) => format!(
+                "{} from {} wants to start a new instance in {}",
+                self.requester, self.client_ip, cwd
+            ),
+            (ApprovalAction::CreateInstance, None) => {
+                format!("{} from {} wants to start a new instance", self.requester, self.client_ip)
+            }
+            (ApprovalAction::Attach, _) => format!(
+                "{} from {} wants to attach to instance {}",
+                self.requester, self.client_ip, self.instance_id
+            ),
+        }
+    }
+
+    /// Environment passed to the approval command
+    fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("TUNNEL_APPROVAL_ACTION", self.action.as_str().to_string()),
+            ("TUNNEL_APPROVAL_REQUESTER", self.requester.clone()),
+            ("TUNNEL_APPROVAL_CLIENT_IP", self.client_ip.clone()),
+            ("TUNNEL_APPROVAL_INSTANCE", self.instance_id.to_string()),
+            ("TUNNEL_APPROVAL_SESSION", self.session_id.to_string()),
+            ("TUNNEL_APPROVAL_CWD", self.cwd.clone().unwrap_or_default()),
+        ]
+    }
+}
+
+/// Asks the person at the machine to approve remote requests
+pub struct Approver {
+    mode: ApprovalMode,
+    command: Option<String>,
+    timeout: Duration,
+    /// Lines typed on the agent's console (prompt mode); the lock serializes prompts
+    console: Option<tokio::sync::Mutex<mpsc::Receiver<String>>>,
+    /// Sessions approved at least once
+    approved_sessions: Mutex<HashSet<Uuid>>,
+    /// Approved instance creations the server has not sent yet
+    approved_creates: Mutex<HashSet<Uuid>>,
+}
+
+impl Approver {
+    /// Build the approver from the config (None when approval is off)
+    pub fn from_config(config: &ApprovalConfig) -> Option<Self> {
+        let console = match config.mode {
+            ApprovalMode::Off => return None,
+            ApprovalMode::Prompt => Some(tokio::sync::Mutex::new(spawn_console_reader())),
+            ApprovalMode::Command => None,
+        };
+
+        Some(Self {
+            mode: config.mode,
+            command: config.command.clone(),
+            timeout: Duration::from_secs(config.timeout_secs.max(1)),
+            console,
+            approved_sessions: Mutex::new(HashSet::new()),
+            approved_creates: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Decide a request: attaches of approved sessions pass, everything else is asked
+    pub async fn decide(&self, request: &ApprovalRequest) -> bool {
+        if request.action == ApprovalAction::Attach && self.is_session_approved(request.session_id) {
+            return true;
+        }
+
+        let approved = match self.ask(request).await {
+            Ok(approved) => approved,
+            Err(e) => {
+                warn!("Approval of {} failed, denying: {}", request.action.as_str(), e);
+                false
+            }
+        };
+        info!(
+            "{} {} (session {})",
+            if approved { "Approved:" } else { "Denied:" },
+            request.describe(),
+            request.session_id
+        );
+
+        if approved {
+            lock(&self.approved_sessions).insert(request.session_id);
+            if request.action == ApprovalAction::CreateInstance {
+                lock(&self.approved_creates).insert(request.instance_id);
+            }
+        }
+        approved
+    }
+
+    /// Consume the approval of an instance creation
+    pub fn take_approved_create(&self, instance_id: Uuid) -> bool {
+        lock(&self.approved_creates).remove(&instance_id)
+    }
+
+    fn is_session_approved(&self, session_id: Uuid) -> bool {
+        lock(&self.approved_sessions).contains(&session_id)
+    }
+
+    async fn ask(&self, request: &ApprovalRequest) -> Result<bool> {
+        match self.mode {
+            ApprovalMode::Off => Ok(true),
+            ApprovalMode::Prompt => self.ask_console(request).await,
+            ApprovalMode::Command => self.run_command(request).await,
+        }
+    }
+
+    async fn ask_console(&self, request: &ApprovalRequest) -> Result<bool> {
+        let console = self.console.as_ref().ok_or_else(|| anyhow!("console is not available"))?;
+        let mut lines = console.lock().await;
+        // Ignore anything typed before the question was asked
+        while lines.try_recv().is_ok() {}
+
+        eprintln!();
+        eprintln!("[approval] {}", request.describe());
+        eprintln!("[approval] Allow? [y/N] ({}s)", self.timeout.as_secs());
+
+        match tokio::time::timeout(self.timeout, lines.recv()).await {
+            Ok(Some(line)) => Ok(parse_answer(&line)),
+            Ok(None) => Err(anyhow!("console input is closed")),
+            Err(_) => {
+                eprintln!("[approval] No answer, denied");
+                Ok(false)
+            }
+        }
+    }
+
+    async fn run_command(&self, request: &ApprovalRequest) -> Result<bool> {
+        let command = self
+            .command
+            .as_deref()
+            .ok_or_else(|| anyhow!("approval.command is not set"))?;
+
+        let mut cmd = shell_command(command);
+        cmd.envs(request.env())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true);
+        let mut child = cmd.spawn().map_err(|e| anyhow!("failed to run approval command: {}", e))?;
+
+        match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(status) => Ok(status?.success()),
+            Err(_) => {
+                let _ = child.kill().await;
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Read console lines on a dedicated thread (stdin reads cannot be cancelled)
+fn spawn_console_reader() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel(8);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Only an explicit yes approves
+fn parse_answer(line: &str) -> bool {
+    matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(action: ApprovalAction, session_id: Uuid) -> ApprovalRequest {
+        ApprovalRequest {
+            action,
+            instance_id: Uuid::new_v4(),
+            session_id,
+            requester: "alice".to_string(),
+            client_ip: "10.0.0.1".to_string(),
+            cwd: Some("/srv/project".to_string()),
+        }
+    }
+
+    fn approver(command: &str, timeout_secs: u64) -> Approver {
+        Approver::from_config(&ApprovalConfig {
+            mode: ApprovalMode::Command,
+            command: Some(command.to_string()),
+            timeout_secs,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert!(parse_answer("y"));
+        assert!(parse_answer(" YES \r"));
+        assert!(!parse_answer(""));
+        assert!(!parse_answer("no"));
+        assert!(!parse_answer("yep"));
+    }
+
+    #[test]
+    fn test_off_mode() {
+        assert!(Approver::from_config(&ApprovalConfig::default()).is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_approval_and_seen_sessions() {
+        // Approves instance creation only
+        let approver = approver(r#"test "$TUNNEL_APPROVAL_ACTION" = create_instance"#, 10);
+        let session = Uuid::new_v4();
+
+        let create = request(ApprovalAction::CreateInstance, session);
+        assert!(approver.decide(&create).await);
+        assert!(approver.take_approved_create(create.instance_id));
+        assert!(!approver.take_approved_create(create.instance_id));
+
+        // The approved session attaches without being asked again, others are asked
+        assert!(approver.decide(&request(ApprovalAction::Attach, session)).await);
+        assert!(!approver.decide(&request(ApprovalAction::Attach, Uuid::new_v4())).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_timeout_denies() {
+        let approver = approver("sleep 5", 1);
+        let create = request(ApprovalAction::CreateInstance, Uuid::new_v4());
+        assert!(!approver.decide(&create).await);
+        assert!(!approver.take_approved_create(create.instance_id));
+    }
+}
@@ -23,6 +23,10 @@ pub struct Args {
     #[arg(long, env = "TUNNEL_SHARE_TOKEN")]
     pub share_token: Option<String>,
 
+    /// Enrollment secret issued by the server operator (needed for first registration)
+    #[arg(long, env = "TUNNEL_ENROLLMENT_SECRET")]
+    pub enrollment_secret: Option<String>,
+
     /// Configuration file path
     #[arg(short, long, default_value = "agent.toml")]
     pub config: PathBuf,
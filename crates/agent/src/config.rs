@@ -1,16 +1,20 @@
 //! Configuration management for the agent
 
+use std::path::PathBuf;
+
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use rand::Rng;
 use uuid::Uuid;
 
 use common::{
-    AgentConfig, AgentIdentityConfig, DirectoryConfig, LoggingConfig, ServerConnectionConfig,
-    TerminalConfig, TokenConfig,
+    AgentConfig, AgentIdentityConfig, ApprovalConfig, ApprovalMode, DirectoryConfig, E2eConfig, LoggingConfig, ServerConnectionConfig,
+    ShareMode, TerminalConfig, TokenConfig, TokenKind,
 };
 
 use crate::cli::Args;
+use crate::e2e::MIN_PASSPHRASE_LEN;
+use crate::tls::{self, SpkiPin};
 
 /// Runtime configuration with resolved values
 pub struct AgentRuntime {
@@ -22,6 +26,12 @@ pub struct AgentRuntime {
     pub admin_token: String,
     /// Resolved share token
     pub share_token: String,
+    /// Resolved agent key (pinned by the server on enrollment)
+    pub agent_key: String,
+    /// Path of the configuration file (rotated tokens are saved here)
+    pub config_path: PathBuf,
+    /// Parsed `server.pinned_spki` fingerprints
+    pub pinned_spki: Vec<SpkiPin>,
 }
 
 impl AgentRuntime {
@@ -48,6 +58,9 @@ impl AgentRuntime {
         if let Some(ref token) = args.share_token {
             config.tokens.share = Some(token.clone());
         }
+        if let Some(ref secret) = args.enrollment_secret {
+            config.agent.enrollment_secret = Some(secret.clone());
+        }
 
         // Validate configuration
         if config.server.url.is_empty() {
@@ -55,6 +68,31 @@ impl AgentRuntime {
                 "Server URL is required. Use --server or set in config file."
             ));
         }
+        if config.server.client_cert_path.is_some() != config.server.client_key_path.is_some() {
+            return Err(anyhow!(
+                "server.client_cert_path and server.client_key_path must be set together"
+            ));
+        }
+        let uses_tls = config.server.url.starts_with("https://") || config.server.url.starts_with("wss://");
+        if !uses_tls && (config.server.ca_file.is_some() || !config.server.pinned_spki.is_empty()) {
+            return Err(anyhow!(
+                "server.ca_file and server.pinned_spki require an https:// or wss:// server URL"
+            ));
+        }
+        let pinned_spki = tls::parse_pins(&config.server.pinned_spki)?;
+        if config.e2e.enabled
+            && config.e2e.passphrase.as_deref().map_or(0, |p| p.chars().count()) < MIN_PASSPHRASE_LEN
+        {
+            return Err(anyhow!(
+                "e2e.passphrase must be at least {} characters when e2e.enabled is true",
+                MIN_PASSPHRASE_LEN
+            ));
+        }
+        if config.approval.mode == ApprovalMode::Command
+            && config.approval.command.as_deref().is_none_or(|c| c.trim().is_empty())
+        {
+            return Err(anyhow!("approval.command is required when approval.mode is \"command\""));
+        }
 
         // Resolve agent ID (generate if not specified)
         let agent_id = config
@@ -76,14 +114,28 @@ impl AgentRuntime {
             .clone()
             .unwrap_or_else(generate_token);
 
+        // Resolve agent key (generate if not specified)
+        // The server pins this key on first registration, so it must be persisted
+        let agent_key_generated = config.tokens.agent_key.is_none();
+        let agent_key = config
+            .tokens
+            .agent_key
+            .clone()
+            .unwrap_or_else(generate_token);
+
         // Update config with resolved values
         config.agent.id = Some(agent_id.to_string());
         config.tokens.admin = Some(admin_token.clone());
         config.tokens.share = Some(share_token.clone());
+        config.tokens.agent_key = Some(agent_key.clone());
 
         // Save updated config if it was auto-generated
         if !args.config.exists() || args.init {
             config.to_file(&args.config).ok(); // Ignore save errors
+        } else if agent_key_generated {
+            config
+                .to_file(&args.config)
+                .map_err(|e| anyhow!("Failed to save generated agent key: {}", e))?;
         }
 
         Ok(Self {
@@ -91,9 +143,33 @@ impl AgentRuntime {
             agent_id,
             admin_token,
             share_token,
+            agent_key,
+            config_path: args.config.clone(),
+            pinned_spki,
         })
     }
 
+    /// Save a token rotated by the server, then start using it
+    /// The in-memory token is only replaced once the config file was written,
+    /// so a restart never falls back to a token the server has revoked
+    pub fn apply_rotated_token(&mut self, kind: TokenKind, token: String) -> Result<()> {
+        let mut config = self.config.clone();
+        match kind {
+            TokenKind::Admin => config.tokens.admin = Some(token.clone()),
+            TokenKind::Share => config.tokens.share = Some(token.clone()),
+        }
+        config
+            .to_file(&self.config_path)
+            .map_err(|e| anyhow!("Failed to save rotated token to {}: {}", self.config_path.display(), e))?;
+
+        self.config = config;
+        match kind {
+            TokenKind::Admin => self.admin_token = token,
+            TokenKind::Share => self.share_token = token,
+        }
+        Ok(())
+    }
+
     /// Create a default configuration
     fn default_config() -> AgentConfig {
         AgentConfig {
@@ -101,6 +177,10 @@ impl AgentRuntime {
                 url: String::new(),
                 reconnect_interval: 5,
                 heartbeat_interval: 30,
+                client_cert_path: None,
+                client_key_path: None,
+                ca_file: None,
+                pinned_spki: Vec::new(),
             },
             agent: AgentIdentityConfig {
                 name: hostname::get()
@@ -108,13 +188,17 @@ impl AgentRuntime {
                     .and_then(|h| h.into_string().ok())
                     .unwrap_or_else(|| "Unknown".to_string()),
                 id: None,
+                enrollment_secret: None,
             },
             tokens: TokenConfig {
                 admin: None,
                 share: None,
+                share_mode: ShareMode::default(),
+                agent_key: None,
             },
             directories: DirectoryConfig {
-                allowed: vec![],
+                // New configs only allow the directory the agent was set up in
+                allowed: std::env::current_dir().ok().into_iter().collect(),
                 default: std::env::current_dir().ok(),
             },
             logging: LoggingConfig {
@@ -123,6 +207,8 @@ impl AgentRuntime {
                 rotation: "daily".to_string(),
             },
             terminal: TerminalConfig::default(),
+            e2e: E2eConfig::default(),
+            approval: ApprovalConfig::default(),
         }
     }
 }
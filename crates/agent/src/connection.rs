@@ -6,15 +6,18 @@ use anyhow::{anyhow, Result};
 use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::Message};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use common::{AgentMessage, ExistingInstance, ServerToAgentMessage};
+use common::{AgentMessage, ErrorCode, ExistingInstance, ServerToAgentMessage, TunnelError};
 
+use crate::approval::{ApprovalRequest, Approver};
 use crate::config::AgentRuntime;
+use crate::e2e::StreamCipher;
 use crate::instance::InstanceManager;
 use crate::pty::PtyMode;
+use crate::tls;
 
 /// Tunnel connection handler
 pub struct TunnelConnection {
@@ -22,6 +25,10 @@ pub struct TunnelConnection {
     pub runtime: AgentRuntime,
     /// Instance manager
     instances: Arc<tokio::sync::Mutex<InstanceManager>>,
+    /// End-to-end cipher of the PTY streams (None = plaintext)
+    cipher: Option<StreamCipher>,
+    /// Local approval of remote requests (None = approval off)
+    approver: Option<Arc<Approver>>,
 }
 
 impl TunnelConnection {
@@ -35,9 +42,50 @@ impl TunnelConnection {
             PtyMode::Background
         };
 
+        let mut instances = InstanceManager::with_mode(pty_mode);
+        if runtime.config.directories.allowed.is_empty() {
+            warn!("directories.allowed is empty - instances may be created in any directory");
+        } else {
+            instances.set_allowed_directories(&runtime.config.directories.allowed);
+        }
+
+        let cipher = match (&runtime.config.e2e.passphrase, runtime.config.e2e.enabled) {
+            (Some(passphrase), true) => {
+                info!("End-to-end encryption enabled - the server only relays ciphertext");
+                Some(StreamCipher::new(passphrase, runtime.agent_id))
+            }
+            _ => None,
+        };
+
+        let approver = Approver::from_config(&runtime.config.approval).map(Arc::new);
+        if approver.is_some() {
+            info!("Local approval enabled - remote creates and first attaches must be approved on this machine");
+        }
+
         Self {
             runtime,
-            instances: Arc::new(tokio::sync::Mutex::new(InstanceManager::with_mode(pty_mode))),
+            instances: Arc::new(tokio::sync::Mutex::new(instances)),
+            cipher,
+            approver,
+        }
+    }
+
+    /// Encode PTY output for the server, sealing it when end-to-end encryption is on
+    fn encode_output(&self, instance_id: Uuid, data: &[u8]) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal_output(instance_id, data),
+            None => Ok(base64::engine::general_purpose::STANDARD.encode(data)),
+        }
+    }
+
+    /// Decode PTY input from the server, opening it when end-to-end encryption is on
+    /// Plaintext input is refused for encrypted instances, so the server cannot inject keystrokes
+    fn decode_input(&mut self, instance_id: Uuid, data: &str) -> Result<Vec<u8>> {
+        match &mut self.cipher {
+            Some(cipher) => cipher.open_input(instance_id, data),
+            None => base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map_err(|e| anyhow!("Invalid base64 input: {}", e)),
         }
     }
 
@@ -64,13 +112,15 @@ impl TunnelConnection {
     pub async fn run(&mut self) -> Result<()> {
         let url = &self.runtime.config.server.url;
         let ws_url = Self::build_ws_url(url)?;
+        let connector = tls::connector(&self.runtime.config.server)?;
 
         info!("Connecting to server: {}", ws_url);
 
         // Connect to the WebSocket server
-        let (ws_stream, _response) = connect_async(&ws_url)
+        let (ws_stream, _response) = connect_async_tls_with_config(&ws_url, None, false, connector)
             .await
             .map_err(|e| anyhow!("Failed to connect: {}", e))?;
+        tls::verify_pins(ws_stream.get_ref(), &self.runtime.pinned_spki)?;
 
         info!("Connected to server");
 
@@ -79,6 +129,9 @@ impl TunnelConnection {
         // Create channel for PTY output
         let (pty_tx, mut pty_rx) = mpsc::channel::<(Uuid, Vec<u8>)>(256);
 
+        // Channel for messages produced by background tasks (approval answers)
+        let (out_tx, mut out_rx) = mpsc::channel::<AgentMessage>(32);
+
         // ====================================================================
         // Reconnection Recovery: Rebind existing instances to new channel
         // ====================================================================
@@ -99,6 +152,7 @@ impl TunnelConnection {
                 .map(|info| ExistingInstance {
                     id: info.id,
                     cwd: info.cwd,
+                    encrypted: self.cipher.is_some(),
                 })
                 .collect()
         };
@@ -116,7 +170,11 @@ impl TunnelConnection {
             name: self.runtime.config.agent.name.clone(),
             admin_token: self.runtime.admin_token.clone(),
             share_token: self.runtime.share_token.clone(),
+            share_mode: self.runtime.config.tokens.share_mode,
             existing_instances,
+            agent_key: Some(self.runtime.agent_key.clone()),
+            enrollment_secret: self.runtime.config.agent.enrollment_secret.clone(),
+            approval_required: self.approver.is_some(),
         };
 
         ws_sink
@@ -134,15 +192,20 @@ impl TunnelConnection {
             let buffers = instances.drain_all_buffers().await;
             for (instance_id, data) in buffers {
                 if !data.is_empty() {
-                    let msg = AgentMessage::PtyOutput {
-                        instance_id,
-                        data: base64::engine::general_purpose::STANDARD.encode(&data),
+                    let data_len = data.len();
+                    let data = match self.encode_output(instance_id, &data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!("Failed to encrypt buffered output for instance {}: {}", instance_id, e);
+                            continue;
+                        }
                     };
+                    let msg = AgentMessage::PtyOutput { instance_id, data };
                     if let Ok(json) = msg.to_json() {
                         if let Err(e) = ws_sink.send(Message::Text(json)).await {
                             warn!("Failed to send buffered output for instance {}: {}", instance_id, e);
                         } else {
-                            debug!("Sent {} bytes of buffered output for instance {}", data.len(), instance_id);
+                            debug!("Sent {} bytes of buffered output for instance {}", data_len, instance_id);
                         }
                     }
                 }
@@ -164,7 +227,7 @@ impl TunnelConnection {
                 msg = ws_stream.next() => {
                     match msg {
                         Some(Ok(Message::Text(text))) => {
-                            if let Err(e) = self.handle_server_message(&text, &mut ws_sink, &pty_tx).await {
+                            if let Err(e) = self.handle_server_message(&text, &mut ws_sink, &pty_tx, &out_tx).await {
                                 error!("Error handling message: {}", e);
                             }
                         }
@@ -201,10 +264,14 @@ impl TunnelConnection {
 
                 // Handle PTY output
                 Some((instance_id, data)) = pty_rx.recv() => {
-                    let msg = AgentMessage::PtyOutput {
-                        instance_id,
-                        data: base64::engine::general_purpose::STANDARD.encode(&data),
+                    let data = match self.encode_output(instance_id, &data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!("Failed to encrypt PTY output: {}", e);
+                            continue;
+                        }
                     };
+                    let msg = AgentMessage::PtyOutput { instance_id, data };
                     if let Ok(json) = msg.to_json() {
                         if let Err(e) = ws_sink.send(Message::Text(json)).await {
                             warn!("Failed to send PTY output: {}", e);
@@ -212,6 +279,15 @@ impl TunnelConnection {
                     }
                 }
 
+                // Send messages from background tasks
+                Some(msg) = out_rx.recv() => {
+                    if let Ok(json) = msg.to_json() {
+                        if let Err(e) = ws_sink.send(Message::Text(json)).await {
+                            warn!("Failed to send message: {}", e);
+                        }
+                    }
+                }
+
                 // Send heartbeat - failure triggers reconnection
                 _ = heartbeat_timer.tick() => {
                     let msg = AgentMessage::Heartbeat;
@@ -245,6 +321,7 @@ impl TunnelConnection {
             Message,
         >,
         pty_tx: &mpsc::Sender<(Uuid, Vec<u8>)>,
+        out_tx: &mpsc::Sender<AgentMessage>,
     ) -> Result<()> {
         let msg: ServerToAgentMessage = ServerToAgentMessage::from_json(text)?;
 
@@ -252,16 +329,37 @@ impl TunnelConnection {
             ServerToAgentMessage::Registered { message } => {
                 info!("Registration successful: {}", message);
             }
+            ServerToAgentMessage::RegistrationRejected { reason } => {
+                // The server closes the connection after rejecting
+                error!("Registration rejected by server: {}", reason);
+            }
             ServerToAgentMessage::CreateInstance { instance_id, cwd } => {
                 info!("Creating instance {} in {}", instance_id, cwd);
 
+                // Only creations approved on this machine are carried out
+                if let Some(approver) = &self.approver {
+                    if !approver.take_approved_create(instance_id) {
+                        warn!("Refusing to create instance {}: it was not approved on this machine", instance_id);
+                        let msg = AgentMessage::Error {
+                            message: "Instance creation was not approved on the agent's machine".to_string(),
+                            code: Some(ErrorCode::ApprovalDenied),
+                            instance_id: Some(instance_id),
+                        };
+                        if let Ok(json) = msg.to_json() {
+                            ws_sink.send(Message::Text(json)).await.ok();
+                        }
+                        return Ok(());
+                    }
+                }
+
                 let mut instances = self.instances.lock().await;
                 match instances.create_instance(instance_id, &cwd, pty_tx.clone()).await {
-                    Ok(_) => {
+                    Ok(resolved_cwd) => {
                         // Send instance created confirmation
                         let msg = AgentMessage::InstanceCreated {
                             instance_id,
-                            cwd: cwd.clone(),
+                            cwd: resolved_cwd,
+                            encrypted: self.cipher.is_some(),
                         };
                         if let Ok(json) = msg.to_json() {
                             ws_sink.send(Message::Text(json)).await.ok();
@@ -270,8 +368,14 @@ impl TunnelConnection {
                     }
                     Err(e) => {
                         error!("Failed to create instance: {}", e);
+                        let code = e
+                            .downcast_ref::<TunnelError>()
+                            .and_then(TunnelError::code)
+                            .unwrap_or(ErrorCode::InstanceCreateFailed);
                         let msg = AgentMessage::Error {
                             message: format!("Failed to create instance: {}", e),
+                            code: Some(code),
+                            instance_id: Some(instance_id),
                         };
                         if let Ok(json) = msg.to_json() {
                             ws_sink.send(Message::Text(json)).await.ok();
@@ -294,11 +398,14 @@ impl TunnelConnection {
                 }
             }
             ServerToAgentMessage::PtyInput { instance_id, data } => {
-                if let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(&data) {
-                    let instances = self.instances.lock().await;
-                    if let Err(e) = instances.write_to_instance(instance_id, &bytes).await {
-                        warn!("Failed to write to instance {}: {}", instance_id, e);
+                match self.decode_input(instance_id, &data) {
+                    Ok(bytes) => {
+                        let instances = self.instances.lock().await;
+                        if let Err(e) = instances.write_to_instance(instance_id, &bytes).await {
+                            warn!("Failed to write to instance {}: {}", instance_id, e);
+                        }
                     }
+                    Err(e) => warn!("Dropped input for instance {}: {}", instance_id, e),
                 }
             }
             ServerToAgentMessage::Resize { instance_id, size } => {
@@ -318,6 +425,63 @@ impl TunnelConnection {
             ServerToAgentMessage::Error { message } => {
                 error!("Server error: {}", message);
             }
+            ServerToAgentMessage::RotateToken { kind, token } => {
+                info!("Server rotated the {} token", kind.as_str());
+
+                let msg = match self.runtime.apply_rotated_token(kind, token) {
+                    Ok(()) => {
+                        info!("New {} token saved to {}", kind.as_str(), self.runtime.config_path.display());
+                        AgentMessage::TokenRotated {
+                            kind,
+                            success: true,
+                            error: None,
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to apply rotated {} token: {}", kind.as_str(), e);
+                        AgentMessage::TokenRotated {
+                            kind,
+                            success: false,
+                            error: Some(e.to_string()),
+                        }
+                    }
+                };
+                if let Ok(json) = msg.to_json() {
+                    ws_sink.send(Message::Text(json)).await.ok();
+                }
+            }
+            ServerToAgentMessage::ApprovalRequest {
+                request_id,
+                action,
+                instance_id,
+                session_id,
+                requester,
+                client_ip,
+                cwd,
+            } => {
+                let request = ApprovalRequest {
+                    action,
+                    instance_id,
+                    session_id,
+                    requester,
+                    client_ip,
+                    cwd,
+                };
+
+                // Waiting for the person at the machine must not block the connection
+                let approver = self.approver.clone();
+                let out_tx = out_tx.clone();
+                tokio::spawn(async move {
+                    let approved = match approver {
+                        Some(approver) => approver.decide(&request).await,
+                        None => {
+                            warn!("Server asked for approval but approval is off, approving");
+                            true
+                        }
+                    };
+                    let _ = out_tx.send(AgentMessage::ApprovalResponse { request_id, approved }).await;
+                });
+            }
         }
 
         Ok(())
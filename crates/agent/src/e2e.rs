@@ -0,0 +1,237 @@
+//! End-to-end encryption of terminal streams
+//!
+//! With `[e2e]` enabled, PTY input and output are sealed with AES-256-GCM
+//! between the browser and the agent; the server only relays and stores the
+//! ciphertext. The key is derived from a passphrase that is entered in the
+//! browser and never sent to the server:
+//!
+//! ```text
+//! key      = PBKDF2-HMAC-SHA256(passphrase, "claude-web-tunnel/e2e/<agent_id>", 210000) (32 bytes)
+//! payload  = base64(0x01 || nonce(12) || AES-256-GCM(key, nonce, aad, timestamp_ms(u64 BE) || data))
+//! aad      = "<instance_id>/input" or "<instance_id>/output"
+//! ```
+//!
+//! The AAD binds every message to its instance and direction, so the server
+//! cannot move ciphertext between terminals or echo output back as input.
+//! Input older than `MAX_INPUT_AGE_MS`, or already seen, is refused so recorded
+//! keystrokes cannot be replayed.
+
+use std::collections::{HashSet, VecDeque};
+use std::num::NonZeroU32;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use uuid::Uuid;
+
+/// Envelope format version
+const VERSION: u8 = 1;
+
+/// PBKDF2 iterations for the passphrase
+const PBKDF2_ITERATIONS: u32 = 210_000;
+
+/// Input older (or further in the future) than this is refused
+const MAX_INPUT_AGE_MS: u64 = 120_000;
+
+/// Minimum passphrase length accepted in the config
+pub const MIN_PASSPHRASE_LEN: usize = 12;
+
+/// Direction of a terminal stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Browser to agent
+    Input,
+    /// Agent to browser
+    Output,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+}
+
+/// Seals terminal output and opens terminal input of one agent
+pub struct StreamCipher {
+    key: LessSafeKey,
+    rng: SystemRandom,
+    /// Nonces of recently accepted input, with when they were accepted (oldest first)
+    seen: VecDeque<([u8; NONCE_LEN], u64)>,
+    seen_index: HashSet<[u8; NONCE_LEN]>,
+}
+
+impl StreamCipher {
+    /// Derive the stream key from the passphrase, salted with the agent ID
+    pub fn new(passphrase: &str, agent_id: Uuid) -> Self {
+        let salt = format!("claude-web-tunnel/e2e/{}", agent_id);
+        let mut key = [0u8; 32];
+        ring::pbkdf2::derive(
+            ring::pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are non-zero"),
+            salt.as_bytes(),
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        let key = UnboundKey::new(&AES_256_GCM, &key).expect("AES-256 key is 32 bytes");
+
+        Self {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+            seen: VecDeque::new(),
+            seen_index: HashSet::new(),
+        }
+    }
+
+    /// Encrypt terminal output for the browser
+    pub fn seal_output(&self, instance_id: Uuid, data: &[u8]) -> Result<String> {
+        self.seal(instance_id, Direction::Output, now_ms(), data)
+    }
+
+    /// Decrypt terminal input from the browser, refusing stale and replayed messages
+    pub fn open_input(&mut self, instance_id: Uuid, payload: &str) -> Result<Vec<u8>> {
+        self.open_at(instance_id, Direction::Input, payload, now_ms())
+    }
+
+    fn seal(&self, instance_id: Uuid, direction: Direction, timestamp_ms: u64, data: &[u8]) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Failed to generate a nonce"))?;
+
+        let mut buffer = Vec::with_capacity(8 + data.len() + AES_256_GCM.tag_len());
+        buffer.extend_from_slice(&timestamp_ms.to_be_bytes());
+        buffer.extend_from_slice(data);
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), aad(instance_id, direction), &mut buffer)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+
+        let mut envelope = Vec::with_capacity(1 + NONCE_LEN + buffer.len());
+        envelope.push(VERSION);
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&buffer);
+        Ok(STANDARD.encode(envelope))
+    }
+
+    fn open_at(&mut self, instance_id: Uuid, direction: Direction, payload: &str, now_ms: u64) -> Result<Vec<u8>> {
+        let envelope = STANDARD
+            .decode(payload)
+            .map_err(|_| anyhow!("Encrypted payload is not valid base64"))?;
+        if envelope.len() < 1 + NONCE_LEN + 8 + AES_256_GCM.tag_len() || envelope[0] != VERSION {
+            return Err(anyhow!("Payload is not an end-to-end encrypted message"));
+        }
+        let nonce: [u8; NONCE_LEN] = envelope[1..1 + NONCE_LEN].try_into().expect("length checked");
+
+        let mut buffer = envelope[1 + NONCE_LEN..].to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(Nonce::assume_unique_for_key(nonce), aad(instance_id, direction), &mut buffer)
+            .map_err(|_| anyhow!("Decryption failed: wrong passphrase or tampered message"))?;
+
+        let timestamp_ms = u64::from_be_bytes(plaintext[..8].try_into().expect("length checked"));
+        if timestamp_ms.abs_diff(now_ms) > MAX_INPUT_AGE_MS {
+            return Err(anyhow!("Encrypted input is too old (clock skew or replay)"));
+        }
+        self.remember(nonce, now_ms)?;
+
+        Ok(plaintext[8..].to_vec())
+    }
+
+    /// Record an accepted nonce; nonces outside the age window are forgotten
+    /// (their messages are refused by the timestamp check anyway)
+    fn remember(&mut self, nonce: [u8; NONCE_LEN], now_ms: u64) -> Result<()> {
+        while let Some((old, at)) = self.seen.front() {
+            if now_ms.saturating_sub(*at) <= 2 * MAX_INPUT_AGE_MS {
+                break;
+            }
+            self.seen_index.remove(old);
+            self.seen.pop_front();
+        }
+
+        if !self.seen_index.insert(nonce) {
+            return Err(anyhow!("Encrypted input was replayed"));
+        }
+        self.seen.push_back((nonce, now_ms));
+        Ok(())
+    }
+}
+
+fn aad(instance_id: Uuid, direction: Direction) -> Aad<Vec<u8>> {
+    Aad::from(format!("{}/{}", instance_id, direction.as_str()).into_bytes())
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AGENT: &str = "00000000-0000-0000-0000-000000000001";
+    const INSTANCE: &str = "00000000-0000-0000-0000-0000000000aa";
+
+    fn cipher() -> StreamCipher {
+        StreamCipher::new("correct horse battery", AGENT.parse().unwrap())
+    }
+
+    #[test]
+    fn test_roundtrip_and_binding() {
+        let mut c = cipher();
+        let instance: Uuid = INSTANCE.parse().unwrap();
+        let now = 1_700_000_000_000;
+
+        let sealed = c.seal(instance, Direction::Input, now, b"ls -la\r").unwrap();
+        assert!(!sealed.contains("ls"));
+        assert_eq!(c.open_at(instance, Direction::Input, &sealed, now + 5).unwrap(), b"ls -la\r");
+
+        // Bound to its instance and direction
+        let sealed = c.seal(instance, Direction::Output, now, b"secret").unwrap();
+        assert!(c.open_at(instance, Direction::Input, &sealed, now).is_err());
+        let sealed = c.seal(instance, Direction::Input, now, b"secret").unwrap();
+        assert!(c.open_at(Uuid::new_v4(), Direction::Input, &sealed, now).is_err());
+
+        // Another passphrase or agent derives another key
+        let mut other = StreamCipher::new("correct horse battery", Uuid::new_v4());
+        assert!(other.open_at(instance, Direction::Input, &sealed, now).is_err());
+
+        // Tampering is detected
+        let mut bytes = STANDARD.decode(&sealed).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(c.open_at(instance, Direction::Input, &STANDARD.encode(bytes), now).is_err());
+        assert!(c.open_at(instance, Direction::Input, "bHMK", now).is_err());
+    }
+
+    #[test]
+    fn test_replay_and_stale_input() {
+        let mut c = cipher();
+        let instance: Uuid = INSTANCE.parse().unwrap();
+        let now = 1_700_000_000_000;
+
+        let sealed = c.seal(instance, Direction::Input, now, b"rm -rf build\r").unwrap();
+        assert!(c.open_at(instance, Direction::Input, &sealed, now).is_ok());
+        let err = c.open_at(instance, Direction::Input, &sealed, now + 1_000).unwrap_err();
+        assert!(err.to_string().contains("replayed"));
+
+        let sealed = c.seal(instance, Direction::Input, now, b"y\r").unwrap();
+        let err = c.open_at(instance, Direction::Input, &sealed, now + MAX_INPUT_AGE_MS + 1).unwrap_err();
+        assert!(err.to_string().contains("too old"));
+    }
+
+    /// Envelope produced by the web client (`web/src/services/e2e.ts`) for the same
+    /// passphrase, agent and instance, so both sides stay compatible
+    #[test]
+    fn test_opens_browser_envelope() {
+        let mut c = cipher();
+        let instance: Uuid = INSTANCE.parse().unwrap();
+        let payload = "AZpC8n9ZCPdIdy2OPFUIzE3/j9vOjVyXcMp8Q9oR0SQYY5wcyPijcvA7aA==";
+        assert_eq!(c.open_at(instance, Direction::Input, payload, 1_700_000_000_000).unwrap(), b"hello\r");
+    }
+}
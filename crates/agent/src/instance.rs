@@ -1,13 +1,16 @@
 //! Instance management for Claude Code instances
 
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
 use tokio::sync::{mpsc, Mutex};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+use common::TunnelError;
+
 use crate::pty::{PtyInstance, PtyMode};
 
 /// Information about an existing instance for reconnection sync
@@ -25,6 +28,8 @@ pub struct InstanceManager {
     instances: HashMap<Uuid, Arc<Mutex<PtyInstance>>>,
     /// Default PTY mode for new instances
     default_mode: PtyMode,
+    /// Canonicalized allowed directories (None = no restriction)
+    allowed_dirs: Option<Vec<PathBuf>>,
 }
 
 impl InstanceManager {
@@ -33,6 +38,7 @@ impl InstanceManager {
         Self {
             instances: HashMap::new(),
             default_mode: PtyMode::Background,
+            allowed_dirs: None,
         }
     }
 
@@ -41,6 +47,7 @@ impl InstanceManager {
         Self {
             instances: HashMap::new(),
             default_mode: mode,
+            allowed_dirs: None,
         }
     }
 
@@ -50,36 +57,61 @@ impl InstanceManager {
         self.default_mode = mode;
     }
 
+    /// Set the allowed directories whitelist (an empty list means no restriction)
+    /// Entries are canonicalized once here; entries that cannot be resolved are skipped
+    pub fn set_allowed_directories(&mut self, dirs: &[PathBuf]) {
+        if dirs.is_empty() {
+            self.allowed_dirs = None;
+            return;
+        }
+
+        let resolved: Vec<PathBuf> = dirs
+            .iter()
+            .filter_map(|dir| match dir.canonicalize() {
+                Ok(canonical) => Some(canonical),
+                Err(e) => {
+                    warn!("Ignoring allowed directory {}: {}", dir.display(), e);
+                    None
+                }
+            })
+            .collect();
+
+        // Fail closed: a configured but unresolvable whitelist must not allow everything
+        if resolved.is_empty() {
+            warn!("No allowed directory could be resolved, instance creation will be refused");
+        }
+        self.allowed_dirs = Some(resolved);
+    }
+
     /// Create a new Claude Code instance with default mode
+    /// Returns the resolved working directory
     pub async fn create_instance(
         &mut self,
         id: Uuid,
         cwd: &str,
         output_tx: mpsc::Sender<(Uuid, Vec<u8>)>,
-    ) -> Result<()> {
+    ) -> Result<String> {
         self.create_instance_with_mode(id, cwd, output_tx, self.default_mode).await
     }
 
     /// Create a new Claude Code instance with specified mode
+    /// Returns the resolved working directory
     pub async fn create_instance_with_mode(
         &mut self,
         id: Uuid,
         cwd: &str,
         output_tx: mpsc::Sender<(Uuid, Vec<u8>)>,
         mode: PtyMode,
-    ) -> Result<()> {
+    ) -> Result<String> {
         if self.instances.contains_key(&id) {
             return Err(anyhow!("Instance {} already exists", id));
         }
 
-        // Validate working directory exists
-        let path = std::path::Path::new(cwd);
-        if !path.exists() {
-            return Err(anyhow!("Directory does not exist: {}", cwd));
-        }
-        if !path.is_dir() {
-            return Err(anyhow!("Path is not a directory: {}", cwd));
-        }
+        // Validate working directory against the whitelist
+        // The shell is started in the resolved path, not the requested one
+        let resolved = resolve_working_dir(cwd, self.allowed_dirs.as_deref())?;
+        let cwd = resolved.to_string_lossy().to_string();
+        let cwd = cwd.as_str();
 
         let instance = PtyInstance::new_with_mode(id, cwd, output_tx, mode)?;
         self.instances.insert(id, Arc::new(Mutex::new(instance)));
@@ -89,7 +121,7 @@ impl InstanceManager {
             PtyMode::Visible => "visible",
         };
         info!("Created {} instance {} in {}", mode_str, id, cwd);
-        Ok(())
+        Ok(cwd.to_string())
     }
 
     /// Close an instance
@@ -222,3 +254,122 @@ impl Default for InstanceManager {
         Self::new()
     }
 }
+
+/// Resolve a requested working directory and check it against the whitelist
+///
+/// The path is canonicalized first, so `..` components and symlinks are resolved
+/// before the prefix check and cannot be used to escape an allowed directory.
+/// `None` allows any existing directory.
+pub fn resolve_working_dir(cwd: &str, allowed_dirs: Option<&[PathBuf]>) -> Result<PathBuf, TunnelError> {
+    let path = Path::new(cwd);
+    let canonical = path
+        .canonicalize()
+        .map_err(|_| TunnelError::InvalidDirectory(format!("Directory does not exist: {}", cwd)))?;
+    if !canonical.is_dir() {
+        return Err(TunnelError::InvalidDirectory(format!("Path is not a directory: {}", cwd)));
+    }
+
+    if let Some(allowed) = allowed_dirs {
+        if !allowed.iter().any(|dir| canonical.starts_with(dir)) {
+            return Err(TunnelError::DirectoryNotAllowed(format!(
+                "{} is outside the allowed directories",
+                cwd
+            )));
+        }
+    }
+
+    Ok(strip_verbatim_prefix(canonical))
+}
+
+/// Strip the `\\?\` prefix added by canonicalize on Windows (cmd.exe cannot use it as cwd)
+#[cfg(windows)]
+fn strip_verbatim_prefix(path: PathBuf) -> PathBuf {
+    let stripped = path
+        .to_str()
+        .and_then(|s| s.strip_prefix(r"\\?\"))
+        .filter(|rest| !rest.starts_with(r"UNC\"))
+        .map(PathBuf::from);
+    stripped.unwrap_or(path)
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim_prefix(path: PathBuf) -> PathBuf {
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a fresh directory tree under the system temp dir
+    fn temp_tree() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cwt-instance-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("allowed/project")).unwrap();
+        std::fs::create_dir_all(root.join("secret")).unwrap();
+        root.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn test_resolve_allowed_directory() {
+        let root = temp_tree();
+        let allowed = [root.join("allowed")];
+        let allowed = Some(&allowed[..]);
+
+        let project = root.join("allowed/project");
+        let resolved = resolve_working_dir(project.to_str().unwrap(), allowed).unwrap();
+        assert_eq!(resolved, project);
+
+        // The allowed directory itself is allowed
+        assert!(resolve_working_dir(root.join("allowed").to_str().unwrap(), allowed).is_ok());
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_resolve_rejects_escapes() {
+        let root = temp_tree();
+        let allowed = [root.join("allowed")];
+        let allowed = Some(&allowed[..]);
+
+        // Outside the whitelist
+        let err = resolve_working_dir(root.join("secret").to_str().unwrap(), allowed).unwrap_err();
+        assert!(matches!(err, TunnelError::DirectoryNotAllowed(_)));
+
+        // `..` escape
+        let dotdot = root.join("allowed/project/../../secret");
+        let err = resolve_working_dir(dotdot.to_str().unwrap(), allowed).unwrap_err();
+        assert!(matches!(err, TunnelError::DirectoryNotAllowed(_)));
+
+        // Sibling with a common string prefix is not inside the allowed directory
+        std::fs::create_dir_all(root.join("allowed-other")).unwrap();
+        let err = resolve_working_dir(root.join("allowed-other").to_str().unwrap(), allowed).unwrap_err();
+        assert!(matches!(err, TunnelError::DirectoryNotAllowed(_)));
+
+        // Symlink escape
+        #[cfg(unix)]
+        {
+            let link = root.join("allowed/link");
+            std::os::unix::fs::symlink(root.join("secret"), &link).unwrap();
+            let err = resolve_working_dir(link.to_str().unwrap(), allowed).unwrap_err();
+            assert!(matches!(err, TunnelError::DirectoryNotAllowed(_)));
+        }
+
+        // Missing directory
+        let err = resolve_working_dir(root.join("missing").to_str().unwrap(), allowed).unwrap_err();
+        assert!(matches!(err, TunnelError::InvalidDirectory(_)));
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_unresolvable_whitelist_fails_closed() {
+        let root = temp_tree();
+        let mut manager = InstanceManager::new();
+        manager.set_allowed_directories(&[root.join("does-not-exist")]);
+
+        let err = resolve_working_dir(root.to_str().unwrap(), manager.allowed_dirs.as_deref()).unwrap_err();
+        assert!(matches!(err, TunnelError::DirectoryNotAllowed(_)));
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -8,11 +8,14 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+mod approval;
 mod cli;
 mod config;
 mod connection;
+mod e2e;
 mod instance;
 mod pty;
+mod tls;
 mod logging;
 
 use anyhow::Result;
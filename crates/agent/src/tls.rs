@@ -0,0 +1,199 @@
+//! TLS settings for the connection to the server
+//!
+//! `server.ca_file` replaces the system trust store with a private CA bundle,
+//! `server.pinned_spki` additionally requires the server certificate's public
+//! key to match one of the configured SHA-256 fingerprints.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{Connector, MaybeTlsStream};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use common::ServerConnectionConfig;
+
+/// SHA-256 of a DER-encoded SubjectPublicKeyInfo
+pub type SpkiPin = [u8; 32];
+
+/// Parse the configured pins (base64 SHA-256, `sha256/` prefix optional)
+pub fn parse_pins(pins: &[String]) -> Result<Vec<SpkiPin>> {
+    pins.iter()
+        .map(|pin| {
+            let encoded = pin.trim();
+            let encoded = encoded.strip_prefix("sha256/").unwrap_or(encoded);
+            STANDARD
+                .decode(encoded)
+                .ok()
+                .and_then(|bytes| SpkiPin::try_from(bytes).ok())
+                .ok_or_else(|| anyhow!("Invalid server.pinned_spki entry {:?}: expected a base64 SHA-256 digest", pin))
+        })
+        .collect()
+}
+
+/// TLS connector for the configured client certificate and CA bundle
+/// The files are read on every connection attempt, so a renewed certificate is picked up
+pub fn connector(server: &ServerConnectionConfig) -> Result<Option<Connector>> {
+    let identity = match (&server.client_cert_path, &server.client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = std::fs::read(cert_path)
+                .map_err(|e| anyhow!("Failed to read client certificate {}: {}", cert_path.display(), e))?;
+            let key = std::fs::read(key_path)
+                .map_err(|e| anyhow!("Failed to read client key {}: {}", key_path.display(), e))?;
+            Some(
+                native_tls::Identity::from_pkcs8(&cert, &key)
+                    .map_err(|e| anyhow!("Invalid client certificate or key (the key must be PKCS#8 PEM): {}", e))?,
+            )
+        }
+        _ => None,
+    };
+
+    if identity.is_none() && server.ca_file.is_none() {
+        return Ok(None);
+    }
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(identity) = identity {
+        builder.identity(identity);
+    }
+    if let Some(ca_path) = &server.ca_file {
+        let pem = std::fs::read(ca_path)
+            .map_err(|e| anyhow!("Failed to read CA file {}: {}", ca_path.display(), e))?;
+        let roots = native_tls::Certificate::stack_from_pem(&pem)
+            .map_err(|e| anyhow!("Invalid CA file {}: {}", ca_path.display(), e))?;
+        if roots.is_empty() {
+            return Err(anyhow!("CA file {} contains no certificates", ca_path.display()));
+        }
+        // Only the private CA is trusted, not the system store
+        builder.disable_built_in_roots(true);
+        for root in roots {
+            builder.add_root_certificate(root);
+        }
+    }
+
+    let connector = builder
+        .build()
+        .map_err(|e| anyhow!("Failed to build TLS connector: {}", e))?;
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
+/// Check the server certificate of an established connection against the pins
+/// Runs before anything (tokens included) is sent to the server
+pub fn verify_pins(stream: &MaybeTlsStream<TcpStream>, pins: &[SpkiPin]) -> Result<()> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let MaybeTlsStream::NativeTls(tls) = stream else {
+        return Err(anyhow!("server.pinned_spki is set but the connection is not using TLS"));
+    };
+    let cert = tls
+        .get_ref()
+        .peer_certificate()
+        .map_err(|e| anyhow!("Failed to read the server certificate: {}", e))?
+        .ok_or_else(|| anyhow!("Server did not present a certificate"))?;
+    let der = cert
+        .to_der()
+        .map_err(|e| anyhow!("Failed to encode the server certificate: {}", e))?;
+
+    check_pins(&der, pins)
+}
+
+/// Compare the public key of a DER certificate with the pins
+fn check_pins(cert_der: &[u8], pins: &[SpkiPin]) -> Result<()> {
+    let actual = spki_fingerprint(cert_der)?;
+    if pins.contains(&actual) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Server certificate public key does not match server.pinned_spki (server presented sha256/{})",
+            STANDARD.encode(actual)
+        ))
+    }
+}
+
+/// SHA-256 of the certificate's SubjectPublicKeyInfo
+fn spki_fingerprint(cert_der: &[u8]) -> Result<SpkiPin> {
+    let (_, cert) = X509Certificate::from_der(cert_der)
+        .map_err(|e| anyhow!("Failed to parse the server certificate: {}", e))?;
+    Ok(Sha256::digest(cert.public_key().raw).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate for `tunnel.internal`
+    const CERT_PEM: &[u8] = b"-----BEGIN CERTIFICATE-----
+MIIBizCCATGgAwIBAgIUD8tSx6fYKzpTmcR/XAxqHsV11yIwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPdHVubmVsLmludGVybmFsMCAXDTI2MTAxNjIyMjExMloYDzIx
+MjYwOTIyMjIyMTEyWjAaMRgwFgYDVQQDDA90dW5uZWwuaW50ZXJuYWwwWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAR0N3ToKe1YqTLa7Mw3spcTGqDDfaHVIrjyba+T
+z2JI0+eKD9WCDNnxdRC6Csi8R2t2YwEdQvVOoHO6hE7X12U9o1MwUTAdBgNVHQ4E
+FgQUsIuIQfhlG5R3qV17fiGNUlfMUzkwHwYDVR0jBBgwFoAUsIuIQfhlG5R3qV17
+fiGNUlfMUzkwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEAon+d
+pgFbf3iUgNDn6EB/HVj33QM5rvpCrW0IAHAZYMcCICicAMyxqdqJWxulvJ6frYQw
+/AfdQEbir+Dopak4yby5
+-----END CERTIFICATE-----
+";
+
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    const CERT_PIN: &str = "q58kaXlLHHc+0i7Fe0TwwruB0c5WimIkFDr2g2NJdGk=";
+
+    fn cert_der() -> Vec<u8> {
+        native_tls::Certificate::from_pem(CERT_PEM).unwrap().to_der().unwrap()
+    }
+
+    #[test]
+    fn test_parse_pins() {
+        let pins = parse_pins(&[CERT_PIN.to_string(), format!("sha256/{}", CERT_PIN)]).unwrap();
+        assert_eq!(pins.len(), 2);
+        assert_eq!(pins[0], pins[1]);
+
+        assert!(parse_pins(&["not base64!".to_string()]).is_err());
+        // A SHA-1 sized digest is rejected
+        assert!(parse_pins(&[STANDARD.encode([0u8; 20])]).is_err());
+    }
+
+    #[test]
+    fn test_check_pins() {
+        let der = cert_der();
+        let good = parse_pins(&[CERT_PIN.to_string()]).unwrap();
+        let other = [[7u8; 32]];
+
+        assert!(check_pins(&der, &good).is_ok());
+        assert!(check_pins(&der, &[other[0], good[0]]).is_ok());
+
+        let err = check_pins(&der, &other).unwrap_err().to_string();
+        assert!(err.contains("does not match server.pinned_spki"));
+        assert!(err.contains(CERT_PIN));
+    }
+
+    #[test]
+    fn test_connector_with_ca_file() {
+        let dir = std::env::temp_dir().join(format!("agent-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut server = ServerConnectionConfig {
+            url: "wss://tunnel.internal".to_string(),
+            reconnect_interval: 5,
+            heartbeat_interval: 30,
+            client_cert_path: None,
+            client_key_path: None,
+            ca_file: None,
+            pinned_spki: Vec::new(),
+        };
+
+        // Default settings keep tungstenite's own connector
+        assert!(connector(&server).unwrap().is_none());
+
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, CERT_PEM).unwrap();
+        server.ca_file = Some(ca_path.clone());
+        assert!(connector(&server).unwrap().is_some());
+
+        std::fs::write(&ca_path, b"").unwrap();
+        assert!(connector(&server).is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
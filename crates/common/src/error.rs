@@ -29,6 +29,14 @@ pub enum TunnelError {
     #[error("Agent is offline: {0}")]
     AgentOffline(String),
 
+    /// Working directory outside the allowed directories
+    #[error("Directory not allowed: {0}")]
+    DirectoryNotAllowed(String),
+
+    /// Working directory missing or not a directory
+    #[error("Invalid directory: {0}")]
+    InvalidDirectory(String),
+
     /// PTY error
     #[error("PTY error: {0}")]
     PtyError(String),
@@ -68,5 +76,17 @@ impl From<serde_json::Error> for TunnelError {
     }
 }
 
+impl TunnelError {
+    /// Machine-readable code for errors reported over the protocol
+    pub fn code(&self) -> Option<crate::protocol::ErrorCode> {
+        use crate::protocol::ErrorCode;
+        match self {
+            TunnelError::DirectoryNotAllowed(_) => Some(ErrorCode::DirectoryNotAllowed),
+            TunnelError::InvalidDirectory(_) => Some(ErrorCode::InvalidDirectory),
+            _ => None,
+        }
+    }
+}
+
 /// Result type with TunnelError
 pub type TunnelResult<T> = Result<T, TunnelError>;
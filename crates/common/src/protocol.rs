@@ -7,7 +7,12 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::types::{AgentInfo, AuditLogEntry, GlobalStats, Instance, Role, TerminalSize};
+use chrono::{DateTime, Utc};
+
+use crate::types::{
+    AgentInfo, ApprovalAction, AuditChainReport, AuditLogEntry, GlobalStats, Instance, Role, Scopes, SessionInfo, ShareMode,
+    TerminalSize, TokenKind,
+};
 
 // ============================================================================
 // Reconnection Support
@@ -20,6 +25,31 @@ pub struct ExistingInstance {
     pub id: Uuid,
     /// Working directory
     pub cwd: String,
+    /// PTY streams are end-to-end encrypted
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+// ============================================================================
+// Error Codes
+// ============================================================================
+
+/// Machine-readable error codes carried alongside error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Working directory is outside the agent's `directories.allowed` list
+    DirectoryNotAllowed,
+    /// Working directory does not exist or is not a directory
+    InvalidDirectory,
+    /// Instance could not be created for another reason
+    InstanceCreateFailed,
+    /// Session is read-only (viewer)
+    ReadOnly,
+    /// Command line matched the server's command blocklist
+    CommandBlocked,
+    /// The person at the agent's machine denied the request, or did not answer in time
+    ApprovalDenied,
 }
 
 // ============================================================================
@@ -40,9 +70,21 @@ pub enum AgentMessage {
         admin_token: String,
         /// Share token for this agent
         share_token: String,
+        /// Permission granted by the share token (defaults to operator)
+        #[serde(default)]
+        share_mode: ShareMode,
         /// Existing instances for reconnection sync (optional, backward compatible)
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         existing_instances: Vec<ExistingInstance>,
+        /// Per-agent key, pinned by the server on first enrollment
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent_key: Option<String>,
+        /// Server-issued enrollment secret (required for first registration if configured)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        enrollment_secret: Option<String>,
+        /// Remote creates and first attaches must be approved at the agent's machine
+        #[serde(default)]
+        approval_required: bool,
     },
     /// Report instance created
     InstanceCreated {
@@ -50,6 +92,9 @@ pub enum AgentMessage {
         instance_id: Uuid,
         /// Working directory
         cwd: String,
+        /// PTY streams are end-to-end encrypted
+        #[serde(default)]
+        encrypted: bool,
     },
     /// Report instance closed
     InstanceClosed {
@@ -69,6 +114,29 @@ pub enum AgentMessage {
     Error {
         /// Error message
         message: String,
+        /// Error code (optional, backward compatible)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        /// Instance the error relates to, e.g. a failed CreateInstance (optional)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance_id: Option<Uuid>,
+    },
+    /// Token rotation result (sent after the new token was persisted, or failed to be)
+    TokenRotated {
+        /// Kind of token that was rotated
+        kind: TokenKind,
+        /// Whether the new token was saved to the agent configuration
+        success: bool,
+        /// Error message if saving failed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Answer to an ApprovalRequest
+    ApprovalResponse {
+        /// Request ID from the ApprovalRequest
+        request_id: Uuid,
+        /// Whether the person at the machine approved the action
+        approved: bool,
     },
 }
 
@@ -81,6 +149,11 @@ pub enum ServerToAgentMessage {
         /// Confirmation message
         message: String,
     },
+    /// Registration rejected (enrollment check failed)
+    RegistrationRejected {
+        /// Rejection reason
+        reason: String,
+    },
     /// Create a new Claude Code instance
     CreateInstance {
         /// Instance ID (pre-assigned by server)
@@ -115,6 +188,31 @@ pub enum ServerToAgentMessage {
         /// Error message
         message: String,
     },
+    /// Replace a token; the agent must persist it and answer with TokenRotated
+    RotateToken {
+        /// Kind of token to replace
+        kind: TokenKind,
+        /// New token value
+        token: String,
+    },
+    /// Ask the person at the machine to approve a remote action (answered with ApprovalResponse)
+    ApprovalRequest {
+        /// Request ID
+        request_id: Uuid,
+        /// Action waiting for approval
+        action: ApprovalAction,
+        /// Instance to create or attach to
+        instance_id: Uuid,
+        /// Requesting session ID
+        session_id: Uuid,
+        /// Who is asking (account name or role)
+        requester: String,
+        /// Requesting client IP address
+        client_ip: String,
+        /// Working directory of a new instance
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+    },
 }
 
 // ============================================================================
@@ -130,6 +228,21 @@ pub enum UserMessage {
         /// Authentication token
         token: String,
     },
+    /// Authenticate with a web session issued by the server (e.g. after an OIDC login)
+    SessionAuth {
+        /// Session token
+        session: String,
+    },
+    /// Resume a previous session with the ticket from `SessionTicket`
+    Resume {
+        /// Signed session ticket
+        ticket: String,
+    },
+    /// Second factor code, sent after an `AuthResult` with `second_factor_required`
+    TotpCode {
+        /// 6-digit TOTP code
+        code: String,
+    },
     /// Request to create a new instance (admin only)
     CreateInstance {
         /// Working directory
@@ -170,6 +283,27 @@ pub enum UserMessage {
     /// Heartbeat
     Heartbeat,
     // ========================================================================
+    // Invite commands (Admin only)
+    // ========================================================================
+    /// Mint an invite token that only grants access to one instance
+    CreateInvite {
+        /// Instance the invite is bound to
+        instance_id: Uuid,
+        /// Lifetime of the invite in seconds
+        expires_in_secs: u64,
+        /// Maximum number of logins with the invite (0 = unlimited)
+        #[serde(default)]
+        max_uses: u32,
+        /// Invited users can only watch the terminal
+        #[serde(default)]
+        read_only: bool,
+    },
+    /// Revoke every invite of an instance
+    RevokeInvites {
+        /// Instance ID
+        instance_id: Uuid,
+    },
+    // ========================================================================
     // Admin commands (SuperAdmin only)
     // ========================================================================
     /// Request admin statistics (SuperAdmin only)
@@ -189,6 +323,31 @@ pub enum UserMessage {
         /// Agent ID to delete
         agent_id: Uuid,
     },
+    /// Rotate an agent token (SuperAdmin only, agent must be online)
+    RotateToken {
+        /// Agent ID
+        agent_id: Uuid,
+        /// Kind of token to rotate
+        kind: TokenKind,
+        /// Lifetime of the new token in seconds, 60 seconds to 10 years
+        /// (defaults to security.token_ttl_days)
+        #[serde(default)]
+        expires_in_secs: Option<u64>,
+    },
+    /// Revoke an agent token (SuperAdmin only)
+    RevokeToken {
+        /// Agent ID
+        agent_id: Uuid,
+        /// Kind of token to revoke
+        kind: TokenKind,
+    },
+    /// List the connected user sessions (SuperAdmin only)
+    ListSessions,
+    /// Disconnect a user session; it cannot be resumed (SuperAdmin only)
+    KickSession {
+        /// Session ID to disconnect
+        session_id: Uuid,
+    },
     // ========================================================================
     // Tag commands (Admin only)
     // ========================================================================
@@ -227,7 +386,33 @@ pub enum UserMessage {
         /// Filter by event type (optional)
         #[serde(default)]
         event_type: Option<String>,
+        /// Only events at or after this time (optional)
+        #[serde(default)]
+        since: Option<DateTime<Utc>>,
+        /// Only events before this time (optional)
+        #[serde(default)]
+        until: Option<DateTime<Utc>>,
+        /// Filter by agent (optional)
+        #[serde(default)]
+        agent_id: Option<Uuid>,
+        /// Filter by instance (optional)
+        #[serde(default)]
+        instance_id: Option<Uuid>,
+        /// Filter by client IP address (optional)
+        #[serde(default)]
+        client_ip: Option<String>,
+        /// Only successful (true) or failed (false) operations (optional)
+        #[serde(default)]
+        success: Option<bool>,
+        /// Filter by user session (optional)
+        #[serde(default)]
+        session_id: Option<Uuid>,
+        /// Substring searched in the details (optional)
+        #[serde(default)]
+        search: Option<String>,
     },
+    /// Verify the audit log hash chain (SuperAdmin only)
+    VerifyAuditLog,
     // ========================================================================
     // Working Agent commands (SuperAdmin only)
     // ========================================================================
@@ -262,6 +447,22 @@ pub enum ServerToUserMessage {
         agent_id: Option<Uuid>,
         /// Error message if failed
         error: Option<String>,
+        /// Token was accepted but a TOTP code is still required (send `TotpCode`)
+        #[serde(default)]
+        second_factor_required: bool,
+        /// An existing session was resumed, with its attached instances and working agent
+        #[serde(default)]
+        resumed: bool,
+        /// Scopes granted to the session (empty if failed)
+        #[serde(default, skip_serializing_if = "Scopes::is_empty")]
+        scopes: Scopes,
+    },
+    /// Signed ticket to resume this session after a reconnect, renewed periodically
+    SessionTicket {
+        /// Ticket to send in `Resume`
+        ticket: String,
+        /// Seconds until the ticket expires
+        expires_in_secs: u64,
     },
     /// List of instances
     InstanceList {
@@ -299,6 +500,14 @@ pub enum ServerToUserMessage {
         /// Current user count
         user_count: usize,
     },
+    /// The request waits for approval at the agent's machine
+    /// (followed by the normal result, or an Error with `ApprovalDenied`)
+    ApprovalPending {
+        /// Action waiting for approval
+        action: ApprovalAction,
+        /// Instance to create or attach to
+        instance_id: Uuid,
+    },
     /// Agent status changed
     AgentStatusChanged {
         /// Agent ID
@@ -310,6 +519,9 @@ pub enum ServerToUserMessage {
     Error {
         /// Error message
         message: String,
+        /// Error code (optional)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
     },
     /// Pong (keep-alive response)
     Pong,
@@ -333,6 +545,57 @@ pub enum ServerToUserMessage {
         /// Agent ID
         agent_id: Uuid,
     },
+    /// Token rotated (sent only to the requesting SuperAdmin)
+    TokenRotated {
+        /// Agent ID
+        agent_id: Uuid,
+        /// Kind of token that was rotated
+        kind: TokenKind,
+        /// New token value
+        token: String,
+        /// Expiry of the new token (None = never expires)
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// Token revoked notification
+    TokenRevoked {
+        /// Agent ID
+        agent_id: Uuid,
+        /// Kind of token that was revoked
+        kind: TokenKind,
+    },
+    /// Connected user sessions
+    SessionList {
+        /// Sessions, oldest connection first
+        sessions: Vec<SessionInfo>,
+    },
+    /// User session disconnected (sent only to the requesting SuperAdmin)
+    SessionKicked {
+        /// Session ID
+        session_id: Uuid,
+    },
+    // ========================================================================
+    // Invite responses
+    // ========================================================================
+    /// Invite minted (sent only to the requesting user; the token is not stored in clear)
+    InviteCreated {
+        /// Instance the invite is bound to
+        instance_id: Uuid,
+        /// Invite token, used like any other login token
+        token: String,
+        /// Expiry of the invite
+        expires_at: DateTime<Utc>,
+        /// Maximum number of logins (0 = unlimited)
+        max_uses: u32,
+        /// Invited users can only watch the terminal
+        read_only: bool,
+    },
+    /// Invites of an instance revoked
+    InvitesRevoked {
+        /// Instance ID
+        instance_id: Uuid,
+        /// Number of invites revoked
+        count: u64,
+    },
     // ========================================================================
     // Tag responses
     // ========================================================================
@@ -372,6 +635,11 @@ pub enum ServerToUserMessage {
         /// Total count (for pagination)
         total: u64,
     },
+    /// Audit log hash chain verification result
+    AuditLogVerified {
+        /// Verification report
+        report: AuditChainReport,
+    },
     // ========================================================================
     // Working Agent responses (SuperAdmin only)
     // ========================================================================
@@ -453,18 +721,127 @@ mod tests {
             name: "Test Agent".to_string(),
             admin_token: "admin123".to_string(),
             share_token: "share456".to_string(),
+            share_mode: ShareMode::Viewer,
             existing_instances: vec![],
+            agent_key: Some("key789".to_string()),
+            enrollment_secret: None,
+            approval_required: false,
         };
         let json = msg.to_json().unwrap();
         assert!(json.contains("\"type\":\"register\""));
+        assert!(!json.contains("enrollment_secret"));
 
         let parsed = AgentMessage::from_json(&json).unwrap();
         match parsed {
-            AgentMessage::Register { name, .. } => assert_eq!(name, "Test Agent"),
+            AgentMessage::Register { name, agent_key, .. } => {
+                assert_eq!(name, "Test Agent");
+                assert_eq!(agent_key.as_deref(), Some("key789"));
+            }
             _ => panic!("Wrong message type"),
         }
     }
 
+    #[test]
+    fn test_register_without_enrollment_fields() {
+        // Older agents do not send agent_key / enrollment_secret
+        let json = r#"{"type":"register","agent_id":"6f1c1a52-3b0e-4c43-9a43-1f0f5b1c2d3e","name":"Old","admin_token":"a","share_token":"s"}"#;
+        match AgentMessage::from_json(json).unwrap() {
+            AgentMessage::Register { agent_key, enrollment_secret, share_mode, .. } => {
+                assert_eq!(share_mode, ShareMode::Operator);
+                assert!(agent_key.is_none());
+                assert!(enrollment_secret.is_none());
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_instance_created_encrypted_flag() {
+        // Older agents do not report end-to-end encryption
+        let json = r#"{"type":"instance_created","instance_id":"00000000-0000-0000-0000-0000000000aa","cwd":"/tmp"}"#;
+        match AgentMessage::from_json(json).unwrap() {
+            AgentMessage::InstanceCreated { encrypted, .. } => assert!(!encrypted),
+            _ => panic!("Wrong message type"),
+        }
+
+        let json = r#"{"type":"instance_created","instance_id":"00000000-0000-0000-0000-0000000000aa","cwd":"/tmp","encrypted":true}"#;
+        match AgentMessage::from_json(json).unwrap() {
+            AgentMessage::InstanceCreated { encrypted, .. } => assert!(encrypted),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_approval_messages() {
+        // Older agents do not ask for approval
+        let json = r#"{"type":"register","agent_id":"00000000-0000-0000-0000-000000000001","name":"a","admin_token":"x","share_token":"y"}"#;
+        match AgentMessage::from_json(json).unwrap() {
+            AgentMessage::Register { approval_required, .. } => assert!(!approval_required),
+            _ => panic!("Wrong message type"),
+        }
+
+        let msg = ServerToAgentMessage::ApprovalRequest {
+            request_id: Uuid::new_v4(),
+            action: ApprovalAction::CreateInstance,
+            instance_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            requester: "alice".to_string(),
+            client_ip: "10.0.0.1".to_string(),
+            cwd: Some("/srv".to_string()),
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("\"type\":\"approval_request\""));
+        assert!(json.contains("\"action\":\"create_instance\""));
+
+        let json = r#"{"type":"approval_response","request_id":"00000000-0000-0000-0000-0000000000aa","approved":false}"#;
+        match AgentMessage::from_json(json).unwrap() {
+            AgentMessage::ApprovalResponse { approved, .. } => assert!(!approved),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_get_audit_logs_filters() {
+        // Old clients send only the event type
+        let json = r#"{"type":"get_audit_logs","event_type":"attach"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::GetAuditLogs { event_type, since, success, search, .. } => {
+                assert_eq!(event_type.as_deref(), Some("attach"));
+                assert!(since.is_none() && success.is_none() && search.is_none());
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        let json = r#"{"type":"get_audit_logs","since":"2026-01-01T00:00:00Z","success":false,"search":"rm -rf"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::GetAuditLogs { since, success, search, .. } => {
+                assert_eq!(since.unwrap().to_rfc3339(), "2026-01-01T00:00:00+00:00");
+                assert_eq!(success, Some(false));
+                assert_eq!(search.as_deref(), Some("rm -rf"));
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_invite_messages() {
+        // Use limit and read-only default to unlimited and read-write
+        let json = r#"{"type":"create_invite","instance_id":"00000000-0000-0000-0000-000000000001","expires_in_secs":3600}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::CreateInvite { expires_in_secs, max_uses, read_only, .. } => {
+                assert_eq!(expires_in_secs, 3600);
+                assert_eq!(max_uses, 0);
+                assert!(!read_only);
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        let msg = ServerToUserMessage::InvitesRevoked { instance_id: Uuid::new_v4(), count: 2 };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("\"type\":\"invites_revoked\""));
+        assert!(json.contains("\"count\":2"));
+    }
+
     #[test]
     fn test_user_message_serialization() {
         let msg = UserMessage::Auth {
@@ -480,6 +857,86 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rotate_token_message() {
+        let json = r#"{"type":"rotate_token","agent_id":"00000000-0000-0000-0000-000000000001","kind":"share"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::RotateToken { kind, expires_in_secs, .. } => {
+                assert_eq!(kind, TokenKind::Share);
+                assert!(expires_in_secs.is_none());
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_second_factor_auth_result() {
+        let msg = ServerToUserMessage::AuthResult {
+            success: false,
+            role: None,
+            agent_name: None,
+            agent_id: None,
+            error: None,
+            second_factor_required: true,
+            resumed: false,
+            scopes: Scopes::default(),
+        };
+        assert!(msg.to_json().unwrap().contains("\"second_factor_required\":true"));
+
+        let json = r#"{"type":"totp_code","code":"123456"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::TotpCode { code } => assert_eq!(code, "123456"),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_session_auth() {
+        let json = r#"{"type":"session_auth","session":"abc"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::SessionAuth { session } => assert_eq!(session, "abc"),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let json = r#"{"type":"resume","ticket":"abc"}"#;
+        match UserMessage::from_json(json).unwrap() {
+            UserMessage::Resume { ticket } => assert_eq!(ticket, "abc"),
+            _ => panic!("Wrong message type"),
+        }
+
+        // Older servers do not send `resumed`
+        let json = r#"{"type":"auth_result","success":true,"role":"admin","agent_name":null,"agent_id":null,"error":null}"#;
+        match ServerToUserMessage::from_json(json).unwrap() {
+            ServerToUserMessage::AuthResult { resumed, .. } => assert!(!resumed),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_agent_error_with_code() {
+        let instance_id = Uuid::new_v4();
+        let msg = AgentMessage::Error {
+            message: "not allowed".to_string(),
+            code: Some(ErrorCode::DirectoryNotAllowed),
+            instance_id: Some(instance_id),
+        };
+        let json = msg.to_json().unwrap();
+        assert!(json.contains("\"code\":\"directory_not_allowed\""));
+
+        // Older agents only send a message
+        let parsed = AgentMessage::from_json(r#"{"type":"error","message":"boom"}"#).unwrap();
+        match parsed {
+            AgentMessage::Error { code, instance_id, .. } => {
+                assert!(code.is_none());
+                assert!(instance_id.is_none());
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
     #[test]
     fn test_pty_output_message() {
         let instance_id = Uuid::new_v4();
@@ -1,5 +1,7 @@
 //! Core data types for Claude Web Tunnel
 
+use std::collections::BTreeSet;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -14,22 +16,274 @@ pub enum Role {
     Admin,
     /// Regular user - can only operate terminals
     User,
+    /// Viewer - can only watch terminals (read-only share token)
+    Viewer,
 }
 
 impl Role {
-    /// Check if this role can create instances
-    pub fn can_create_instance(&self) -> bool {
-        matches!(self, Role::SuperAdmin | Role::Admin)
+    /// Scopes granted to the role when the credential does not narrow them
+    pub fn default_scopes(&self) -> Scopes {
+        use Scope::*;
+        let scopes: &[Scope] = match self {
+            Role::SuperAdmin => &Scope::ALL,
+            Role::Admin => &[
+                InstanceList,
+                InstanceCreate,
+                InstanceClose,
+                InstanceAttach,
+                InstanceInput,
+                InstanceResize,
+                InstanceInvite,
+                TagsRead,
+                TagsWrite,
+            ],
+            Role::User => &[InstanceList, InstanceAttach, InstanceInput, InstanceResize],
+            Role::Viewer => &[InstanceList, InstanceAttach],
+        };
+        Scopes(scopes.iter().copied().collect())
+    }
+}
+
+/// Named permission checked for every user request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Scope {
+    /// List the instances of the session's agent
+    #[serde(rename = "instance.list")]
+    InstanceList,
+    /// Create instances
+    #[serde(rename = "instance.create")]
+    InstanceCreate,
+    /// Close instances
+    #[serde(rename = "instance.close")]
+    InstanceClose,
+    /// Attach to and detach from instances
+    #[serde(rename = "instance.attach")]
+    InstanceAttach,
+    /// Send terminal input
+    #[serde(rename = "instance.input")]
+    InstanceInput,
+    /// Resize terminals
+    #[serde(rename = "instance.resize")]
+    InstanceResize,
+    /// Mint and revoke invites to single instances
+    #[serde(rename = "instance.invite")]
+    InstanceInvite,
+    /// Read agent tags
+    #[serde(rename = "tags.read")]
+    TagsRead,
+    /// Add and remove agent tags
+    #[serde(rename = "tags.write")]
+    TagsWrite,
+    /// Read audit logs
+    #[serde(rename = "audit.read")]
+    AuditRead,
+    /// Read global statistics of all agents
+    #[serde(rename = "admin.stats")]
+    AdminStats,
+    /// Select a working agent and list the instances of any agent
+    #[serde(rename = "agent.select")]
+    AgentSelect,
+    /// Force an agent to disconnect
+    #[serde(rename = "agent.disconnect")]
+    AgentDisconnect,
+    /// Delete agents
+    #[serde(rename = "agent.delete")]
+    AgentDelete,
+    /// Force close instances of any agent
+    #[serde(rename = "instance.force_close")]
+    InstanceForceClose,
+    /// Rotate and revoke agent tokens
+    #[serde(rename = "token.manage")]
+    TokenManage,
+    /// List the connected user sessions
+    #[serde(rename = "session.list")]
+    SessionList,
+    /// Disconnect user sessions
+    #[serde(rename = "session.kick")]
+    SessionKick,
+}
+
+impl Scope {
+    /// Every scope
+    pub const ALL: [Scope; 18] = [
+        Scope::InstanceList,
+        Scope::InstanceCreate,
+        Scope::InstanceClose,
+        Scope::InstanceAttach,
+        Scope::InstanceInput,
+        Scope::InstanceResize,
+        Scope::InstanceInvite,
+        Scope::TagsRead,
+        Scope::TagsWrite,
+        Scope::AuditRead,
+        Scope::AdminStats,
+        Scope::AgentSelect,
+        Scope::AgentDisconnect,
+        Scope::AgentDelete,
+        Scope::InstanceForceClose,
+        Scope::TokenManage,
+        Scope::SessionList,
+        Scope::SessionKick,
+    ];
+
+    /// Dotted name used in storage, the CLI and the protocol
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::InstanceList => "instance.list",
+            Scope::InstanceCreate => "instance.create",
+            Scope::InstanceClose => "instance.close",
+            Scope::InstanceAttach => "instance.attach",
+            Scope::InstanceInput => "instance.input",
+            Scope::InstanceResize => "instance.resize",
+            Scope::InstanceInvite => "instance.invite",
+            Scope::TagsRead => "tags.read",
+            Scope::TagsWrite => "tags.write",
+            Scope::AuditRead => "audit.read",
+            Scope::AdminStats => "admin.stats",
+            Scope::AgentSelect => "agent.select",
+            Scope::AgentDisconnect => "agent.disconnect",
+            Scope::AgentDelete => "agent.delete",
+            Scope::InstanceForceClose => "instance.force_close",
+            Scope::TokenManage => "token.manage",
+            Scope::SessionList => "session.list",
+            Scope::SessionKick => "session.kick",
+        }
+    }
+
+    /// Parse a dotted scope name
+    pub fn parse(s: &str) -> Option<Self> {
+        Scope::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+}
+
+/// Set of scopes held by a session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Scopes(BTreeSet<Scope>);
+
+impl Scopes {
+    /// Parse a comma separated list of scope names
+    pub fn parse(s: &str) -> Result<Self, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Scope::parse(name).ok_or_else(|| format!("unknown scope '{}'", name)))
+            .collect::<Result<_, _>>()
+            .map(Scopes)
+    }
+
+    /// Whether the set grants a scope
+    pub fn contains(&self, scope: Scope) -> bool {
+        self.0.contains(&scope)
+    }
+
+    /// Scopes held by both sets
+    pub fn intersect(&self, other: &Scopes) -> Scopes {
+        Scopes(self.0.intersection(&other.0).copied().collect())
+    }
+
+    /// Whether the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the scopes in declaration order
+    pub fn iter(&self) -> impl Iterator<Item = Scope> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl std::fmt::Display for Scopes {
+    /// Comma separated list, the storage format parsed by `Scopes::parse`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.iter().map(|scope| scope.as_str()).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// Permission granted by an agent's share token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareMode {
+    /// Share token users can operate existing terminals
+    #[default]
+    Operator,
+    /// Share token users can only watch terminals
+    Viewer,
+}
+
+impl ShareMode {
+    /// Role granted to share token users
+    pub fn role(&self) -> Role {
+        match self {
+            ShareMode::Operator => Role::User,
+            ShareMode::Viewer => Role::Viewer,
+        }
+    }
+
+    /// Parse a stored share mode, falling back to operator
+    pub fn from_str_or_default(s: &str) -> Self {
+        match s {
+            "viewer" => ShareMode::Viewer,
+            _ => ShareMode::Operator,
+        }
+    }
+
+    /// String form used for storage
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareMode::Operator => "operator",
+            ShareMode::Viewer => "viewer",
+        }
+    }
+}
+
+/// Kind of agent token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// Admin token
+    Admin,
+    /// Share token
+    Share,
+}
+
+impl TokenKind {
+    /// String form used for storage
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenKind::Admin => "admin",
+            TokenKind::Share => "share",
+        }
     }
 
-    /// Check if this role can close instances
-    pub fn can_close_instance(&self) -> bool {
-        matches!(self, Role::SuperAdmin | Role::Admin)
+    /// Kind of agent token a role authenticates with (None for SuperAdmin)
+    pub fn from_role(role: Role) -> Option<Self> {
+        match role {
+            Role::SuperAdmin => None,
+            Role::Admin => Some(TokenKind::Admin),
+            Role::User | Role::Viewer => Some(TokenKind::Share),
+        }
     }
+}
 
-    /// Check if this role can manage all agents
-    pub fn can_manage_all_agents(&self) -> bool {
-        matches!(self, Role::SuperAdmin)
+/// Remote action that waits for approval at the agent's machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalAction {
+    /// Create a new instance
+    CreateInstance,
+    /// First attach of a session to an instance of the agent
+    Attach,
+}
+
+impl ApprovalAction {
+    /// String form used in logs and the approval command's environment
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApprovalAction::CreateInstance => "create_instance",
+            ApprovalAction::Attach => "attach",
+        }
     }
 }
 
@@ -100,6 +354,9 @@ pub struct Instance {
     /// Number of attached users
     #[serde(default)]
     pub attached_users: usize,
+    /// PTY streams are end-to-end encrypted between browser and agent
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
 impl Instance {
@@ -112,6 +369,7 @@ impl Instance {
             status: InstanceStatus::Running,
             created_at: Utc::now(),
             attached_users: 0,
+            encrypted: false,
         }
     }
 }
@@ -190,6 +448,31 @@ pub struct GlobalStats {
     pub total_users: usize,
 }
 
+/// Connected user session for admin viewing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Session ID
+    pub session_id: Uuid,
+    /// User role
+    pub role: Role,
+    /// Credential the session logged in with (config_token, account, agent_token, sso, invite)
+    pub credential: String,
+    /// Named account (SuperAdmin account or `oidc:<user>`)
+    pub account: Option<String>,
+    /// Agent the session belongs to (None for SuperAdmin)
+    pub agent_id: Option<Uuid>,
+    /// Working agent selected by a SuperAdmin
+    pub working_agent_id: Option<Uuid>,
+    /// Client IP address of the current connection
+    pub client_ip: String,
+    /// When the current connection was established
+    pub connected_at: DateTime<Utc>,
+    /// Last message received from the session
+    pub last_activity: DateTime<Utc>,
+    /// Currently attached instance IDs
+    pub attached_instances: Vec<Uuid>,
+}
+
 /// Audit log entry for admin viewing
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuditLogEntry {
@@ -199,6 +482,9 @@ pub struct AuditLogEntry {
     pub timestamp: String,
     /// Event type (auth_success, auth_failure, create_instance, etc.)
     pub event_type: String,
+    /// User session ID
+    #[serde(default)]
+    pub session_id: String,
     /// User role (super_admin, admin, user)
     pub user_role: String,
     /// Associated agent ID (optional)
@@ -213,6 +499,34 @@ pub struct AuditLogEntry {
     pub success: bool,
     /// Additional details (optional)
     pub details: Option<String>,
+    /// SuperAdmin account that performed the action (optional)
+    #[serde(default)]
+    pub actor: Option<String>,
+}
+
+/// First broken link found while verifying the audit log hash chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditChainBreak {
+    /// ID of the first row that fails verification
+    pub id: i64,
+    /// Why the row fails (edited contents, missing predecessor, missing hash)
+    pub reason: String,
+}
+
+/// Result of verifying the audit log hash chain
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditChainReport {
+    /// Chained rows verified before the first break (or all of them)
+    pub checked: u64,
+    /// Rows written before the hash chain existed, skipped at the start
+    pub unchained: u64,
+    /// ID of the last verified row
+    pub last_id: Option<i64>,
+    /// Hash of the last verified row; recording it elsewhere lets a later
+    /// check notice rows removed from the end of the log
+    pub last_hash: Option<String>,
+    /// First broken link, `None` if the whole chain verifies
+    pub broken: Option<AuditChainBreak>,
 }
 
 #[cfg(test)]
@@ -220,18 +534,66 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_role_permissions() {
-        assert!(Role::SuperAdmin.can_create_instance());
-        assert!(Role::SuperAdmin.can_close_instance());
-        assert!(Role::SuperAdmin.can_manage_all_agents());
-
-        assert!(Role::Admin.can_create_instance());
-        assert!(Role::Admin.can_close_instance());
-        assert!(!Role::Admin.can_manage_all_agents());
-
-        assert!(!Role::User.can_create_instance());
-        assert!(!Role::User.can_close_instance());
-        assert!(!Role::User.can_manage_all_agents());
+    fn test_role_default_scopes() {
+        let super_admin = Role::SuperAdmin.default_scopes();
+        assert!(Scope::ALL.iter().all(|scope| super_admin.contains(*scope)));
+
+        let admin = Role::Admin.default_scopes();
+        assert!(admin.contains(Scope::InstanceCreate));
+        assert!(admin.contains(Scope::InstanceClose));
+        assert!(admin.contains(Scope::TagsWrite));
+        assert!(admin.contains(Scope::InstanceInvite));
+        assert!(!admin.contains(Scope::AuditRead));
+        assert!(!admin.contains(Scope::AgentDelete));
+
+        let user = Role::User.default_scopes();
+        assert!(!user.contains(Scope::InstanceCreate));
+        assert!(!user.contains(Scope::TagsRead));
+        assert!(user.contains(Scope::InstanceInput));
+        assert!(user.contains(Scope::InstanceResize));
+
+        let viewer = Role::Viewer.default_scopes();
+        assert!(viewer.contains(Scope::InstanceAttach));
+        assert!(!viewer.contains(Scope::InstanceInput));
+        assert!(!viewer.contains(Scope::InstanceResize));
+    }
+
+    #[test]
+    fn test_scopes_parse() {
+        let scopes = Scopes::parse("audit.read, tags.read,").unwrap();
+        assert!(scopes.contains(Scope::AuditRead));
+        assert!(scopes.contains(Scope::TagsRead));
+        assert_eq!(scopes.to_string(), "tags.read,audit.read");
+        assert_eq!(Scopes::parse(&scopes.to_string()).unwrap(), scopes);
+        assert!(Scopes::parse("").unwrap().is_empty());
+        assert!(Scopes::parse("audit.write").is_err());
+
+        // A credential never gets more than its role allows
+        let narrowed = Role::Admin.default_scopes().intersect(&Scopes::parse("tags.write,agent.delete").unwrap());
+        assert_eq!(narrowed.to_string(), "tags.write");
+
+        for scope in Scope::ALL {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+            assert_eq!(serde_json::to_string(&scope).unwrap(), format!("\"{}\"", scope.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_token_kind_from_role() {
+        assert_eq!(TokenKind::from_role(Role::SuperAdmin), None);
+        assert_eq!(TokenKind::from_role(Role::Admin), Some(TokenKind::Admin));
+        assert_eq!(TokenKind::from_role(Role::User), Some(TokenKind::Share));
+        assert_eq!(TokenKind::from_role(Role::Viewer), Some(TokenKind::Share));
+    }
+
+    #[test]
+    fn test_share_mode() {
+        assert_eq!(ShareMode::default(), ShareMode::Operator);
+        assert_eq!(ShareMode::Operator.role(), Role::User);
+        assert_eq!(ShareMode::Viewer.role(), Role::Viewer);
+        assert_eq!(ShareMode::from_str_or_default("viewer"), ShareMode::Viewer);
+        assert_eq!(ShareMode::from_str_or_default("unknown"), ShareMode::Operator);
+        assert_eq!(ShareMode::Viewer.as_str(), "viewer");
     }
 
     #[test]
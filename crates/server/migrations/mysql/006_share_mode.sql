@@ -0,0 +1,2 @@
+-- Share token permission: 'operator' can type into terminals, 'viewer' is read-only
+ALTER TABLE agents ADD COLUMN share_mode VARCHAR(16) NOT NULL DEFAULT 'operator';
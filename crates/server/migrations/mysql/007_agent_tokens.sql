@@ -0,0 +1,13 @@
+-- Agent tokens: expiry, last use and revocation for admin/share tokens
+-- A row is created the first time a token is seen, rotated and revoked tokens keep their row
+CREATE TABLE IF NOT EXISTS agent_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    agent_id VARCHAR(36) NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    created_at VARCHAR(64) NOT NULL,
+    expires_at VARCHAR(64) NULL,
+    last_used_at VARCHAR(64) NULL,
+    revoked TINYINT NOT NULL DEFAULT 0,
+
+    INDEX idx_agent_tokens_agent (agent_id, kind)
+);
@@ -0,0 +1,7 @@
+-- Server-wide settings stored in the database (e.g. the SuperAdmin TOTP secret)
+-- Kept out of server.toml so a leaked config file alone does not expose them
+CREATE TABLE IF NOT EXISTS server_settings (
+    name VARCHAR(64) PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at VARCHAR(64) NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Named SuperAdmin accounts, managed with the server CLI (--add-admin / --disable-admin)
+CREATE TABLE IF NOT EXISTS super_admins (
+    id VARCHAR(36) PRIMARY KEY,
+    name VARCHAR(64) NOT NULL UNIQUE,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at VARCHAR(64) NOT NULL,
+    last_login_at VARCHAR(64) NULL,
+    disabled TINYINT NOT NULL DEFAULT 0
+);
+
+-- SuperAdmin account that performed an audited action
+ALTER TABLE audit_logs ADD COLUMN actor VARCHAR(64) NULL;
@@ -0,0 +1,15 @@
+-- Invite tokens scoped to a single instance, minted by the instance's admin
+-- Rows are deleted when the invites are revoked or the instance closes
+CREATE TABLE IF NOT EXISTS instance_invites (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    agent_id VARCHAR(36) NOT NULL,
+    instance_id VARCHAR(36) NOT NULL,
+    read_only TINYINT NOT NULL DEFAULT 1,
+    max_uses INT NOT NULL DEFAULT 0,
+    uses INT NOT NULL DEFAULT 0,
+    created_at VARCHAR(64) NOT NULL,
+    expires_at VARCHAR(64) NOT NULL,
+    created_by VARCHAR(64) NULL,
+
+    INDEX idx_instance_invites_instance (instance_id)
+);
@@ -0,0 +1,5 @@
+-- Hash chain over audit log rows: hash of the previous row and of this row's contents (hex HMAC-SHA256)
+ALTER TABLE audit_logs ADD COLUMN prev_hash CHAR(64) NULL;
+ALTER TABLE audit_logs ADD COLUMN entry_hash CHAR(64) NULL;
+-- Timestamp exactly as hashed: the TIMESTAMP column drops the sub-second part and offset when storing it
+ALTER TABLE audit_logs ADD COLUMN chain_timestamp VARCHAR(40) NULL;
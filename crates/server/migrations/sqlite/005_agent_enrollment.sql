@@ -0,0 +1,3 @@
+-- Agent enrollment: pin a per-agent key on first registration
+-- Re-registrations must present the same key, so an agent identity cannot be hijacked
+ALTER TABLE agents ADD COLUMN agent_key_hash TEXT;
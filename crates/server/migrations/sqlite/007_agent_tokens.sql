@@ -0,0 +1,13 @@
+-- Agent tokens: expiry, last use and revocation for admin/share tokens
+-- A row is created the first time a token is seen, rotated and revoked tokens keep their row
+CREATE TABLE IF NOT EXISTS agent_tokens (
+    token_hash TEXT PRIMARY KEY,
+    agent_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    last_used_at TEXT,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_agent_tokens_agent ON agent_tokens(agent_id, kind);
@@ -0,0 +1,12 @@
+-- Named SuperAdmin accounts, managed with the server CLI (--add-admin / --disable-admin)
+CREATE TABLE IF NOT EXISTS super_admins (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL,
+    last_login_at TEXT,
+    disabled INTEGER NOT NULL DEFAULT 0
+);
+
+-- SuperAdmin account that performed an audited action
+ALTER TABLE audit_logs ADD COLUMN actor TEXT;
@@ -0,0 +1,2 @@
+-- Per-account TOTP secret of a named SuperAdmin (--setup-totp <NAME>), NULL = shared secret
+ALTER TABLE super_admins ADD COLUMN totp_secret TEXT;
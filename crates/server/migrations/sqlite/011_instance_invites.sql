@@ -0,0 +1,15 @@
+-- Invite tokens scoped to a single instance, minted by the instance's admin
+-- Rows are deleted when the invites are revoked or the instance closes
+CREATE TABLE IF NOT EXISTS instance_invites (
+    token_hash TEXT PRIMARY KEY,
+    agent_id TEXT NOT NULL,
+    instance_id TEXT NOT NULL,
+    read_only INTEGER NOT NULL DEFAULT 1,
+    max_uses INTEGER NOT NULL DEFAULT 0,
+    uses INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    created_by TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_instance_invites_instance ON instance_invites(instance_id);
@@ -0,0 +1,3 @@
+-- Scopes of a SuperAdmin account or agent token (comma separated, NULL = all scopes of its role)
+ALTER TABLE super_admins ADD COLUMN scopes TEXT;
+ALTER TABLE agent_tokens ADD COLUMN scopes TEXT;
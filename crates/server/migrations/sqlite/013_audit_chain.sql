@@ -0,0 +1,5 @@
+-- Hash chain over audit log rows: hash of the previous row and of this row's contents (hex HMAC-SHA256)
+ALTER TABLE audit_logs ADD COLUMN prev_hash TEXT;
+ALTER TABLE audit_logs ADD COLUMN entry_hash TEXT;
+-- Timestamp exactly as hashed (MySQL's TIMESTAMP column normalizes the stored value)
+ALTER TABLE audit_logs ADD COLUMN chain_timestamp TEXT;
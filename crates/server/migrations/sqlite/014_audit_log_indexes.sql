@@ -0,0 +1,5 @@
+-- Indexes for audit log filters (instance, client IP, outcome and event type within a time range)
+CREATE INDEX IF NOT EXISTS idx_audit_logs_instance ON audit_logs(instance_id);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_client_ip ON audit_logs(client_ip);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_success_time ON audit_logs(success, timestamp);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_event_time ON audit_logs(event_type, timestamp);
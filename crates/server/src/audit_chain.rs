@@ -0,0 +1,323 @@
+//! Tamper-evident audit log: every row stores the hash of the row before it
+//! and a hash over its own contents chained to that value, so editing,
+//! deleting or inserting a row breaks the chain at that point
+//!
+//! The hashes are HMAC-SHA256 keyed with a secret derived from the token
+//! pepper, which never lives in the database: write access to the database
+//! alone is not enough to recompute the chain after editing a row. Whoever
+//! also holds the pepper can, so keep `last_hash` values (or the syslog /
+//! JSON-lines copies of the log) somewhere the server cannot rewrite.
+//!
+//! Rows written before the chain existed carry no hash and are skipped while
+//! they precede the first chained row. Retention cleanup removes the oldest
+//! rows, so the first remaining chained row is the anchor and its `prev_hash`
+//! is taken as given. Rows removed from the end of the log cannot be detected
+//! from the chain alone; compare `last_hash` with a previously recorded value.
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use common::{AuditChainBreak, AuditChainReport};
+
+use crate::auth::TokenHasher;
+use crate::db::{AgentRepository, AuditLogRecord};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `prev_hash` of the first row of a new chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Input hashed with the token pepper to derive the chain key
+const KEY_LABEL: &str = "claude-tunnel audit chain v1";
+
+/// Key of the audit chain HMAC
+#[derive(Clone)]
+pub struct AuditChainKey {
+    mac: HmacSha256,
+}
+
+impl AuditChainKey {
+    /// Key the chain with the given secret
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    /// Derive the key from the token pepper, so it is kept wherever the pepper is
+    pub fn from_token_hasher(hasher: &TokenHasher) -> Self {
+        Self::new(hasher.hash(KEY_LABEL).as_bytes())
+    }
+}
+
+/// Rows fetched per query while verifying
+const VERIFY_BATCH: i64 = 1000;
+
+/// Audit row fields covered by the entry hash
+///
+/// The row ID is not covered: it is assigned on insert, and the order of
+/// rows is already fixed by each row naming its predecessor's hash.
+#[derive(Debug, Clone, Copy)]
+pub struct AuditEntry<'a> {
+    pub timestamp: &'a str,
+    pub event_type: &'a str,
+    pub session_id: &'a str,
+    pub user_role: &'a str,
+    pub agent_id: Option<&'a str>,
+    pub instance_id: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub client_ip: &'a str,
+    pub success: bool,
+    pub details: Option<&'a str>,
+    pub actor: Option<&'a str>,
+}
+
+impl<'a> AuditEntry<'a> {
+    /// Fields of a stored row
+    ///
+    /// The timestamp is taken from `chain_timestamp`: MySQL stores `timestamp`
+    /// in a TIMESTAMP column, which drops the sub-second part and the offset
+    /// of the hashed RFC 3339 string. Rows chained before that column existed
+    /// (SQLite only) still carry the exact string in `timestamp`.
+    pub fn from_record(record: &'a AuditLogRecord) -> Self {
+        let timestamp = if record.chain_timestamp.is_empty() {
+            &record.timestamp
+        } else {
+            &record.chain_timestamp
+        };
+        Self {
+            timestamp,
+            event_type: &record.event_type,
+            session_id: &record.session_id,
+            user_role: &record.user_role,
+            agent_id: record.agent_id.as_deref(),
+            instance_id: record.instance_id.as_deref(),
+            target_id: record.target_id.as_deref(),
+            client_ip: &record.client_ip,
+            success: record.success != 0,
+            details: record.details.as_deref(),
+            actor: record.actor.as_deref(),
+        }
+    }
+
+    /// Hex HMAC-SHA256 over `prev_hash` and the fields
+    ///
+    /// Each field is length-prefixed so values cannot shift between fields.
+    /// A missing optional field hashes like an empty one, matching how rows
+    /// are read back.
+    pub fn hash(&self, key: &AuditChainKey, prev_hash: &str) -> String {
+        let mut mac = key.mac.clone();
+        let fields = [
+            prev_hash,
+            self.timestamp,
+            self.event_type,
+            self.session_id,
+            self.user_role,
+            self.agent_id.unwrap_or(""),
+            self.instance_id.unwrap_or(""),
+            self.target_id.unwrap_or(""),
+            self.client_ip,
+            if self.success { "1" } else { "0" },
+            self.details.unwrap_or(""),
+            self.actor.unwrap_or(""),
+        ];
+        for field in fields {
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Walks audit rows in ID order and records the first broken link
+struct ChainVerifier<'a> {
+    key: &'a AuditChainKey,
+    report: AuditChainReport,
+}
+
+impl<'a> ChainVerifier<'a> {
+    fn new(key: &'a AuditChainKey) -> Self {
+        Self {
+            key,
+            report: AuditChainReport::default(),
+        }
+    }
+
+    /// Check the next row; returns false once the chain is broken
+    fn push(&mut self, record: &AuditLogRecord) -> bool {
+        let report = &mut self.report;
+        if record.entry_hash.is_empty() {
+            if report.last_id.is_none() {
+                report.unchained += 1;
+                return true;
+            }
+            return self.fail(record.id, "row has no hash".to_string());
+        }
+
+        if AuditEntry::from_record(record).hash(self.key, &record.prev_hash) != record.entry_hash {
+            return self.fail(record.id, "row contents do not match its hash".to_string());
+        }
+        if let (Some(last_id), Some(last_hash)) = (report.last_id, report.last_hash.as_deref()) {
+            if record.prev_hash != last_hash {
+                return self.fail(
+                    record.id,
+                    format!("previous hash does not match row {} (rows removed or inserted)", last_id),
+                );
+            }
+        }
+
+        report.checked += 1;
+        report.last_id = Some(record.id);
+        report.last_hash = Some(record.entry_hash.clone());
+        true
+    }
+
+    fn fail(&mut self, id: i64, reason: String) -> bool {
+        self.report.broken = Some(AuditChainBreak { id, reason });
+        false
+    }
+}
+
+/// Verify the whole audit log and report the first broken link
+pub async fn verify_audit_chain(repo: &AgentRepository, key: &AuditChainKey) -> Result<AuditChainReport> {
+    let mut verifier = ChainVerifier::new(key);
+    let mut after_id = 0;
+    loop {
+        let rows = repo.get_audit_logs_after(after_id, VERIFY_BATCH).await?;
+        for row in &rows {
+            if !verifier.push(row) {
+                return Ok(verifier.report);
+            }
+        }
+        match rows.last() {
+            Some(last) if rows.len() as i64 == VERIFY_BATCH => after_id = last.id,
+            _ => return Ok(verifier.report),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: i64, details: &str) -> AuditLogRecord {
+        AuditLogRecord {
+            id,
+            timestamp: format!("2026-01-01T00:00:{:02}+00:00", id),
+            event_type: "create_instance".to_string(),
+            session_id: "session".to_string(),
+            user_role: "admin".to_string(),
+            agent_id: None,
+            instance_id: None,
+            target_id: None,
+            client_ip: "127.0.0.1".to_string(),
+            success: 1,
+            details: Some(details.to_string()),
+            actor: None,
+            prev_hash: String::new(),
+            entry_hash: String::new(),
+            chain_timestamp: String::new(),
+        }
+    }
+
+    fn key() -> AuditChainKey {
+        AuditChainKey::new(b"test-key")
+    }
+
+    /// Chain the records the way `insert_audit_log` does
+    fn chain_with(key: &AuditChainKey, mut records: Vec<AuditLogRecord>) -> Vec<AuditLogRecord> {
+        let mut prev = GENESIS_HASH.to_string();
+        for record in &mut records {
+            record.prev_hash = prev;
+            record.entry_hash = AuditEntry::from_record(record).hash(key, &record.prev_hash);
+            prev = record.entry_hash.clone();
+        }
+        records
+    }
+
+    fn chain(records: Vec<AuditLogRecord>) -> Vec<AuditLogRecord> {
+        chain_with(&key(), records)
+    }
+
+    fn verify(records: &[AuditLogRecord]) -> AuditChainReport {
+        let key = key();
+        let mut verifier = ChainVerifier::new(&key);
+        for record in records {
+            if !verifier.push(record) {
+                break;
+            }
+        }
+        verifier.report
+    }
+
+    #[test]
+    fn test_entry_hash_fields_do_not_shift() {
+        let mut a = record(1, "ab");
+        a.actor = Some("c".to_string());
+        let mut b = record(1, "a");
+        b.actor = Some("bc".to_string());
+        assert_ne!(
+            AuditEntry::from_record(&a).hash(&key(), GENESIS_HASH),
+            AuditEntry::from_record(&b).hash(&key(), GENESIS_HASH)
+        );
+    }
+
+    #[test]
+    fn test_verify_intact_chain() {
+        let records = chain((1..=5).map(|id| record(id, "ok")).collect());
+        let report = verify(&records);
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.last_id, Some(5));
+        assert_eq!(report.last_hash.as_deref(), Some(records[4].entry_hash.as_str()));
+        assert!(report.broken.is_none());
+
+        // Retention cleanup removes the oldest rows; the rest still verifies
+        let report = verify(&records[2..]);
+        assert_eq!(report.checked, 3);
+        assert!(report.broken.is_none());
+
+        // Rows older than the chain are skipped at the start only
+        let mut records = records;
+        records.insert(0, record(0, "legacy"));
+        let report = verify(&records);
+        assert_eq!(report.unchained, 1);
+        assert_eq!(report.checked, 5);
+        assert!(report.broken.is_none());
+
+        // A timestamp normalized by the database still verifies against the hashed string
+        let mut records = chain((1..=2).map(|id| record(id, "ok")).collect());
+        for record in &mut records {
+            record.chain_timestamp = std::mem::replace(&mut record.timestamp, "2026-01-01 00:00:00".to_string());
+        }
+        assert!(verify(&records).broken.is_none());
+    }
+
+    #[test]
+    fn test_verify_reports_first_broken_link() {
+        // Edited row
+        let mut records = chain((1..=5).map(|id| record(id, "ok")).collect());
+        records[2].details = Some("edited".to_string());
+        let report = verify(&records);
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.broken.unwrap().id, 3);
+
+        // Deleted row
+        let mut records = chain((1..=5).map(|id| record(id, "ok")).collect());
+        records.remove(1);
+        let broken = verify(&records).broken.unwrap();
+        assert_eq!(broken.id, 3);
+        assert!(broken.reason.contains("row 1"));
+
+        // Edited row with the chain recomputed by someone without the key
+        let mut records = chain((1..=5).map(|id| record(id, "ok")).collect());
+        records[2].details = Some("edited".to_string());
+        let records = chain_with(&AuditChainKey::new(b"guessed-key"), records);
+        assert_eq!(verify(&records).broken.unwrap().id, 1);
+
+        // Hash stripped after the chain started
+        let mut records = chain((1..=5).map(|id| record(id, "ok")).collect());
+        records[3].entry_hash.clear();
+        assert_eq!(verify(&records).broken.unwrap().id, 4);
+    }
+}
@@ -0,0 +1,432 @@
+//! Audit log sinks: stored audit events are also forwarded to a syslog
+//! server (RFC 5424) and/or appended to a JSON-lines file, and the whole
+//! log can be exported as CSV, JSON or JSON lines
+//!
+//! Forwarding runs on a background task fed by a bounded queue so a slow
+//! or unreachable sink never delays request handling; events that do not
+//! fit in the queue are dropped with a warning. Only events that were
+//! stored in the database are forwarded.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use common::{AuditLogConfig, SyslogProtocol, SyslogSinkConfig};
+
+use crate::db::{AgentRepository, AuditLogRecord};
+
+/// Events waiting for the sinks before new ones are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Rows fetched per query while exporting
+const EXPORT_BATCH: i64 = 1000;
+
+/// Structured data ID of the audit parameters in syslog messages
+/// (32473 is the private enterprise number reserved for examples, RFC 5612)
+const SD_ID: &str = "audit@32473";
+
+/// Serialized form of an audit row for JSON sinks and exports
+#[derive(Debug, Serialize)]
+pub struct AuditLine<'a> {
+    pub id: i64,
+    pub timestamp: &'a str,
+    pub event_type: &'a str,
+    pub session_id: &'a str,
+    pub user_role: &'a str,
+    pub agent_id: Option<&'a str>,
+    pub instance_id: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub client_ip: &'a str,
+    pub success: bool,
+    pub details: Option<&'a str>,
+    pub actor: Option<&'a str>,
+    pub prev_hash: &'a str,
+    pub entry_hash: &'a str,
+}
+
+impl<'a> From<&'a AuditLogRecord> for AuditLine<'a> {
+    fn from(record: &'a AuditLogRecord) -> Self {
+        Self {
+            id: record.id,
+            timestamp: &record.timestamp,
+            event_type: &record.event_type,
+            session_id: &record.session_id,
+            user_role: &record.user_role,
+            agent_id: record.agent_id.as_deref(),
+            instance_id: record.instance_id.as_deref(),
+            target_id: record.target_id.as_deref(),
+            client_ip: &record.client_ip,
+            success: record.success != 0,
+            details: record.details.as_deref(),
+            actor: record.actor.as_deref(),
+            prev_hash: &record.prev_hash,
+            entry_hash: &record.entry_hash,
+        }
+    }
+}
+
+/// Handle to the configured sinks; cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct AuditSinks {
+    tx: Option<mpsc::Sender<AuditLogRecord>>,
+}
+
+impl AuditSinks {
+    /// Open the configured sinks and start the forwarding task
+    /// The JSON-lines file is opened here so a bad path fails at startup
+    pub fn from_config(config: &AuditLogConfig) -> Result<Self> {
+        let syslog = config.syslog.clone().map(SyslogSink::new).transpose()?;
+        let jsonl = match &config.jsonl_path {
+            Some(path) => Some(open_append(path).map_err(|e| {
+                anyhow!("audit_log.jsonl_path {}: {}", path.display(), e)
+            })?),
+            None => None,
+        };
+        if syslog.is_none() && jsonl.is_none() {
+            return Ok(Self::default());
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run_sinks(rx, syslog, jsonl.map(tokio::fs::File::from_std)));
+        Ok(Self { tx: Some(tx) })
+    }
+
+    /// Queue a stored audit row for the sinks
+    pub fn send(&self, record: AuditLogRecord) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send(record).is_err() {
+                warn!("Audit sink queue is full, dropping event");
+            }
+        }
+    }
+}
+
+/// Open a file for appending, creating it owner-only if it does not exist
+fn open_append(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Forward queued rows to every sink until the queue closes
+async fn run_sinks(
+    mut rx: mpsc::Receiver<AuditLogRecord>,
+    mut syslog: Option<SyslogSink>,
+    mut jsonl: Option<tokio::fs::File>,
+) {
+    while let Some(record) = rx.recv().await {
+        if let Some(sink) = &mut syslog {
+            if let Err(e) = sink.send(&record).await {
+                warn!("Failed to send audit event to syslog {}: {}", sink.config.address, e);
+            }
+        }
+        if let Some(file) = &mut jsonl {
+            let line = json_line(&record);
+            if let Err(e) = async {
+                file.write_all(line.as_bytes()).await?;
+                file.flush().await
+            }
+            .await
+            {
+                warn!("Failed to append audit event to the JSON-lines file: {}", e);
+            }
+        }
+    }
+}
+
+/// One JSON object followed by a newline
+fn json_line(record: &AuditLogRecord) -> String {
+    let mut line = serde_json::to_string(&AuditLine::from(record)).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// RFC 5424 syslog client
+#[derive(Debug)]
+struct SyslogSink {
+    config: SyslogSinkConfig,
+    udp: Option<UdpSocket>,
+    /// Reconnected on the next event after a write fails
+    tcp: Option<TcpStream>,
+}
+
+impl SyslogSink {
+    fn new(config: SyslogSinkConfig) -> Result<Self> {
+        if config.facility > 23 {
+            return Err(anyhow!("audit_log.syslog.facility must be 0-23"));
+        }
+        if config.address.trim().is_empty() {
+            return Err(anyhow!("audit_log.syslog.address is empty"));
+        }
+        Ok(Self { config, udp: None, tcp: None })
+    }
+
+    async fn send(&mut self, record: &AuditLogRecord) -> Result<()> {
+        let message = syslog_message(&self.config, record);
+        match self.config.protocol {
+            SyslogProtocol::Udp => {
+                if self.udp.is_none() {
+                    let addr = tokio::net::lookup_host(&self.config.address)
+                        .await?
+                        .next()
+                        .ok_or_else(|| anyhow!("address does not resolve"))?;
+                    let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                    let socket = UdpSocket::bind(local).await?;
+                    socket.connect(addr).await?;
+                    self.udp = Some(socket);
+                }
+                if let Some(socket) = &self.udp {
+                    socket.send(message.as_bytes()).await?;
+                }
+            }
+            SyslogProtocol::Tcp => {
+                if self.tcp.is_none() {
+                    self.tcp = Some(TcpStream::connect(&self.config.address).await?);
+                }
+                if let Some(stream) = &mut self.tcp {
+                    // Octet counting framing (RFC 6587)
+                    let frame = format!("{} {}", message.len(), message);
+                    if let Err(e) = stream.write_all(frame.as_bytes()).await {
+                        self.tcp = None;
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format a row as an RFC 5424 message
+///
+/// Failed operations are sent with severity warning, others as informational.
+/// MSGID is the event type, the row fields go into one structured data element
+/// and the details become the free-form message.
+fn syslog_message(config: &SyslogSinkConfig, record: &AuditLogRecord) -> String {
+    let severity = if record.success != 0 { 6 } else { 4 };
+    let pri = u32::from(config.facility) * 8 + severity;
+    let hostname = config.hostname.as_deref().map(header_field).unwrap_or_else(|| "-".to_string());
+
+    let mut data = format!("[{}", SD_ID);
+    let params = [
+        ("id", Some(record.id.to_string())),
+        ("session", Some(record.session_id.clone())),
+        ("role", Some(record.user_role.clone())),
+        ("agent", record.agent_id.clone()),
+        ("instance", record.instance_id.clone()),
+        ("target", record.target_id.clone()),
+        ("ip", Some(record.client_ip.clone())),
+        ("success", Some(record.success.to_string())),
+        ("actor", record.actor.clone()),
+        ("hash", Some(record.entry_hash.clone()).filter(|h| !h.is_empty())),
+    ];
+    for (name, value) in params {
+        if let Some(value) = value {
+            data.push_str(&format!(" {}=\"{}\"", name, escape_param(&value)));
+        }
+    }
+    data.push(']');
+
+    let mut message = format!(
+        "<{}>1 {} {} {} {} {} {}",
+        pri,
+        record.timestamp,
+        hostname,
+        header_field(&config.app_name),
+        std::process::id(),
+        header_field(&record.event_type),
+        data
+    );
+    if let Some(details) = &record.details {
+        message.push(' ');
+        message.push_str(details);
+    }
+    message
+}
+
+/// Header fields are printable ASCII without spaces; anything else becomes '_'
+fn header_field(value: &str) -> String {
+    if value.is_empty() {
+        return "-".to_string();
+    }
+    value.chars().map(|c| if c.is_ascii_graphic() { c } else { '_' }).collect()
+}
+
+/// Escape '"', '\' and ']' in a structured data parameter value
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Bulk export format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON array
+    Json,
+    /// One JSON object per line, like the JSON-lines sink
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err(anyhow!("Export format must be csv, json or jsonl")),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "id,timestamp,event_type,session_id,user_role,agent_id,instance_id,target_id,\
+     client_ip,success,details,actor,prev_hash,entry_hash";
+
+/// Write every audit row, oldest first; returns the number of rows written
+pub async fn export_audit_log(repo: &AgentRepository, format: ExportFormat, out: &mut dyn Write) -> Result<u64> {
+    match format {
+        ExportFormat::Csv => writeln!(out, "{}", CSV_HEADER)?,
+        ExportFormat::Json => write!(out, "[")?,
+        ExportFormat::Jsonl => {}
+    }
+
+    let mut count = 0u64;
+    let mut after_id = 0;
+    loop {
+        let rows = repo.get_audit_logs_after(after_id, EXPORT_BATCH).await?;
+        for row in &rows {
+            match format {
+                ExportFormat::Csv => writeln!(out, "{}", csv_row(row))?,
+                ExportFormat::Json => {
+                    let separator = if count == 0 { "\n" } else { ",\n" };
+                    write!(out, "{}{}", separator, serde_json::to_string(&AuditLine::from(row))?)?;
+                }
+                ExportFormat::Jsonl => out.write_all(json_line(row).as_bytes())?,
+            }
+            count += 1;
+        }
+        match rows.last() {
+            Some(last) if rows.len() as i64 == EXPORT_BATCH => after_id = last.id,
+            _ => break,
+        }
+    }
+
+    if format == ExportFormat::Json {
+        writeln!(out, "\n]")?;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+fn csv_row(record: &AuditLogRecord) -> String {
+    let line = AuditLine::from(record);
+    let id = line.id.to_string();
+    let success = if line.success { "1" } else { "0" };
+    let fields = [
+        id.as_str(),
+        line.timestamp,
+        line.event_type,
+        line.session_id,
+        line.user_role,
+        line.agent_id.unwrap_or(""),
+        line.instance_id.unwrap_or(""),
+        line.target_id.unwrap_or(""),
+        line.client_ip,
+        success,
+        line.details.unwrap_or(""),
+        line.actor.unwrap_or(""),
+        line.prev_hash,
+        line.entry_hash,
+    ];
+    fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",")
+}
+
+/// Quote a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AuditLogRecord {
+        AuditLogRecord {
+            id: 7,
+            timestamp: "2026-01-01T00:00:00+00:00".to_string(),
+            event_type: "auth_failure".to_string(),
+            session_id: "s1".to_string(),
+            user_role: "Admin".to_string(),
+            agent_id: None,
+            instance_id: None,
+            target_id: None,
+            client_ip: "10.0.0.1".to_string(),
+            success: 0,
+            details: Some("reason: \"bad\", token]".to_string()),
+            actor: None,
+            prev_hash: String::new(),
+            entry_hash: "ab".to_string(),
+            chain_timestamp: "2026-01-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    fn syslog_config() -> SyslogSinkConfig {
+        SyslogSinkConfig {
+            address: "127.0.0.1:514".to_string(),
+            protocol: SyslogProtocol::Udp,
+            facility: 13,
+            hostname: Some("tunnel host".to_string()),
+            app_name: "claude-tunnel".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        let message = syslog_message(&syslog_config(), &record());
+        // facility 13 * 8 + severity 4 (failure)
+        assert!(message.starts_with("<108>1 2026-01-01T00:00:00+00:00 tunnel_host claude-tunnel "));
+        assert!(message.contains(" auth_failure [audit@32473 id=\"7\" session=\"s1\" role=\"Admin\" ip=\"10.0.0.1\" success=\"0\" hash=\"ab\"] "));
+        assert!(!message.contains("agent="));
+        assert!(message.ends_with(" reason: \"bad\", token]"));
+
+        assert_eq!(escape_param("a\"b\\c]"), "a\\\"b\\\\c\\]");
+        let mut config = syslog_config();
+        config.facility = 24;
+        assert!(SyslogSink::new(config).is_err());
+    }
+
+    #[test]
+    fn test_csv_and_json_rows() {
+        let row = csv_row(&record());
+        assert_eq!(row.split(',').next(), Some("7"));
+        assert!(row.contains(",10.0.0.1,0,\"reason: \"\"bad\"\", token]\",,,ab"));
+
+        let line = json_line(&record());
+        assert!(line.ends_with("}\n"));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["success"], false);
+        assert_eq!(value["agent_id"], serde_json::Value::Null);
+        assert!(ExportFormat::parse("xml").is_err());
+    }
+}
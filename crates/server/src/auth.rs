@@ -1,40 +1,488 @@
 //! Authentication utilities
 
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
 use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::info;
+use uuid::Uuid;
+
+use common::SecurityConfig;
+
+use crate::db::{AgentRecord, AgentRepository, TokenRecord};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name of the `server_settings` row where earlier versions kept the generated pepper
+/// It is moved to the pepper key file on start
+pub const TOKEN_PEPPER_SETTING: &str = "token_pepper";
+
+/// Minimum length of a configured pepper
+const MIN_PEPPER_LEN: usize = 16;
+
+/// Keyed token hashing: HMAC-SHA256 with a server-wide pepper
+///
+/// Hashes stay deterministic so tokens can still be looked up by an indexed
+/// column, but a leaked database is useless without the pepper. The pepper
+/// therefore never lives in the database: it comes from the config, the
+/// environment or a key file.
+#[derive(Clone)]
+pub struct TokenHasher {
+    mac: HmacSha256,
+}
+
+impl TokenHasher {
+    /// Create a hasher keyed with the given pepper
+    pub fn new(pepper: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(pepper).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    /// Hash a token for storage
+    pub fn hash(&self, token: &str) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Current and legacy hashes of a token, for lookups that must also find old rows
+    pub fn hashes(&self, token: &str) -> TokenHashes {
+        TokenHashes {
+            current: self.hash(token),
+            legacy: legacy_hash_token(token),
+        }
+    }
+
+    /// Verify a token against a stored hash of either format
+    pub fn verify(&self, token: &str, stored: &str) -> bool {
+        self.hashes(token).matches(stored)
+    }
+}
 
-/// Hash a token using SHA-256
-pub fn hash_token(token: &str) -> String {
+/// Both hash formats of one token
+#[derive(Debug, Clone)]
+pub struct TokenHashes {
+    /// Keyed hash, the format written by this version
+    pub current: String,
+    /// Unsalted SHA-256 hash written by earlier versions
+    pub legacy: String,
+}
+
+impl TokenHashes {
+    /// Whether a stored hash belongs to this token (constant time)
+    pub fn matches(&self, stored: &str) -> bool {
+        // Non-short-circuiting so both comparisons always run
+        hashes_equal(&self.current, stored) | hashes_equal(&self.legacy, stored)
+    }
+
+    /// Whether a stored hash is still in the legacy format and should be upgraded
+    pub fn is_legacy(&self, stored: &str) -> bool {
+        hashes_equal(&self.legacy, stored)
+    }
+}
+
+/// Unsalted SHA-256 hash, only used to find rows stored before token peppering
+pub fn legacy_hash_token(token: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(token.as_bytes());
     let result = hasher.finalize();
     hex::encode(result)
 }
 
-/// Verify a token against a hash
-#[allow(dead_code)]
-pub fn verify_token(token: &str, hash: &str) -> bool {
-    hash_token(token) == hash
+/// Compare two hashes in constant time
+pub fn hashes_equal(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Build the token hasher from `security.token_pepper`, or from the pepper key file
+/// that is generated on first start
+pub async fn load_token_hasher(security: &SecurityConfig, repo: &AgentRepository) -> Result<TokenHasher> {
+    if let Some(pepper) = &security.token_pepper {
+        if pepper.len() < MIN_PEPPER_LEN {
+            return Err(anyhow!("security.token_pepper must be at least {} characters", MIN_PEPPER_LEN));
+        }
+        return Ok(TokenHasher::new(pepper.as_bytes()));
+    }
+
+    let path = security
+        .token_pepper_file
+        .as_deref()
+        .ok_or_else(|| anyhow!("security.token_pepper_file is not resolved"))?;
+    let stored = repo.get_setting(TOKEN_PEPPER_SETTING).await?;
+
+    let pepper = if path.exists() {
+        let pepper = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read token pepper file {}: {}", path.display(), e))?
+            .trim()
+            .to_string();
+        if pepper.len() < MIN_PEPPER_LEN {
+            return Err(anyhow!("Token pepper file {} is shorter than {} characters", path.display(), MIN_PEPPER_LEN));
+        }
+        if stored.as_ref().is_some_and(|s| *s != pepper) {
+            return Err(anyhow!(
+                "The database holds a different token pepper than {}; remove one of them",
+                path.display()
+            ));
+        }
+        pepper
+    } else {
+        // Earlier versions kept the generated pepper in the database: move it out
+        let (pepper, origin) = match &stored {
+            Some(pepper) => (pepper.clone(), "moved from the database"),
+            None => (generate_token(), "generated"),
+        };
+        write_pepper_file(path, &pepper)?;
+        info!("Token pepper {} to {}", origin, path.display());
+        pepper
+    };
+
+    if stored.is_some() {
+        repo.delete_setting(TOKEN_PEPPER_SETTING).await?;
+    }
+    Ok(TokenHasher::new(pepper.as_bytes()))
+}
+
+/// Create the pepper key file, readable by the owner only
+fn write_pepper_file(path: &Path, pepper: &str) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow!("Failed to create token pepper file {}: {}", path.display(), e))?;
+    writeln!(file, "{}", pepper)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Generate a random token (32 bytes, URL-safe base64)
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Check that a stored token is neither revoked nor expired
+/// An expiry that cannot be parsed counts as expired
+pub fn check_token_record(record: &TokenRecord, now: DateTime<Utc>) -> Result<(), &'static str> {
+    if record.is_revoked() {
+        return Err("token has been revoked");
+    }
+    if !record.expires_at.is_empty() {
+        match record.expires_at_datetime() {
+            Some(expires_at) if expires_at > now => {}
+            _ => return Err("token has expired"),
+        }
+    }
+    Ok(())
+}
+
+/// Claims of a verified session ticket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketClaims {
+    /// Session the ticket resumes
+    pub session_id: Uuid,
+    /// Renewal counter, older tickets of the session are refused
+    pub generation: u64,
+    /// Expiry as a Unix timestamp
+    pub expires_at: i64,
+}
+
+/// Signs and verifies the session tickets used to resume a web session
+///
+/// A ticket is `<session_id>.<generation>.<expires_at>.<signature>`, signed with
+/// HMAC-SHA256. The key is random per process: resumable sessions only live in
+/// memory, so tickets of a previous server run are useless anyway.
+#[derive(Clone)]
+pub struct SessionTicketSigner {
+    mac: HmacSha256,
+}
+
+impl SessionTicketSigner {
+    /// Create a signer with a fresh random key
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill(&mut key);
+        Self::new(&key)
+    }
+
+    /// Create a signer with the given key
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    /// Issue a ticket for a session
+    pub fn issue(&self, claims: &TicketClaims) -> String {
+        let payload = format!("{}.{}.{}", claims.session_id, claims.generation, claims.expires_at);
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&payload));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Verify a ticket's signature and expiry
+    pub fn verify(&self, ticket: &str, now: i64) -> Result<TicketClaims, &'static str> {
+        let (payload, signature) = ticket.rsplit_once('.').ok_or("malformed ticket")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed ticket")?;
+        if !bool::from(self.sign(payload).as_slice().ct_eq(&signature)) {
+            return Err("invalid ticket signature");
+        }
+
+        let mut parts = payload.split('.');
+        let (Some(session_id), Some(generation), Some(expires_at), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed ticket");
+        };
+        let claims = TicketClaims {
+            session_id: session_id.parse().map_err(|_| "malformed ticket")?,
+            generation: generation.parse().map_err(|_| "malformed ticket")?,
+            expires_at: expires_at.parse().map_err(|_| "malformed ticket")?,
+        };
+
+        if claims.expires_at <= now {
+            return Err("ticket has expired");
+        }
+        Ok(claims)
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Check an agent registration against its stored record before it is persisted
+///
+/// - An agent with a pinned key must present the same key
+/// - An agent enrolled before key pinning must prove ownership with its current
+///   admin token or the enrollment secret; its key is pinned afterwards
+/// - An unknown agent needs the enrollment secret when one is configured
+pub fn verify_enrollment(
+    hasher: &TokenHasher,
+    record: Option<&AgentRecord>,
+    admin_token: &str,
+    agent_key: Option<&str>,
+    enrollment_secret: Option<&str>,
+    required_secret: Option<&str>,
+) -> Result<(), String> {
+    let secret_ok = match (required_secret, enrollment_secret) {
+        (Some(required), Some(presented)) => hashes_equal(required, presented),
+        _ => false,
+    };
+
+    match record {
+        Some(record) => match (record.pinned_key_hash(), agent_key) {
+            (Some(pinned), Some(key)) if hasher.verify(key, pinned) => Ok(()),
+            (Some(_), _) => Err("agent key does not match the enrolled key for this agent".to_string()),
+            (None, _) if hasher.verify(admin_token, &record.admin_token_hash) || secret_ok => Ok(()),
+            (None, _) => Err("agent is already registered with a different admin token".to_string()),
+        },
+        None if required_secret.is_none() || secret_ok => Ok(()),
+        None => Err("a valid enrollment secret is required to register a new agent".to_string()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn hasher() -> TokenHasher {
+        TokenHasher::new(b"test-pepper-0123456789")
+    }
+
+    fn record(admin_token: &str, agent_key: Option<&str>) -> AgentRecord {
+        let hasher = hasher();
+        AgentRecord {
+            id: "00000000-0000-0000-0000-000000000001".to_string(),
+            name: "agent".to_string(),
+            admin_token_hash: hasher.hash(admin_token),
+            share_token_hash: hasher.hash("share"),
+            created_at: String::new(),
+            last_connected_at: None,
+            agent_key_hash: agent_key.map(|k| hasher.hash(k)).unwrap_or_default(),
+            share_mode: "operator".to_string(),
+        }
+    }
+
+    fn token(expires_at: &str, revoked: i32) -> TokenRecord {
+        TokenRecord {
+            token_hash: hasher().hash("token"),
+            agent_id: "00000000-0000-0000-0000-000000000001".to_string(),
+            kind: "admin".to_string(),
+            created_at: String::new(),
+            expires_at: expires_at.to_string(),
+            last_used_at: String::new(),
+            revoked,
+            scopes: String::new(),
+        }
+    }
+
     #[test]
     fn test_hash_and_verify() {
+        let hasher = hasher();
         let token = "test_token_123";
-        let hash = hash_token(token);
+        let hash = hasher.hash(token);
 
-        assert!(verify_token(token, &hash));
-        assert!(!verify_token("wrong_token", &hash));
+        assert!(hasher.verify(token, &hash));
+        assert!(!hasher.verify("wrong_token", &hash));
     }
 
     #[test]
     fn test_hash_consistency() {
         let token = "my_secret_token";
-        let hash1 = hash_token(token);
-        let hash2 = hash_token(token);
+        let hash1 = hasher().hash(token);
+        let hash2 = hasher().hash(token);
 
         assert_eq!(hash1, hash2);
+        // The pepper is part of the hash
+        assert_ne!(hash1, TokenHasher::new(b"another-pepper-0123456789").hash(token));
+        assert_ne!(hash1, legacy_hash_token(token));
+    }
+
+    #[test]
+    fn test_legacy_hashes() {
+        let hashes = hasher().hashes("token");
+        let legacy = legacy_hash_token("token");
+
+        assert!(hashes.matches(&legacy));
+        assert!(hashes.is_legacy(&legacy));
+        assert!(hashes.matches(&hashes.current));
+        assert!(!hashes.is_legacy(&hashes.current));
+        assert!(!hashes.matches(&legacy_hash_token("other")));
+        assert!(!hashes.matches(""));
+    }
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), 43);
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn test_session_ticket() {
+        let signer = SessionTicketSigner::new(b"ticket-key");
+        let claims = TicketClaims {
+            session_id: Uuid::new_v4(),
+            generation: 3,
+            expires_at: 1_000,
+        };
+        let ticket = signer.issue(&claims);
+        assert_eq!(signer.verify(&ticket, 999), Ok(claims));
+        assert_eq!(signer.verify(&ticket, 1_000), Err("ticket has expired"));
+
+        // Tampered claims, another key and garbage are refused
+        let forged = ticket.replacen(".3.", ".4.", 1);
+        assert_eq!(signer.verify(&forged, 0), Err("invalid ticket signature"));
+        assert!(SessionTicketSigner::generate().verify(&ticket, 0).is_err());
+        assert!(signer.verify("not-a-ticket", 0).is_err());
+    }
+
+    #[test]
+    fn test_check_token_record() {
+        let now = Utc::now();
+        let future = (now + chrono::Duration::hours(1)).to_rfc3339();
+        let past = (now - chrono::Duration::hours(1)).to_rfc3339();
+
+        assert!(check_token_record(&token("", 0), now).is_ok());
+        assert!(check_token_record(&token(&future, 0), now).is_ok());
+        assert_eq!(check_token_record(&token(&past, 0), now), Err("token has expired"));
+        assert_eq!(check_token_record(&token("garbage", 0), now), Err("token has expired"));
+        assert_eq!(check_token_record(&token(&future, 1), now), Err("token has been revoked"));
+    }
+
+    #[test]
+    fn test_enrollment_pinned_key() {
+        let h = hasher();
+        let rec = record("admin", Some("key"));
+        assert!(verify_enrollment(&h, Some(&rec), "admin", Some("key"), None, None).is_ok());
+        // A different admin token is fine as long as the pinned key matches
+        assert!(verify_enrollment(&h, Some(&rec), "rotated", Some("key"), None, None).is_ok());
+        assert!(verify_enrollment(&h, Some(&rec), "admin", Some("other"), None, None).is_err());
+        assert!(verify_enrollment(&h, Some(&rec), "admin", None, None, None).is_err());
+        // The enrollment secret does not override a pinned key
+        assert!(verify_enrollment(&h, Some(&rec), "admin", Some("other"), Some("s"), Some("s")).is_err());
+    }
+
+    #[test]
+    fn test_enrollment_legacy_record() {
+        let h = hasher();
+        let rec = record("admin", None);
+        assert!(verify_enrollment(&h, Some(&rec), "admin", Some("key"), None, None).is_ok());
+        assert!(verify_enrollment(&h, Some(&rec), "attacker", Some("key"), None, None).is_err());
+        assert!(verify_enrollment(&h, Some(&rec), "attacker", Some("key"), Some("s"), Some("s")).is_ok());
+
+        // Rows written before token peppering hold unsalted hashes
+        let mut rec = record("admin", None);
+        rec.admin_token_hash = legacy_hash_token("admin");
+        assert!(verify_enrollment(&h, Some(&rec), "admin", Some("key"), None, None).is_ok());
+        rec.agent_key_hash = legacy_hash_token("key");
+        assert!(verify_enrollment(&h, Some(&rec), "rotated", Some("key"), None, None).is_ok());
+        assert!(verify_enrollment(&h, Some(&rec), "rotated", Some("other"), None, None).is_err());
+    }
+
+    #[test]
+    fn test_enrollment_new_agent() {
+        let h = hasher();
+        assert!(verify_enrollment(&h, None, "admin", Some("key"), None, None).is_ok());
+        assert!(verify_enrollment(&h, None, "admin", Some("key"), None, Some("s")).is_err());
+        assert!(verify_enrollment(&h, None, "admin", Some("key"), Some("wrong"), Some("s")).is_err());
+        assert!(verify_enrollment(&h, None, "admin", Some("key"), Some("s"), Some("s")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pepper_moves_out_of_database() {
+        use crate::config::ServerRuntime;
+        use crate::db::init_database;
+
+        let dir = std::env::temp_dir().join(format!("tunnel-pepper-{}", Uuid::new_v4()));
+        let mut config = ServerRuntime::default_config();
+        config.database.sqlite_path = Some(dir.join("server.db"));
+        config.security.token_pepper_file = Some(dir.join("token_pepper.key"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let runtime = ServerRuntime { config };
+        let repo = AgentRepository::new(init_database(&runtime).await.unwrap());
+        let security = &runtime.config.security;
+
+        // A pepper generated by an earlier version is moved to the key file
+        let legacy = generate_token();
+        repo.set_setting(TOKEN_PEPPER_SETTING, &legacy).await.unwrap();
+        let hasher = load_token_hasher(security, &repo).await.unwrap();
+        assert_eq!(hasher.hash("token"), TokenHasher::new(legacy.as_bytes()).hash("token"));
+        assert!(repo.get_setting(TOKEN_PEPPER_SETTING).await.unwrap().is_none());
+        let stored = std::fs::read_to_string(dir.join("token_pepper.key")).unwrap();
+        assert_eq!(stored.trim(), legacy);
+
+        // Later starts read the file
+        let hasher = load_token_hasher(security, &repo).await.unwrap();
+        assert_eq!(hasher.hash("token"), TokenHasher::new(legacy.as_bytes()).hash("token"));
+
+        // A different pepper left in the database is a conflict, not silently dropped
+        repo.set_setting(TOKEN_PEPPER_SETTING, &generate_token()).await.unwrap();
+        assert!(load_token_hasher(security, &repo).await.is_err());
+
+        // The configured pepper wins over the file
+        let mut configured = security.clone();
+        configured.token_pepper = Some("configured-pepper-0123".to_string());
+        let hasher = load_token_hasher(&configured, &repo).await.unwrap();
+        assert_eq!(hasher.hash("token"), TokenHasher::new(b"configured-pepper-0123").hash("token"));
+
+        std::fs::remove_dir_all(&dir).ok();
     }
 }
@@ -1,11 +1,12 @@
 //! Command line argument parsing for the server
 
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 use std::path::PathBuf;
 
 /// Claude Tunnel Server - Host Claude Code tunnel service
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("scope_target").args(["add_admin", "set_scopes"])))]
 pub struct Args {
     /// Configuration file path
     #[arg(short, long, default_value = "server.toml")]
@@ -22,4 +23,46 @@ pub struct Args {
     /// Override port to listen on
     #[arg(long)]
     pub port: Option<u16>,
+
+    /// Generate a TOTP secret, store it in the database and exit
+    /// With NAME the secret belongs to that SuperAdmin account, otherwise it is the shared secret
+    #[arg(long, value_name = "NAME", num_args = 0..=1, conflicts_with = "disable_totp")]
+    pub setup_totp: Option<Option<String>>,
+
+    /// Remove a TOTP secret (of account NAME, or the shared one) from the database and exit
+    #[arg(long, value_name = "NAME", num_args = 0..=1)]
+    pub disable_totp: Option<Option<String>>,
+
+    /// Create a named SuperAdmin account, print its token and exit
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["disable_admin", "list_admins"])]
+    pub add_admin: Option<String>,
+
+    /// Disable a named SuperAdmin account and exit
+    #[arg(long, value_name = "NAME", conflicts_with = "list_admins")]
+    pub disable_admin: Option<String>,
+
+    /// List SuperAdmin accounts and exit
+    #[arg(long)]
+    pub list_admins: bool,
+
+    /// Set the scopes of a SuperAdmin account NAME or an agent token AGENT_ID:admin|share and exit
+    /// Without --scopes the target gets every scope of its role back
+    #[arg(long, value_name = "TARGET", conflicts_with_all = ["add_admin", "disable_admin", "list_admins"])]
+    pub set_scopes: Option<String>,
+
+    /// Comma separated scopes for --add-admin or --set-scopes (e.g. audit.read,admin.stats)
+    #[arg(long, value_name = "LIST", requires = "scope_target")]
+    pub scopes: Option<String>,
+
+    /// Verify the audit log hash chain, report the first broken link and exit
+    #[arg(long)]
+    pub verify_audit_log: bool,
+
+    /// Export the whole audit log to FILE and exit
+    #[arg(long, value_name = "FILE", conflicts_with = "verify_audit_log")]
+    pub export_audit_log: Option<PathBuf>,
+
+    /// Format of --export-audit-log
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = ["csv", "json", "jsonl"])]
+    pub export_format: String,
 }
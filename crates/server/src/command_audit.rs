@@ -0,0 +1,264 @@
+//! Command auditing: rebuilds submitted command lines from PTY input
+//! and checks them against the configured blocklist
+//!
+//! Reconstruction is best effort: cursor movement and history recall
+//! (arrow keys) are not tracked, only typed and pasted text, backspace,
+//! line-kill keys and Enter.
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use common::CommandAuditConfig;
+
+/// Longest command line kept in the buffer; longer lines are marked truncated
+const MAX_LINE_BYTES: usize = 4096;
+
+const ESC: u8 = 0x1b;
+const BRACKETED_PASTE_START: &[u8] = b"200~";
+const BRACKETED_PASTE_END: &[u8] = b"201~";
+
+/// Escape sequence parser state, kept across input chunks
+#[derive(Debug, Default)]
+enum EscapeState {
+    #[default]
+    Ground,
+    /// After ESC
+    Escape,
+    /// Inside `ESC [ ...`, collecting parameter bytes
+    Csi(Vec<u8>),
+    /// After `ESC O` (SS3, e.g. application-mode arrow keys)
+    Ss3,
+}
+
+/// A command line submitted with Enter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    /// Reconstructed text (the first `MAX_LINE_BYTES` bytes if truncated)
+    pub text: String,
+    /// The line was longer than `MAX_LINE_BYTES`; its end was not seen
+    /// and the blocklist cannot be trusted on it
+    pub truncated: bool,
+}
+
+impl PartialEq<&str> for CommandLine {
+    fn eq(&self, other: &&str) -> bool {
+        !self.truncated && self.text == *other
+    }
+}
+
+/// Rebuilds command lines from the raw input of one terminal instance
+#[derive(Debug, Default)]
+pub struct LineAssembler {
+    line: Vec<u8>,
+    /// Input was dropped from the current line
+    overflowed: bool,
+    in_paste: bool,
+    escape: EscapeState,
+}
+
+impl LineAssembler {
+    /// Feed raw input bytes and return every line completed by Enter
+    pub fn feed(&mut self, input: &[u8]) -> Vec<CommandLine> {
+        let mut lines = Vec::new();
+
+        for &byte in input {
+            match std::mem::take(&mut self.escape) {
+                EscapeState::Ground => self.ground(byte, &mut lines),
+                EscapeState::Escape => match byte {
+                    b'[' => self.escape = EscapeState::Csi(Vec::new()),
+                    b'O' => self.escape = EscapeState::Ss3,
+                    // Alt+key and other two-byte sequences are ignored
+                    _ => {}
+                },
+                EscapeState::Csi(mut params) => {
+                    if (0x40..=0x7e).contains(&byte) {
+                        params.push(byte);
+                        if params == BRACKETED_PASTE_START {
+                            self.in_paste = true;
+                        } else if params == BRACKETED_PASTE_END {
+                            self.in_paste = false;
+                        }
+                    } else if params.len() < 16 {
+                        params.push(byte);
+                        self.escape = EscapeState::Csi(params);
+                    }
+                }
+                EscapeState::Ss3 => {}
+            }
+        }
+
+        lines
+    }
+
+    /// Discard the partially typed line
+    pub fn reset(&mut self) {
+        self.line.clear();
+        self.overflowed = false;
+        self.in_paste = false;
+        self.escape = EscapeState::Ground;
+    }
+
+    fn ground(&mut self, byte: u8, lines: &mut Vec<CommandLine>) {
+        match byte {
+            ESC => self.escape = EscapeState::Escape,
+            // Newlines inside a bracketed paste are part of the pasted text
+            b'\r' | b'\n' if self.in_paste => self.push(b'\n'),
+            b'\r' | b'\n' => {
+                let text = String::from_utf8_lossy(&self.line).trim().to_string();
+                let truncated = std::mem::take(&mut self.overflowed);
+                self.line.clear();
+                // An overflowed line is reported even if its kept part is blank
+                if !text.is_empty() || truncated {
+                    lines.push(CommandLine { text, truncated });
+                }
+            }
+            // Backspace / DEL remove one UTF-8 character
+            0x08 | 0x7f => {
+                while let Some(b) = self.line.pop() {
+                    if b & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            // Ctrl+C, Ctrl+U: the line is abandoned
+            0x03 | 0x15 => {
+                self.line.clear();
+                self.overflowed = false;
+            }
+            // Ctrl+W: delete the previous word
+            0x17 => {
+                while self.line.last() == Some(&b' ') {
+                    self.line.pop();
+                }
+                while self.line.last().is_some_and(|b| *b != b' ') {
+                    self.line.pop();
+                }
+            }
+            b'\t' => self.push(b' '),
+            b if b < 0x20 => {}
+            b => self.push(b),
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.line.len() < MAX_LINE_BYTES {
+            self.line.push(byte);
+        } else {
+            // Still forwarded to the shell, so the line can no longer be checked
+            self.overflowed = true;
+        }
+    }
+}
+
+/// Regex blocklist for command lines
+#[derive(Debug, Default)]
+pub struct CommandFilter {
+    /// Whether command lines are written to the audit log
+    pub audit: bool,
+    blocklist: Vec<Regex>,
+}
+
+impl CommandFilter {
+    /// Build the filter, failing on an invalid pattern
+    pub fn from_config(config: &CommandAuditConfig) -> Result<Self> {
+        let blocklist = config
+            .blocklist
+            .iter()
+            .map(|p| Regex::new(p).map_err(|e| anyhow!("Invalid command blocklist pattern '{}': {}", p, e)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            audit: config.enabled,
+            blocklist,
+        })
+    }
+
+    /// Whether input needs to be assembled into lines at all
+    pub fn is_active(&self) -> bool {
+        self.audit || !self.blocklist.is_empty()
+    }
+
+    /// Whether a blocklist is configured
+    pub fn has_blocklist(&self) -> bool {
+        !self.blocklist.is_empty()
+    }
+
+    /// Return the first blocklist pattern matching the line
+    pub fn blocked_by(&self, line: &str) -> Option<&str> {
+        self.blocklist
+            .iter()
+            .find(|re| re.is_match(line))
+            .map(|re| re.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assembler_typing_and_backspace() {
+        let mut asm = LineAssembler::default();
+        assert!(asm.feed(b"ls -l").is_empty());
+        assert!(asm.feed(b"x\x7f").is_empty());
+        assert_eq!(asm.feed(b"a\r"), vec!["ls -la"]);
+
+        // Multi-byte characters are removed as a whole
+        assert_eq!(asm.feed("echo 你好\x7f\x7f\r".as_bytes()), vec!["echo"]);
+        assert_eq!(asm.feed(b"rm foo\x15pwd\r"), vec!["pwd"]);
+        assert_eq!(asm.feed(b"git push origin\x17main\r"), vec!["git push main"]);
+        assert!(asm.feed(b"\r").is_empty());
+    }
+
+    #[test]
+    fn test_assembler_escape_sequences() {
+        let mut asm = LineAssembler::default();
+        // Arrow keys are dropped, even when split across chunks
+        assert!(asm.feed(b"ab\x1b[").is_empty());
+        assert_eq!(asm.feed(b"Dc\x1bOA\r"), vec!["abc"]);
+
+        // Bracketed paste keeps newlines until Enter
+        assert!(asm.feed(b"\x1b[200~echo 1\necho 2\x1b[201~").is_empty());
+        assert_eq!(asm.feed(b"\r"), vec!["echo 1\necho 2"]);
+        assert_eq!(asm.feed(b"a\rb\r"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_assembler_overflow() {
+        let mut asm = LineAssembler::default();
+
+        // Padding pushes the command past the buffer: the line is flagged, not silently cut
+        let mut input = vec![b' '; MAX_LINE_BYTES];
+        input.extend_from_slice(b"rm -rf /\r");
+        let lines = asm.feed(&input);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].truncated);
+        assert_eq!(lines[0].text, "");
+
+        // The flag does not leak into the next line
+        assert_eq!(asm.feed(b"ls\r"), vec!["ls"]);
+
+        // Killing an overflowed line clears the flag too
+        asm.feed(&vec![b'x'; MAX_LINE_BYTES + 1]);
+        assert_eq!(asm.feed(b"\x15pwd\r"), vec!["pwd"]);
+    }
+
+    #[test]
+    fn test_filter() {
+        let config = CommandAuditConfig {
+            enabled: false,
+            blocklist: vec![r"rm\s+-rf\s+/(\s|$)".to_string(), r"curl.*\|\s*(ba)?sh".to_string()],
+        };
+        let filter = CommandFilter::from_config(&config).unwrap();
+        assert!(filter.is_active());
+        assert!(filter.blocked_by("sudo rm -rf /").is_some());
+        assert!(filter.blocked_by("curl https://x.sh | sh").is_some());
+        assert!(filter.blocked_by("rm -rf /tmp/build").is_none());
+
+        let config = CommandAuditConfig {
+            enabled: true,
+            blocklist: vec!["(".to_string()],
+        };
+        assert!(CommandFilter::from_config(&config).is_err());
+    }
+}
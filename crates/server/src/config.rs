@@ -3,10 +3,16 @@
 use anyhow::{anyhow, Result};
 use std::path::PathBuf;
 
-use common::{AuditLogConfig, DatabaseConfig, HttpServerConfig, LoggingConfig, SecurityConfig, ServerConfig, TerminalHistoryConfig};
+use common::{AgentMtlsConfig, AuditLogConfig, CommandAuditConfig, DatabaseConfig, HttpServerConfig, LoggingConfig, OidcConfig, SecurityConfig, ServerConfig, TerminalHistoryConfig};
 
 use crate::cli::Args;
 
+/// Environment variable overriding `security.token_pepper`
+const TOKEN_PEPPER_ENV: &str = "CLAUDE_TUNNEL_TOKEN_PEPPER";
+
+/// Generated pepper file, next to the config file
+const DEFAULT_TOKEN_PEPPER_FILE: &str = "token_pepper.key";
+
 /// Runtime configuration with resolved values
 #[derive(Clone)]
 pub struct ServerRuntime {
@@ -34,23 +40,42 @@ impl ServerRuntime {
         }
 
         // Validate configuration
-        if config.security.super_admin_token.is_empty()
-            || config.security.super_admin_token == "CHANGE_ME_TO_A_RANDOM_TOKEN"
-        {
+        // An empty token is allowed: SuperAdmin logins then use database accounts only
+        if config.security.super_admin_token == "CHANGE_ME_TO_A_RANDOM_TOKEN" {
             return Err(anyhow!(
-                "Super admin token must be configured. Please set security.super_admin_token in config file."
+                "Super admin token must be changed. Please set security.super_admin_token in config file, \
+                 or leave it empty and create accounts with --add-admin."
             ));
         }
 
+        // The pepper may be injected at deploy time instead of living in the config file
+        if let Ok(pepper) = std::env::var(TOKEN_PEPPER_ENV) {
+            config.security.token_pepper = Some(pepper);
+        }
+        let pepper_file = config
+            .security
+            .token_pepper_file
+            .take()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_TOKEN_PEPPER_FILE));
+        config.security.token_pepper_file = Some(match args.config.parent() {
+            Some(dir) if pepper_file.is_relative() => dir.join(pepper_file),
+            _ => pepper_file,
+        });
+
+        if config.security.session_ticket_ttl_secs < 60 {
+            return Err(anyhow!("security.session_ticket_ttl_secs must be at least 60 seconds"));
+        }
+
         Ok(Self { config })
     }
 
     /// Create a default configuration
-    fn default_config() -> ServerConfig {
+    pub(crate) fn default_config() -> ServerConfig {
         ServerConfig {
             server: HttpServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8080,
+                tls: None,
             },
             database: DatabaseConfig {
                 db_type: "sqlite".to_string(),
@@ -61,7 +86,22 @@ impl ServerRuntime {
             security: SecurityConfig {
                 super_admin_token: String::new(),
                 rate_limit_per_minute: 10,
+                auth_lockout_threshold: 5,
+                auth_lockout_base_secs: 30,
+                auth_lockout_max_secs: 3600,
                 token_min_length: 32,
+                agent_enrollment_secret: None,
+                token_ttl_days: None,
+                token_pepper: None,
+                token_pepper_file: None,
+                user_allow_cidrs: Vec::new(),
+                user_deny_cidrs: Vec::new(),
+                agent_allow_cidrs: Vec::new(),
+                agent_deny_cidrs: Vec::new(),
+                trusted_proxies: Vec::new(),
+                super_admin_totp_secret: None,
+                session_ticket_ttl_secs: 900,
+                max_sessions_per_token: 0,
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
@@ -70,6 +110,9 @@ impl ServerRuntime {
             },
             terminal_history: TerminalHistoryConfig::default(),
             audit_log: AuditLogConfig::default(),
+            command_audit: CommandAuditConfig::default(),
+            oidc: OidcConfig::default(),
+            agent_mtls: AgentMtlsConfig::default(),
         }
     }
 }
@@ -4,6 +4,7 @@ mod schema;
 mod repository;
 
 pub use repository::*;
+pub use schema::{AgentRecord, AuditLogRecord, TokenRecord};
 
 use anyhow::Result;
 use sqlx::{any::AnyPoolOptions, AnyPool};
@@ -32,7 +33,7 @@ pub async fn init_database(runtime: &ServerRuntime) -> Result<AnyPool> {
         }
     };
 
-    tracing::info!("Connecting to database: {} (type: {})", url.split('@').last().unwrap_or(&url), db_type);
+    tracing::info!("Connecting to database: {} (type: {})", url.split('@').next_back().unwrap_or(&url), db_type);
 
     let pool = AnyPoolOptions::new()
         .max_connections(5)
@@ -53,12 +54,32 @@ async fn run_migrations(pool: &AnyPool, db_type: &str) -> Result<()> {
             include_str!("../../migrations/sqlite/002_agent_tags.sql"),
             include_str!("../../migrations/sqlite/003_terminal_history.sql"),
             include_str!("../../migrations/sqlite/004_audit_logs.sql"),
+            include_str!("../../migrations/sqlite/005_agent_enrollment.sql"),
+            include_str!("../../migrations/sqlite/006_share_mode.sql"),
+            include_str!("../../migrations/sqlite/007_agent_tokens.sql"),
+            include_str!("../../migrations/sqlite/008_server_settings.sql"),
+            include_str!("../../migrations/sqlite/009_super_admins.sql"),
+            include_str!("../../migrations/sqlite/010_super_admin_totp.sql"),
+            include_str!("../../migrations/sqlite/011_instance_invites.sql"),
+            include_str!("../../migrations/sqlite/012_scopes.sql"),
+            include_str!("../../migrations/sqlite/013_audit_chain.sql"),
+            include_str!("../../migrations/sqlite/014_audit_log_indexes.sql"),
         ],
         "mysql" => vec![
             include_str!("../../migrations/mysql/001_create_agents.sql"),
             include_str!("../../migrations/mysql/002_agent_tags.sql"),
             include_str!("../../migrations/mysql/003_terminal_history.sql"),
             include_str!("../../migrations/mysql/004_audit_logs.sql"),
+            include_str!("../../migrations/mysql/005_agent_enrollment.sql"),
+            include_str!("../../migrations/mysql/006_share_mode.sql"),
+            include_str!("../../migrations/mysql/007_agent_tokens.sql"),
+            include_str!("../../migrations/mysql/008_server_settings.sql"),
+            include_str!("../../migrations/mysql/009_super_admins.sql"),
+            include_str!("../../migrations/mysql/010_super_admin_totp.sql"),
+            include_str!("../../migrations/mysql/011_instance_invites.sql"),
+            include_str!("../../migrations/mysql/012_scopes.sql"),
+            include_str!("../../migrations/mysql/013_audit_chain.sql"),
+            include_str!("../../migrations/mysql/014_audit_log_indexes.sql"),
         ],
         _ => return Err(anyhow::anyhow!("Unsupported database type: {}", db_type)),
     };
@@ -70,8 +91,12 @@ async fn run_migrations(pool: &AnyPool, db_type: &str) -> Result<()> {
                 .execute(pool)
                 .await
                 .map_err(|e| {
-                    // Ignore "already exists" errors for CREATE INDEX
-                    if e.to_string().contains("already exists") || e.to_string().contains("Duplicate key name") {
+                    // Ignore "already exists" errors for CREATE INDEX / ALTER TABLE ADD COLUMN
+                    if e.to_string().contains("already exists")
+                        || e.to_string().contains("Duplicate key name")
+                        || e.to_string().contains("duplicate column name")
+                        || e.to_string().contains("Duplicate column name")
+                    {
                         tracing::debug!("Skipping existing object: {}", e);
                         return anyhow::anyhow!("skip");
                     }
@@ -1,56 +1,174 @@
 //! Database repository for CRUD operations
 
+use std::sync::Arc;
+
 use anyhow::Result;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use sqlx::AnyPool;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use super::schema::{AgentRecord, AuditLogRecord, TerminalHistoryRecord, TerminalHistoryMetaRecord};
-use crate::auth::hash_token;
+use common::{ShareMode, TokenKind};
+
+use super::schema::{
+    AgentRecord, AuditLogRecord, InviteRecord, SuperAdminRecord, TerminalHistoryMetaRecord,
+    TerminalHistoryRecord, TokenRecord,
+};
+use crate::audit_chain::{AuditChainKey, AuditEntry, GENESIS_HASH};
+use crate::auth::TokenHashes;
+
+/// Columns selected for `AgentRecord`
+/// IFNULL converts NULL to empty string for SQLite compatibility with SQLx
+const AGENT_COLUMNS: &str = "id, name, admin_token_hash, share_token_hash, created_at, last_connected_at, \
+     IFNULL(agent_key_hash, '') AS agent_key_hash, IFNULL(share_mode, 'operator') AS share_mode";
+
+/// Columns selected for `TokenRecord`
+const TOKEN_COLUMNS: &str = "token_hash, agent_id, kind, created_at, \
+     IFNULL(expires_at, '') AS expires_at, IFNULL(last_used_at, '') AS last_used_at, revoked, \
+     IFNULL(scopes, '') AS scopes";
+
+/// Columns selected for `SuperAdminRecord`
+const SUPER_ADMIN_COLUMNS: &str = "id, name, token_hash, created_at, \
+     IFNULL(last_login_at, '') AS last_login_at, disabled, IFNULL(totp_secret, '') AS totp_secret, \
+     IFNULL(scopes, '') AS scopes";
+
+/// Columns selected for `AuditLogRecord`
+/// Use IFNULL to convert NULL to empty string for SQLite compatibility with SQLx
+const AUDIT_LOG_COLUMNS: &str = "id, timestamp, event_type, session_id, user_role, \
+     IFNULL(agent_id, '') AS agent_id, IFNULL(instance_id, '') AS instance_id, \
+     IFNULL(target_id, '') AS target_id, client_ip, success, IFNULL(details, '') AS details, \
+     IFNULL(actor, '') AS actor, IFNULL(prev_hash, '') AS prev_hash, IFNULL(entry_hash, '') AS entry_hash, \
+     IFNULL(chain_timestamp, '') AS chain_timestamp";
+
+/// Columns selected for `InviteRecord`
+const INVITE_COLUMNS: &str = "token_hash, agent_id, instance_id, read_only, max_uses, uses, created_at, \
+     expires_at, IFNULL(created_by, '') AS created_by";
+
+/// Column of the `agents` table holding the current hash of a token kind
+fn token_hash_column(kind: TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Admin => "admin_token_hash",
+        TokenKind::Share => "share_token_hash",
+    }
+}
+
+/// Filters for `get_audit_logs`; every field that is set must match
+#[derive(Debug, Clone, Default)]
+pub struct AuditLogFilter {
+    /// Event type
+    pub event_type: Option<String>,
+    /// Events at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Events before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Associated agent ID
+    pub agent_id: Option<String>,
+    /// Associated instance ID
+    pub instance_id: Option<String>,
+    /// Client IP address
+    pub client_ip: Option<String>,
+    /// Operation outcome
+    pub success: Option<bool>,
+    /// User session ID
+    pub session_id: Option<String>,
+    /// Substring of the details (case-insensitive for ASCII)
+    pub search: Option<String>,
+}
+
+impl AuditLogFilter {
+    /// WHERE clause with `?` placeholders and the values to bind, in order
+    /// Timestamps are compared as RFC 3339 strings, the format they are stored in
+    fn where_clause(&self) -> (String, Vec<String>) {
+        let mut conditions = Vec::new();
+        let mut binds = Vec::new();
+        let columns = [
+            ("event_type = ?", &self.event_type),
+            ("agent_id = ?", &self.agent_id),
+            ("instance_id = ?", &self.instance_id),
+            ("client_ip = ?", &self.client_ip),
+            ("session_id = ?", &self.session_id),
+        ];
+        for (condition, value) in columns {
+            if let Some(value) = value {
+                conditions.push(condition.to_string());
+                binds.push(value.clone());
+            }
+        }
+        if let Some(since) = self.since {
+            conditions.push("timestamp >= ?".to_string());
+            binds.push(since.to_rfc3339());
+        }
+        if let Some(until) = self.until {
+            conditions.push("timestamp < ?".to_string());
+            binds.push(until.to_rfc3339());
+        }
+        if let Some(success) = self.success {
+            conditions.push(format!("success = {}", if success { 1 } else { 0 }));
+        }
+        if let Some(search) = self.search.as_deref().filter(|s| !s.is_empty()) {
+            // '!' is the escape character: SQLite and MySQL read a backslash in a literal differently
+            let pattern = search.replace('!', "!!").replace('%', "!%").replace('_', "!_");
+            conditions.push("details LIKE ? ESCAPE '!'".to_string());
+            binds.push(format!("%{}%", pattern));
+        }
+
+        if conditions.is_empty() {
+            return (String::new(), binds);
+        }
+        (format!("WHERE {}", conditions.join(" AND ")), binds)
+    }
+}
 
 /// Repository for agent database operations
 #[derive(Clone)]
 pub struct AgentRepository {
     pool: AnyPool,
+    /// Serializes audit log inserts so each row chains to the one before it
+    audit_chain: Arc<Mutex<()>>,
 }
 
 impl AgentRepository {
     /// Create a new repository
     pub fn new(pool: AnyPool) -> Self {
-        Self { pool }
+        Self { pool, audit_chain: Arc::new(Mutex::new(())) }
     }
 
     /// Create or update an agent in the database
+    /// The agent key hash is only written once; an already pinned key is never replaced
     pub async fn upsert_agent(
         &self,
         id: Uuid,
         name: &str,
-        admin_token: &str,
-        share_token: &str,
+        admin_hash: &str,
+        share_hash: &str,
+        share_mode: ShareMode,
+        agent_key_hash: Option<&str>,
     ) -> Result<()> {
         let id_str = id.to_string();
-        let admin_hash = hash_token(admin_token);
-        let share_hash = hash_token(share_token);
         let now = Utc::now().to_rfc3339();
 
         // Try to insert, if exists update
         sqlx::query(
             r#"
-            INSERT INTO agents (id, name, admin_token_hash, share_token_hash, created_at, last_connected_at)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO agents (id, name, admin_token_hash, share_token_hash, created_at, last_connected_at, agent_key_hash, share_mode)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(id) DO UPDATE SET
                 name = excluded.name,
                 admin_token_hash = excluded.admin_token_hash,
                 share_token_hash = excluded.share_token_hash,
-                last_connected_at = excluded.last_connected_at
+                share_mode = excluded.share_mode,
+                last_connected_at = excluded.last_connected_at,
+                agent_key_hash = COALESCE(agents.agent_key_hash, excluded.agent_key_hash)
             "#,
         )
         .bind(&id_str)
         .bind(name)
-        .bind(&admin_hash)
-        .bind(&share_hash)
+        .bind(admin_hash)
+        .bind(share_hash)
         .bind(&now)
         .bind(&now)
+        .bind(agent_key_hash)
+        .bind(share_mode.as_str())
         .execute(&self.pool)
         .await?;
 
@@ -72,28 +190,26 @@ impl AgentRepository {
         Ok(())
     }
 
-    /// Find agent by admin token hash
-    pub async fn find_by_admin_token(&self, token: &str) -> Result<Option<AgentRecord>> {
-        let hash = hash_token(token);
-
+    /// Find agent by admin token (current or legacy hash)
+    pub async fn find_by_admin_token(&self, hashes: &TokenHashes) -> Result<Option<AgentRecord>> {
         let record = sqlx::query_as::<_, AgentRecord>(
-            "SELECT id, name, admin_token_hash, share_token_hash, created_at, last_connected_at FROM agents WHERE admin_token_hash = ?"
+            &format!("SELECT {} FROM agents WHERE admin_token_hash IN (?, ?)", AGENT_COLUMNS)
         )
-        .bind(&hash)
+        .bind(&hashes.current)
+        .bind(&hashes.legacy)
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(record)
     }
 
-    /// Find agent by share token hash
-    pub async fn find_by_share_token(&self, token: &str) -> Result<Option<AgentRecord>> {
-        let hash = hash_token(token);
-
+    /// Find agent by share token (current or legacy hash)
+    pub async fn find_by_share_token(&self, hashes: &TokenHashes) -> Result<Option<AgentRecord>> {
         let record = sqlx::query_as::<_, AgentRecord>(
-            "SELECT id, name, admin_token_hash, share_token_hash, created_at, last_connected_at FROM agents WHERE share_token_hash = ?"
+            &format!("SELECT {} FROM agents WHERE share_token_hash IN (?, ?)", AGENT_COLUMNS)
         )
-        .bind(&hash)
+        .bind(&hashes.current)
+        .bind(&hashes.legacy)
         .fetch_optional(&self.pool)
         .await?;
 
@@ -101,12 +217,11 @@ impl AgentRepository {
     }
 
     /// Find agent by ID
-    #[allow(dead_code)]
     pub async fn find_by_id(&self, id: Uuid) -> Result<Option<AgentRecord>> {
         let id_str = id.to_string();
 
         let record = sqlx::query_as::<_, AgentRecord>(
-            "SELECT id, name, admin_token_hash, share_token_hash, created_at, last_connected_at FROM agents WHERE id = ?"
+            &format!("SELECT {} FROM agents WHERE id = ?", AGENT_COLUMNS)
         )
         .bind(&id_str)
         .fetch_optional(&self.pool)
@@ -119,7 +234,7 @@ impl AgentRepository {
     #[allow(dead_code)]
     pub async fn find_all(&self) -> Result<Vec<AgentRecord>> {
         let records = sqlx::query_as::<_, AgentRecord>(
-            "SELECT id, name, admin_token_hash, share_token_hash, created_at, last_connected_at FROM agents ORDER BY created_at DESC"
+            &format!("SELECT {} FROM agents ORDER BY created_at DESC", AGENT_COLUMNS)
         )
         .fetch_all(&self.pool)
         .await?;
@@ -137,6 +252,472 @@ impl AgentRepository {
             .execute(&self.pool)
             .await?;
 
+        sqlx::query("DELETE FROM agent_tokens WHERE agent_id = ?")
+            .bind(&id_str)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // ========================================================================
+    // Token operations
+    // ========================================================================
+
+    /// Record a token the first time it is seen
+    /// Existing rows are left untouched, so a revoked token stays revoked
+    pub async fn ensure_token(
+        &self,
+        agent_id: Uuid,
+        kind: TokenKind,
+        token_hash: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            INSERT INTO agent_tokens (token_hash, agent_id, kind, created_at, expires_at, revoked)
+            VALUES (?, ?, ?, ?, ?, 0)
+            ON CONFLICT(token_hash) DO NOTHING
+            "#,
+        )
+        .bind(token_hash)
+        .bind(agent_id.to_string())
+        .bind(kind.as_str())
+        .bind(&now)
+        .bind(expires_at.map(|t| t.to_rfc3339()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find a token by its hash
+    pub async fn find_token(&self, token_hash: &str) -> Result<Option<TokenRecord>> {
+        let record = sqlx::query_as::<_, TokenRecord>(
+            &format!("SELECT {} FROM agent_tokens WHERE token_hash = ?", TOKEN_COLUMNS)
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Find a token by its current or legacy hash
+    pub async fn find_token_by_hashes(&self, hashes: &TokenHashes) -> Result<Option<TokenRecord>> {
+        let record = sqlx::query_as::<_, TokenRecord>(
+            &format!("SELECT {} FROM agent_tokens WHERE token_hash IN (?, ?)", TOKEN_COLUMNS)
+        )
+        .bind(&hashes.current)
+        .bind(&hashes.legacy)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Update the last used timestamp of a token
+    pub async fn touch_token(&self, token_hash: &str) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query("UPDATE agent_tokens SET last_used_at = ? WHERE token_hash = ?")
+            .bind(&now)
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace an agent token: revoke every previous token of the kind,
+    /// store the new one and point the agent record at it
+    pub async fn rotate_token(
+        &self,
+        agent_id: Uuid,
+        kind: TokenKind,
+        new_token_hash: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let id_str = agent_id.to_string();
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.pool.begin().await?;
+
+        // Write first so SQLite takes the write lock (waiting for other writers)
+        // instead of failing with "database is locked" when a read lock is upgraded
+        sqlx::query(&format!("UPDATE agents SET {} = ? WHERE id = ?", token_hash_column(kind)))
+            .bind(new_token_hash)
+            .bind(&id_str)
+            .execute(&mut *tx)
+            .await?;
+
+        // The new token keeps the scopes of the one it replaces
+        let scopes = sqlx::query_scalar::<_, String>(
+            "SELECT scopes FROM agent_tokens WHERE agent_id = ? AND kind = ? AND revoked = 0 AND scopes IS NOT NULL",
+        )
+        .bind(&id_str)
+        .bind(kind.as_str())
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE agent_tokens SET revoked = 1 WHERE agent_id = ? AND kind = ?")
+            .bind(&id_str)
+            .bind(kind.as_str())
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO agent_tokens (token_hash, agent_id, kind, created_at, expires_at, revoked, scopes)
+            VALUES (?, ?, ?, ?, ?, 0, ?)
+            "#,
+        )
+        .bind(new_token_hash)
+        .bind(&id_str)
+        .bind(kind.as_str())
+        .bind(&now)
+        .bind(expires_at.map(|t| t.to_rfc3339()))
+        .bind(scopes)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Revoke every token of a kind for an agent, including its current one
+    /// Returns false if the agent is unknown
+    pub async fn revoke_tokens(&self, agent_id: Uuid, kind: TokenKind) -> Result<bool> {
+        let Some(record) = self.find_by_id(agent_id).await? else {
+            return Ok(false);
+        };
+        let current_hash = match kind {
+            TokenKind::Admin => &record.admin_token_hash,
+            TokenKind::Share => &record.share_token_hash,
+        };
+
+        // Agents not seen since the tokens table was added have no row yet
+        self.ensure_token(agent_id, kind, current_hash, None).await?;
+
+        sqlx::query("UPDATE agent_tokens SET revoked = 1 WHERE agent_id = ? AND kind = ?")
+            .bind(agent_id.to_string())
+            .bind(kind.as_str())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Set the scopes of an agent's live tokens of a kind (None = every scope of its role)
+    /// Returns false if the agent is unknown
+    pub async fn set_token_scopes(&self, agent_id: Uuid, kind: TokenKind, scopes: Option<&str>) -> Result<bool> {
+        let Some(record) = self.find_by_id(agent_id).await? else {
+            return Ok(false);
+        };
+        let current_hash = match kind {
+            TokenKind::Admin => &record.admin_token_hash,
+            TokenKind::Share => &record.share_token_hash,
+        };
+
+        // Agents not seen since the tokens table was added have no row yet
+        self.ensure_token(agent_id, kind, current_hash, None).await?;
+
+        sqlx::query("UPDATE agent_tokens SET scopes = ? WHERE agent_id = ? AND kind = ? AND revoked = 0")
+            .bind(scopes)
+            .bind(agent_id.to_string())
+            .bind(kind.as_str())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Replace a legacy (unsalted) token hash with its keyed hash wherever it is stored
+    /// The token's row in `agent_tokens` keeps its expiry and revocation state
+    pub async fn upgrade_token_hash(&self, hashes: &TokenHashes) -> Result<()> {
+        let legacy_row = self.find_token(&hashes.legacy).await?;
+        let current_row = self.find_token(&hashes.current).await?;
+        let mut tx = self.pool.begin().await?;
+
+        for column in ["admin_token_hash", "share_token_hash", "agent_key_hash"] {
+            sqlx::query(&format!("UPDATE agents SET {column} = ? WHERE {column} = ?"))
+                .bind(&hashes.current)
+                .bind(&hashes.legacy)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("UPDATE super_admins SET token_hash = ? WHERE token_hash = ?")
+            .bind(&hashes.current)
+            .bind(&hashes.legacy)
+            .execute(&mut *tx)
+            .await?;
+
+        match (legacy_row, current_row) {
+            (Some(_), None) => {
+                sqlx::query("UPDATE agent_tokens SET token_hash = ? WHERE token_hash = ?")
+                    .bind(&hashes.current)
+                    .bind(&hashes.legacy)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            (Some(legacy), Some(_)) => {
+                // Both rows exist: keep the keyed one, never losing a revocation
+                if legacy.is_revoked() {
+                    sqlx::query("UPDATE agent_tokens SET revoked = 1 WHERE token_hash = ?")
+                        .bind(&hashes.current)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+                sqlx::query("DELETE FROM agent_tokens WHERE token_hash = ?")
+                    .bind(&hashes.legacy)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            (None, _) => {}
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // ========================================================================
+    // SuperAdmin account operations
+    // ========================================================================
+
+    /// Create a SuperAdmin account (scopes None = every scope)
+    pub async fn create_super_admin(
+        &self,
+        name: &str,
+        token_hash: &str,
+        scopes: Option<&str>,
+    ) -> Result<SuperAdminRecord> {
+        let record = SuperAdminRecord {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            token_hash: token_hash.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+            last_login_at: String::new(),
+            disabled: 0,
+            totp_secret: String::new(),
+            scopes: scopes.unwrap_or_default().to_string(),
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO super_admins (id, name, token_hash, created_at, disabled, scopes)
+            VALUES (?, ?, ?, ?, 0, ?)
+            "#,
+        )
+        .bind(&record.id)
+        .bind(&record.name)
+        .bind(&record.token_hash)
+        .bind(&record.created_at)
+        .bind(scopes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Find a SuperAdmin account by token (current or legacy hash)
+    pub async fn find_super_admin_by_token(&self, hashes: &TokenHashes) -> Result<Option<SuperAdminRecord>> {
+        let record = sqlx::query_as::<_, SuperAdminRecord>(
+            &format!("SELECT {} FROM super_admins WHERE token_hash IN (?, ?)", SUPER_ADMIN_COLUMNS)
+        )
+        .bind(&hashes.current)
+        .bind(&hashes.legacy)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Find a SuperAdmin account by name
+    pub async fn find_super_admin_by_name(&self, name: &str) -> Result<Option<SuperAdminRecord>> {
+        let record = sqlx::query_as::<_, SuperAdminRecord>(
+            &format!("SELECT {} FROM super_admins WHERE name = ?", SUPER_ADMIN_COLUMNS)
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// List all SuperAdmin accounts
+    pub async fn find_all_super_admins(&self) -> Result<Vec<SuperAdminRecord>> {
+        let records = sqlx::query_as::<_, SuperAdminRecord>(
+            &format!("SELECT {} FROM super_admins ORDER BY created_at", SUPER_ADMIN_COLUMNS)
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Enable or disable a SuperAdmin account, returns false if it does not exist
+    pub async fn set_super_admin_disabled(&self, name: &str, disabled: bool) -> Result<bool> {
+        let result = sqlx::query("UPDATE super_admins SET disabled = ? WHERE name = ?")
+            .bind(if disabled { 1 } else { 0 })
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set or clear the TOTP secret of a SuperAdmin account, returns false if it does not exist
+    pub async fn set_super_admin_totp(&self, name: &str, secret: Option<&str>) -> Result<bool> {
+        let result = sqlx::query("UPDATE super_admins SET totp_secret = ? WHERE name = ?")
+            .bind(secret)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set the scopes of a SuperAdmin account (None = every scope), returns false if it does not exist
+    pub async fn set_super_admin_scopes(&self, name: &str, scopes: Option<&str>) -> Result<bool> {
+        let result = sqlx::query("UPDATE super_admins SET scopes = ? WHERE name = ?")
+            .bind(scopes)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Update the last login timestamp of a SuperAdmin account
+    pub async fn touch_super_admin(&self, id: &str) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query("UPDATE super_admins SET last_login_at = ? WHERE id = ?")
+            .bind(&now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // ========================================================================
+    // Instance invite operations
+    // ========================================================================
+
+    /// Store a new instance invite
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_invite(
+        &self,
+        token_hash: &str,
+        agent_id: Uuid,
+        instance_id: Uuid,
+        read_only: bool,
+        max_uses: u32,
+        expires_at: DateTime<Utc>,
+        created_by: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO instance_invites
+                (token_hash, agent_id, instance_id, read_only, max_uses, uses, created_at, expires_at, created_by)
+            VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?)
+            "#,
+        )
+        .bind(token_hash)
+        .bind(agent_id.to_string())
+        .bind(instance_id.to_string())
+        .bind(if read_only { 1 } else { 0 })
+        .bind(max_uses as i32)
+        .bind(Utc::now().to_rfc3339())
+        .bind(expires_at.to_rfc3339())
+        .bind(created_by)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find an invite by its hash
+    pub async fn find_invite(&self, token_hash: &str) -> Result<Option<InviteRecord>> {
+        let record = sqlx::query_as::<_, InviteRecord>(
+            &format!("SELECT {} FROM instance_invites WHERE token_hash = ?", INVITE_COLUMNS)
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// Count a login with an invite, returns false once its uses are exhausted
+    /// The check and the increment are one statement, so concurrent logins cannot exceed `max_uses`
+    pub async fn use_invite(&self, token_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE instance_invites SET uses = uses + 1 WHERE token_hash = ? AND (max_uses = 0 OR uses < max_uses)",
+        )
+        .bind(token_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete all invites of an instance, returns how many were deleted
+    pub async fn delete_instance_invites(&self, instance_id: Uuid) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM instance_invites WHERE instance_id = ?")
+            .bind(instance_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // ========================================================================
+    // Server settings operations
+    // ========================================================================
+
+    /// Get a server setting by name
+    pub async fn get_setting(&self, name: &str) -> Result<Option<String>> {
+        let value = sqlx::query_scalar::<_, String>("SELECT value FROM server_settings WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(value)
+    }
+
+    /// Insert or replace a server setting
+    pub async fn set_setting(&self, name: &str, value: &str) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            INSERT INTO server_settings (name, value, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(name) DO UPDATE SET
+                value = excluded.value,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(name)
+        .bind(value)
+        .bind(&now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete a server setting, returns true if it existed
+    pub async fn delete_setting(&self, name: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM server_settings WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
         Ok(result.rows_affected() > 0)
     }
 
@@ -422,9 +1003,12 @@ impl AgentRepository {
     // Audit log operations
     // ========================================================================
 
-    /// Insert an audit log entry
+    /// Insert an audit log entry, chained to the hash of the previous row with `key`
+    /// Returns the stored row
+    #[allow(clippy::too_many_arguments)]
     pub async fn insert_audit_log(
         &self,
+        key: &AuditChainKey,
         event_type: &str,
         session_id: &str,
         user_role: &str,
@@ -434,14 +1018,41 @@ impl AgentRepository {
         client_ip: &str,
         success: bool,
         details: Option<&str>,
-    ) -> Result<()> {
+        actor: Option<&str>,
+    ) -> Result<AuditLogRecord> {
         let now = Utc::now().to_rfc3339();
         let success_int = if success { 1 } else { 0 };
 
-        sqlx::query(
+        // Held until the row is written so no other insert reads the same predecessor
+        let _chain = self.audit_chain.lock().await;
+        let last: Option<(String,)> = sqlx::query_as(
+            "SELECT IFNULL(entry_hash, '') FROM audit_logs ORDER BY id DESC LIMIT 1"
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let prev_hash = match last {
+            Some((hash,)) if !hash.is_empty() => hash,
+            _ => GENESIS_HASH.to_string(),
+        };
+        let entry_hash = AuditEntry {
+            timestamp: &now,
+            event_type,
+            session_id,
+            user_role,
+            agent_id,
+            instance_id,
+            target_id,
+            client_ip,
+            success,
+            details,
+            actor,
+        }
+        .hash(key, &prev_hash);
+
+        let result = sqlx::query(
             r#"
-            INSERT INTO audit_logs (timestamp, event_type, session_id, user_role, agent_id, instance_id, target_id, client_ip, success, details)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO audit_logs (timestamp, event_type, session_id, user_role, agent_id, instance_id, target_id, client_ip, success, details, actor, prev_hash, entry_hash, chain_timestamp)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#
         )
         .bind(&now)
@@ -454,94 +1065,77 @@ impl AgentRepository {
         .bind(client_ip)
         .bind(success_int)
         .bind(details)
+        .bind(actor)
+        .bind(&prev_hash)
+        .bind(&entry_hash)
+        .bind(&now)
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(AuditLogRecord {
+            id: result.last_insert_id().unwrap_or_default(),
+            timestamp: now.clone(),
+            event_type: event_type.to_string(),
+            session_id: session_id.to_string(),
+            user_role: user_role.to_string(),
+            agent_id: agent_id.map(str::to_string),
+            instance_id: instance_id.map(str::to_string),
+            target_id: target_id.map(str::to_string),
+            client_ip: client_ip.to_string(),
+            success: success_int,
+            details: details.map(str::to_string),
+            actor: actor.map(str::to_string),
+            prev_hash,
+            entry_hash,
+            chain_timestamp: now,
+        })
     }
 
-    /// Get audit logs with optional filters and pagination
+    /// Get audit logs matching the filter, newest first, with pagination
     pub async fn get_audit_logs(
         &self,
-        event_type: Option<&str>,
+        filter: &AuditLogFilter,
         limit: i64,
         offset: i64,
     ) -> Result<(Vec<AuditLogRecord>, u64)> {
-        // Build WHERE clause
-        let where_clause = if event_type.is_some() {
-            "WHERE event_type = ?"
-        } else {
-            "WHERE 1=1"
-        };
+        let (where_clause, binds) = filter.where_clause();
 
         // Get total count
         let count_query = format!("SELECT COUNT(*) as count FROM audit_logs {}", where_clause);
-        let total: (i64,) = if let Some(et) = event_type {
-            sqlx::query_as(&count_query)
-                .bind(et)
-                .fetch_one(&self.pool)
-                .await?
-        } else {
-            sqlx::query_as(&count_query)
-                .fetch_one(&self.pool)
-                .await?
-        };
+        let mut count = sqlx::query_as::<_, (i64,)>(&count_query);
+        for value in &binds {
+            count = count.bind(value);
+        }
+        let total = count.fetch_one(&self.pool).await?;
 
         // Get records with pagination
-        // Use IFNULL to convert NULL to empty string for SQLite compatibility with SQLx
         let query = format!(
-            "SELECT id, timestamp, event_type, session_id, user_role, \
-             IFNULL(agent_id, '') as agent_id, \
-             IFNULL(instance_id, '') as instance_id, \
-             IFNULL(target_id, '') as target_id, \
-             client_ip, success, \
-             IFNULL(details, '') as details \
-             FROM audit_logs {} ORDER BY timestamp DESC LIMIT ? OFFSET ?",
-            where_clause
+            "SELECT {} FROM audit_logs {} ORDER BY timestamp DESC, id DESC LIMIT ? OFFSET ?",
+            AUDIT_LOG_COLUMNS, where_clause
         );
+        let mut rows = sqlx::query(&query);
+        for value in &binds {
+            rows = rows.bind(value);
+        }
+        let rows = rows.bind(limit).bind(offset).fetch_all(&self.pool).await?;
+        let records = rows.iter().map(audit_log_from_row).collect();
 
-        let rows = if let Some(et) = event_type {
-            sqlx::query(&query)
-                .bind(et)
-                .bind(limit)
-                .bind(offset)
-                .fetch_all(&self.pool)
-                .await?
-        } else {
-            sqlx::query(&query)
-                .bind(limit)
-                .bind(offset)
-                .fetch_all(&self.pool)
-                .await?
-        };
+        Ok((records, total.0 as u64))
+    }
 
-        // Manually build records to handle empty strings as None
-        use sqlx::Row;
-        let records: Vec<AuditLogRecord> = rows
-            .into_iter()
-            .map(|row| {
-                let agent_id: String = row.get("agent_id");
-                let instance_id: String = row.get("instance_id");
-                let target_id: String = row.get("target_id");
-                let details: String = row.get("details");
-
-                AuditLogRecord {
-                    id: row.get("id"),
-                    timestamp: row.get("timestamp"),
-                    event_type: row.get("event_type"),
-                    session_id: row.get("session_id"),
-                    user_role: row.get("user_role"),
-                    agent_id: if agent_id.is_empty() { None } else { Some(agent_id) },
-                    instance_id: if instance_id.is_empty() { None } else { Some(instance_id) },
-                    target_id: if target_id.is_empty() { None } else { Some(target_id) },
-                    client_ip: row.get("client_ip"),
-                    success: row.get("success"),
-                    details: if details.is_empty() { None } else { Some(details) },
-                }
-            })
-            .collect();
+    /// Get audit log rows with an ID above `after_id`, oldest first
+    /// Used to walk the whole log for chain verification and export
+    pub async fn get_audit_logs_after(&self, after_id: i64, limit: i64) -> Result<Vec<AuditLogRecord>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM audit_logs WHERE id > ? ORDER BY id LIMIT ?",
+            AUDIT_LOG_COLUMNS
+        ))
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
 
-        Ok((records, total.0 as u64))
+        Ok(rows.iter().map(audit_log_from_row).collect())
     }
 
     /// Clean up old audit logs (older than retention_days)
@@ -557,3 +1151,31 @@ impl AgentRepository {
         Ok(result.rows_affected())
     }
 }
+
+/// Build an `AuditLogRecord` from a row selected with `AUDIT_LOG_COLUMNS`,
+/// turning empty optional columns back into None
+fn audit_log_from_row(row: &sqlx::any::AnyRow) -> AuditLogRecord {
+    use sqlx::Row;
+    let optional = |column: &str| {
+        let value: String = row.get(column);
+        if value.is_empty() { None } else { Some(value) }
+    };
+
+    AuditLogRecord {
+        id: row.get("id"),
+        timestamp: row.get("timestamp"),
+        event_type: row.get("event_type"),
+        session_id: row.get("session_id"),
+        user_role: row.get("user_role"),
+        agent_id: optional("agent_id"),
+        instance_id: optional("instance_id"),
+        target_id: optional("target_id"),
+        client_ip: row.get("client_ip"),
+        success: row.get("success"),
+        details: optional("details"),
+        actor: optional("actor"),
+        prev_hash: row.get("prev_hash"),
+        entry_hash: row.get("entry_hash"),
+        chain_timestamp: row.get("chain_timestamp"),
+    }
+}
@@ -3,6 +3,8 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use common::ShareMode;
+
 /// Agent record in database
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct AgentRecord {
@@ -10,14 +12,20 @@ pub struct AgentRecord {
     pub id: String,
     /// Display name
     pub name: String,
-    /// SHA-256 hash of admin token
+    /// Keyed hash of admin token
     pub admin_token_hash: String,
-    /// SHA-256 hash of share token
+    /// Keyed hash of share token
     pub share_token_hash: String,
     /// Creation timestamp
     pub created_at: String,
     /// Last connection timestamp
     pub last_connected_at: Option<String>,
+    /// Keyed hash of the pinned agent key (empty for agents enrolled before key pinning)
+    #[sqlx(default)]
+    pub agent_key_hash: String,
+    /// Permission granted by the share token ("operator" or "viewer")
+    #[sqlx(default)]
+    pub share_mode: String,
 }
 
 impl AgentRecord {
@@ -35,6 +43,20 @@ impl AgentRecord {
             })
     }
 
+    /// Get the share token permission
+    pub fn share_mode(&self) -> ShareMode {
+        ShareMode::from_str_or_default(&self.share_mode)
+    }
+
+    /// Get the pinned agent key hash, if any
+    pub fn pinned_key_hash(&self) -> Option<&str> {
+        if self.agent_key_hash.is_empty() {
+            None
+        } else {
+            Some(&self.agent_key_hash)
+        }
+    }
+
     /// Parse last_connected_at as DateTime
     #[allow(dead_code)]
     pub fn last_connected_at_datetime(&self) -> Option<DateTime<Utc>> {
@@ -122,4 +144,121 @@ pub struct AuditLogRecord {
     /// Additional details in JSON format (optional)
     #[sqlx(default)]
     pub details: Option<String>,
+    /// SuperAdmin account that performed the action (optional)
+    #[sqlx(default)]
+    pub actor: Option<String>,
+    /// Hash of the previous row in the audit chain (empty on rows older than the chain)
+    #[sqlx(default)]
+    pub prev_hash: String,
+    /// Hash of this row's contents chained to `prev_hash` (empty on rows older than the chain)
+    #[sqlx(default)]
+    pub entry_hash: String,
+    /// `timestamp` as covered by `entry_hash` (empty on rows written before it was stored)
+    #[sqlx(default)]
+    pub chain_timestamp: String,
+}
+
+/// SuperAdmin account record in database
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SuperAdminRecord {
+    /// Account UUID
+    pub id: String,
+    /// Display name (unique)
+    pub name: String,
+    /// Keyed hash of the account token
+    pub token_hash: String,
+    /// Creation timestamp
+    pub created_at: String,
+    /// Last successful login (empty = never)
+    #[sqlx(default)]
+    pub last_login_at: String,
+    /// Disabled (1) or active (0)
+    pub disabled: i32,
+    /// Base32 TOTP secret of this account (empty = the shared SuperAdmin secret applies)
+    #[sqlx(default)]
+    pub totp_secret: String,
+    /// Comma separated scopes of the account (empty = every scope)
+    #[sqlx(default)]
+    pub scopes: String,
+}
+
+impl SuperAdminRecord {
+    /// Check if the account has been disabled
+    pub fn is_disabled(&self) -> bool {
+        self.disabled != 0
+    }
+}
+
+/// Agent token record in database
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TokenRecord {
+    /// Keyed hash of the token
+    pub token_hash: String,
+    /// Owning agent UUID
+    pub agent_id: String,
+    /// Token kind ("admin" or "share")
+    pub kind: String,
+    /// Creation timestamp
+    pub created_at: String,
+    /// Expiry timestamp (empty = never expires)
+    #[sqlx(default)]
+    pub expires_at: String,
+    /// Last successful authentication (empty = never used)
+    #[sqlx(default)]
+    pub last_used_at: String,
+    /// Revoked (1) or active (0)
+    pub revoked: i32,
+    /// Comma separated scopes of the token (empty = every scope of its role)
+    #[sqlx(default)]
+    pub scopes: String,
+}
+
+impl TokenRecord {
+    /// Check if the token has been revoked
+    pub fn is_revoked(&self) -> bool {
+        self.revoked != 0
+    }
+
+    /// Parse expires_at as DateTime
+    pub fn expires_at_datetime(&self) -> Option<DateTime<Utc>> {
+        if self.expires_at.is_empty() {
+            return None;
+        }
+        DateTime::parse_from_rfc3339(&self.expires_at)
+            .map(|dt| dt.with_timezone(&Utc))
+            .ok()
+    }
+}
+
+/// Invite token scoped to a single instance
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct InviteRecord {
+    /// Keyed hash of the invite token
+    pub token_hash: String,
+    /// Agent owning the instance
+    pub agent_id: String,
+    /// Instance the invite grants access to
+    pub instance_id: String,
+    /// Read-only (1) or read-write (0)
+    pub read_only: i32,
+    /// Maximum number of logins (0 = unlimited)
+    pub max_uses: i32,
+    /// Logins so far
+    pub uses: i32,
+    /// Creation timestamp
+    pub created_at: String,
+    /// Expiry timestamp
+    pub expires_at: String,
+    /// Who minted the invite (account name or role)
+    #[sqlx(default)]
+    pub created_by: String,
+}
+
+impl InviteRecord {
+    /// Parse expires_at as DateTime
+    pub fn expires_at_datetime(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.expires_at)
+            .map(|dt| dt.with_timezone(&Utc))
+            .ok()
+    }
 }
@@ -0,0 +1,184 @@
+//! CIDR-based IP allow/deny lists and client IP resolution
+
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+use common::SecurityConfig;
+
+/// Allow/deny lists for one WebSocket endpoint
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Build a filter from CIDR strings (bare addresses are treated as single hosts)
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self> {
+        Ok(Self {
+            allow: parse_cidrs(allow)?,
+            deny: parse_cidrs(deny)?,
+        })
+    }
+
+    /// Check an address: the deny list wins, then a non-empty allow list must match
+    pub fn check(&self, ip: IpAddr) -> Result<(), &'static str> {
+        let ip = normalize(ip);
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return Err("address is in the deny list");
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&ip)) {
+            return Err("address is not in the allow list");
+        }
+        Ok(())
+    }
+}
+
+/// IP policy for the WebSocket endpoints, built from `SecurityConfig`
+#[derive(Debug, Clone, Default)]
+pub struct IpPolicy {
+    /// Filter for /ws/user
+    pub user: IpFilter,
+    /// Filter for /ws/agent
+    pub agent: IpFilter,
+    /// Reverse proxies allowed to report the client address
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl IpPolicy {
+    /// Build the policy, failing on any malformed CIDR
+    pub fn from_config(security: &SecurityConfig) -> Result<Self> {
+        Ok(Self {
+            user: IpFilter::new(&security.user_allow_cidrs, &security.user_deny_cidrs)
+                .map_err(|e| anyhow!("Invalid user CIDR list: {}", e))?,
+            agent: IpFilter::new(&security.agent_allow_cidrs, &security.agent_deny_cidrs)
+                .map_err(|e| anyhow!("Invalid agent CIDR list: {}", e))?,
+            trusted_proxies: parse_cidrs(&security.trusted_proxies)
+                .map_err(|e| anyhow!("Invalid trusted_proxies: {}", e))?,
+        })
+    }
+
+    /// Resolve the real client address of a request
+    ///
+    /// Forwarding headers are only honored when the TCP peer is a trusted proxy.
+    /// X-Forwarded-For is walked from the right, skipping trusted proxies, so a
+    /// client cannot spoof its address by sending the header itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = normalize(peer);
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        if let Some(forwarded) = headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            let mut client = peer;
+            for hop in forwarded.rsplit(',') {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        client = normalize(ip);
+                        if !self.is_trusted(client) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            return client;
+        }
+
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok())
+            .map(normalize)
+            .unwrap_or(peer)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parse CIDR strings, accepting bare addresses as /32 or /128
+fn parse_cidrs(list: &[String]) -> Result<Vec<IpNet>> {
+    list.iter()
+        .map(|s| {
+            let s = s.trim();
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow!("'{}' is not a valid CIDR or IP address", s))
+        })
+        .collect()
+}
+
+/// Treat IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) as IPv4
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_filter_allow_and_deny() {
+        let filter = IpFilter::new(
+            &strings(&["10.0.0.0/8", "192.168.1.5"]),
+            &strings(&["10.66.0.0/16"]),
+        )
+        .unwrap();
+
+        assert!(filter.check(ip("10.1.2.3")).is_ok());
+        assert!(filter.check(ip("192.168.1.5")).is_ok());
+        assert!(filter.check(ip("::ffff:10.1.2.3")).is_ok());
+        assert!(filter.check(ip("192.168.1.6")).is_err());
+        assert!(filter.check(ip("10.66.1.1")).is_err());
+    }
+
+    #[test]
+    fn test_filter_empty_allows_all() {
+        let filter = IpFilter::new(&[], &strings(&["203.0.113.0/24"])).unwrap();
+        assert!(filter.check(ip("8.8.8.8")).is_ok());
+        assert!(filter.check(ip("2001:db8::1")).is_ok());
+        assert!(filter.check(ip("203.0.113.9")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_cidr() {
+        assert!(IpFilter::new(&strings(&["10.0.0.0/33"]), &[]).is_err());
+        assert!(IpFilter::new(&strings(&["office"]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_client_ip_resolution() {
+        let policy = IpPolicy {
+            trusted_proxies: parse_cidrs(&strings(&["127.0.0.1"])).unwrap(),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 10.1.2.3, 127.0.0.1".parse().unwrap());
+
+        // Untrusted peers cannot set their own address
+        assert_eq!(policy.client_ip(ip("203.0.113.9"), &headers), ip("203.0.113.9"));
+        // The rightmost untrusted hop is the client
+        assert_eq!(policy.client_ip(ip("127.0.0.1"), &headers), ip("10.1.2.3"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "10.9.9.9".parse().unwrap());
+        assert_eq!(policy.client_ip(ip("127.0.0.1"), &headers), ip("10.9.9.9"));
+        assert_eq!(policy.client_ip(ip("127.0.0.1"), &HeaderMap::new()), ip("127.0.0.1"));
+    }
+}
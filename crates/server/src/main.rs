@@ -18,8 +18,16 @@ mod auth;
 mod ws_agent;
 mod ws_user;
 mod routes;
+mod ip_filter;
+mod totp;
+mod audit_chain;
+mod audit_sink;
+mod command_audit;
+mod oidc;
+mod tls;
 mod db;
 mod rate_limit;
+mod redaction;
 mod logging;
 mod static_files;
 
@@ -37,7 +45,7 @@ use crate::cli::Args;
 use crate::config::ServerRuntime;
 use crate::state::AppState;
 use crate::db::{init_database, AgentRepository};
-use crate::rate_limit::{init_redis, RateLimiter};
+use crate::rate_limit::{init_redis, MemoryRateLimiter, RateLimit, RedisRateLimiter};
 use crate::logging::init_logging;
 
 #[tokio::main]
@@ -54,28 +62,64 @@ async fn main() -> Result<()> {
     info!("Claude Tunnel Server starting...");
     info!("Server: {}:{}", runtime.config.server.host, runtime.config.server.port);
     info!("Database: {}", runtime.config.database.db_type);
+    if runtime.config.security.agent_enrollment_secret.is_none() {
+        warn!("security.agent_enrollment_secret is not set: unknown agents will be enrolled on first use");
+    }
 
     // Initialize database
     let db_pool = init_database(&runtime).await?;
     let agent_repo = AgentRepository::new(db_pool);
 
-    // Initialize Redis (optional - only if redis_url is configured)
-    let rate_limiter = match &runtime.config.database.redis_url {
+    // One-off TOTP management commands
+    if args.setup_totp.is_some() || args.disable_totp.is_some() {
+        return run_totp_command(&args, &runtime, &agent_repo).await;
+    }
+
+    // One-off SuperAdmin account commands
+    if args.add_admin.is_some() || args.disable_admin.is_some() || args.list_admins {
+        return run_admin_command(&args, &runtime, &agent_repo).await;
+    }
+
+    // One-off scope command
+    if let Some(target) = &args.set_scopes {
+        return run_scopes_command(target, args.scopes.as_deref(), &agent_repo).await;
+    }
+
+    // One-off audit log verification
+    if args.verify_audit_log {
+        return run_verify_audit_log(&runtime, &agent_repo).await;
+    }
+
+    // One-off audit log export
+    if let Some(path) = &args.export_audit_log {
+        return run_export_audit_log(path, &args.export_format, &agent_repo).await;
+    }
+
+    if runtime.config.security.super_admin_token.is_empty()
+        && agent_repo.find_all_super_admins().await?.iter().all(|a| a.is_disabled())
+    {
+        warn!("security.super_admin_token is empty and no SuperAdmin account is active: create one with --add-admin");
+    }
+
+    // Rate limit authentication in Redis when configured, in memory otherwise
+    let limit = runtime.config.security.rate_limit_per_minute;
+    let rate_limiter: Option<Box<dyn RateLimit>> = match &runtime.config.database.redis_url {
+        _ if limit == 0 => {
+            info!("security.rate_limit_per_minute is 0, rate limiting disabled");
+            None
+        }
         Some(redis_url) => match init_redis(redis_url).await {
-            Ok(pool) => {
-                let limit = runtime.config.security.rate_limit_per_minute;
-                Some(RateLimiter::new(pool, limit))
-            }
+            Ok(pool) => Some(Box::new(RedisRateLimiter::new(pool, limit))),
             Err(e) => {
-                warn!("Redis not available, rate limiting disabled: {}", e);
-                None
+                warn!("Redis not available, rate limiting falls back to server memory: {}", e);
+                Some(Box::new(MemoryRateLimiter::new(limit)))
             }
         },
-        None => {
-            info!("Redis not configured, rate limiting disabled");
-            None
-        }
+        None => Some(Box::new(MemoryRateLimiter::new(limit))),
     };
+    if let Some(limiter) = &rate_limiter {
+        info!("Rate limiting: {} attempts per minute per IP ({})", limit, limiter.backend());
+    }
 
     // Create application state
     let state = Arc::new(AppState::new(runtime.clone(), agent_repo, rate_limiter).await?);
@@ -99,6 +143,16 @@ async fn main() -> Result<()> {
             }
         });
         info!("Terminal history cleanup task started (runs hourly)");
+
+        // Output held back by secret redaction is stored once its terminal goes quiet
+        let flush_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                flush_state.flush_idle_redactions(std::time::Duration::from_secs(2)).await;
+            }
+        });
     }
 
     // Start background cleanup task for audit logs
@@ -153,10 +207,202 @@ async fn main() -> Result<()> {
     )
     .parse()?;
 
-    info!("Listening on {}", addr);
+    match runtime.config.server.tls.clone() {
+        Some(tls_config) => {
+            let rustls = tls::load(&tls_config).await?;
+            tls::spawn_reloader(rustls.clone(), tls_config);
 
-    let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+            info!("Listening on {} (HTTPS)", addr);
+            axum_server::bind(addr)
+                .acceptor(tls::ClientCertAcceptor::new(rustls))
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            info!("Listening on {}", addr);
+
+            let listener = TcpListener::bind(addr).await?;
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle `--setup-totp [NAME]` / `--disable-totp [NAME]`
+async fn run_totp_command(args: &Args, runtime: &ServerRuntime, repo: &AgentRepository) -> Result<()> {
+    let account = args.setup_totp.clone().or_else(|| args.disable_totp.clone()).flatten();
+    if let Some(name) = account {
+        return run_account_totp_command(args, &name, repo).await;
+    }
+
+    if runtime.config.security.super_admin_totp_secret.is_some() {
+        warn!("security.super_admin_totp_secret is set in the config file and takes precedence over the database");
+    }
+
+    if args.disable_totp.is_some() {
+        if repo.delete_setting(totp::TOTP_SECRET_SETTING).await? {
+            println!("Shared SuperAdmin TOTP secret removed from the database.");
+        } else {
+            println!("No shared SuperAdmin TOTP secret was stored in the database.");
+        }
+        return Ok(());
+    }
+
+    let secret = totp::generate_secret();
+    repo.set_setting(totp::TOTP_SECRET_SETTING, &secret).await?;
+
+    println!("Shared SuperAdmin TOTP enabled. Add this secret to your authenticator app:");
+    println!();
+    println!("  Secret: {}", secret);
+    println!("  URI:    {}", totp::provisioning_uri(&secret, "SuperAdmin"));
+    println!();
+    println!("It applies to the config token and to accounts without a secret of their own");
+    println!("(see --setup-totp <NAME>). Running --setup-totp again replaces it.");
+    Ok(())
+}
+
+/// Handle `--setup-totp <NAME>` / `--disable-totp <NAME>` for a named SuperAdmin account
+async fn run_account_totp_command(args: &Args, name: &str, repo: &AgentRepository) -> Result<()> {
+    if repo.find_super_admin_by_name(name).await?.is_none() {
+        anyhow::bail!("No SuperAdmin account named '{}'", name);
+    }
+
+    if args.disable_totp.is_some() {
+        repo.set_super_admin_totp(name, None).await?;
+        println!("TOTP secret of SuperAdmin account '{}' removed; the shared secret applies again, if any.", name);
+        return Ok(());
+    }
+
+    let secret = totp::generate_secret();
+    repo.set_super_admin_totp(name, Some(&secret)).await?;
+
+    println!("TOTP enabled for SuperAdmin account '{}'. Add this secret to its owner's authenticator app:", name);
+    println!();
+    println!("  Secret: {}", secret);
+    println!("  URI:    {}", totp::provisioning_uri(&secret, name));
+    println!();
+    println!("Only this account accepts codes from this secret; running --setup-totp {} again replaces it.", name);
+    Ok(())
+}
+
+/// Handle `--add-admin` / `--disable-admin` / `--list-admins`
+async fn run_admin_command(args: &Args, runtime: &ServerRuntime, repo: &AgentRepository) -> Result<()> {
+    if let Some(name) = &args.add_admin {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 64 {
+            anyhow::bail!("Account name must be 1-64 characters");
+        }
+
+        let scopes = args.scopes.as_deref().map(parse_scopes).transpose()?;
+        let hasher = auth::load_token_hasher(&runtime.config.security, repo).await?;
+        let token = auth::generate_token();
+        repo.create_super_admin(name, &hasher.hash(&token), scopes.as_deref())
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create account '{}': {}", name, e))?;
+
+        println!("SuperAdmin account '{}' created. Its login token is shown only once:", name);
+        println!();
+        println!("  Token: {}", token);
+        println!();
+        println!("Only the token hash is stored; create a new account if the token is lost.");
+        return Ok(());
+    }
+
+    if let Some(name) = &args.disable_admin {
+        if repo.set_super_admin_disabled(name, true).await? {
+            println!("SuperAdmin account '{}' disabled.", name);
+            println!("Its live sessions are disconnected at their next session ticket renewal");
+            println!("(within security.session_ticket_ttl_secs / 3) and can no longer be resumed.");
+        } else {
+            anyhow::bail!("No SuperAdmin account named '{}'", name);
+        }
+        return Ok(());
+    }
+
+    let admins = repo.find_all_super_admins().await?;
+    if admins.is_empty() {
+        println!("No SuperAdmin accounts.");
+        return Ok(());
+    }
+    println!("{:<24} {:<9} {:<7} {:<36} {:<36} SCOPES", "NAME", "STATUS", "TOTP", "CREATED", "LAST LOGIN");
+    for admin in admins {
+        let status = if admin.is_disabled() { "disabled" } else { "active" };
+        let totp = if admin.totp_secret.is_empty() { "shared" } else { "own" };
+        let last_login = if admin.last_login_at.is_empty() { "-" } else { &admin.last_login_at };
+        let scopes = if admin.scopes.is_empty() { "all" } else { &admin.scopes };
+        println!(
+            "{:<24} {:<9} {:<7} {:<36} {:<36} {}",
+            admin.name, status, totp, admin.created_at, last_login, scopes
+        );
+    }
+    Ok(())
+}
+
+/// Validate a comma separated scope list and return it in storage form
+fn parse_scopes(list: &str) -> Result<String> {
+    let scopes = common::Scopes::parse(list).map_err(|e| anyhow::anyhow!("Invalid --scopes: {}", e))?;
+    if scopes.is_empty() {
+        anyhow::bail!("--scopes is empty; leave it out to grant every scope");
+    }
+    Ok(scopes.to_string())
+}
+
+/// Set the scopes of a SuperAdmin account (NAME) or agent token (AGENT_ID:admin|share)
+async fn run_scopes_command(target: &str, scopes: Option<&str>, repo: &AgentRepository) -> Result<()> {
+    let scopes = scopes.map(parse_scopes).transpose()?;
+    let shown = scopes.as_deref().unwrap_or("every scope of its role");
+
+    if let Some((agent, kind)) = target.split_once(':') {
+        let agent_id: uuid::Uuid = agent.parse().map_err(|_| anyhow::anyhow!("Invalid agent ID '{}'", agent))?;
+        let kind = match kind {
+            "admin" => common::TokenKind::Admin,
+            "share" => common::TokenKind::Share,
+            _ => anyhow::bail!("Token kind must be 'admin' or 'share'"),
+        };
+        if !repo.set_token_scopes(agent_id, kind, scopes.as_deref()).await? {
+            anyhow::bail!("No agent with ID {}", agent_id);
+        }
+        println!("The {} token of agent {} now has: {}", kind.as_str(), agent_id, shown);
+        println!("Scopes outside the token's role are never granted; rotated tokens keep their scopes.");
+    } else {
+        if !repo.set_super_admin_scopes(target, scopes.as_deref()).await? {
+            anyhow::bail!("No SuperAdmin account named '{}'", target);
+        }
+        println!("SuperAdmin account '{}' now has: {}", target, shown);
+    }
+    println!("Live sessions keep their scopes until they log in again.");
+    Ok(())
+}
+
+/// Handle `--verify-audit-log`; fails when the chain is broken
+async fn run_verify_audit_log(runtime: &ServerRuntime, repo: &AgentRepository) -> Result<()> {
+    let hasher = auth::load_token_hasher(&runtime.config.security, repo).await?;
+    let report = audit_chain::verify_audit_chain(repo, &audit_chain::AuditChainKey::from_token_hasher(&hasher)).await?;
+    if report.unchained > 0 {
+        println!("Skipped {} rows written before the hash chain existed.", report.unchained);
+    }
+    println!("Verified {} chained rows.", report.checked);
+    if let (Some(id), Some(hash)) = (report.last_id, &report.last_hash) {
+        println!("Last row: {} ({})", id, hash);
+    }
+    if let Some(broken) = report.broken {
+        anyhow::bail!("Audit log chain broken at row {}: {}", broken.id, broken.reason);
+    }
+    println!("The audit log chain is intact. Keep the last row hash to detect rows removed from the end later.");
+    Ok(())
+}
 
+/// Handle `--export-audit-log`; refuses to overwrite an existing file
+async fn run_export_audit_log(path: &std::path::Path, format: &str, repo: &AgentRepository) -> Result<()> {
+    let format = audit_sink::ExportFormat::parse(format)?;
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Cannot create {}: {}", path.display(), e))?;
+    let mut out = std::io::BufWriter::new(file);
+    let count = audit_sink::export_audit_log(repo, format, &mut out).await?;
+    println!("Exported {} audit log rows to {}.", count, path.display());
     Ok(())
 }
@@ -1,74 +1,132 @@
-//! Rate limiting module using Redis
+//! Rate limiting and lockout of authentication attempts
+//!
+//! `RateLimit` counts attempts per key in a sliding window, in Redis when it is
+//! configured (shared by every server node) and in process memory otherwise.
+//! `AuthLockout` locks out client IPs and credentials with an exponentially
+//! growing delay after repeated authentication failures.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use deadpool_redis::{Pool, Connection};
-use redis::AsyncCommands;
+use async_trait::async_trait;
+use deadpool_redis::{Connection, Pool};
+use sha2::{Digest, Sha256};
+
+use common::SecurityConfig;
+
+/// Sliding-window rate limiter backend
+#[async_trait]
+pub trait RateLimit: Send + Sync {
+    /// Record an attempt and check if it is within the limit
+    /// Returns Ok(true) if allowed, Ok(false) if rate limited
+    async fn check_limit(&self, key: &str) -> Result<bool>;
+
+    /// Backend name for logs
+    fn backend(&self) -> &'static str;
+}
 
-/// Rate limiter configuration
-#[derive(Clone)]
-pub struct RateLimiter {
+/// Rate limiter shared through Redis (sorted set of attempt timestamps per key)
+pub struct RedisRateLimiter {
     pool: Pool,
-    /// Maximum requests per minute
-    limit_per_minute: u32,
-    /// Window size in seconds
-    window_seconds: u64,
+    /// Maximum attempts per window
+    limit: u32,
+    /// Window size
+    window: Duration,
 }
 
-impl RateLimiter {
-    /// Create a new rate limiter
+impl RedisRateLimiter {
+    /// Create a new Redis rate limiter
     pub fn new(pool: Pool, limit_per_minute: u32) -> Self {
         Self {
             pool,
-            limit_per_minute,
-            window_seconds: 60,
+            limit: limit_per_minute,
+            window: Duration::from_secs(60),
         }
     }
 
-    /// Check if the request is within rate limit
-    /// Returns Ok(true) if allowed, Ok(false) if rate limited
-    pub async fn check_limit(&self, key: &str) -> Result<bool> {
+    async fn get_connection(&self) -> Result<Connection> {
+        self.pool.get().await.map_err(|e| anyhow::anyhow!("Failed to get Redis connection: {}", e))
+    }
+}
+
+#[async_trait]
+impl RateLimit for RedisRateLimiter {
+    async fn check_limit(&self, key: &str) -> Result<bool> {
         let mut conn = self.get_connection().await?;
         let redis_key = format!("rate_limit:{}", key);
+        let now_us = chrono::Utc::now().timestamp_micros();
+        let window_us = self.window.as_micros() as i64;
+        // Unique member, two attempts in the same microsecond must both count
+        let member = format!("{}-{}", now_us, rand::random::<u32>());
 
-        // Increment counter
-        let count: u32 = conn.incr(&redis_key, 1).await?;
+        let (count,): (u32,) = redis::pipe()
+            .atomic()
+            .zrembyscore(&redis_key, 0, now_us - window_us)
+            .ignore()
+            .zadd(&redis_key, member, now_us)
+            .ignore()
+            .zcard(&redis_key)
+            .expire(&redis_key, self.window.as_secs() as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
 
-        // Set expiry on first request
-        if count == 1 {
-            conn.expire::<_, ()>(&redis_key, self.window_seconds as i64).await?;
-        }
+        Ok(count <= self.limit)
+    }
 
-        Ok(count <= self.limit_per_minute)
+    fn backend(&self) -> &'static str {
+        "redis"
     }
+}
 
-    /// Get current request count for a key
-    #[allow(dead_code)]
-    pub async fn get_count(&self, key: &str) -> Result<u32> {
-        let mut conn = self.get_connection().await?;
-        let redis_key = format!("rate_limit:{}", key);
+/// Number of keys after which idle keys are swept on the next check
+const SWEEP_THRESHOLD: usize = 4096;
 
-        let count: Option<u32> = conn.get(&redis_key).await?;
-        Ok(count.unwrap_or(0))
+/// In-process rate limiter for single-node deployments without Redis
+pub struct MemoryRateLimiter {
+    /// Maximum attempts per window
+    limit: u32,
+    /// Window size
+    window: Duration,
+    /// Attempt times per key (oldest first)
+    attempts: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl MemoryRateLimiter {
+    /// Create a new in-memory rate limiter
+    pub fn new(limit_per_minute: u32) -> Self {
+        Self {
+            limit: limit_per_minute,
+            window: Duration::from_secs(60),
+            attempts: Mutex::new(HashMap::new()),
+        }
     }
 
-    /// Get remaining requests for a key
-    #[allow(dead_code)]
-    pub async fn get_remaining(&self, key: &str) -> Result<u32> {
-        let count = self.get_count(key).await?;
-        Ok(self.limit_per_minute.saturating_sub(count))
+    fn check_at(&self, key: &str, now: Instant) -> bool {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        if attempts.len() >= SWEEP_THRESHOLD {
+            attempts.retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < self.window));
+        }
+
+        let times = attempts.entry(key.to_string()).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= self.window) {
+            times.pop_front();
+        }
+        times.push_back(now);
+        times.len() <= self.limit as usize
     }
+}
 
-    /// Reset rate limit for a key (useful for testing)
-    #[allow(dead_code)]
-    pub async fn reset(&self, key: &str) -> Result<()> {
-        let mut conn = self.get_connection().await?;
-        let redis_key = format!("rate_limit:{}", key);
-        conn.del::<_, ()>(&redis_key).await?;
-        Ok(())
+#[async_trait]
+impl RateLimit for MemoryRateLimiter {
+    async fn check_limit(&self, key: &str) -> Result<bool> {
+        Ok(self.check_at(key, Instant::now()))
     }
 
-    async fn get_connection(&self) -> Result<Connection> {
-        self.pool.get().await.map_err(|e| anyhow::anyhow!("Failed to get Redis connection: {}", e))
+    fn backend(&self) -> &'static str {
+        "memory"
     }
 }
 
@@ -84,3 +142,178 @@ pub async fn init_redis(url: &str) -> Result<Pool> {
     tracing::info!("Connected to Redis");
     Ok(pool)
 }
+
+/// Lockout key of a client IP
+pub fn ip_key(client_ip: &str) -> String {
+    format!("ip:{}", client_ip)
+}
+
+/// Lockout key of a credential (token, web session or ticket), without keeping the secret itself
+pub fn credential_key(credential: &str) -> String {
+    let digest = Sha256::digest(credential.as_bytes());
+    format!("credential:{}", hex::encode(&digest[..16]))
+}
+
+/// Failure streak of one IP or credential
+#[derive(Debug)]
+struct LockoutEntry {
+    /// Consecutive failures
+    failures: u32,
+    /// Time of the last failure
+    last_failure: Instant,
+    /// End of the current lockout
+    locked_until: Option<Instant>,
+}
+
+/// Exponential lockout after repeated authentication failures
+///
+/// From the `auth_lockout_threshold`-th consecutive failure on, a key is locked
+/// for `auth_lockout_base_secs`, doubling with every further failure up to
+/// `auth_lockout_max_secs`. A streak is forgotten after a success or once no
+/// failure happened for `auth_lockout_max_secs`.
+pub struct AuthLockout {
+    /// Failures before the first lockout (0 = disabled)
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+    entries: Mutex<HashMap<String, LockoutEntry>>,
+}
+
+impl AuthLockout {
+    /// Build the lockout policy from the security settings
+    pub fn from_config(config: &SecurityConfig) -> Self {
+        Self {
+            threshold: config.auth_lockout_threshold,
+            base: Duration::from_secs(config.auth_lockout_base_secs.max(1)),
+            max: Duration::from_secs(config.auth_lockout_max_secs.max(config.auth_lockout_base_secs.max(1))),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remaining lockout of a key, if it is locked
+    pub fn remaining(&self, key: &str) -> Option<Duration> {
+        self.remaining_at(key, Instant::now())
+    }
+
+    /// Record a failed attempt; returns the lockout it started, if any
+    pub fn record_failure(&self, key: &str) -> Option<Duration> {
+        self.record_failure_at(key, Instant::now())
+    }
+
+    /// Record a successful attempt, clearing the key's failure streak
+    pub fn record_success(&self, key: &str) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+    }
+
+    fn remaining_at(&self, key: &str, now: Instant) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(key)
+            .and_then(|entry| entry.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn record_failure_at(&self, key: &str, now: Instant) -> Option<Duration> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget streaks that went quiet, so the map does not grow without bound
+        entries.retain(|_, entry| now.duration_since(entry.last_failure) < self.max);
+
+        let entry = entries.entry(key.to_string()).or_insert(LockoutEntry {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        entry.failures += 1;
+        entry.last_failure = now;
+        if entry.failures < self.threshold {
+            return None;
+        }
+
+        let doublings = (entry.failures - self.threshold).min(16);
+        let duration = self.base.saturating_mul(1 << doublings).min(self.max);
+        entry.locked_until = Some(now + duration);
+        Some(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout(threshold: u32) -> AuthLockout {
+        let mut config = crate::config::ServerRuntime::default_config().security;
+        config.auth_lockout_threshold = threshold;
+        config.auth_lockout_base_secs = 30;
+        config.auth_lockout_max_secs = 600;
+        AuthLockout::from_config(&config)
+    }
+
+    #[test]
+    fn test_memory_sliding_window() {
+        let limiter = MemoryRateLimiter::new(3);
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert!(limiter.check_at("ip:1", start + Duration::from_secs(i * 10)));
+        }
+        assert!(!limiter.check_at("ip:1", start + Duration::from_secs(30)));
+        // Other keys have their own budget
+        assert!(limiter.check_at("ip:2", start + Duration::from_secs(30)));
+
+        // The window slides: only the attempts of the last 60 seconds count
+        assert!(!limiter.check_at("ip:1", start + Duration::from_secs(59)));
+        assert!(!limiter.check_at("ip:1", start + Duration::from_secs(65)));
+        assert!(limiter.check_at("ip:1", start + Duration::from_secs(125)));
+    }
+
+    #[test]
+    fn test_lockout_grows_exponentially() {
+        let lockout = lockout(3);
+        let start = Instant::now();
+
+        assert_eq!(lockout.record_failure_at("ip:1", start), None);
+        assert_eq!(lockout.record_failure_at("ip:1", start), None);
+        assert_eq!(lockout.remaining_at("ip:1", start), None);
+
+        assert_eq!(lockout.record_failure_at("ip:1", start), Some(Duration::from_secs(30)));
+        assert_eq!(lockout.remaining_at("ip:1", start + Duration::from_secs(10)), Some(Duration::from_secs(20)));
+        assert_eq!(lockout.remaining_at("ip:1", start + Duration::from_secs(30)), None);
+        assert_eq!(lockout.remaining_at("ip:2", start), None);
+
+        let later = start + Duration::from_secs(31);
+        assert_eq!(lockout.record_failure_at("ip:1", later), Some(Duration::from_secs(60)));
+        assert_eq!(lockout.record_failure_at("ip:1", later), Some(Duration::from_secs(120)));
+        for _ in 0..10 {
+            lockout.record_failure_at("ip:1", later);
+        }
+        assert_eq!(lockout.remaining_at("ip:1", later), Some(Duration::from_secs(600)));
+
+        // A quiet period longer than the maximum lockout forgets the streak
+        let quiet = later + Duration::from_secs(601);
+        assert_eq!(lockout.record_failure_at("ip:1", quiet), None);
+    }
+
+    #[test]
+    fn test_lockout_success_and_disabled() {
+        let lockout = lockout(2);
+        let key = credential_key("some-token");
+        assert!(!key.contains("some-token"));
+
+        lockout.record_failure(&key);
+        lockout.record_success(&key);
+        assert_eq!(lockout.record_failure(&key), None);
+        assert!(lockout.record_failure(&key).is_some());
+        assert!(lockout.remaining(&key).is_some());
+
+        let disabled = self::lockout(0);
+        for _ in 0..10 {
+            assert_eq!(disabled.record_failure("ip:1"), None);
+        }
+        assert_eq!(disabled.remaining("ip:1"), None);
+    }
+}
@@ -4,14 +4,19 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use axum::{
-    extract::{ConnectInfo, State, WebSocketUpgrade},
-    response::{Html, IntoResponse},
+    extract::{ConnectInfo, Query, State, WebSocketUpgrade},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::get,
-    Router,
+    Extension, Json, Router,
 };
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
 
 use crate::state::AppState;
 use crate::static_files::{has_web_assets, static_handler};
+use crate::tls::PeerCertificate;
 use crate::ws_agent::handle_agent_connection;
 use crate::ws_user::handle_user_connection;
 
@@ -22,7 +27,11 @@ pub fn create_routes() -> Router<Arc<AppState>> {
         .route("/health", get(health_check))
         // WebSocket endpoints
         .route("/ws/agent", get(ws_agent_handler))
-        .route("/ws/user", get(ws_user_handler));
+        .route("/ws/user", get(ws_user_handler))
+        // OpenID Connect login
+        .route("/auth/oidc/status", get(oidc_status_handler))
+        .route("/auth/oidc/login", get(oidc_login_handler))
+        .route("/auth/oidc/callback", get(oidc_callback_handler));
 
     // Add static file serving for embedded web frontend
     if has_web_assets() {
@@ -46,21 +55,189 @@ async fn fallback_index_handler() -> Html<&'static str> {
 /// WebSocket handler for agent connections
 async fn ws_agent_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    peer_cert: Option<Extension<PeerCertificate>>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_agent_connection(socket, state))
+) -> Response {
+    let client_ip = state.ip_policy.client_ip(addr.ip(), &headers);
+    if let Err(reason) = state.ip_policy.agent.check(client_ip) {
+        return reject_ip(&state, "/ws/agent", "agent", &client_ip.to_string(), reason);
+    }
+
+    let client_ip = client_ip.to_string();
+    let client_cert = peer_cert.and_then(|Extension(PeerCertificate(cert))| cert);
+    ws.on_upgrade(move |socket| handle_agent_connection(socket, state, client_ip, client_cert))
 }
 
 /// WebSocket handler for user connections
 async fn ws_user_handler(
     ws: WebSocketUpgrade,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let client_ip = addr.ip().to_string();
+) -> Response {
+    let client_ip = state.ip_policy.client_ip(addr.ip(), &headers);
+    if let Err(reason) = state.ip_policy.user.check(client_ip) {
+        return reject_ip(&state, "/ws/user", "unknown", &client_ip.to_string(), reason);
+    }
+
+    let client_ip = client_ip.to_string();
     ws.on_upgrade(move |socket| handle_user_connection(socket, state, client_ip))
 }
 
+/// Whether SSO login is available (used by the login page)
+async fn oidc_status_handler(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "enabled": state.oidc.is_some() }))
+}
+
+/// Start an OIDC login: redirect the browser to the IdP
+async fn oidc_login_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let client_ip = state.ip_policy.client_ip(addr.ip(), &headers);
+    if let Err(reason) = state.ip_policy.user.check(client_ip) {
+        return reject_ip(&state, "/auth/oidc/login", "unknown", &client_ip.to_string(), reason);
+    }
+    let Some(oidc) = state.oidc.as_ref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match oidc.authorization_url().await {
+        Ok(start) => {
+            let cookie = format!(
+                "{}={}; Path=/auth/oidc; Max-Age=600; HttpOnly; SameSite=Lax{}",
+                OIDC_LOGIN_COOKIE,
+                start.browser_binding,
+                if oidc.secure_cookies() { "; Secure" } else { "" }
+            );
+            ([(header::SET_COOKIE, cookie)], Redirect::to(&start.url)).into_response()
+        }
+        Err(e) => {
+            warn!("Failed to start OIDC login: {:#}", e);
+            (StatusCode::BAD_GATEWAY, "Identity provider unavailable").into_response()
+        }
+    }
+}
+
+/// Cookie binding a started OIDC login to the browser (login CSRF protection)
+const OIDC_LOGIN_COOKIE: &str = "oidc_login";
+
+/// Expire the login cookie once the callback has been handled
+fn clear_login_cookie() -> String {
+    format!("{}=; Path=/auth/oidc; Max-Age=0; HttpOnly; SameSite=Lax", OIDC_LOGIN_COOKIE)
+}
+
+/// Value of a cookie from the request headers
+fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Query parameters of the IdP redirect
+#[derive(Debug, Deserialize)]
+struct OidcCallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Finish an OIDC login and hand the web session to the frontend
+/// The session is passed in the URL fragment so it never reaches server logs
+async fn oidc_callback_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<OidcCallbackParams>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let client_ip = state.ip_policy.client_ip(addr.ip(), &headers);
+    if let Err(reason) = state.ip_policy.user.check(client_ip) {
+        return reject_ip(&state, "/auth/oidc/callback", "unknown", &client_ip.to_string(), reason);
+    }
+    let client_ip = client_ip.to_string();
+    let Some(oidc) = state.oidc.as_ref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let browser_binding = cookie_value(&headers, OIDC_LOGIN_COOKIE);
+    let result = match (params.code, params.state, params.error) {
+        (Some(code), Some(login_state), None) => {
+            oidc.complete_login(&code, &login_state, browser_binding.as_deref()).await
+        }
+        (_, _, Some(error)) => Err(anyhow::anyhow!("identity provider returned error: {}", error)),
+        _ => Err(anyhow::anyhow!("missing code or state")),
+    };
+
+    match result {
+        Ok(login) => {
+            let account = login.identity.account.as_deref().unwrap_or_default();
+            info!("OIDC login: {} as {:?} (group {})", account, login.identity.role, login.group);
+            state.log_audit_event(
+                "oidc_login",
+                Uuid::new_v4(),
+                &format!("{:?}", login.identity.role),
+                login.identity.agent_id,
+                None,
+                None,
+                &client_ip,
+                true,
+                Some(&format!("user: {}, group: {}", account, login.group)),
+            );
+            (
+                [(header::SET_COOKIE, clear_login_cookie())],
+                Redirect::to(&format!("/#oidc_session={}", login.session)),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            warn!("OIDC login from {} failed: {:#}", client_ip, e);
+            state.log_audit_event(
+                "oidc_login",
+                Uuid::new_v4(),
+                "unknown",
+                None,
+                None,
+                None,
+                &client_ip,
+                false,
+                Some(&format!("{:#}", e)),
+            );
+            ([(header::SET_COOKIE, clear_login_cookie())], Redirect::to("/#oidc_error=login_failed")).into_response()
+        }
+    }
+}
+
+/// Reject a connection from a filtered address before the WebSocket upgrade
+fn reject_ip(
+    state: &AppState,
+    endpoint: &str,
+    role: &str,
+    client_ip: &str,
+    reason: &str,
+) -> Response {
+    warn!("Rejected {} connection from {}: {}", endpoint, client_ip, reason);
+    state.log_audit_event(
+        "ip_rejected",
+        Uuid::new_v4(),
+        role,
+        None,
+        None,
+        None,
+        client_ip,
+        false,
+        Some(&format!("endpoint: {}, reason: {}", endpoint, reason)),
+    );
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
+
 /// Simple HTML fallback page (used when web frontend is not built)
 const FALLBACK_HTML: &str = r#"<!DOCTYPE html>
 <html lang="en">
@@ -1,30 +1,56 @@
 //! Application state management
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::RwLock as SyncRwLock;
 
 use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
 use tokio::sync::{broadcast, mpsc, RwLock};
+use totp_rs::TOTP;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use common::{Agent, AgentInfo, AgentStatus, GlobalStats, Instance, InstanceStatus, Role, ServerToAgentMessage, ServerToUserMessage};
-
-use crate::auth::hash_token;
+use common::{
+    Agent, AgentInfo, AgentStatus, ApprovalAction, ErrorCode, GlobalStats, Instance, InstanceStatus, Role, Scopes,
+    ServerToAgentMessage, ServerToUserMessage, ShareMode, TokenKind,
+};
+
+use crate::audit_chain::AuditChainKey;
+use crate::audit_sink::AuditSinks;
+use crate::auth::{
+    check_token_record, generate_token, hashes_equal, load_token_hasher, verify_enrollment, SessionTicketSigner,
+    TicketClaims, TokenHasher, TokenHashes,
+};
+use crate::command_audit::{CommandFilter, CommandLine, LineAssembler};
 use crate::config::ServerRuntime;
-use crate::db::AgentRepository;
-use crate::rate_limit::RateLimiter;
+use crate::db::{AgentRepository, AuditLogFilter};
+use crate::ip_filter::IpPolicy;
+use crate::oidc::OidcProvider;
+use crate::rate_limit::{AuthLockout, RateLimit};
+use crate::redaction::{RedactionStream, Redactor};
+use crate::tls::{AgentCertPolicy, ClientCertificate};
+use crate::totp::{build_totp, TotpGuards, TOTP_SECRET_SETTING};
 
 /// Connected agent information
 pub struct ConnectedAgent {
     /// Agent data
     pub agent: Agent,
-    /// Admin token hash (SHA-256)
+    /// Admin token hash (keyed HMAC-SHA256, see `TokenHasher`)
     pub admin_token_hash: String,
-    /// Share token hash (SHA-256)
+    /// Share token hash (keyed HMAC-SHA256, see `TokenHasher`)
     pub share_token_hash: String,
+    /// Permission granted by the share token
+    pub share_mode: ShareMode,
     /// Channel to send messages to agent
     pub tx: mpsc::Sender<ServerToAgentMessage>,
     /// Active instances
     pub instances: HashMap<Uuid, Instance>,
+    /// Remote creates and first attaches must be approved at the agent's machine
+    pub approval_required: bool,
+    /// Sessions approved at the agent's machine during this connection
+    pub approved_sessions: HashSet<Uuid>,
 }
 
 /// Connected user session
@@ -33,16 +59,219 @@ pub struct UserSession {
     #[allow(dead_code)]
     pub id: Uuid,
     /// User role
-    #[allow(dead_code)]
     pub role: Role,
     /// Associated agent ID (None for super admin viewing all)
     pub agent_id: Option<Uuid>,
     /// Working agent ID for SuperAdmin (allows operating on specific agent's instances)
     pub working_agent_id: Option<Uuid>,
+    /// Named account (None for the config token and agent tokens)
+    #[allow(dead_code)]
+    pub account: Option<String>,
+    /// Credential the session logged in with
+    pub credential: SessionCredential,
+    /// Scopes granted to the session
+    pub scopes: Scopes,
     /// Currently attached instance IDs
     pub attached_instances: Vec<Uuid>,
     /// Channel to send messages to user
     pub tx: mpsc::Sender<ServerToUserMessage>,
+    /// WebSocket connection currently serving the session (changes when it is resumed)
+    pub connection_id: Uuid,
+    /// Generation of the last session ticket issued (0 = none yet)
+    pub ticket_generation: u64,
+    /// Expiry of the last session ticket (Unix timestamp)
+    pub ticket_expires_at: i64,
+    /// Client IP address of the current connection
+    pub client_ip: String,
+    /// When the current connection was established
+    pub connected_at: DateTime<Utc>,
+    /// Last message received (Unix timestamp), updated without the write lock
+    pub last_activity: AtomicI64,
+}
+
+/// Session kept after its connection closed, until its last ticket expires
+pub struct SuspendedSession {
+    /// Session ID
+    pub session_id: Uuid,
+    /// Identity the session logged in with
+    pub identity: AuthIdentity,
+    /// Working agent ID for SuperAdmin
+    pub working_agent_id: Option<Uuid>,
+    /// Instance IDs the session was attached to
+    pub attached_instances: Vec<Uuid>,
+    /// Generation of the last session ticket issued
+    ticket_generation: u64,
+    /// Expiry of the last session ticket (Unix timestamp)
+    ticket_expires_at: i64,
+}
+
+impl From<UserSession> for SuspendedSession {
+    fn from(session: UserSession) -> Self {
+        Self {
+            session_id: session.id,
+            identity: AuthIdentity {
+                role: session.role,
+                agent_id: session.agent_id,
+                account: session.account,
+                credential: session.credential,
+                scopes: session.scopes,
+            },
+            working_agent_id: session.working_agent_id,
+            attached_instances: session.attached_instances,
+            ticket_generation: session.ticket_generation,
+            ticket_expires_at: session.ticket_expires_at,
+        }
+    }
+}
+
+impl SuspendedSession {
+    /// Skip a generation so the tickets issued so far no longer match
+    fn retire_tickets(mut self) -> Self {
+        self.ticket_generation += 1;
+        self
+    }
+}
+
+/// Whether a ticket generation may still resume a session
+/// The previous generation is accepted too, in case the renewed ticket never reached the client
+fn is_current_ticket(presented: u64, latest: u64) -> bool {
+    presented == latest || presented + 1 == latest
+}
+
+/// Result of a successful token authentication
+#[derive(Debug, Clone)]
+pub struct AuthIdentity {
+    /// Granted role
+    pub role: Role,
+    /// Associated agent ID (None for super admin)
+    pub agent_id: Option<Uuid>,
+    /// Named account: a SuperAdmin database account, or `oidc:<user>` for SSO logins
+    pub account: Option<String>,
+    /// Credential that was presented, checked again whenever the session renews its ticket
+    pub credential: SessionCredential,
+    /// Scopes granted to the session
+    pub scopes: Scopes,
+}
+
+/// Scopes of a role, narrowed by the scopes stored with its credential (empty = not narrowed)
+/// A credential can never gain scopes its role does not have; unreadable scopes grant nothing
+pub fn credential_scopes(role: Role, stored: &str) -> Scopes {
+    let defaults = role.default_scopes();
+    if stored.is_empty() {
+        return defaults;
+    }
+    match Scopes::parse(stored) {
+        Ok(scopes) => defaults.intersect(&scopes),
+        Err(e) => {
+            warn!("Ignoring stored scopes '{}': {}", stored, e);
+            Scopes::default()
+        }
+    }
+}
+
+/// Reference to the credential a session logged in with
+/// Session tickets only extend a session while this credential stays valid
+#[derive(Debug, Clone)]
+pub enum SessionCredential {
+    /// `security.super_admin_token` from the config
+    ConfigToken,
+    /// Named SuperAdmin account
+    SuperAdmin(String),
+    /// Agent admin or share token
+    AgentToken(TokenHashes),
+    /// OIDC web session (map key of the session token)
+    WebSession(String),
+    /// Invite bound to a single instance
+    Invite {
+        /// Current hash of the invite token
+        hash: String,
+        /// Instance the invite grants access to
+        instance_id: Uuid,
+    },
+}
+
+impl SessionCredential {
+    /// Name of the credential kind shown in session listings
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SessionCredential::ConfigToken => "config_token",
+            SessionCredential::SuperAdmin(_) => "account",
+            SessionCredential::AgentToken(_) => "agent_token",
+            SessionCredential::WebSession(_) => "sso",
+            SessionCredential::Invite { .. } => "invite",
+        }
+    }
+
+    /// Whether two sessions logged in with the same token (counted together by
+    /// `security.max_sessions_per_token`)
+    pub fn same_token(&self, other: &SessionCredential) -> bool {
+        match (self, other) {
+            (SessionCredential::ConfigToken, SessionCredential::ConfigToken) => true,
+            (SessionCredential::SuperAdmin(a), SessionCredential::SuperAdmin(b)) => a == b,
+            (SessionCredential::AgentToken(a), SessionCredential::AgentToken(b)) => a.current == b.current,
+            (SessionCredential::WebSession(a), SessionCredential::WebSession(b)) => a == b,
+            (SessionCredential::Invite { hash: a, .. }, SessionCredential::Invite { hash: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Instance a session is limited to (invites only)
+    pub fn instance_scope(&self) -> Option<Uuid> {
+        match self {
+            SessionCredential::Invite { instance_id, .. } => Some(*instance_id),
+            _ => None,
+        }
+    }
+}
+
+/// Longest lifetime of an instance invite
+pub const MAX_INVITE_TTL_SECS: u64 = 7 * 24 * 3600;
+
+/// Longest lifetime a SuperAdmin can give a rotated agent token
+pub const MAX_TOKEN_TTL_SECS: u64 = 10 * 365 * 24 * 3600;
+
+/// Instance creation requested by a user and not yet confirmed by the agent
+pub struct PendingInstance {
+    /// Agent the instance is being created on
+    pub agent_id: Uuid,
+    /// Requesting session ID
+    pub session_id: Uuid,
+    /// Requesting user role
+    pub role: Role,
+    /// Requesting client IP address
+    pub client_ip: String,
+}
+
+/// Token rotation sent to an agent and waiting for it to confirm the new token was saved
+pub struct PendingRotation {
+    /// New token value
+    pub token: String,
+    /// Expiry of the new token
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Requesting session ID
+    pub session_id: Uuid,
+    /// Requesting user role
+    pub role: Role,
+    /// Requesting client IP address
+    pub client_ip: String,
+}
+
+/// Create or attach request waiting for approval at the agent's machine
+pub struct PendingApproval {
+    /// Agent that must approve the request
+    pub agent_id: Uuid,
+    /// Action waiting for approval
+    pub action: ApprovalAction,
+    /// Instance to create or attach to
+    pub instance_id: Uuid,
+    /// Working directory of a new instance
+    pub cwd: Option<String>,
+    /// Requesting session ID
+    pub session_id: Uuid,
+    /// Requesting user role
+    pub role: Role,
+    /// Requesting client IP address
+    pub client_ip: String,
 }
 
 /// Application state shared across handlers
@@ -51,14 +280,51 @@ pub struct AppState {
     pub runtime: ServerRuntime,
     /// Agent repository for database operations
     pub agent_repo: AgentRepository,
-    /// Rate limiter (optional)
-    pub rate_limiter: Option<RateLimiter>,
+    /// Rate limiter of authentication attempts (None = unlimited)
+    pub rate_limiter: Option<Box<dyn RateLimit>>,
+    /// Lockout of client IPs and credentials after repeated authentication failures
+    pub auth_lockout: AuthLockout,
     /// Connected agents (agent_id -> ConnectedAgent)
     pub agents: RwLock<HashMap<Uuid, ConnectedAgent>>,
     /// Connected users (session_id -> UserSession)
     pub users: RwLock<HashMap<Uuid, UserSession>>,
     /// Broadcast channel for agent status changes
     pub agent_status_tx: broadcast::Sender<(Uuid, bool)>,
+    /// Pending instance creations (instance_id -> PendingInstance)
+    pub pending_instances: RwLock<HashMap<Uuid, PendingInstance>>,
+    /// Pending token rotations ((agent_id, kind) -> PendingRotation)
+    pub pending_rotations: RwLock<HashMap<(Uuid, TokenKind), PendingRotation>>,
+    /// Requests waiting for approval at the agent's machine (request_id -> PendingApproval)
+    pub pending_approvals: RwLock<HashMap<Uuid, PendingApproval>>,
+    /// IP allow/deny policy for the WebSocket endpoints
+    pub ip_policy: IpPolicy,
+    /// Replay and brute-force protection for SuperAdmin TOTP codes, per account
+    totp_guards: RwLock<TotpGuards>,
+    /// Command audit settings and blocklist
+    pub command_filter: CommandFilter,
+    /// Command line assemblers (instance_id -> LineAssembler)
+    line_assemblers: RwLock<HashMap<Uuid, LineAssembler>>,
+    /// Syslog / JSON-lines forwarding of stored audit events
+    audit_sinks: AuditSinks,
+    /// Key of the audit log hash chain (derived from the token pepper)
+    audit_chain_key: AuditChainKey,
+    /// Secret patterns removed from stored terminal history
+    redactor: Redactor,
+    /// Redaction state of each instance's output (instance_id -> RedactionStream)
+    redaction_streams: RwLock<HashMap<Uuid, RedactionStream>>,
+    /// Keyed hashing for stored tokens
+    pub token_hasher: TokenHasher,
+    /// OpenID Connect login (None when disabled)
+    pub oidc: Option<OidcProvider>,
+    /// Client certificates allowed to register as each agent (mutual TLS)
+    agent_cert_policy: AgentCertPolicy,
+    /// Signs the tickets used to resume user sessions
+    session_tickets: SessionTicketSigner,
+    /// Disconnected sessions that can still be resumed (session_id -> SuspendedSession)
+    suspended_sessions: RwLock<HashMap<Uuid, SuspendedSession>>,
+    /// Named account of each session (session_id -> account name)
+    /// Kept outside `users` so the synchronous audit logger can read it
+    session_accounts: SyncRwLock<HashMap<Uuid, String>>,
 }
 
 impl AppState {
@@ -66,32 +332,129 @@ impl AppState {
     pub async fn new(
         runtime: ServerRuntime,
         agent_repo: AgentRepository,
-        rate_limiter: Option<RateLimiter>,
+        rate_limiter: Option<Box<dyn RateLimit>>,
     ) -> Result<Self> {
         let (agent_status_tx, _) = broadcast::channel(100);
+        let ip_policy = IpPolicy::from_config(&runtime.config.security)?;
+        let command_filter = CommandFilter::from_config(&runtime.config.command_audit)?;
+        if let Some(secret) = &runtime.config.security.super_admin_totp_secret {
+            build_totp(secret).map_err(|e| anyhow::anyhow!("security.super_admin_totp_secret: {}", e))?;
+        }
+        let token_hasher = load_token_hasher(&runtime.config.security, &agent_repo).await?;
+        let audit_chain_key = AuditChainKey::from_token_hasher(&token_hasher);
+        let oidc = OidcProvider::from_config(&runtime.config.oidc)?;
+        let agent_cert_policy =
+            AgentCertPolicy::from_config(&runtime.config.agent_mtls, runtime.config.server.tls.as_ref())?;
+        let auth_lockout = AuthLockout::from_config(&runtime.config.security);
+        let redactor = Redactor::from_config(&runtime.config.terminal_history)?;
+        let audit_sinks = AuditSinks::from_config(&runtime.config.audit_log)?;
 
         Ok(Self {
             runtime,
             agent_repo,
             rate_limiter,
+            auth_lockout,
             agents: RwLock::new(HashMap::new()),
             users: RwLock::new(HashMap::new()),
             agent_status_tx,
+            pending_instances: RwLock::new(HashMap::new()),
+            pending_rotations: RwLock::new(HashMap::new()),
+            pending_approvals: RwLock::new(HashMap::new()),
+            ip_policy,
+            totp_guards: RwLock::new(TotpGuards::default()),
+            command_filter,
+            line_assemblers: RwLock::new(HashMap::new()),
+            audit_sinks,
+            audit_chain_key,
+            redactor,
+            redaction_streams: RwLock::new(HashMap::new()),
+            token_hasher,
+            oidc,
+            agent_cert_policy,
+            session_tickets: SessionTicketSigner::generate(),
+            suspended_sessions: RwLock::new(HashMap::new()),
+            session_accounts: SyncRwLock::new(HashMap::new()),
         })
     }
 
+    /// Verify an agent registration before it is accepted
+    /// A client certificate mapped to the agent proves its identity on its own;
+    /// otherwise the enrollment secret / pinned agent key are checked against the
+    /// `agents` table. The presented tokens must not belong to another agent.
+    pub async fn verify_agent_registration(
+        &self,
+        agent_id: Uuid,
+        admin_token: &str,
+        share_token: &str,
+        agent_key: Option<&str>,
+        enrollment_secret: Option<&str>,
+        client_cert: Option<&ClientCertificate>,
+    ) -> Result<()> {
+        let cert_verified = self
+            .agent_cert_policy
+            .check(agent_id, client_cert)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        if !cert_verified {
+            let record = self.agent_repo.find_by_id(agent_id).await?;
+            verify_enrollment(
+                &self.token_hasher,
+                record.as_ref(),
+                admin_token,
+                agent_key,
+                enrollment_secret,
+                self.runtime.config.security.agent_enrollment_secret.as_deref(),
+            )
+            .map_err(|e| anyhow::anyhow!(e))?;
+        }
+
+        // Tokens must not collide with another agent's tokens (in memory or persisted)
+        let admin_hashes = self.token_hasher.hashes(admin_token);
+        let share_hashes = self.token_hasher.hashes(share_token);
+        {
+            let agents = self.agents.read().await;
+            let collision = agents.iter().any(|(id, agent)| {
+                *id != agent_id
+                    && [&agent.admin_token_hash, &agent.share_token_hash]
+                        .iter()
+                        .any(|h| hashes_equal(h, &admin_hashes.current) | hashes_equal(h, &share_hashes.current))
+            });
+            if collision {
+                return Err(anyhow::anyhow!("token is already in use by another agent"));
+            }
+        }
+        for hashes in [&admin_hashes, &share_hashes] {
+            let owners = [
+                self.agent_repo.find_by_admin_token(hashes).await?,
+                self.agent_repo.find_by_share_token(hashes).await?,
+            ];
+            if owners.iter().flatten().any(|r| r.id != agent_id.to_string()) {
+                return Err(anyhow::anyhow!("token is already in use by another agent"));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Register a new agent
+    /// Callers must run `verify_agent_registration` first
+    #[allow(clippy::too_many_arguments)]
     pub async fn register_agent(
         &self,
         agent_id: Uuid,
         name: String,
         admin_token: String,
         share_token: String,
+        share_mode: ShareMode,
+        agent_key: Option<String>,
+        approval_required: bool,
         tx: mpsc::Sender<ServerToAgentMessage>,
     ) {
         // Hash tokens before storing
-        let admin_token_hash = hash_token(&admin_token);
-        let share_token_hash = hash_token(&share_token);
+        let admin_hashes = self.token_hasher.hashes(&admin_token);
+        let share_hashes = self.token_hasher.hashes(&share_token);
+        let key_hashes = agent_key.as_deref().map(|key| self.token_hasher.hashes(key));
+        let admin_token_hash = admin_hashes.current.clone();
+        let share_token_hash = share_hashes.current.clone();
 
         let agent = Agent {
             id: agent_id,
@@ -105,8 +468,11 @@ impl AppState {
             agent,
             admin_token_hash: admin_token_hash.clone(),
             share_token_hash: share_token_hash.clone(),
-            tx,
+            share_mode,
+            tx: tx.clone(),
             instances: HashMap::new(),
+            approval_required,
+            approved_sessions: HashSet::new(),
         };
 
         let mut agents = self.agents.write().await;
@@ -115,9 +481,50 @@ impl AppState {
         // Persist to database (non-blocking, log errors)
         let repo = self.agent_repo.clone();
         let name_clone = name.clone();
+        let expires_at = self.default_token_expiry();
         tokio::spawn(async move {
-            if let Err(e) = repo.upsert_agent(agent_id, &name_clone, &admin_token, &share_token).await {
+            // Rows written before token peppering are moved to the keyed hashes first,
+            // so a pinned key and the tokens' revocation state carry over
+            for hashes in [Some(&admin_hashes), Some(&share_hashes), key_hashes.as_ref()].into_iter().flatten() {
+                if let Err(e) = repo.upgrade_token_hash(hashes).await {
+                    tracing::error!("Failed to upgrade token hash: {}", e);
+                }
+            }
+
+            if let Err(e) = repo
+                .upsert_agent(
+                    agent_id,
+                    &name_clone,
+                    &admin_token_hash,
+                    &share_token_hash,
+                    share_mode,
+                    key_hashes.as_ref().map(|h| h.current.as_str()),
+                )
+                .await
+            {
                 tracing::error!("Failed to persist agent to database: {}", e);
+                return;
+            }
+
+            // Track the tokens; a token revoked earlier stays revoked
+            for (kind, hash) in [(TokenKind::Admin, &admin_token_hash), (TokenKind::Share, &share_token_hash)] {
+                if let Err(e) = repo.ensure_token(agent_id, kind, hash, expires_at).await {
+                    tracing::error!("Failed to persist agent token: {}", e);
+                    continue;
+                }
+                if let Ok(Some(record)) = repo.find_token(hash).await {
+                    if let Err(reason) = check_token_record(&record, Utc::now()) {
+                        warn!("Agent {} registered with an unusable {} token: {}", agent_id, kind.as_str(), reason);
+                        let msg = ServerToAgentMessage::Error {
+                            message: format!(
+                                "{} {}; users cannot log in with it until it is rotated or replaced in the agent config",
+                                kind.as_str(),
+                                reason
+                            ),
+                        };
+                        let _ = tx.send(msg).await;
+                    }
+                }
             }
         });
 
@@ -129,47 +536,527 @@ impl AppState {
     pub async fn unregister_agent(&self, agent_id: Uuid) {
         let mut agents = self.agents.write().await;
         agents.remove(&agent_id);
+        drop(agents);
+
+        // Creations the agent never confirmed will not complete
+        let mut pending = self.pending_instances.write().await;
+        pending.retain(|_, p| p.agent_id != agent_id);
+        drop(pending);
+        let mut rotations = self.pending_rotations.write().await;
+        rotations.retain(|(id, _), _| *id != agent_id);
+        drop(rotations);
+
+        // Nobody is left to approve requests still waiting for the agent
+        let mut approvals = self.pending_approvals.write().await;
+        let dropped: Vec<Uuid> = approvals
+            .iter()
+            .filter(|(_, p)| p.agent_id == agent_id)
+            .map(|(id, _)| *id)
+            .collect();
+        let dropped: Vec<PendingApproval> = dropped.iter().filter_map(|id| approvals.remove(id)).collect();
+        drop(approvals);
+        for pending in dropped {
+            let msg = ServerToUserMessage::Error {
+                message: "The agent disconnected before the request was approved".to_string(),
+                code: Some(ErrorCode::ApprovalDenied),
+            };
+            let _ = self.send_to_user(pending.session_id, msg).await;
+        }
 
         // Broadcast agent offline
         let _ = self.agent_status_tx.send((agent_id, false));
     }
 
-    /// Authenticate a token and return role and agent ID
+    /// Authenticate a token and return the role, agent ID and SuperAdmin account
     /// Uses hashed token comparison for security
-    pub async fn authenticate(&self, token: &str) -> Option<(Role, Option<Uuid>)> {
-        // Check super admin token (direct comparison for config-based token)
-        if token == self.runtime.config.security.super_admin_token {
-            return Some((Role::SuperAdmin, None));
+    pub async fn authenticate(&self, token: &str) -> Option<AuthIdentity> {
+        // Check super admin token (constant-time comparison for config-based token, empty = disabled)
+        let config_token = &self.runtime.config.security.super_admin_token;
+        if !config_token.is_empty() && hashes_equal(token, config_token) {
+            return Some(AuthIdentity {
+                role: Role::SuperAdmin,
+                agent_id: None,
+                account: None,
+                credential: SessionCredential::ConfigToken,
+                scopes: Role::SuperAdmin.default_scopes(),
+            });
+        }
+
+        let hashes = self.token_hasher.hashes(token);
+
+        // Named SuperAdmin accounts
+        match self.agent_repo.find_super_admin_by_token(&hashes).await {
+            Ok(Some(record)) => {
+                if record.is_disabled() {
+                    warn!("Login attempt with disabled SuperAdmin account '{}'", record.name);
+                    return None;
+                }
+                if hashes.is_legacy(&record.token_hash) {
+                    self.upgrade_token_hash(&hashes);
+                }
+                let repo = self.agent_repo.clone();
+                let id = record.id.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = repo.touch_super_admin(&id).await {
+                        warn!("Failed to update SuperAdmin last login: {}", e);
+                    }
+                });
+                return Some(AuthIdentity {
+                    role: Role::SuperAdmin,
+                    agent_id: None,
+                    account: Some(record.name.clone()),
+                    scopes: credential_scopes(Role::SuperAdmin, &record.scopes),
+                    credential: SessionCredential::SuperAdmin(record.name),
+                });
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look up SuperAdmin account: {}", e),
         }
 
-        let token_hash = hash_token(token);
+        let mut matched = None;
+        let mut legacy = false;
 
         // First check in-memory connected agents (fast path)
+        // Every entry is compared in constant time, without stopping at the first match
         {
             let agents = self.agents.read().await;
             for (agent_id, agent) in agents.iter() {
-                if token_hash == agent.admin_token_hash {
-                    return Some((Role::Admin, Some(*agent_id)));
-                }
-                if token_hash == agent.share_token_hash {
-                    return Some((Role::User, Some(*agent_id)));
+                let admin = hashes_equal(&hashes.current, &agent.admin_token_hash);
+                let share = hashes_equal(&hashes.current, &agent.share_token_hash);
+                if admin {
+                    matched = Some((Role::Admin, *agent_id));
+                } else if share {
+                    matched = Some((agent.share_mode.role(), *agent_id));
                 }
             }
         }
 
         // Then check database for offline/registered agents
-        if let Ok(Some(record)) = self.agent_repo.find_by_admin_token(token).await {
-            if let Ok(id) = record.id.parse::<Uuid>() {
-                return Some((Role::Admin, Some(id)));
+        if matched.is_none() {
+            if let Ok(Some(record)) = self.agent_repo.find_by_admin_token(&hashes).await {
+                if let Ok(id) = record.id.parse::<Uuid>() {
+                    legacy |= hashes.is_legacy(&record.admin_token_hash);
+                    matched = Some((Role::Admin, id));
+                }
             }
         }
-        if let Ok(Some(record)) = self.agent_repo.find_by_share_token(token).await {
-            if let Ok(id) = record.id.parse::<Uuid>() {
-                return Some((Role::User, Some(id)));
+        if matched.is_none() {
+            if let Ok(Some(record)) = self.agent_repo.find_by_share_token(&hashes).await {
+                if let Ok(id) = record.id.parse::<Uuid>() {
+                    legacy |= hashes.is_legacy(&record.share_token_hash);
+                    matched = Some((record.share_mode().role(), id));
+                }
             }
         }
 
-        None
+        let Some((role, agent_id)) = matched else {
+            return self.authenticate_invite(&hashes).await;
+        };
+        let stored_scopes = match self.check_token_state(&hashes).await {
+            Some((token_legacy, stored_scopes)) => {
+                legacy |= token_legacy;
+                stored_scopes
+            }
+            None => return None,
+        };
+        if legacy {
+            self.upgrade_token_hash(&hashes);
+        }
+        Some(AuthIdentity {
+            role,
+            agent_id: Some(agent_id),
+            account: None,
+            credential: SessionCredential::AgentToken(hashes),
+            scopes: credential_scopes(role, &stored_scopes),
+        })
+    }
+
+    /// Authenticate an instance invite and count the login against its use limit
+    async fn authenticate_invite(&self, hashes: &TokenHashes) -> Option<AuthIdentity> {
+        let record = match self.agent_repo.find_invite(&hashes.current).await {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to look up invite: {}", e);
+                return None;
+            }
+        };
+        let (Ok(agent_id), Ok(instance_id)) = (record.agent_id.parse::<Uuid>(), record.instance_id.parse::<Uuid>())
+        else {
+            return None;
+        };
+        if record.expires_at_datetime().is_none_or(|at| at <= Utc::now()) {
+            warn!("Rejected expired invite for instance {}", instance_id);
+            return None;
+        }
+        match self.agent_repo.use_invite(&record.token_hash).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Rejected exhausted invite for instance {}", instance_id);
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to record invite use: {}", e);
+                return None;
+            }
+        }
+
+        let role = if record.read_only != 0 { Role::Viewer } else { Role::User };
+        Some(AuthIdentity {
+            role,
+            agent_id: Some(agent_id),
+            account: None,
+            scopes: role.default_scopes(),
+            credential: SessionCredential::Invite {
+                hash: record.token_hash,
+                instance_id,
+            },
+        })
+    }
+
+    /// Mint an invite to one instance, returns the token and its expiry
+    pub async fn create_invite(
+        &self,
+        agent_id: Uuid,
+        instance_id: Uuid,
+        expires_in_secs: u64,
+        max_uses: u32,
+        read_only: bool,
+        created_by: &str,
+    ) -> Result<(String, DateTime<Utc>)> {
+        let ttl = expires_in_secs.clamp(60, MAX_INVITE_TTL_SECS);
+        let expires_at = Utc::now() + chrono::Duration::seconds(ttl as i64);
+        let token = generate_token();
+        let hash = self.token_hasher.hashes(&token).current;
+        self.agent_repo
+            .create_invite(&hash, agent_id, instance_id, read_only, max_uses, expires_at, created_by)
+            .await?;
+        Ok((token, expires_at))
+    }
+
+    /// Instance a session is limited to (None = every instance of its agent)
+    pub async fn get_instance_scope(&self, session_id: Uuid) -> Option<Uuid> {
+        let users = self.users.read().await;
+        users.get(&session_id).and_then(|s| s.credential.instance_scope())
+    }
+
+    /// Instances of an agent visible to a session
+    pub async fn get_session_instances(&self, session_id: Uuid, agent_id: Uuid) -> Vec<Instance> {
+        let mut instances = self.get_instances(agent_id).await;
+        if let Some(scope) = self.get_instance_scope(session_id).await {
+            instances.retain(|i| i.id == scope);
+        }
+        instances
+    }
+
+    /// Check that the credential a session logged in with is still accepted
+    /// Returns when it expires (None = never), or why the session must end
+    pub async fn check_session_credential(
+        &self,
+        credential: &SessionCredential,
+    ) -> Result<Option<DateTime<Utc>>, &'static str> {
+        match credential {
+            SessionCredential::ConfigToken => {
+                if self.runtime.config.security.super_admin_token.is_empty() {
+                    return Err("the config SuperAdmin token is disabled");
+                }
+                Ok(None)
+            }
+            SessionCredential::SuperAdmin(name) => match self.agent_repo.find_super_admin_by_name(name).await {
+                Ok(Some(record)) if !record.is_disabled() => Ok(None),
+                Ok(Some(_)) => Err("SuperAdmin account has been disabled"),
+                Ok(None) => Err("SuperAdmin account no longer exists"),
+                Err(e) => {
+                    warn!("Failed to look up SuperAdmin account '{}': {}", name, e);
+                    Err("credential could not be checked")
+                }
+            },
+            SessionCredential::AgentToken(hashes) => {
+                let expires_at = match self.agent_repo.find_token_by_hashes(hashes).await {
+                    Ok(Some(record)) => {
+                        check_token_record(&record, Utc::now())?;
+                        record.expires_at_datetime()
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("Failed to look up agent token: {}", e);
+                        return Err("credential could not be checked");
+                    }
+                };
+
+                // The token must still belong to its agent (it may have been rotated)
+                let in_memory = self.agents.read().await.values().any(|agent| {
+                    hashes.matches(&agent.admin_token_hash) | hashes.matches(&agent.share_token_hash)
+                });
+                let in_database = in_memory
+                    || matches!(self.agent_repo.find_by_admin_token(hashes).await, Ok(Some(_)))
+                    || matches!(self.agent_repo.find_by_share_token(hashes).await, Ok(Some(_)));
+                if !in_database {
+                    return Err("token is no longer valid");
+                }
+                Ok(expires_at)
+            }
+            SessionCredential::WebSession(key) => match &self.oidc {
+                Some(oidc) => oidc.session_expiry(key).await.map(Some).ok_or("web session has expired"),
+                None => Err("SSO login is disabled"),
+            },
+            SessionCredential::Invite { hash, .. } => match self.agent_repo.find_invite(hash).await {
+                Ok(Some(record)) => match record.expires_at_datetime() {
+                    Some(at) if at > Utc::now() => Ok(Some(at)),
+                    _ => Err("invite has expired"),
+                },
+                Ok(None) => Err("invite has been revoked"),
+                Err(e) => {
+                    warn!("Failed to look up invite: {}", e);
+                    Err("credential could not be checked")
+                }
+            },
+        }
+    }
+
+    /// Authenticate a web session issued after an OIDC login
+    pub async fn authenticate_session(&self, session: &str) -> Option<AuthIdentity> {
+        self.oidc.as_ref()?.authenticate_session(session).await
+    }
+
+    /// Check a token against the `agent_tokens` table and record its use
+    /// Tokens without a row (agents not seen since the table was added) are accepted
+    /// Returns whether the row still holds a legacy hash and the token's stored scopes,
+    /// or None if the token is rejected
+    async fn check_token_state(&self, hashes: &TokenHashes) -> Option<(bool, String)> {
+        match self.agent_repo.find_token_by_hashes(hashes).await {
+            Ok(Some(record)) => {
+                if let Err(reason) = check_token_record(&record, Utc::now()) {
+                    warn!("Rejected {} token of agent {}: {}", record.kind, record.agent_id, reason);
+                    return None;
+                }
+                let repo = self.agent_repo.clone();
+                let token_hash = record.token_hash.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = repo.touch_token(&token_hash).await {
+                        tracing::error!("Failed to update token last use: {}", e);
+                    }
+                });
+                Some((hashes.is_legacy(&record.token_hash), record.scopes))
+            }
+            Ok(None) => Some((false, String::new())),
+            Err(e) => {
+                // Fail closed: a revoked token must not slip through while the database is unavailable
+                warn!("Token lookup failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Move a token stored with a legacy hash to its keyed hash (non-blocking)
+    fn upgrade_token_hash(&self, hashes: &TokenHashes) {
+        let repo = self.agent_repo.clone();
+        let hashes = hashes.clone();
+        tokio::spawn(async move {
+            match repo.upgrade_token_hash(&hashes).await {
+                Ok(()) => tracing::info!("Upgraded a legacy token hash"),
+                Err(e) => tracing::error!("Failed to upgrade token hash: {}", e),
+            }
+        });
+    }
+
+
+    /// Expiry for tokens issued now, from `security.token_ttl_days`
+    fn default_token_expiry(&self) -> Option<DateTime<Utc>> {
+        self.runtime
+            .config
+            .security
+            .token_ttl_days
+            .map(|days| Utc::now() + chrono::Duration::days(days as i64))
+    }
+
+    // ========================================================================
+    // SuperAdmin second factor
+    // ========================================================================
+
+    /// TOTP verifier for a SuperAdmin login, None when no secret is configured
+    /// A named account uses its own secret when it has one; otherwise the shared secret
+    /// applies, and one in the config file takes precedence over the one in the database
+    pub async fn super_admin_totp(&self, account: Option<&str>) -> Result<Option<TOTP>> {
+        if let Some(name) = account {
+            if let Some(record) = self.agent_repo.find_super_admin_by_name(name).await? {
+                if !record.totp_secret.is_empty() {
+                    return build_totp(&record.totp_secret).map(Some);
+                }
+            }
+        }
+
+        let secret = match &self.runtime.config.security.super_admin_totp_secret {
+            Some(secret) => Some(secret.clone()),
+            None => self.agent_repo.get_setting(TOTP_SECRET_SETTING).await?,
+        };
+        if secret.is_some() {
+            if let Some(name) = account {
+                warn!("SuperAdmin account '{}' uses the shared TOTP secret: run --setup-totp {} to give it its own", name, name);
+            }
+        }
+        secret.map(|s| build_totp(&s)).transpose()
+    }
+
+    /// Verify a SuperAdmin TOTP code for an account (None = the config token)
+    /// Returns an error while the account is locked after repeated failures
+    pub async fn verify_super_admin_totp(
+        &self,
+        account: Option<&str>,
+        totp: &TOTP,
+        code: &str,
+    ) -> Result<bool, &'static str> {
+        let now = Utc::now().timestamp().max(0) as u64;
+        let mut guards = self.totp_guards.write().await;
+        let guard = guards.get(account);
+        if guard.is_locked(now) {
+            return Err("too many failed verification codes, try again later");
+        }
+        Ok(guard.verify(totp, code, now))
+    }
+
+    // ========================================================================
+    // Token rotation and revocation (SuperAdmin only)
+    // ========================================================================
+
+    /// Generate a new token and send it to the agent
+    /// The rotation only takes effect once the agent confirms it saved the token
+    pub async fn start_token_rotation(
+        &self,
+        agent_id: Uuid,
+        kind: TokenKind,
+        expires_in_secs: Option<u64>,
+        session_id: Uuid,
+        role: Role,
+        client_ip: &str,
+    ) -> Result<()> {
+        let expires_at = match expires_in_secs {
+            Some(secs) => {
+                let ttl = secs.clamp(60, MAX_TOKEN_TTL_SECS);
+                Some(Utc::now() + chrono::Duration::seconds(ttl as i64))
+            }
+            None => self.default_token_expiry(),
+        };
+        let token = generate_token();
+
+        let agents = self.agents.read().await;
+        let agent = agents
+            .get(&agent_id)
+            .ok_or_else(|| anyhow::anyhow!("Agent is offline: a rotated token can only be delivered to a connected agent"))?;
+
+        let mut rotations = self.pending_rotations.write().await;
+        if rotations.contains_key(&(agent_id, kind)) {
+            return Err(anyhow::anyhow!("A {} token rotation is already in progress for this agent", kind.as_str()));
+        }
+
+        let cmd = ServerToAgentMessage::RotateToken {
+            kind,
+            token: token.clone(),
+        };
+        agent.tx.send(cmd).await.map_err(|e| anyhow::anyhow!("Failed to send to agent: {}", e))?;
+
+        rotations.insert(
+            (agent_id, kind),
+            PendingRotation {
+                token,
+                expires_at,
+                session_id,
+                role,
+                client_ip: client_ip.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Take the pending rotation for an agent token, if any
+    pub async fn take_pending_rotation(&self, agent_id: Uuid, kind: TokenKind) -> Option<PendingRotation> {
+        let mut rotations = self.pending_rotations.write().await;
+        rotations.remove(&(agent_id, kind))
+    }
+
+    /// Apply a rotation the agent has confirmed: store the new token, revoke the old one
+    /// and disconnect sessions that logged in with it
+    pub async fn apply_token_rotation(&self, agent_id: Uuid, kind: TokenKind, rotation: &PendingRotation) -> Result<()> {
+        let old_hash = self.agent_token_hash(agent_id, kind).await?;
+        let new_hash = self.token_hasher.hash(&rotation.token);
+        self.agent_repo
+            .rotate_token(agent_id, kind, &new_hash, rotation.expires_at)
+            .await?;
+
+        {
+            let mut agents = self.agents.write().await;
+            if let Some(agent) = agents.get_mut(&agent_id) {
+                match kind {
+                    TokenKind::Admin => agent.admin_token_hash = new_hash,
+                    TokenKind::Share => agent.share_token_hash = new_hash,
+                }
+            }
+        }
+
+        if let Some(old_hash) = old_hash {
+            self.disconnect_token_sessions(agent_id, &old_hash, "Token has been rotated").await;
+        }
+        Ok(())
+    }
+
+    /// Revoke an agent token and disconnect sessions that logged in with it
+    pub async fn revoke_token(&self, agent_id: Uuid, kind: TokenKind) -> Result<()> {
+        let hash = self.agent_token_hash(agent_id, kind).await?;
+        match hash {
+            Some(hash) if self.agent_repo.revoke_tokens(agent_id, kind).await? => {
+                self.disconnect_token_sessions(agent_id, &hash, "Token has been revoked").await;
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!("Agent not found: {}", agent_id)),
+        }
+    }
+
+    /// Stored hash of an agent's current token of a kind (connected agent first, then the database)
+    async fn agent_token_hash(&self, agent_id: Uuid, kind: TokenKind) -> Result<Option<String>> {
+        if let Some(agent) = self.agents.read().await.get(&agent_id) {
+            return Ok(Some(match kind {
+                TokenKind::Admin => agent.admin_token_hash.clone(),
+                TokenKind::Share => agent.share_token_hash.clone(),
+            }));
+        }
+        Ok(self.agent_repo.find_by_id(agent_id).await?.map(|record| match kind {
+            TokenKind::Admin => record.admin_token_hash,
+            TokenKind::Share => record.share_token_hash,
+        }))
+    }
+
+    /// Disconnect every session that logged in with the agent token stored as `token_hash`
+    /// Invites and SSO logins mapped to the agent are separate credentials and stay connected
+    async fn disconnect_token_sessions(&self, agent_id: Uuid, token_hash: &str, reason: &str) {
+        let uses_token =
+            |credential: &SessionCredential| matches!(credential, SessionCredential::AgentToken(hashes) if hashes.matches(token_hash));
+
+        let mut users = self.users.write().await;
+        let session_ids: Vec<Uuid> = users
+            .iter()
+            .filter(|(_, s)| uses_token(&s.credential))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for session_id in &session_ids {
+            if let Some(session) = users.remove(session_id) {
+                // Dropping the session closes its channel, which closes the WebSocket
+                let msg = ServerToUserMessage::Error {
+                    message: reason.to_string(),
+                    code: None,
+                };
+                let _ = session.tx.send(msg).await;
+            }
+        }
+
+        drop(users);
+
+        // Sessions waiting to be resumed must not come back either
+        self.suspended_sessions.write().await.retain(|_, s| !uses_token(&s.identity.credential));
+
+        if !session_ids.is_empty() {
+            tracing::info!("Disconnected {} session(s) of agent {}: {}", session_ids.len(), agent_id, reason);
+        }
     }
 
     /// Get agent by ID (with instances populated from HashMap)
@@ -216,6 +1103,99 @@ impl AppState {
         if let Some(agent) = agents.get_mut(&agent_id) {
             agent.instances.remove(&instance_id);
         }
+        drop(agents);
+
+        self.line_assemblers.write().await.remove(&instance_id);
+        self.redaction_streams.write().await.remove(&instance_id);
+    }
+
+    /// Feed terminal input of an instance into its line assembler
+    /// Returns the command lines completed by this input
+    pub async fn assemble_command_lines(&self, instance_id: Uuid, input: &[u8]) -> Vec<CommandLine> {
+        let mut assemblers = self.line_assemblers.write().await;
+        assemblers.entry(instance_id).or_default().feed(input)
+    }
+
+    /// Forget the partially typed line of an instance
+    pub async fn reset_command_line(&self, instance_id: Uuid) {
+        if let Some(assembler) = self.line_assemblers.write().await.get_mut(&instance_id) {
+            assembler.reset();
+        }
+    }
+
+    /// Track an instance creation until the agent confirms or rejects it
+    pub async fn add_pending_instance(&self, instance_id: Uuid, pending: PendingInstance) {
+        let mut pending_instances = self.pending_instances.write().await;
+        pending_instances.insert(instance_id, pending);
+    }
+
+    /// Take the pending creation for an instance, if any
+    pub async fn take_pending_instance(&self, instance_id: Uuid) -> Option<PendingInstance> {
+        let mut pending_instances = self.pending_instances.write().await;
+        pending_instances.remove(&instance_id)
+    }
+
+    /// Whether an action of a session must first be approved at the agent's machine
+    /// Creates always are; attaches only until the session was approved once
+    pub async fn needs_approval(&self, agent_id: Uuid, session_id: Uuid, action: ApprovalAction) -> bool {
+        let agents = self.agents.read().await;
+        agents.get(&agent_id).is_some_and(|agent| {
+            agent.approval_required
+                && (action == ApprovalAction::CreateInstance || !agent.approved_sessions.contains(&session_id))
+        })
+    }
+
+    /// Ask the agent's machine to approve a request; returns the request ID
+    /// A session can have one request waiting per agent
+    pub async fn request_approval(&self, pending: PendingApproval) -> Result<Uuid> {
+        let requester = self
+            .session_account(pending.session_id)
+            .unwrap_or_else(|| format!("{:?}", pending.role));
+
+        let agents = self.agents.read().await;
+        let agent = agents
+            .get(&pending.agent_id)
+            .ok_or_else(|| anyhow::anyhow!("Agent is offline"))?;
+
+        let mut approvals = self.pending_approvals.write().await;
+        if approvals
+            .values()
+            .any(|p| p.agent_id == pending.agent_id && p.session_id == pending.session_id)
+        {
+            return Err(anyhow::anyhow!("Another request of this session is still waiting for approval"));
+        }
+
+        let request_id = Uuid::new_v4();
+        let cmd = ServerToAgentMessage::ApprovalRequest {
+            request_id,
+            action: pending.action,
+            instance_id: pending.instance_id,
+            session_id: pending.session_id,
+            requester,
+            client_ip: pending.client_ip.clone(),
+            cwd: pending.cwd.clone(),
+        };
+        agent.tx.send(cmd).await.map_err(|e| anyhow::anyhow!("Failed to send to agent: {}", e))?;
+
+        approvals.insert(request_id, pending);
+        Ok(request_id)
+    }
+
+    /// Take a request waiting for approval by an agent
+    pub async fn take_pending_approval(&self, agent_id: Uuid, request_id: Uuid) -> Option<PendingApproval> {
+        let mut approvals = self.pending_approvals.write().await;
+        match approvals.get(&request_id) {
+            Some(p) if p.agent_id == agent_id => approvals.remove(&request_id),
+            _ => None,
+        }
+    }
+
+    /// Remember that a session was approved at the agent's machine
+    pub async fn mark_session_approved(&self, agent_id: Uuid, session_id: Uuid) {
+        let mut agents = self.agents.write().await;
+        if let Some(agent) = agents.get_mut(&agent_id) {
+            agent.approved_sessions.insert(session_id);
+        }
     }
 
     /// Send message to agent
@@ -227,31 +1207,268 @@ impl AppState {
         Ok(())
     }
 
-    /// Register a user session
+    /// Register a user session on a connection
+    /// Returns false if the token already has `security.max_sessions_per_token` sessions
     pub async fn register_user(
         &self,
         session_id: Uuid,
-        role: Role,
-        agent_id: Option<Uuid>,
+        connection_id: Uuid,
+        identity: &AuthIdentity,
+        client_ip: &str,
         tx: mpsc::Sender<ServerToUserMessage>,
-    ) {
+    ) -> bool {
+        let now = Utc::now();
         let session = UserSession {
             id: session_id,
-            role,
-            agent_id,
+            role: identity.role,
+            agent_id: identity.agent_id,
             working_agent_id: None,
+            account: identity.account.clone(),
+            credential: identity.credential.clone(),
+            scopes: identity.scopes.clone(),
             attached_instances: Vec::new(),
             tx,
+            connection_id,
+            ticket_generation: 0,
+            ticket_expires_at: 0,
+            client_ip: client_ip.to_string(),
+            connected_at: now,
+            last_activity: AtomicI64::new(now.timestamp()),
+        };
+        self.insert_user(session).await
+    }
+
+    /// Register a resumed session on a new connection
+    /// The working agent and attached instances are kept if they are still available
+    /// Returns false if the token already has `security.max_sessions_per_token` sessions
+    pub async fn restore_user(
+        &self,
+        connection_id: Uuid,
+        suspended: SuspendedSession,
+        client_ip: &str,
+        tx: mpsc::Sender<ServerToUserMessage>,
+    ) -> bool {
+        let working_agent_id = match suspended.working_agent_id {
+            Some(id) if self.agents.read().await.contains_key(&id) => Some(id),
+            _ => None,
+        };
+        let effective_agent_id = working_agent_id.or(suspended.identity.agent_id);
+
+        let mut attached_instances = Vec::new();
+        for instance_id in suspended.attached_instances {
+            if effective_agent_id.is_some() && self.find_instance_agent(instance_id).await == effective_agent_id {
+                attached_instances.push(instance_id);
+            }
+        }
+
+        let session = UserSession {
+            id: suspended.session_id,
+            role: suspended.identity.role,
+            agent_id: suspended.identity.agent_id,
+            working_agent_id,
+            account: suspended.identity.account,
+            credential: suspended.identity.credential,
+            scopes: suspended.identity.scopes,
+            attached_instances,
+            tx,
+            connection_id,
+            ticket_generation: suspended.ticket_generation,
+            ticket_expires_at: suspended.ticket_expires_at,
+            client_ip: client_ip.to_string(),
+            connected_at: Utc::now(),
+            last_activity: AtomicI64::new(Utc::now().timestamp()),
+        };
+        self.insert_user(session).await
+    }
+
+    /// Add a session unless its token has reached `security.max_sessions_per_token`
+    /// Counted under the write lock so concurrent logins cannot both take the last slot
+    async fn insert_user(&self, session: UserSession) -> bool {
+        let limit = self.runtime.config.security.max_sessions_per_token as usize;
+        let mut users = self.users.write().await;
+        if limit > 0 && users.values().filter(|s| s.credential.same_token(&session.credential)).count() >= limit {
+            warn!("Session limit of {} reached for a {} credential", limit, session.credential.kind());
+            return false;
+        }
+
+        if let Some(account) = &session.account {
+            if let Ok(mut accounts) = self.session_accounts.write() {
+                accounts.insert(session.id, account.clone());
+            }
+        }
+        users.insert(session.id, session);
+        true
+    }
+
+    /// Record activity of a session
+    pub async fn touch_user(&self, session_id: Uuid) {
+        if let Some(session) = self.users.read().await.get(&session_id) {
+            session.last_activity.store(Utc::now().timestamp(), Ordering::Relaxed);
+        }
+    }
+
+    /// Connected user sessions, oldest connection first
+    pub async fn list_user_sessions(&self) -> Vec<common::SessionInfo> {
+        let users = self.users.read().await;
+        let mut sessions: Vec<common::SessionInfo> = users
+            .values()
+            .map(|s| common::SessionInfo {
+                session_id: s.id,
+                role: s.role,
+                credential: s.credential.kind().to_string(),
+                account: s.account.clone(),
+                agent_id: s.agent_id,
+                working_agent_id: s.working_agent_id,
+                client_ip: s.client_ip.clone(),
+                connected_at: s.connected_at,
+                last_activity: DateTime::from_timestamp(s.last_activity.load(Ordering::Relaxed), 0)
+                    .unwrap_or(s.connected_at),
+                attached_instances: s.attached_instances.clone(),
+            })
+            .collect();
+        sessions.sort_by_key(|s| s.connected_at);
+        sessions
+    }
+
+    /// Disconnect a session at a SuperAdmin's request; it cannot be resumed afterwards
+    /// A suspended session is dropped as well. Returns the role of the session, None if unknown
+    pub async fn kick_user_session(&self, session_id: Uuid) -> Option<Role> {
+        let live = self.users.read().await.get(&session_id).map(|s| s.role);
+        if let Some(role) = live {
+            self.end_user_session(session_id, "disconnected by a SuperAdmin").await;
+            return Some(role);
+        }
+        self.suspended_sessions.write().await.remove(&session_id).map(|s| s.identity.role)
+    }
+
+    /// Check if a user session is still served by this connection
+    /// (it may have been disconnected by the server or resumed elsewhere)
+    pub async fn has_user_session(&self, session_id: Uuid, connection_id: Uuid) -> bool {
+        let users = self.users.read().await;
+        users.get(&session_id).is_some_and(|s| s.connection_id == connection_id)
+    }
+
+    /// Unregister a user session when its connection closes
+    /// Sessions holding an unexpired ticket are kept so they can be resumed
+    pub async fn unregister_user(&self, session_id: Uuid, connection_id: Uuid) {
+        let mut users = self.users.write().await;
+        match users.get(&session_id) {
+            Some(s) if s.connection_id == connection_id => {}
+            // Already dropped by the server, or resumed on a newer connection
+            _ => return,
+        }
+        let session = users.remove(&session_id).expect("session is registered");
+        drop(users);
+
+        if let Ok(mut accounts) = self.session_accounts.write() {
+            accounts.remove(&session_id);
+        }
+
+        let now = Utc::now().timestamp();
+        if session.ticket_generation > 0 && session.ticket_expires_at > now {
+            let mut suspended = self.suspended_sessions.write().await;
+            suspended.retain(|_, s| s.ticket_expires_at > now);
+            suspended.insert(session_id, SuspendedSession::from(session));
+        }
+    }
+
+    /// Issue a new ticket for a session, replacing the previous one
+    /// The login credential is checked first: a session whose token expired, was revoked or
+    /// whose account was disabled is disconnected instead. Tickets never outlive the credential.
+    /// Returns the ticket and its lifetime in seconds
+    pub async fn issue_session_ticket(&self, session_id: Uuid) -> Option<(String, u64)> {
+        let credential = self.users.read().await.get(&session_id)?.credential.clone();
+        let credential_expires_at = match self.check_session_credential(&credential).await {
+            Ok(expires_at) => expires_at,
+            Err(reason) => {
+                self.end_user_session(session_id, reason).await;
+                return None;
+            }
         };
 
+        let now = Utc::now().timestamp();
+        let ttl = self.runtime.config.security.session_ticket_ttl_secs as i64;
+        let expires_at = credential_expires_at.map_or(now + ttl, |e| e.timestamp().min(now + ttl));
+
         let mut users = self.users.write().await;
-        users.insert(session_id, session);
+        let session = users.get_mut(&session_id)?;
+        session.ticket_generation += 1;
+        session.ticket_expires_at = expires_at;
+
+        let ticket = self.session_tickets.issue(&TicketClaims {
+            session_id,
+            generation: session.ticket_generation,
+            expires_at,
+        });
+        Some((ticket, (expires_at - now).max(0) as u64))
+    }
+
+    /// Disconnect a session whose login credential is no longer accepted
+    /// The session is not suspended, so it cannot be resumed either
+    async fn end_user_session(&self, session_id: Uuid, reason: &str) {
+        let Some(session) = self.users.write().await.remove(&session_id) else {
+            return;
+        };
+        if let Ok(mut accounts) = self.session_accounts.write() {
+            accounts.remove(&session_id);
+        }
+
+        info!("Ended user session {}: {}", session_id, reason);
+        // Dropping the session closes its channel, which closes the WebSocket
+        let msg = ServerToUserMessage::Error {
+            message: format!("Session ended: {}", reason),
+            code: None,
+        };
+        let _ = session.tx.send(msg).await;
+    }
+
+    /// Take the session a ticket resumes
+    /// A session still registered on another connection is taken over: its old
+    /// connection may be half-open and not noticed to be gone yet.
+    /// Every ticket issued before the resume becomes unusable, and the session
+    /// is only resumed while its login credential is still valid.
+    pub async fn resume_user(&self, ticket: &str) -> Result<SuspendedSession, &'static str> {
+        let session = self.take_resumable_session(ticket).await?;
+        self.check_session_credential(&session.identity.credential).await?;
+        Ok(session)
     }
 
-    /// Unregister a user session
-    pub async fn unregister_user(&self, session_id: Uuid) {
+    async fn take_resumable_session(&self, ticket: &str) -> Result<SuspendedSession, &'static str> {
+        let now = Utc::now().timestamp();
+        let claims = self.session_tickets.verify(ticket, now)?;
+
         let mut users = self.users.write().await;
-        users.remove(&session_id);
+        if let Some(session) = users.get(&claims.session_id) {
+            if !is_current_ticket(claims.generation, session.ticket_generation) {
+                return Err("ticket has been superseded");
+            }
+            let session = users.remove(&claims.session_id).expect("session is registered");
+            let msg = ServerToUserMessage::Error {
+                message: "Session was resumed from another connection".to_string(),
+                code: None,
+            };
+            let _ = session.tx.send(msg).await;
+            return Ok(SuspendedSession::from(session).retire_tickets());
+        }
+        drop(users);
+
+        let mut suspended = self.suspended_sessions.write().await;
+        suspended.retain(|_, s| s.ticket_expires_at > now);
+        match suspended.get(&claims.session_id) {
+            Some(s) if is_current_ticket(claims.generation, s.ticket_generation) => {
+                Ok(suspended.remove(&claims.session_id).expect("session is suspended").retire_tickets())
+            }
+            Some(_) => Err("ticket has been superseded"),
+            None => Err("session has ended"),
+        }
+    }
+
+    /// Named account of a session, if any
+    pub fn session_account(&self, session_id: Uuid) -> Option<String> {
+        self.session_accounts
+            .read()
+            .ok()
+            .and_then(|accounts| accounts.get(&session_id).cloned())
     }
 
     /// Broadcast message to all users attached to an instance
@@ -351,8 +1568,74 @@ impl AppState {
         None
     }
 
+    /// Find the agent that owns an instance
+    pub async fn find_instance_agent(&self, instance_id: Uuid) -> Option<Uuid> {
+        let agents = self.agents.read().await;
+        agents
+            .iter()
+            .find(|(_, agent)| agent.instances.contains_key(&instance_id))
+            .map(|(agent_id, _)| *agent_id)
+    }
+
+    /// Whether an instance's PTY streams are end-to-end encrypted (only ciphertext passes the server)
+    pub async fn is_instance_encrypted(&self, agent_id: Uuid, instance_id: Uuid) -> bool {
+        let agents = self.agents.read().await;
+        agents
+            .get(&agent_id)
+            .and_then(|a| a.instances.get(&instance_id))
+            .is_some_and(|i| i.encrypted)
+    }
+
+    /// Check that an instance belongs to the session's effective agent
+    /// Every instance-scoped user operation goes through here, denials are audited.
+    /// Returns the agent that owns the instance.
+    pub async fn authorize_instance(
+        &self,
+        session_id: Uuid,
+        instance_id: Uuid,
+        action: &str,
+        client_ip: &str,
+    ) -> Result<Uuid> {
+        let (role, effective_agent_id, scope) = {
+            let users = self.users.read().await;
+            let session = users
+                .get(&session_id)
+                .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+            (
+                session.role,
+                session.working_agent_id.or(session.agent_id),
+                session.credential.instance_scope(),
+            )
+        };
+        let owner_agent_id = self.find_instance_agent(instance_id).await;
+
+        let reason = match (effective_agent_id, owner_agent_id) {
+            _ if scope.is_some_and(|scope| scope != instance_id) => "session is limited to another instance",
+            (Some(effective), Some(owner)) if effective == owner => return Ok(owner),
+            (None, _) => "no agent selected for this session",
+            (_, None) => "instance not found",
+            _ => "instance belongs to another agent",
+        };
+
+        warn!(
+            "Denied {} on instance {} for session {}: {}",
+            action, instance_id, session_id, reason
+        );
+        self.log_audit_event(
+            "instance_access_denied",
+            session_id,
+            &format!("{:?}", role),
+            effective_agent_id,
+            Some(instance_id),
+            owner_agent_id,
+            client_ip,
+            false,
+            Some(&format!("action: {}, reason: {}", action, reason)),
+        );
+        Err(anyhow::anyhow!("Permission denied: instance {} is not available to this session", instance_id))
+    }
+
     /// Get the working agent ID for a session (SuperAdmin only)
-    #[allow(dead_code)]
     pub async fn get_working_agent_id(&self, session_id: Uuid) -> Option<Uuid> {
         let users = self.users.read().await;
         users.get(&session_id).and_then(|s| s.working_agent_id)
@@ -377,7 +1660,7 @@ impl AppState {
         let mut agents = self.agents.write().await;
         if let Some(agent) = agents.get_mut(&agent_id) {
             for instance in agent.instances.values_mut() {
-                instance.status = status.clone();
+                instance.status = status;
             }
         }
     }
@@ -420,6 +1703,9 @@ impl AppState {
             }
             for instance_id in to_remove {
                 agent.instances.remove(&instance_id);
+                self.delete_instance_invites(instance_id);
+                self.line_assemblers.write().await.remove(&instance_id);
+                self.redaction_streams.write().await.remove(&instance_id);
                 tracing::info!(
                     "Cleaned up expired suspended instance {} for agent {}",
                     instance_id,
@@ -464,7 +1750,7 @@ impl AppState {
             agent_infos.push(AgentInfo {
                 id: connected_agent.agent.id,
                 name: connected_agent.agent.name.clone(),
-                status: connected_agent.agent.status.clone(),
+                status: connected_agent.agent.status,
                 connected_at: connected_agent.agent.connected_at,
                 instance_count,
                 user_count,
@@ -580,15 +1866,53 @@ impl AppState {
     // ========================================================================
 
     /// Save PTY output to terminal history (async, non-blocking)
-    pub async fn save_pty_output(&self, instance_id: Uuid, data: &str) {
+    /// Secrets are redacted first; end-to-end encrypted output is stored as is
+    pub async fn save_pty_output(&self, agent_id: Uuid, instance_id: Uuid, data: &str) {
         if !self.runtime.config.terminal_history.enabled {
             return;
         }
 
-        let byte_size = data.len() as i32;
+        if !self.redactor.is_active() || self.is_instance_encrypted(agent_id, instance_id).await {
+            self.store_pty_output(instance_id, data.to_string());
+            return;
+        }
+
+        let Ok(output) = BASE64.decode(data) else {
+            warn!("Not storing undecodable output of instance {}", instance_id);
+            return;
+        };
+        let redacted = {
+            let mut streams = self.redaction_streams.write().await;
+            streams.entry(instance_id).or_default().feed(&self.redactor, &output)
+        };
+        if !redacted.is_empty() {
+            self.store_pty_output(instance_id, BASE64.encode(redacted));
+        }
+    }
+
+    /// Store output held back by redaction once its instance has been quiet for `idle`
+    /// (it may have ended with the start of a secret that never continued)
+    pub async fn flush_idle_redactions(&self, idle: std::time::Duration) {
+        let flushed: Vec<(Uuid, Vec<u8>)> = {
+            let mut streams = self.redaction_streams.write().await;
+            streams
+                .iter_mut()
+                .filter(|(_, stream)| stream.held_since().is_some_and(|since| since.elapsed() >= idle))
+                .map(|(instance_id, stream)| (*instance_id, stream.flush(&self.redactor)))
+                .collect()
+        };
+        for (instance_id, output) in flushed {
+            if !output.is_empty() {
+                self.store_pty_output(instance_id, BASE64.encode(output));
+            }
+        }
+    }
+
+    /// Write a chunk of (base64) output to terminal history in the background
+    fn store_pty_output(&self, instance_id: Uuid, data_owned: String) {
+        let byte_size = data_owned.len() as i32;
         let buffer_size_kb = self.runtime.config.terminal_history.default_buffer_size_kb as i32;
         let repo = self.agent_repo.clone();
-        let data_owned = data.to_string();
 
         // Spawn non-blocking task to avoid slowing down real-time output
         tokio::spawn(async move {
@@ -625,6 +1949,16 @@ impl AppState {
         });
     }
 
+    /// Delete the invites of a closed instance
+    pub fn delete_instance_invites(&self, instance_id: Uuid) {
+        let repo = self.agent_repo.clone();
+        tokio::spawn(async move {
+            if let Err(e) = repo.delete_instance_invites(instance_id).await {
+                tracing::warn!("Failed to delete invites for instance {}: {}", instance_id, e);
+            }
+        });
+    }
+
     /// Cleanup old terminal history records
     pub async fn cleanup_old_terminal_history(&self) -> Result<u64> {
         let retention_days = self.runtime.config.terminal_history.retention_days;
@@ -636,6 +1970,7 @@ impl AppState {
     // ========================================================================
 
     /// Log an audit event (async, non-blocking)
+    #[allow(clippy::too_many_arguments)]
     pub fn log_audit_event(
         &self,
         event_type: &str,
@@ -653,6 +1988,8 @@ impl AppState {
         }
 
         let repo = self.agent_repo.clone();
+        let sinks = self.audit_sinks.clone();
+        let key = self.audit_chain_key.clone();
         let event_type = event_type.to_string();
         let session_id_str = session_id.to_string();
         let user_role = user_role.to_string();
@@ -661,11 +1998,13 @@ impl AppState {
         let target_id_str = target_id.map(|id| id.to_string());
         let client_ip = client_ip.to_string();
         let details = details.map(|s| s.to_string());
+        let actor = self.session_account(session_id);
 
         // Spawn non-blocking task to avoid slowing down request handling
         tokio::spawn(async move {
-            if let Err(e) = repo
+            match repo
                 .insert_audit_log(
+                    &key,
                     &event_type,
                     &session_id_str,
                     &user_role,
@@ -675,24 +2014,26 @@ impl AppState {
                     &client_ip,
                     success,
                     details.as_deref(),
+                    actor.as_deref(),
                 )
                 .await
             {
-                tracing::warn!("Failed to write audit log: {}", e);
+                Ok(record) => sinks.send(record),
+                Err(e) => tracing::warn!("Failed to write audit log: {}", e),
             }
         });
     }
 
-    /// Get audit logs with pagination
+    /// Get audit logs matching the filter with pagination
     pub async fn get_audit_logs(
         &self,
-        event_type: Option<&str>,
+        filter: &AuditLogFilter,
         limit: u32,
         offset: u32,
     ) -> Result<(Vec<common::AuditLogEntry>, u64)> {
         let (records, total) = self
             .agent_repo
-            .get_audit_logs(event_type, limit as i64, offset as i64)
+            .get_audit_logs(filter, limit as i64, offset as i64)
             .await?;
 
         // Convert AuditLogRecord to AuditLogEntry
@@ -702,6 +2043,7 @@ impl AppState {
                 id: r.id,
                 timestamp: r.timestamp,
                 event_type: r.event_type,
+                session_id: r.session_id,
                 user_role: r.user_role,
                 agent_id: r.agent_id.and_then(|s| Uuid::parse_str(&s).ok()),
                 instance_id: r.instance_id.and_then(|s| Uuid::parse_str(&s).ok()),
@@ -709,15 +2051,610 @@ impl AppState {
                 client_ip: r.client_ip,
                 success: r.success != 0,
                 details: r.details,
+                actor: r.actor,
             })
             .collect();
 
         Ok((entries, total))
     }
 
+    /// Verify the audit log hash chain
+    pub async fn verify_audit_log(&self) -> Result<common::AuditChainReport> {
+        crate::audit_chain::verify_audit_chain(&self.agent_repo, &self.audit_chain_key).await
+    }
+
     /// Cleanup old audit logs
     pub async fn cleanup_old_audit_logs(&self) -> Result<u64> {
         let retention_days = self.runtime.config.audit_log.retention_days;
         self.agent_repo.cleanup_old_audit_logs(retention_days).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::init_database;
+    use common::Scope;
+
+    /// State backed by a fresh SQLite file
+    async fn test_state() -> AppState {
+        let mut config = ServerRuntime::default_config();
+        let path = std::env::temp_dir().join(format!("tunnel-state-{}.db", Uuid::new_v4()));
+        config.database.sqlite_path = Some(path);
+        config.database.redis_url = None;
+        config.security.token_pepper = Some(generate_token());
+        let runtime = ServerRuntime { config };
+
+        let pool = init_database(&runtime).await.unwrap();
+        AppState::new(runtime, AgentRepository::new(pool), None).await.unwrap()
+    }
+
+    /// Register an online agent that owns one instance
+    async fn add_agent_with_instance(state: &AppState) -> (Uuid, Uuid) {
+        let agent_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        state
+            .register_agent(agent_id, "test".to_string(), generate_token(), generate_token(), ShareMode::default(), None, false, tx)
+            .await;
+        let instance = Instance::new(agent_id, "/tmp");
+        let instance_id = instance.id;
+        state.add_instance(agent_id, instance).await;
+        (agent_id, instance_id)
+    }
+
+    async fn add_session(state: &AppState, role: Role, agent_id: Option<Uuid>) -> Uuid {
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        let identity = AuthIdentity {
+            role,
+            agent_id,
+            account: None,
+            credential: SessionCredential::ConfigToken,
+            scopes: role.default_scopes(),
+        };
+        state.register_user(session_id, Uuid::new_v4(), &identity, "127.0.0.1", tx).await;
+        session_id
+    }
+
+    /// Wait for the denial of an instance to reach the audit log (it is written in the background)
+    async fn denied_event(state: &AppState, instance_id: Uuid) -> common::AuditLogEntry {
+        for _ in 0..100 {
+            let filter = AuditLogFilter {
+                event_type: Some("instance_access_denied".to_string()),
+                instance_id: Some(instance_id.to_string()),
+                ..Default::default()
+            };
+            let (logs, _) = state.get_audit_logs(&filter, 100, 0).await.unwrap();
+            if let Some(entry) = logs.into_iter().find(|e| e.instance_id == Some(instance_id)) {
+                return entry;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("no instance_access_denied event for instance {}", instance_id);
+    }
+
+    #[tokio::test]
+    async fn test_authorize_own_instance() {
+        let state = test_state().await;
+        let (agent_id, instance_id) = add_agent_with_instance(&state).await;
+        let session_id = add_session(&state, Role::Admin, Some(agent_id)).await;
+
+        let owner = state.authorize_instance(session_id, instance_id, "attach", "10.0.0.1").await.unwrap();
+        assert_eq!(owner, agent_id);
+    }
+
+    #[tokio::test]
+    async fn test_authorize_other_agents_instance() {
+        let state = test_state().await;
+        let (agent_a, _) = add_agent_with_instance(&state).await;
+        let (agent_b, instance_b) = add_agent_with_instance(&state).await;
+        let session_id = add_session(&state, Role::Admin, Some(agent_a)).await;
+
+        let err = state.authorize_instance(session_id, instance_b, "pty_input", "10.0.0.1").await.unwrap_err();
+        assert!(err.to_string().contains("Permission denied"));
+
+        let event = denied_event(&state, instance_b).await;
+        assert!(!event.success);
+        assert_eq!(event.agent_id, Some(agent_a));
+        assert_eq!(event.target_id, Some(agent_b));
+        assert_eq!(event.client_ip, "10.0.0.1");
+        assert_eq!(
+            event.details.as_deref(),
+            Some("action: pty_input, reason: instance belongs to another agent")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorize_without_selected_agent() {
+        let state = test_state().await;
+        let (agent_id, instance_id) = add_agent_with_instance(&state).await;
+        // A SuperAdmin must select a working agent before touching its instances
+        let session_id = add_session(&state, Role::SuperAdmin, None).await;
+
+        assert!(state.authorize_instance(session_id, instance_id, "attach", "10.0.0.2").await.is_err());
+        let event = denied_event(&state, instance_id).await;
+        assert_eq!(event.agent_id, None);
+        assert_eq!(event.target_id, Some(agent_id));
+        assert_eq!(event.details.as_deref(), Some("action: attach, reason: no agent selected for this session"));
+
+        state.set_working_agent(session_id, agent_id).await;
+        assert_eq!(state.authorize_instance(session_id, instance_id, "attach", "10.0.0.2").await.unwrap(), agent_id);
+    }
+
+    #[tokio::test]
+    async fn test_instance_invite() {
+        let state = test_state().await;
+        let (agent_id, instance_id) = add_agent_with_instance(&state).await;
+        let other = Instance::new(agent_id, "/srv");
+        let other_id = other.id;
+        state.add_instance(agent_id, other).await;
+
+        let (token, _) = state.create_invite(agent_id, instance_id, 3600, 2, true, "Admin").await.unwrap();
+        let identity = state.authenticate(&token).await.unwrap();
+        assert_eq!(identity.role, Role::Viewer);
+        assert_eq!(identity.agent_id, Some(agent_id));
+        assert_eq!(identity.credential.instance_scope(), Some(instance_id));
+        assert!(state.check_session_credential(&identity.credential).await.unwrap().is_some());
+
+        // The session only sees and reaches the invited instance
+        let session_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        state.register_user(session_id, Uuid::new_v4(), &identity, "127.0.0.1", tx).await;
+        let visible: Vec<Uuid> = state.get_session_instances(session_id, agent_id).await.iter().map(|i| i.id).collect();
+        assert_eq!(visible, vec![instance_id]);
+        assert!(state.authorize_instance(session_id, instance_id, "attach", "10.0.0.3").await.is_ok());
+        assert!(state.authorize_instance(session_id, other_id, "attach", "10.0.0.3").await.is_err());
+        let event = denied_event(&state, other_id).await;
+        assert_eq!(
+            event.details.as_deref(),
+            Some("action: attach, reason: session is limited to another instance")
+        );
+
+        // Second use is the last one
+        assert!(state.authenticate(&token).await.is_some());
+        assert!(state.authenticate(&token).await.is_none());
+
+        // Revoking ends sessions at their next ticket renewal
+        state.agent_repo.delete_instance_invites(instance_id).await.unwrap();
+        assert_eq!(
+            state.check_session_credential(&identity.credential).await,
+            Err("invite has been revoked")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_instance_invite_read_write() {
+        let state = test_state().await;
+        let (agent_id, instance_id) = add_agent_with_instance(&state).await;
+
+        // Unlimited uses
+        let (token, _) = state.create_invite(agent_id, instance_id, 3600, 0, false, "Admin").await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(state.authenticate(&token).await.unwrap().role, Role::User);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorize_unknown_instance() {
+        let state = test_state().await;
+        let (agent_id, _) = add_agent_with_instance(&state).await;
+        let session_id = add_session(&state, Role::User, Some(agent_id)).await;
+        let unknown = Uuid::new_v4();
+
+        assert!(state.authorize_instance(session_id, unknown, "resize", "10.0.0.3").await.is_err());
+        let event = denied_event(&state, unknown).await;
+        assert_eq!(event.agent_id, Some(agent_id));
+        assert_eq!(event.target_id, None);
+        assert_eq!(event.details.as_deref(), Some("action: resize, reason: instance not found"));
+    }
+
+    #[tokio::test]
+    async fn test_account_totp_secrets() {
+        let state = test_state().await;
+        let own = crate::totp::generate_secret();
+        for name in ["alice", "bob"] {
+            state.agent_repo.create_super_admin(name, &state.token_hasher.hash(name), None).await.unwrap();
+        }
+        assert!(state.agent_repo.set_super_admin_totp("alice", Some(&own)).await.unwrap());
+        assert!(!state.agent_repo.set_super_admin_totp("nobody", Some(&own)).await.unwrap());
+
+        // Without a shared secret only the account with its own secret needs a code
+        let alice = state.super_admin_totp(Some("alice")).await.unwrap().unwrap();
+        assert_eq!(alice.generate(1_700_000_000), build_totp(&own).unwrap().generate(1_700_000_000));
+        assert!(state.super_admin_totp(Some("bob")).await.unwrap().is_none());
+        assert!(state.super_admin_totp(None).await.unwrap().is_none());
+
+        // The shared secret covers accounts without one, never replacing an account's own
+        let shared = crate::totp::generate_secret();
+        state.agent_repo.set_setting(TOTP_SECRET_SETTING, &shared).await.unwrap();
+        let bob = state.super_admin_totp(Some("bob")).await.unwrap().unwrap();
+        assert_eq!(bob.generate(1_700_000_000), build_totp(&shared).unwrap().generate(1_700_000_000));
+        let alice = state.super_admin_totp(Some("alice")).await.unwrap().unwrap();
+        assert_ne!(alice.generate(1_700_000_000), bob.generate(1_700_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_account_cannot_resume_or_renew() {
+        let state = test_state().await;
+        let token = generate_token();
+        state.agent_repo.create_super_admin("alice", &state.token_hasher.hash(&token), None).await.unwrap();
+        let identity = state.authenticate(&token).await.unwrap();
+
+        // A suspended session of a disabled account cannot be resumed
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        state.register_user(session_id, connection_id, &identity, "127.0.0.1", tx).await;
+        let (ticket, _) = state.issue_session_ticket(session_id).await.unwrap();
+        state.unregister_user(session_id, connection_id).await;
+        state.agent_repo.set_super_admin_disabled("alice", true).await.unwrap();
+        assert_eq!(state.resume_user(&ticket).await.err(), Some("SuperAdmin account has been disabled"));
+
+        // A live session is ended at its next renewal
+        state.agent_repo.set_super_admin_disabled("alice", false).await.unwrap();
+        let (tx, _rx) = mpsc::channel(8);
+        state.register_user(session_id, connection_id, &identity, "127.0.0.1", tx).await;
+        assert!(state.issue_session_ticket(session_id).await.is_some());
+        state.agent_repo.set_super_admin_disabled("alice", true).await.unwrap();
+        assert!(state.issue_session_ticket(session_id).await.is_none());
+        assert!(!state.has_user_session(session_id, connection_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_credential_scopes() {
+        let state = test_state().await;
+
+        // An account limited to reading audit logs
+        let token = generate_token();
+        state
+            .agent_repo
+            .create_super_admin("auditor", &state.token_hasher.hash(&token), Some("audit.read"))
+            .await
+            .unwrap();
+        let identity = state.authenticate(&token).await.unwrap();
+        assert_eq!(identity.role, Role::SuperAdmin);
+        assert!(identity.scopes.contains(Scope::AuditRead));
+        assert!(!identity.scopes.contains(Scope::AgentDelete));
+
+        // A narrowed agent token, whose scopes survive rotation but never exceed its role
+        let agent_id = Uuid::new_v4();
+        let admin_token = generate_token();
+        let share_token = generate_token();
+        state
+            .agent_repo
+            .upsert_agent(
+                agent_id,
+                "test",
+                &state.token_hasher.hash(&admin_token),
+                &state.token_hasher.hash(&share_token),
+                ShareMode::default(),
+                None,
+            )
+            .await
+            .unwrap();
+        state.agent_repo.set_token_scopes(agent_id, TokenKind::Admin, Some("tags.read,agent.delete")).await.unwrap();
+        let identity = state.authenticate(&admin_token).await.unwrap();
+        assert_eq!(identity.scopes.to_string(), "tags.read");
+
+        let rotated = generate_token();
+        state
+            .agent_repo
+            .rotate_token(agent_id, TokenKind::Admin, &state.token_hasher.hash(&rotated), None)
+            .await
+            .unwrap();
+        assert_eq!(state.authenticate(&rotated).await.unwrap().scopes.to_string(), "tags.read");
+        assert_eq!(state.authenticate(&share_token).await.unwrap().scopes, Role::User.default_scopes());
+
+        // Unreadable stored scopes grant nothing
+        assert!(credential_scopes(Role::Admin, "tags.read,bogus").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_keeps_invite_sessions() {
+        let state = test_state().await;
+        let (agent_id, share_token) = (Uuid::new_v4(), generate_token());
+        let (tx, _rx) = mpsc::channel(8);
+        state
+            .register_agent(agent_id, "test".to_string(), generate_token(), share_token.clone(), ShareMode::default(), None, false, tx)
+            .await;
+        let session = |credential| AuthIdentity {
+            role: Role::User,
+            agent_id: Some(agent_id),
+            account: None,
+            credential,
+            scopes: Role::User.default_scopes(),
+        };
+
+        // One session logged in with the share token, one with an invite to an instance of the agent
+        let (share_session, invite_session) = (Uuid::new_v4(), Uuid::new_v4());
+        let (share_connection, invite_connection) = (Uuid::new_v4(), Uuid::new_v4());
+        let (tx, _share_rx) = mpsc::channel(8);
+        let identity = session(SessionCredential::AgentToken(state.token_hasher.hashes(&share_token)));
+        state.register_user(share_session, share_connection, &identity, "127.0.0.1", tx).await;
+        let (tx, _invite_rx) = mpsc::channel(8);
+        let identity = session(SessionCredential::Invite {
+            hash: state.token_hasher.hash(&generate_token()),
+            instance_id: Uuid::new_v4(),
+        });
+        state.register_user(invite_session, invite_connection, &identity, "127.0.0.1", tx).await;
+
+        let rotation = PendingRotation {
+            token: generate_token(),
+            expires_at: None,
+            session_id: Uuid::new_v4(),
+            role: Role::SuperAdmin,
+            client_ip: "127.0.0.1".to_string(),
+        };
+        state.apply_token_rotation(agent_id, TokenKind::Share, &rotation).await.unwrap();
+        assert!(!state.has_user_session(share_session, share_connection).await);
+        assert!(state.has_user_session(invite_session, invite_connection).await);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_lifetime_is_clamped() {
+        let state = test_state().await;
+        let agent_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        state
+            .register_agent(agent_id, "test".to_string(), generate_token(), generate_token(), ShareMode::default(), None, false, tx)
+            .await;
+        state
+            .start_token_rotation(agent_id, TokenKind::Share, Some(u64::MAX), Uuid::new_v4(), Role::SuperAdmin, "127.0.0.1")
+            .await
+            .unwrap();
+        let expires_at = state.pending_rotations.read().await[&(agent_id, TokenKind::Share)].expires_at.unwrap();
+        let max = Utc::now() + chrono::Duration::seconds(MAX_TOKEN_TTL_SECS as i64);
+        assert!(expires_at > Utc::now() && expires_at <= max);
+    }
+
+    #[tokio::test]
+    async fn test_ticket_capped_by_token_expiry() {
+        let state = test_state().await;
+        let agent_id = Uuid::new_v4();
+        let token = generate_token();
+        let hash = state.token_hasher.hash(&token);
+        let repo = &state.agent_repo;
+        repo.upsert_agent(agent_id, "test", &hash, &state.token_hasher.hash(&generate_token()), ShareMode::default(), None)
+            .await
+            .unwrap();
+        let expires_at = Utc::now() + chrono::Duration::seconds(120);
+        repo.ensure_token(agent_id, TokenKind::Admin, &hash, Some(expires_at)).await.unwrap();
+
+        let identity = state.authenticate(&token).await.unwrap();
+        let session_id = Uuid::new_v4();
+        let connection_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(8);
+        state.register_user(session_id, connection_id, &identity, "127.0.0.1", tx).await;
+
+        // The ticket ends with the token, well before session_ticket_ttl_secs
+        let (ticket, expires_in_secs) = state.issue_session_ticket(session_id).await.unwrap();
+        assert!(expires_in_secs <= 120);
+        let claims = state.session_tickets.verify(&ticket, Utc::now().timestamp()).unwrap();
+        assert!(claims.expires_at <= expires_at.timestamp());
+
+        // Once the token is no longer valid the session is not renewed
+        repo.revoke_tokens(agent_id, TokenKind::Admin).await.unwrap();
+        assert!(state.issue_session_ticket(session_id).await.is_none());
+        assert!(!state.has_user_session(session_id, connection_id).await);
+    }
+
+    #[tokio::test]
+    async fn test_approval_pending_state() {
+        let state = test_state().await;
+        let agent_id = Uuid::new_v4();
+        let (tx, mut agent_rx) = mpsc::channel(8);
+        state
+            .register_agent(agent_id, "test".to_string(), generate_token(), generate_token(), ShareMode::default(), None, true, tx)
+            .await;
+        let instance = Instance::new(agent_id, "/tmp");
+        let instance_id = instance.id;
+        state.add_instance(agent_id, instance).await;
+        let session_id = Uuid::new_v4();
+        let identity = AuthIdentity {
+            role: Role::User,
+            agent_id: Some(agent_id),
+            account: None,
+            credential: SessionCredential::ConfigToken,
+            scopes: Role::User.default_scopes(),
+        };
+        let (user_tx, mut user_rx) = mpsc::channel(8);
+        state.register_user(session_id, Uuid::new_v4(), &identity, "127.0.0.1", user_tx).await;
+
+        let pending = || PendingApproval {
+            agent_id,
+            action: ApprovalAction::Attach,
+            instance_id,
+            cwd: None,
+            session_id,
+            role: Role::User,
+            client_ip: "10.0.0.1".to_string(),
+        };
+        assert!(state.needs_approval(agent_id, session_id, ApprovalAction::Attach).await);
+        let request_id = state.request_approval(pending()).await.unwrap();
+        match agent_rx.recv().await.unwrap() {
+            ServerToAgentMessage::ApprovalRequest { request_id: id, requester, .. } => {
+                assert_eq!(id, request_id);
+                assert_eq!(requester, "User");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // One waiting request per session; only the asked agent can answer it
+        assert!(state.request_approval(pending()).await.is_err());
+        assert!(state.take_pending_approval(Uuid::new_v4(), request_id).await.is_none());
+        assert!(state.take_pending_approval(agent_id, request_id).await.is_some());
+
+        // Once approved, the session attaches without asking again; creates always ask
+        state.mark_session_approved(agent_id, session_id).await;
+        assert!(!state.needs_approval(agent_id, session_id, ApprovalAction::Attach).await);
+        assert!(state.needs_approval(agent_id, session_id, ApprovalAction::CreateInstance).await);
+
+        // Requests still waiting when the agent leaves are denied
+        state.request_approval(pending()).await.unwrap();
+        state.unregister_agent(agent_id).await;
+        assert!(state.pending_approvals.read().await.is_empty());
+        match user_rx.recv().await.unwrap() {
+            ServerToUserMessage::Error { code, .. } => assert_eq!(code, Some(ErrorCode::ApprovalDenied)),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_chain() {
+        let mut config = ServerRuntime::default_config();
+        let path = std::env::temp_dir().join(format!("tunnel-state-{}.db", Uuid::new_v4()));
+        config.database.sqlite_path = Some(path);
+        let pool = init_database(&ServerRuntime { config }).await.unwrap();
+        let repo = AgentRepository::new(pool.clone());
+        let key = AuditChainKey::new(b"test-key");
+
+        // A row written before the chain existed is skipped
+        sqlx::query(
+            "INSERT INTO audit_logs (timestamp, event_type, session_id, user_role, client_ip, success) \
+             VALUES ('2020-01-01T00:00:00+00:00', 'auth_success', 's', 'Admin', '127.0.0.1', 1)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for i in 0..3 {
+            let details = format!("n: {}", i);
+            repo.insert_audit_log(&key, "create_instance", "s", "Admin", None, None, None, "127.0.0.1", true, Some(&details), None)
+                .await
+                .unwrap();
+        }
+
+        let report = crate::audit_chain::verify_audit_chain(&repo, &key).await.unwrap();
+        assert_eq!((report.unchained, report.checked, report.last_id), (1, 3, Some(4)));
+        assert!(report.broken.is_none());
+
+        sqlx::query("UPDATE audit_logs SET success = 0 WHERE id = 3").execute(&pool).await.unwrap();
+        let report = crate::audit_chain::verify_audit_chain(&repo, &key).await.unwrap();
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.broken.unwrap().id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_filters() {
+        let state = test_state().await;
+        let repo = &state.agent_repo;
+        let (agent, instance) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        repo.insert_audit_log(&state.audit_chain_key, "auth_failure", "s1", "Admin", None, None, None, "10.0.0.1", false, Some("token: bad_100%"), None)
+            .await
+            .unwrap();
+        repo.insert_audit_log(&state.audit_chain_key, "create_instance", "s2", "Admin", Some(&agent), Some(&instance), None, "10.0.0.2", true, Some("cwd: /srv"), None)
+            .await
+            .unwrap();
+        repo.insert_audit_log(&state.audit_chain_key, "attach", "s2", "Admin", Some(&agent), Some(&instance), None, "10.0.0.2", true, None, None)
+            .await
+            .unwrap();
+
+        let count = |filter: AuditLogFilter| {
+            let state = &state;
+            async move { state.get_audit_logs(&filter, 100, 0).await.unwrap().1 }
+        };
+        assert_eq!(count(AuditLogFilter::default()).await, 3);
+        assert_eq!(count(AuditLogFilter { success: Some(false), ..Default::default() }).await, 1);
+        assert_eq!(count(AuditLogFilter { agent_id: Some(agent.clone()), ..Default::default() }).await, 2);
+        assert_eq!(count(AuditLogFilter { client_ip: Some("10.0.0.2".into()), ..Default::default() }).await, 2);
+        assert_eq!(count(AuditLogFilter { session_id: Some("s1".into()), ..Default::default() }).await, 1);
+        let attach = AuditLogFilter {
+            event_type: Some("attach".into()),
+            instance_id: Some(instance.clone()),
+            ..Default::default()
+        };
+        assert_eq!(count(attach).await, 1);
+
+        // LIKE wildcards in the search text match literally
+        assert_eq!(count(AuditLogFilter { search: Some("100%".into()), ..Default::default() }).await, 1);
+        assert_eq!(count(AuditLogFilter { search: Some("d_".into()), ..Default::default() }).await, 1);
+        assert_eq!(count(AuditLogFilter { search: Some("CWD".into()), ..Default::default() }).await, 1);
+
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        assert_eq!(count(AuditLogFilter { since: Some(now - hour), until: Some(now + hour), ..Default::default() }).await, 3);
+        assert_eq!(count(AuditLogFilter { since: Some(now + hour), ..Default::default() }).await, 0);
+
+        let (logs, _) = state.get_audit_logs(&AuditLogFilter::default(), 100, 0).await.unwrap();
+        assert_eq!(logs[0].event_type, "attach");
+        assert_eq!(logs[0].session_id, "s2");
+    }
+
+    #[tokio::test]
+    async fn test_audit_jsonl_sink() {
+        let mut config = ServerRuntime::default_config();
+        let dir = std::env::temp_dir();
+        config.database.sqlite_path = Some(dir.join(format!("tunnel-state-{}.db", Uuid::new_v4())));
+        config.database.redis_url = None;
+        config.security.token_pepper = Some(generate_token());
+        let path = dir.join(format!("tunnel-audit-{}.jsonl", Uuid::new_v4()));
+        config.audit_log.jsonl_path = Some(path.clone());
+        let runtime = ServerRuntime { config };
+        let pool = init_database(&runtime).await.unwrap();
+        let state = AppState::new(runtime, AgentRepository::new(pool), None).await.unwrap();
+
+        state.log_audit_event("auth_failure", Uuid::new_v4(), "Admin", None, None, None, "10.0.0.1", false, Some("bad token"));
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = std::fs::read_to_string(&path).unwrap();
+            if !content.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let line: serde_json::Value = serde_json::from_str(content.trim_end()).unwrap();
+        assert_eq!(line["event_type"], "auth_failure");
+        assert_eq!(line["success"], false);
+        assert_eq!(line["entry_hash"].as_str().map(str::len), Some(64));
+    }
+
+    #[tokio::test]
+    async fn test_session_limit_list_and_kick() {
+        let mut config = ServerRuntime::default_config();
+        config.database.sqlite_path = Some(std::env::temp_dir().join(format!("tunnel-state-{}.db", Uuid::new_v4())));
+        config.database.redis_url = None;
+        config.security.token_pepper = Some(generate_token());
+        config.security.max_sessions_per_token = 1;
+        let runtime = ServerRuntime { config };
+        let pool = init_database(&runtime).await.unwrap();
+        let state = AppState::new(runtime, AgentRepository::new(pool), None).await.unwrap();
+
+        let identity = AuthIdentity {
+            role: Role::SuperAdmin,
+            agent_id: None,
+            account: None,
+            credential: SessionCredential::ConfigToken,
+            scopes: Role::SuperAdmin.default_scopes(),
+        };
+        let (session_id, connection_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (tx, mut rx) = mpsc::channel(8);
+        assert!(state.register_user(session_id, connection_id, &identity, "10.0.0.1", tx).await);
+
+        // A second login with the same token is refused, another token is not
+        let (tx, _rx) = mpsc::channel(8);
+        assert!(!state.register_user(Uuid::new_v4(), Uuid::new_v4(), &identity, "10.0.0.2", tx).await);
+        let admin = AuthIdentity {
+            role: Role::Admin,
+            agent_id: Some(Uuid::new_v4()),
+            account: None,
+            credential: SessionCredential::AgentToken(state.token_hasher.hashes(&generate_token())),
+            scopes: Role::Admin.default_scopes(),
+        };
+        let (tx, _rx) = mpsc::channel(8);
+        assert!(state.register_user(Uuid::new_v4(), Uuid::new_v4(), &admin, "10.0.0.3", tx).await);
+
+        let sessions = state.list_user_sessions().await;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].session_id, session_id);
+        assert_eq!(sessions[0].client_ip, "10.0.0.1");
+        assert_eq!(sessions[0].credential, "config_token");
+
+        // Kicking closes the session and frees its slot
+        assert_eq!(state.kick_user_session(session_id).await, Some(Role::SuperAdmin));
+        assert!(!state.has_user_session(session_id, connection_id).await);
+        assert!(matches!(rx.recv().await, Some(ServerToUserMessage::Error { .. })));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(state.kick_user_session(session_id).await, None);
+        let (tx, _rx) = mpsc::channel(8);
+        assert!(state.register_user(Uuid::new_v4(), Uuid::new_v4(), &identity, "10.0.0.1", tx).await);
+    }
+}
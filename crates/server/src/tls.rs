@@ -0,0 +1,323 @@
+//! Native TLS termination with certificate hot reload
+//!
+//! The certificate and key files are polled for changes, so a renewed
+//! certificate (certbot, acme.sh, ...) is picked up without a restart.
+//! Connections that are already open keep the certificate they started with.
+//!
+//! When a client CA is configured, clients may present a certificate. It is
+//! optional at the TLS level (browsers do not have one), agents are checked
+//! against `[agent_mtls]` when they register.
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower_http::add_extension::AddExtension;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use common::{AgentMtlsConfig, TlsConfig};
+
+/// Modification times of the certificate, key and client CA files
+type FileStamp = [Option<SystemTime>; 3];
+
+/// Load the certificate and key
+pub async fn load(config: &TlsConfig) -> Result<RustlsConfig> {
+    // Use ring, like the HTTP client, so only one crypto backend is compiled in
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    Ok(RustlsConfig::from_config(build_server_config(config)?))
+}
+
+/// Reload the certificate whenever one of the files changes
+pub fn spawn_reloader(rustls: RustlsConfig, config: TlsConfig) {
+    let interval = Duration::from_secs(config.reload_interval_secs.max(1));
+
+    tokio::spawn(async move {
+        let mut loaded = stamp(&config);
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let current = stamp(&config);
+            if current == loaded {
+                continue;
+            }
+
+            // A failed reload (e.g. a half-written file) keeps the old certificate
+            // and is retried on the next check
+            match build_server_config(&config) {
+                Ok(server_config) => {
+                    rustls.reload_from_config(server_config);
+                    info!("Reloaded TLS certificate from {}", config.cert_path.display());
+                    loaded = current;
+                }
+                Err(e) => warn!("Failed to reload TLS certificate, keeping the current one: {}", e),
+            }
+        }
+    });
+}
+
+fn build_server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Failed to load TLS certificate {}: {}", config.cert_path.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|e| anyhow!("Failed to load TLS key {}: {}", config.key_path.display(), e))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path)
+                .map_err(|e| anyhow!("Failed to load client CA {}: {}", ca_path.display(), e))?
+            {
+                let cert = cert.map_err(|e| anyhow!("Failed to load client CA {}: {}", ca_path.display(), e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| anyhow!("Invalid client CA certificate in {}: {}", ca_path.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| anyhow!("Invalid client CA {}: {}", ca_path.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("Invalid TLS certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+fn stamp(config: &TlsConfig) -> FileStamp {
+    [
+        modified(&config.cert_path),
+        modified(&config.key_path),
+        config.client_ca_path.as_deref().and_then(modified),
+    ]
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    // metadata() follows symlinks, so a renewal that re-points `live/` links is noticed
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Verified client certificate of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// SHA-256 fingerprint of the DER certificate (lowercase hex)
+    pub fingerprint: String,
+    /// Subject common name
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_der(der: &[u8]) -> Self {
+        let common_name = x509_parser::parse_x509_certificate(der).ok().and_then(|(_, cert)| {
+            cert.subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_string)
+        });
+        Self {
+            fingerprint: hex::encode(Sha256::digest(der)),
+            common_name,
+        }
+    }
+}
+
+/// Request extension carrying the client certificate of the connection, if any
+#[derive(Debug, Clone)]
+pub struct PeerCertificate(pub Option<ClientCertificate>);
+
+/// TLS acceptor that exposes the verified client certificate to handlers
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = AddExtension<S, PeerCertificate>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = inner.accept(stream, service).await?;
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|cert| ClientCertificate::from_der(cert));
+            Ok((stream, AddExtension::new(service, PeerCertificate(certificate))))
+        })
+    }
+}
+
+/// Which client certificates may register as which agent
+#[derive(Debug, Default)]
+pub struct AgentCertPolicy {
+    required: bool,
+    /// (agent_id, normalized fingerprint, common name)
+    identities: Vec<(Uuid, Option<String>, Option<String>)>,
+}
+
+impl AgentCertPolicy {
+    /// Build the policy; client certificates can only be verified with native TLS and a client CA
+    pub fn from_config(config: &AgentMtlsConfig, tls: Option<&TlsConfig>) -> Result<Self> {
+        let verifies_clients = tls.is_some_and(|t| t.client_ca_path.is_some());
+        if (config.required || !config.identities.is_empty()) && !verifies_clients {
+            return Err(anyhow!("[agent_mtls] requires [server.tls] with client_ca_path"));
+        }
+
+        let identities = config
+            .identities
+            .iter()
+            .map(|identity| {
+                if identity.fingerprint.is_none() && identity.common_name.is_none() {
+                    return Err(anyhow!(
+                        "agent_mtls identity for {} needs a fingerprint or common_name",
+                        identity.agent_id
+                    ));
+                }
+                let fingerprint = identity.fingerprint.as_deref().map(normalize_fingerprint);
+                Ok((identity.agent_id, fingerprint, identity.common_name.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            required: config.required,
+            identities,
+        })
+    }
+
+    /// Check the client certificate of a registering agent
+    /// Returns true if the certificate proves the agent's identity, false if
+    /// no certificate was presented and none is required
+    pub fn check(&self, agent_id: Uuid, certificate: Option<&ClientCertificate>) -> Result<bool, String> {
+        let Some(cert) = certificate else {
+            return if self.required {
+                Err("a client certificate is required for agent connections".to_string())
+            } else {
+                Ok(false)
+            };
+        };
+
+        let mut matched = self
+            .identities
+            .iter()
+            .filter(|(_, fingerprint, common_name)| {
+                fingerprint.as_deref() == Some(cert.fingerprint.as_str())
+                    || (common_name.is_some() && *common_name == cert.common_name)
+            })
+            .map(|(id, _, _)| *id)
+            .peekable();
+
+        let allowed = if matched.peek().is_some() {
+            matched.any(|id| id == agent_id)
+        } else {
+            // Unlisted certificates are accepted when their CN is the agent ID
+            cert.common_name.as_deref() == Some(agent_id.to_string().as_str())
+        };
+
+        if allowed {
+            Ok(true)
+        } else {
+            Err(format!(
+                "client certificate (CN {}, SHA-256 {}) is not allowed to register as agent {}",
+                cert.common_name.as_deref().unwrap_or("-"),
+                cert.fingerprint,
+                agent_id
+            ))
+        }
+    }
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::AgentCertIdentity;
+
+    fn cert(fingerprint: &str, common_name: Option<&str>) -> ClientCertificate {
+        ClientCertificate {
+            fingerprint: fingerprint.to_string(),
+            common_name: common_name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_agent_cert_policy() {
+        let agent_a = Uuid::new_v4();
+        let agent_b = Uuid::new_v4();
+        let config = AgentMtlsConfig {
+            required: true,
+            identities: vec![
+                AgentCertIdentity {
+                    agent_id: agent_a,
+                    fingerprint: Some("AB:CD:EF".to_string()),
+                    common_name: None,
+                },
+                AgentCertIdentity {
+                    agent_id: agent_b,
+                    fingerprint: None,
+                    common_name: Some("build-box".to_string()),
+                },
+            ],
+        };
+        let tls = TlsConfig {
+            cert_path: "cert.pem".into(),
+            key_path: "key.pem".into(),
+            reload_interval_secs: 60,
+            client_ca_path: Some("ca.pem".into()),
+        };
+        let policy = AgentCertPolicy::from_config(&config, Some(&tls)).unwrap();
+
+        assert_eq!(policy.check(agent_a, Some(&cert("abcdef", None))), Ok(true));
+        assert_eq!(policy.check(agent_b, Some(&cert("00", Some("build-box")))), Ok(true));
+        // A mapped certificate cannot register as another agent
+        assert!(policy.check(agent_b, Some(&cert("abcdef", None))).is_err());
+        // Unlisted certificates need the agent ID as CN
+        let agent_c = Uuid::new_v4();
+        assert_eq!(policy.check(agent_c, Some(&cert("11", Some(&agent_c.to_string())))), Ok(true));
+        assert!(policy.check(agent_c, Some(&cert("11", Some("other")))).is_err());
+        assert!(policy.check(agent_a, None).is_err());
+
+        // Client certificates cannot be verified without a client CA
+        assert!(AgentCertPolicy::from_config(&config, None).is_err());
+        assert_eq!(AgentCertPolicy::default().check(agent_a, None), Ok(false));
+    }
+}
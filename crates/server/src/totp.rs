@@ -0,0 +1,188 @@
+//! TOTP second factor for SuperAdmin logins (RFC 6238, SHA1 / 6 digits / 30s)
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Name of the `server_settings` row holding the SuperAdmin TOTP secret
+pub const TOTP_SECRET_SETTING: &str = "super_admin_totp_secret";
+
+/// Issuer shown in authenticator apps
+const ISSUER: &str = "Claude Web Tunnel";
+
+/// Time step in seconds
+const STEP: u64 = 30;
+
+/// Failed codes allowed within `FAILURE_WINDOW_SECS` before TOTP logins are locked
+const MAX_FAILURES: usize = 5;
+
+/// Window (and lockout duration) for counting failed codes
+const FAILURE_WINDOW_SECS: u64 = 300;
+
+/// Replay and brute-force protection for one SuperAdmin
+#[derive(Debug, Default)]
+pub struct TotpGuard {
+    /// Last time step that was accepted
+    last_step: u64,
+    /// Unix timestamps of recent failed codes
+    failures: Vec<u64>,
+}
+
+impl TotpGuard {
+    /// Whether too many codes failed recently
+    pub fn is_locked(&mut self, now: u64) -> bool {
+        self.failures.retain(|t| now.saturating_sub(*t) < FAILURE_WINDOW_SECS);
+        self.failures.len() >= MAX_FAILURES
+    }
+
+    /// Check a code and record the outcome
+    pub fn verify(&mut self, totp: &TOTP, code: &str, now: u64) -> bool {
+        match verify_code(totp, code, now, self.last_step) {
+            Some(step) => {
+                self.last_step = step;
+                self.failures.clear();
+                true
+            }
+            None => {
+                self.failures.push(now);
+                false
+            }
+        }
+    }
+}
+
+/// Guards keyed by SuperAdmin account (None = the config token)
+/// One admin's used codes and failures never affect another admin's logins
+#[derive(Debug, Default)]
+pub struct TotpGuards(HashMap<Option<String>, TotpGuard>);
+
+impl TotpGuards {
+    /// Guard of an account, created on first use
+    pub fn get(&mut self, account: Option<&str>) -> &mut TotpGuard {
+        self.0.entry(account.map(str::to_string)).or_default()
+    }
+}
+
+/// Build a TOTP verifier from a base32 secret (at least 128 bits)
+pub fn build_totp(secret: &str) -> Result<TOTP> {
+    let normalized: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect::<String>()
+        .to_uppercase();
+    let bytes = Secret::Encoded(normalized)
+        .to_bytes()
+        .map_err(|_| anyhow!("TOTP secret is not valid base32"))?;
+    TOTP::new(Algorithm::SHA1, 6, 1, STEP, bytes).map_err(|e| anyhow!("Invalid TOTP secret: {}", e))
+}
+
+/// Generate a new random 160-bit secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    match Secret::Raw(bytes.to_vec()).to_encoded() {
+        Secret::Encoded(s) => s,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+/// otpauth:// URI for enrolling the secret in an authenticator app
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period={STEP}",
+        issuer = ISSUER.replace(' ', "%20"),
+        account = account.replace(' ', "%20"),
+        secret = secret,
+    )
+}
+
+/// Check a code at `now` (unix seconds), allowing one step of clock skew
+///
+/// Returns the matched time step. Steps at or before `last_step` are refused
+/// so an observed code cannot be replayed.
+pub fn verify_code(totp: &TOTP, code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP;
+    [current.saturating_sub(1), current, current + 1]
+        .into_iter()
+        .filter(|step| *step > last_step)
+        .find(|step| totp.generate(step * STEP) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_secret_roundtrip() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert!(build_totp(&secret).is_ok());
+        assert!(build_totp(&secret.to_lowercase()).is_ok());
+        assert!(build_totp("not base32!").is_err());
+        // Shorter than 128 bits
+        assert!(build_totp("JBSWY3DPEHPK3PXP").is_err());
+    }
+
+    #[test]
+    fn test_verify_code() {
+        let totp = build_totp(&generate_secret()).unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        let step = now / STEP;
+
+        assert_eq!(verify_code(&totp, &code, now, 0), Some(step));
+        // One step of skew either way
+        assert_eq!(verify_code(&totp, &code, now + STEP, 0), Some(step));
+        assert_eq!(verify_code(&totp, &code, now + 3 * STEP, 0), None);
+        // Replay of an already used step
+        assert_eq!(verify_code(&totp, &code, now, step), None);
+        assert_eq!(verify_code(&totp, "12345", now, 0), None);
+    }
+
+    #[test]
+    fn test_guard_locks_after_failures() {
+        let totp = build_totp(&generate_secret()).unwrap();
+        let now = 1_700_000_000;
+        let mut guard = TotpGuard::default();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(!guard.is_locked(now));
+            assert!(!guard.verify(&totp, "000000x", now));
+        }
+        assert!(guard.is_locked(now));
+        assert!(!guard.is_locked(now + FAILURE_WINDOW_SECS));
+
+        let code = totp.generate(now + FAILURE_WINDOW_SECS);
+        assert!(guard.verify(&totp, &code, now + FAILURE_WINDOW_SECS));
+        assert!(!guard.verify(&totp, &code, now + FAILURE_WINDOW_SECS));
+    }
+
+    #[test]
+    fn test_guards_are_per_account() {
+        let totp = build_totp(&generate_secret()).unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        let mut guards = TotpGuards::default();
+
+        // Two admins sharing a step are not replays of each other
+        assert!(guards.get(Some("alice")).verify(&totp, &code, now));
+        assert!(guards.get(Some("bob")).verify(&totp, &code, now));
+        assert!(guards.get(None).verify(&totp, &code, now));
+        assert!(!guards.get(Some("alice")).verify(&totp, &code, now));
+
+        // Failures lock only the account they were made for
+        for _ in 0..MAX_FAILURES {
+            guards.get(Some("mallory")).verify(&totp, "000000x", now);
+        }
+        assert!(guards.get(Some("mallory")).is_locked(now));
+        assert!(!guards.get(Some("alice")).is_locked(now));
+        assert!(!guards.get(None).is_locked(now));
+    }
+}
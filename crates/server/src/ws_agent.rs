@@ -8,27 +8,120 @@ use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use common::{AgentMessage, ExistingInstance, Instance, InstanceStatus, ServerToAgentMessage};
+use common::{
+    AgentMessage, ApprovalAction, ErrorCode, ExistingInstance, Instance, InstanceStatus, ServerToAgentMessage,
+    ShareMode,
+};
 
 use crate::state::AppState;
+use crate::tls::ClientCertificate;
+use crate::ws_user::{attach_to_instance, start_instance_creation};
+
+/// Registration details sent by an agent
+struct Registration {
+    agent_id: Uuid,
+    name: String,
+    admin_token: String,
+    share_token: String,
+    share_mode: ShareMode,
+    existing_instances: Vec<ExistingInstance>,
+    agent_key: Option<String>,
+    enrollment_secret: Option<String>,
+    approval_required: bool,
+}
 
 /// Handle an agent WebSocket connection
-pub async fn handle_agent_connection(socket: WebSocket, state: Arc<AppState>) {
+pub async fn handle_agent_connection(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    client_ip: String,
+    client_cert: Option<ClientCertificate>,
+) {
     let (mut ws_sink, mut ws_stream) = socket.split();
 
     // Wait for registration message
     let (agent_id, agent_name, existing_instances) = match wait_for_registration(&mut ws_stream).await {
-        Some((id, name, admin_token, share_token, existing_instances)) => {
+        Some(reg) => {
+            let id = reg.agent_id;
+            let name = reg.name;
+
+            // Enrollment check must pass before anything is stored or overwritten
+            if let Err(e) = state
+                .verify_agent_registration(
+                    id,
+                    &reg.admin_token,
+                    &reg.share_token,
+                    reg.agent_key.as_deref(),
+                    reg.enrollment_secret.as_deref(),
+                    client_cert.as_ref(),
+                )
+                .await
+            {
+                warn!("Rejected registration for agent {} ({}) from {}: {}", name, id, client_ip, e);
+
+                state.log_audit_event(
+                    "agent_register_rejected",
+                    id,
+                    "agent",
+                    Some(id),
+                    None,
+                    None,
+                    &client_ip,
+                    false,
+                    Some(&e.to_string()),
+                );
+
+                let reject = ServerToAgentMessage::RegistrationRejected {
+                    reason: e.to_string(),
+                };
+                if let Ok(json) = reject.to_json() {
+                    let _ = ws_sink.send(Message::Text(json)).await;
+                }
+                let _ = ws_sink.send(Message::Close(None)).await;
+                return;
+            }
+
             // Create channel for sending messages to agent
             let (tx, mut rx) = mpsc::channel::<ServerToAgentMessage>(256);
 
             // Register agent (this may be a reconnection)
             state
-                .register_agent(id, name.clone(), admin_token, share_token, tx)
+                .register_agent(
+                    id,
+                    name.clone(),
+                    reg.admin_token,
+                    reg.share_token,
+                    reg.share_mode,
+                    reg.agent_key,
+                    reg.approval_required,
+                    tx,
+                )
                 .await;
 
             info!("Agent registered: {} ({})", name, id);
 
+            let cert_note = client_cert
+                .as_ref()
+                .map(|c| format!(", client certificate: {}", c.fingerprint))
+                .unwrap_or_default();
+            state.log_audit_event(
+                "agent_register",
+                id,
+                "agent",
+                Some(id),
+                None,
+                None,
+                &client_ip,
+                true,
+                Some(&format!(
+                    "name: {}, share_mode: {}{}{}",
+                    name,
+                    reg.share_mode.as_str(),
+                    if reg.approval_required { ", local approval required" } else { "" },
+                    cert_note
+                )),
+            );
+
             // Broadcast agent online status to users
             state.broadcast_agent_status(id, true).await;
 
@@ -55,7 +148,7 @@ pub async fn handle_agent_connection(socket: WebSocket, state: Arc<AppState>) {
                 }
             });
 
-            (id, name, existing_instances)
+            (id, name, reg.existing_instances)
         }
         None => {
             warn!("Agent connection closed before registration");
@@ -82,6 +175,7 @@ pub async fn handle_agent_connection(socket: WebSocket, state: Arc<AppState>) {
                 status: InstanceStatus::Running,
                 created_at: chrono::Utc::now(), // Use current time for recovered instances
                 attached_users: 0,
+                encrypted: existing.encrypted,
             };
 
             // Try to restore from suspended state first, or add as new
@@ -139,7 +233,7 @@ pub async fn handle_agent_connection(socket: WebSocket, state: Arc<AppState>) {
 /// Wait for the registration message from an agent
 async fn wait_for_registration(
     ws_stream: &mut futures_util::stream::SplitStream<WebSocket>,
-) -> Option<(Uuid, String, String, String, Vec<ExistingInstance>)> {
+) -> Option<Registration> {
     while let Some(msg) = ws_stream.next().await {
         match msg {
             Ok(Message::Text(text)) => {
@@ -148,10 +242,24 @@ async fn wait_for_registration(
                     name,
                     admin_token,
                     share_token,
+                    share_mode,
                     existing_instances,
+                    agent_key,
+                    enrollment_secret,
+                    approval_required,
                 }) = AgentMessage::from_json(&text)
                 {
-                    return Some((agent_id, name, admin_token, share_token, existing_instances));
+                    return Some(Registration {
+                        agent_id,
+                        name,
+                        admin_token,
+                        share_token,
+                        share_mode,
+                        existing_instances,
+                        agent_key,
+                        enrollment_secret,
+                        approval_required,
+                    });
                 }
             }
             Ok(Message::Close(_)) | Err(_) => return None,
@@ -174,8 +282,14 @@ async fn handle_agent_message(
             // Already registered, ignore duplicate
             debug!("Ignoring duplicate registration from agent {}", agent_id);
         }
-        AgentMessage::InstanceCreated { instance_id, cwd } => {
-            info!("Agent {} created instance {} in {}", agent_id, instance_id, cwd);
+        AgentMessage::InstanceCreated { instance_id, cwd, encrypted } => {
+            info!(
+                "Agent {} created instance {} in {}{}",
+                agent_id,
+                instance_id,
+                cwd,
+                if encrypted { " (end-to-end encrypted)" } else { "" }
+            );
 
             let instance = Instance {
                 id: instance_id,
@@ -184,9 +298,11 @@ async fn handle_agent_message(
                 status: InstanceStatus::Running,
                 created_at: chrono::Utc::now(),
                 attached_users: 0,
+                encrypted,
             };
 
             state.add_instance(agent_id, instance.clone()).await;
+            state.take_pending_instance(instance_id).await;
 
             // Notify all users of this agent
             let msg = common::ServerToUserMessage::InstanceCreated { instance };
@@ -197,8 +313,9 @@ async fn handle_agent_message(
 
             state.remove_instance(agent_id, instance_id).await;
 
-            // Clean up terminal history for this instance
+            // Clean up terminal history and invites for this instance
             state.delete_terminal_history(instance_id).await;
+            state.delete_instance_invites(instance_id);
 
             // Notify all users
             let msg = common::ServerToUserMessage::InstanceClosed { instance_id };
@@ -206,7 +323,7 @@ async fn handle_agent_message(
         }
         AgentMessage::PtyOutput { instance_id, data } => {
             // Save to terminal history (async, non-blocking)
-            state.save_pty_output(instance_id, &data).await;
+            state.save_pty_output(agent_id, instance_id, &data).await;
 
             // Forward to all attached users
             let msg = common::ServerToUserMessage::PtyOutput { instance_id, data };
@@ -215,8 +332,141 @@ async fn handle_agent_message(
         AgentMessage::Heartbeat => {
             debug!("Heartbeat from agent {}", agent_id);
         }
-        AgentMessage::Error { message } => {
-            warn!("Error from agent {}: {}", agent_id, message);
+        AgentMessage::Error { message, code, instance_id } => {
+            warn!("Error from agent {}: {} (code={:?})", agent_id, message, code);
+
+            // Report failed instance creation back to the requesting user
+            let pending = match instance_id {
+                Some(id) => state.take_pending_instance(id).await.map(|p| (id, p)),
+                None => None,
+            };
+            if let Some((instance_id, pending)) = pending {
+                state.log_audit_event(
+                    "create_instance_failed",
+                    pending.session_id,
+                    &format!("{:?}", pending.role),
+                    Some(agent_id),
+                    Some(instance_id),
+                    None,
+                    &pending.client_ip,
+                    false,
+                    Some(&message),
+                );
+
+                let msg = common::ServerToUserMessage::Error { message, code };
+                let _ = state.send_to_user(pending.session_id, msg).await;
+            }
+        }
+        AgentMessage::TokenRotated { kind, success, error } => {
+            let Some(rotation) = state.take_pending_rotation(agent_id, kind).await else {
+                warn!("Agent {} confirmed a {} token rotation that was not requested", agent_id, kind.as_str());
+                return Ok(());
+            };
+
+            // Only switch tokens once the agent has saved the new one
+            let result = if success {
+                state.apply_token_rotation(agent_id, kind, &rotation).await
+            } else {
+                Err(anyhow::anyhow!(
+                    "Agent could not save the new token: {}",
+                    error.unwrap_or_else(|| "unknown error".to_string())
+                ))
+            };
+
+            state.log_audit_event(
+                "rotate_token",
+                rotation.session_id,
+                &format!("{:?}", rotation.role),
+                None,
+                None,
+                Some(agent_id),
+                &rotation.client_ip,
+                result.is_ok(),
+                Some(&match &result {
+                    Ok(()) => format!("kind: {}", kind.as_str()),
+                    Err(e) => format!("kind: {}, error: {}", kind.as_str(), e),
+                }),
+            );
+
+            let msg = match result {
+                Ok(()) => {
+                    info!("Rotated {} token of agent {}", kind.as_str(), agent_id);
+                    common::ServerToUserMessage::TokenRotated {
+                        agent_id,
+                        kind,
+                        token: rotation.token,
+                        expires_at: rotation.expires_at,
+                    }
+                }
+                Err(e) => {
+                    warn!("Token rotation for agent {} failed: {}", agent_id, e);
+                    common::ServerToUserMessage::Error {
+                        message: format!("Token rotation failed: {}", e),
+                        code: None,
+                    }
+                }
+            };
+            let _ = state.send_to_user(rotation.session_id, msg).await;
+        }
+        AgentMessage::ApprovalResponse { request_id, approved } => {
+            let Some(pending) = state.take_pending_approval(agent_id, request_id).await else {
+                warn!("Agent {} answered approval request {} that is not pending", agent_id, request_id);
+                return Ok(());
+            };
+            info!(
+                "Agent {} {} {} of instance {} by session {}",
+                agent_id,
+                if approved { "approved" } else { "denied" },
+                pending.action.as_str(),
+                pending.instance_id,
+                pending.session_id
+            );
+
+            state.log_audit_event(
+                if approved { "approval_granted" } else { "approval_denied" },
+                pending.session_id,
+                &format!("{:?}", pending.role),
+                Some(agent_id),
+                Some(pending.instance_id),
+                None,
+                &pending.client_ip,
+                approved,
+                Some(&format!("action: {}", pending.action.as_str())),
+            );
+
+            if !approved {
+                let msg = common::ServerToUserMessage::Error {
+                    message: "The request was denied at the agent's machine".to_string(),
+                    code: Some(ErrorCode::ApprovalDenied),
+                };
+                let _ = state.send_to_user(pending.session_id, msg).await;
+                return Ok(());
+            }
+
+            state.mark_session_approved(agent_id, pending.session_id).await;
+            match pending.action {
+                ApprovalAction::CreateInstance => {
+                    let cwd = pending.cwd.unwrap_or_default();
+                    start_instance_creation(
+                        state,
+                        pending.session_id,
+                        pending.role,
+                        agent_id,
+                        pending.instance_id,
+                        cwd,
+                        &pending.client_ip,
+                    )
+                    .await?;
+                }
+                ApprovalAction::Attach => {
+                    // The session may have switched agents or the instance closed while waiting
+                    let owner = state
+                        .authorize_instance(pending.session_id, pending.instance_id, "attach", &pending.client_ip)
+                        .await?;
+                    attach_to_instance(state, pending.session_id, pending.role, owner, pending.instance_id, &pending.client_ip)
+                        .await;
+                }
+            }
         }
     }
 
@@ -238,7 +488,14 @@ async fn broadcast_to_agent_users(
             || session.working_agent_id == Some(agent_id)
             || (session.agent_id.is_none() && session.working_agent_id.is_none());
 
-        if should_send {
+        // Sessions limited to one instance only hear about that instance
+        let in_scope = match (session.credential.instance_scope(), &msg) {
+            (Some(scope), common::ServerToUserMessage::InstanceCreated { instance }) => instance.id == scope,
+            (Some(scope), common::ServerToUserMessage::InstanceClosed { instance_id }) => *instance_id == scope,
+            _ => true,
+        };
+
+        if should_send && in_scope {
             let _ = session.tx.send(msg.clone()).await;
         }
     }
@@ -3,38 +3,126 @@
 use std::sync::Arc;
 
 use axum::extract::ws::{Message, WebSocket};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use common::{Role, ServerToAgentMessage, ServerToUserMessage, UserMessage};
+use common::{ApprovalAction, ErrorCode, Role, Scope, Scopes, ServerToAgentMessage, ServerToUserMessage, UserMessage};
 
-use crate::state::AppState;
+use crate::db::AuditLogFilter;
+use crate::rate_limit::{credential_key, ip_key};
+use crate::state::{AppState, AuthIdentity, PendingApproval, PendingInstance, SuspendedSession};
+
+/// Identity established by the first message of a connection
+struct Login {
+    identity: AuthIdentity,
+    agent_name: Option<String>,
+    /// Session restored from a ticket
+    resumed: Option<SuspendedSession>,
+    /// Lockout key of the presented credential
+    lockout_key: String,
+}
+
+/// Why a connection did not authenticate
+enum AuthFailure {
+    /// The credential was checked and refused (counts toward the lockout)
+    Refused { lockout_key: String },
+    /// The client IP or credential is locked out, or the IP is rate limited
+    Throttled(String),
+    /// No credential was sent (timeout, closed connection, unexpected message)
+    NoCredential,
+}
 
 /// Handle a user WebSocket connection
 pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, client_ip: String) {
     let (mut ws_sink, mut ws_stream) = socket.split();
 
-    let session_id = Uuid::new_v4();
+    let connection_id = Uuid::new_v4();
 
     // Wait for authentication
-    let auth_result = match wait_for_auth(&mut ws_stream, &state, &client_ip).await {
-        Some((role, agent_id, agent_name)) => {
+    let mut authenticated = wait_for_auth(&mut ws_stream, &state, &client_ip).await;
+
+    // A resumed session keeps its ID, a new login gets a fresh one
+    let session_id = authenticated
+        .as_ref()
+        .ok()
+        .and_then(|login| login.resumed.as_ref())
+        .map(|s| s.session_id)
+        .unwrap_or_else(Uuid::new_v4);
+
+    // SuperAdmin logins may also require a TOTP code (resumed sessions already passed it)
+    if let Ok(login) = &authenticated {
+        if login.identity.role == Role::SuperAdmin
+            && login.resumed.is_none()
+            && !wait_for_second_factor(
+                &mut ws_sink,
+                &mut ws_stream,
+                &state,
+                &client_ip,
+                session_id,
+                login.identity.account.as_deref(),
+            )
+            .await
+        {
+            let lockout_key = login.lockout_key.clone();
+            authenticated = Err(AuthFailure::Refused { lockout_key });
+        }
+    }
+
+    let auth_result = match authenticated {
+        Ok(Login { identity, agent_name, resumed, lockout_key }) => {
+            let AuthIdentity { role, agent_id, .. } = identity;
+            let scopes = identity.scopes.clone();
+            let is_resumed = resumed.is_some();
+            state.auth_lockout.record_success(&lockout_key);
+
             // Create channel for sending messages to user
             let (tx, mut rx) = mpsc::channel::<ServerToUserMessage>(256);
 
-            // Register user session
-            state.register_user(session_id, role, agent_id, tx).await;
+            // Register user session (refused once the token has too many sessions)
+            let registered = match resumed {
+                Some(suspended) => state.restore_user(connection_id, suspended, &client_ip, tx).await,
+                None => state.register_user(session_id, connection_id, &identity, &client_ip, tx).await,
+            };
+            if !registered {
+                state.log_audit_event(
+                    "auth_failure",
+                    session_id,
+                    &format!("{:?}", role),
+                    agent_id,
+                    None,
+                    None,
+                    &client_ip,
+                    false,
+                    Some("session limit reached"),
+                );
+                let auth_msg = ServerToUserMessage::AuthResult {
+                    success: false,
+                    role: None,
+                    agent_name: None,
+                    agent_id: None,
+                    error: Some("Too many sessions for this token".to_string()),
+                    second_factor_required: false,
+                    resumed: false,
+                    scopes: Scopes::default(),
+                };
+                if let Ok(json) = auth_msg.to_json() {
+                    let _ = ws_sink.send(Message::Text(json)).await;
+                }
+                return;
+            }
 
             info!(
-                "User authenticated: session={}, role={:?}, agent={:?}",
-                session_id, role, agent_id
+                "User authenticated: session={}, role={:?}, agent={:?}, account={:?}, resumed={}",
+                session_id, role, agent_id, identity.account, is_resumed
             );
 
             // Log successful authentication
             state.log_audit_event(
-                "auth_success",
+                if is_resumed { "session_resumed" } else { "auth_success" },
                 session_id,
                 &format!("{:?}", role),
                 agent_id,
@@ -52,18 +140,37 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
                 agent_name,
                 agent_id,
                 error: None,
+                second_factor_required: false,
+                resumed: is_resumed,
+                scopes: scopes.clone(),
             };
 
             if let Ok(json) = auth_msg.to_json() {
                 if ws_sink.send(Message::Text(json)).await.is_err() {
-                    state.unregister_user(session_id).await;
+                    state.unregister_user(session_id, connection_id).await;
                     return;
                 }
             }
 
+            // A resumed SuperAdmin gets its working agent back
+            let working_agent_id = state.get_working_agent_id(session_id).await;
+            if let Some(working_id) = working_agent_id {
+                if let Some(agent) = state.get_agent(working_id).await {
+                    let msg = ServerToUserMessage::WorkingAgentSelected {
+                        agent_id: working_id,
+                        agent_name: agent.name,
+                        success: true,
+                        error: None,
+                    };
+                    if let Ok(json) = msg.to_json() {
+                        let _ = ws_sink.send(Message::Text(json)).await;
+                    }
+                }
+            }
+
             // Send initial instance list
-            if let Some(aid) = agent_id {
-                let instances = state.get_instances(aid).await;
+            if let Some(aid) = working_agent_id.or(agent_id) {
+                let instances = state.get_session_instances(session_id, aid).await;
                 let list_msg = ServerToUserMessage::InstanceList { instances };
                 if let Ok(json) = list_msg.to_json() {
                     let _ = ws_sink.send(Message::Text(json)).await;
@@ -80,11 +187,30 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
                         }
                     }
                 }
+                // The channel closes when the server drops the session (e.g. revoked token)
+                let _ = ws_sink_clone.send(Message::Close(None)).await;
             });
 
-            (role, agent_id)
+            (role, agent_id, scopes)
         }
-        None => {
+        Err(failure) => {
+            // Refused credentials count toward the lockout of the client IP and the credential
+            let (error, details) = match failure {
+                AuthFailure::Refused { lockout_key } => {
+                    let ip_lock = state.auth_lockout.record_failure(&ip_key(&client_ip));
+                    let credential_lock = state.auth_lockout.record_failure(&lockout_key);
+                    let details = ip_lock.max(credential_lock).map(|d| {
+                        warn!("Authentication from {} locked out for {}s", client_ip, d.as_secs());
+                        format!("locked out for {}s", d.as_secs())
+                    });
+                    ("Authentication failed".to_string(), details)
+                }
+                AuthFailure::Throttled(reason) => {
+                    (format!("Too many failed attempts: {}", reason), Some(reason))
+                }
+                AuthFailure::NoCredential => ("Authentication failed".to_string(), None),
+            };
+
             // Log failed authentication
             state.log_audit_event(
                 "auth_failure",
@@ -95,7 +221,7 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
                 None,
                 &client_ip,
                 false,
-                None,
+                details.as_deref(),
             );
 
             // Send auth failure
@@ -104,7 +230,10 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
                 role: None,
                 agent_name: None,
                 agent_id: None,
-                error: Some("Authentication failed".to_string()),
+                error: Some(error),
+                second_factor_required: false,
+                resumed: false,
+                scopes: Scopes::default(),
             };
             if let Ok(json) = auth_msg.to_json() {
                 let _ = ws_sink.send(Message::Text(json)).await;
@@ -113,20 +242,46 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
         }
     };
 
-    let (role, agent_id) = auth_result;
+    // Keep a fresh session ticket on the client so it can resume after a reconnect
+    // Each renewal re-checks the login credential; the session ends once it is no longer valid
+    let ticket_renewal = tokio::spawn({
+        let state = state.clone();
+        let interval = state.runtime.config.security.session_ticket_ttl_secs / 3;
+        async move {
+            while let Some((ticket, expires_in_secs)) = state.issue_session_ticket(session_id).await {
+                let msg = ServerToUserMessage::SessionTicket { ticket, expires_in_secs };
+                if state.send_to_user(session_id, msg).await.is_err() {
+                    break;
+                }
+                // A ticket capped by an expiring credential is renewed (and the credential checked) when it runs out
+                let wait = interval.min(expires_in_secs).max(1);
+                tokio::time::sleep(tokio::time::Duration::from_secs(wait)).await;
+            }
+        }
+    });
+
+    let (role, agent_id, scopes) = auth_result;
 
     // Handle incoming messages
     while let Some(msg) = ws_stream.next().await {
         match msg {
             Ok(Message::Text(text)) => {
+                // Session was disconnected by the server (e.g. its token was revoked)
+                if !state.has_user_session(session_id, connection_id).await {
+                    info!("User session {} was disconnected by the server", session_id);
+                    break;
+                }
+                state.touch_user(session_id).await;
+
                 if let Err(e) =
-                    handle_user_message(&text, session_id, role, agent_id, &state, &client_ip).await
+                    handle_user_message(&text, session_id, role, agent_id, &scopes, &state, &client_ip).await
                 {
                     error!("Error handling user message: {}", e);
 
                     // Send error to user via their channel
                     let error_msg = ServerToUserMessage::Error {
                         message: e.to_string(),
+                        code: None,
                     };
                     let _ = state.send_to_user(session_id, error_msg).await;
                 }
@@ -146,17 +301,28 @@ pub async fn handle_user_connection(socket: WebSocket, state: Arc<AppState>, cli
         }
     }
 
-    // Unregister user
-    state.unregister_user(session_id).await;
-    info!("User session ended: {}", session_id);
+    // Unregister user (it stays resumable until its ticket expires)
+    ticket_renewal.abort();
+    state.unregister_user(session_id, connection_id).await;
+    info!("User connection closed: session {}", session_id);
+}
+
+/// Credential sent in the first message of a connection
+enum Credential {
+    /// Admin, share or SuperAdmin token
+    Token(String),
+    /// Web session issued after an OIDC login
+    WebSession(String),
+    /// Session ticket from a previous connection
+    Ticket(String),
 }
 
 /// Wait for authentication message
 async fn wait_for_auth(
-    ws_stream: &mut futures_util::stream::SplitStream<WebSocket>,
+    ws_stream: &mut SplitStream<WebSocket>,
     state: &Arc<AppState>,
     client_ip: &str,
-) -> Option<(Role, Option<Uuid>, Option<String>)> {
+) -> Result<Login, AuthFailure> {
     // Set a timeout for authentication
     let timeout = tokio::time::Duration::from_secs(30);
 
@@ -165,14 +331,37 @@ async fn wait_for_auth(
             while let Some(msg) = ws_stream.next().await {
                 match msg {
                     Ok(Message::Text(text)) => {
-                        if let Ok(UserMessage::Auth { token }) = UserMessage::from_json(&text) {
+                        let credential = match UserMessage::from_json(&text) {
+                            Ok(UserMessage::Auth { token }) => Some(Credential::Token(token)),
+                            Ok(UserMessage::SessionAuth { session }) => Some(Credential::WebSession(session)),
+                            Ok(UserMessage::Resume { ticket }) => Some(Credential::Ticket(ticket)),
+                            _ => None,
+                        };
+                        if let Some(credential) = credential {
+                            let lockout_key = match &credential {
+                                Credential::Token(secret) | Credential::WebSession(secret) | Credential::Ticket(secret) => {
+                                    credential_key(secret)
+                                }
+                            };
+
+                            // Locked out IPs and credentials are refused without checking the credential
+                            let locked = state
+                                .auth_lockout
+                                .remaining(&ip_key(client_ip))
+                                .max(state.auth_lockout.remaining(&lockout_key));
+                            if let Some(remaining) = locked {
+                                warn!("Locked out authentication attempt from {}", client_ip);
+                                let secs = remaining.as_secs().max(1);
+                                return Err(AuthFailure::Throttled(format!("locked out for another {}s", secs)));
+                            }
+
                             // Check rate limit before authentication
                             if let Some(ref limiter) = state.rate_limiter {
                                 match limiter.check_limit(client_ip).await {
                                     Ok(allowed) => {
                                         if !allowed {
                                             warn!("Rate limit exceeded for IP: {}", client_ip);
-                                            return None;
+                                            return Err(AuthFailure::Throttled("rate limited".to_string()));
                                         }
                                     }
                                     Err(e) => {
@@ -182,28 +371,391 @@ async fn wait_for_auth(
                                 }
                             }
 
-                            if let Some((role, agent_id)) = state.authenticate(&token).await {
-                                let agent_name = if let Some(aid) = agent_id {
+                            let (identity, resumed) = match credential {
+                                Credential::Token(token) => (state.authenticate(&token).await, None),
+                                Credential::WebSession(session) => (state.authenticate_session(&session).await, None),
+                                Credential::Ticket(ticket) => match state.resume_user(&ticket).await {
+                                    Ok(suspended) => (Some(suspended.identity.clone()), Some(suspended)),
+                                    Err(reason) => {
+                                        warn!("Session resume from {} refused: {}", client_ip, reason);
+                                        (None, None)
+                                    }
+                                },
+                            };
+                            if let Some(identity) = identity {
+                                let agent_name = if let Some(aid) = identity.agent_id {
                                     state.get_agent(aid).await.map(|a| a.name)
                                 } else {
                                     None
                                 };
-                                return Some((role, agent_id, agent_name));
+                                return Ok(Login { identity, agent_name, resumed, lockout_key });
                             }
+                            return Err(AuthFailure::Refused { lockout_key });
                         }
-                        return None;
+                        return Err(AuthFailure::NoCredential);
                     }
-                    Ok(Message::Close(_)) | Err(_) => return None,
+                    Ok(Message::Close(_)) | Err(_) => return Err(AuthFailure::NoCredential),
                     _ => {}
                 }
             }
-            None
+            Err(AuthFailure::NoCredential)
         } => result,
         _ = tokio::time::sleep(timeout) => {
             warn!("Authentication timeout");
-            None
+            Err(AuthFailure::NoCredential)
+        }
+    }
+}
+
+/// Maximum number of TOTP codes accepted on one connection
+const MAX_TOTP_ATTEMPTS: u32 = 3;
+
+/// Ask a SuperAdmin for a TOTP code when a second factor is configured
+/// Returns true if no second factor is needed or a valid code was sent
+async fn wait_for_second_factor(
+    ws_sink: &mut SplitSink<WebSocket, Message>,
+    ws_stream: &mut SplitStream<WebSocket>,
+    state: &Arc<AppState>,
+    client_ip: &str,
+    session_id: Uuid,
+    account: Option<&str>,
+) -> bool {
+    let totp = match state.super_admin_totp(account).await {
+        Ok(Some(totp)) => totp,
+        Ok(None) => return true,
+        Err(e) => {
+            // Fail closed: an unreadable secret must not disable the second factor
+            error!("Failed to load SuperAdmin TOTP secret: {}", e);
+            return false;
+        }
+    };
+
+    let mut error = None;
+    for attempt in 1..=MAX_TOTP_ATTEMPTS {
+        let prompt = ServerToUserMessage::AuthResult {
+            success: false,
+            role: None,
+            agent_name: None,
+            agent_id: None,
+            error: error.take(),
+            second_factor_required: true,
+            resumed: false,
+            scopes: Scopes::default(),
+        };
+        if let Ok(json) = prompt.to_json() {
+            if ws_sink.send(Message::Text(json)).await.is_err() {
+                return false;
+            }
+        }
+
+        let Some(code) = wait_for_totp_code(ws_stream).await else {
+            return false;
+        };
+
+        // Codes share the rate limit budget of the client IP
+        if let Some(ref limiter) = state.rate_limiter {
+            match limiter.check_limit(&format!("totp:{}", client_ip)).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("TOTP rate limit exceeded for IP: {}", client_ip);
+                    return false;
+                }
+                Err(e) => warn!("Rate limit check failed: {}", e),
+            }
+        }
+
+        let (reason, locked) = match state.verify_super_admin_totp(account, &totp, &code).await {
+            Ok(true) => return true,
+            Ok(false) => ("invalid code", false),
+            Err(reason) => (reason, true),
+        };
+
+        warn!(
+            "SuperAdmin TOTP verification failed from {} (account {:?}): {}",
+            client_ip, account, reason
+        );
+        let account_note = account.map(|a| format!(", account: {}", a)).unwrap_or_default();
+        state.log_audit_event(
+            "totp_failure",
+            session_id,
+            &format!("{:?}", Role::SuperAdmin),
+            None,
+            None,
+            None,
+            client_ip,
+            false,
+            Some(&format!(
+                "attempt {}/{}: {}{}",
+                attempt, MAX_TOTP_ATTEMPTS, reason, account_note
+            )),
+        );
+        if locked {
+            return false;
+        }
+        error = Some("Invalid verification code".to_string());
+    }
+
+    false
+}
+
+/// Wait for a `TotpCode` message, ignoring heartbeats
+async fn wait_for_totp_code(ws_stream: &mut SplitStream<WebSocket>) -> Option<String> {
+    let timeout = tokio::time::Duration::from_secs(120);
+
+    tokio::time::timeout(timeout, async {
+        while let Some(msg) = ws_stream.next().await {
+            match msg {
+                Ok(Message::Text(text)) => match UserMessage::from_json(&text) {
+                    Ok(UserMessage::TotpCode { code }) => return Some(code),
+                    Ok(UserMessage::Heartbeat) => {}
+                    _ => return None,
+                },
+                Ok(Message::Close(_)) | Err(_) => return None,
+                _ => {}
+            }
+        }
+        None
+    })
+    .await
+    .unwrap_or_else(|_| {
+        warn!("TOTP code timeout");
+        None
+    })
+}
+
+/// Rebuild command lines from terminal input, audit them and apply the blocklist
+/// Returns false if the input must not be forwarded to the agent
+#[allow(clippy::too_many_arguments)]
+async fn check_command_input(
+    state: &Arc<AppState>,
+    session_id: Uuid,
+    role_str: &str,
+    agent_id: Uuid,
+    instance_id: Uuid,
+    data: &str,
+    client_ip: &str,
+) -> anyhow::Result<bool> {
+    let input = BASE64.decode(data).unwrap_or_default();
+    let lines = state.assemble_command_lines(instance_id, &input).await;
+
+    for line in &lines {
+        // An overflowed line may hide a blocked command behind padding
+        let blocked = if line.truncated && state.command_filter.has_blocklist() {
+            Some("line too long to check")
+        } else {
+            state.command_filter.blocked_by(&line.text)
+        };
+
+        if let Some(pattern) = blocked {
+            warn!("Blocked command from session {} on instance {}: {}", session_id, instance_id, line.text);
+            state.reset_command_line(instance_id).await;
+            state.log_audit_event(
+                "command_blocked",
+                session_id,
+                role_str,
+                Some(agent_id),
+                Some(instance_id),
+                None,
+                client_ip,
+                false,
+                Some(&format!("pattern: {}, command: {}", pattern, line.text)),
+            );
+
+            // The characters typed before Enter already reached the terminal: clear that line (Ctrl+U)
+            let clear = ServerToAgentMessage::PtyInput {
+                instance_id,
+                data: BASE64.encode([0x15]),
+            };
+            state.send_to_agent(agent_id, clear).await?;
+
+            let msg = ServerToUserMessage::Error {
+                message: format!("Command blocked by server policy: {}", line.text),
+                code: Some(ErrorCode::CommandBlocked),
+            };
+            state.send_to_user(session_id, msg).await?;
+            return Ok(false);
         }
     }
+
+    if state.command_filter.audit {
+        for line in &lines {
+            let details = if line.truncated {
+                format!("{} (truncated)", line.text)
+            } else {
+                line.text.clone()
+            };
+            state.log_audit_event(
+                "command",
+                session_id,
+                role_str,
+                Some(agent_id),
+                Some(instance_id),
+                None,
+                client_ip,
+                true,
+                Some(&details),
+            );
+        }
+    }
+
+    Ok(true)
+}
+
+/// Send a create or attach request to the agent's machine for approval
+async fn request_approval(state: &Arc<AppState>, pending: PendingApproval) -> anyhow::Result<()> {
+    let (session_id, agent_id, action, instance_id) =
+        (pending.session_id, pending.agent_id, pending.action, pending.instance_id);
+    let role_str = format!("{:?}", pending.role);
+    let client_ip = pending.client_ip.clone();
+    let details = match &pending.cwd {
+        Some(cwd) => format!("action: {}, cwd: {}", action.as_str(), cwd),
+        None => format!("action: {}", action.as_str()),
+    };
+
+    let request_id = state.request_approval(pending).await?;
+    info!(
+        "Waiting for agent {} to approve {} of instance {} by session {} (request {})",
+        agent_id,
+        action.as_str(),
+        instance_id,
+        session_id,
+        request_id
+    );
+
+    state.log_audit_event(
+        "approval_requested",
+        session_id,
+        &role_str,
+        Some(agent_id),
+        Some(instance_id),
+        None,
+        &client_ip,
+        true,
+        Some(&details),
+    );
+
+    let msg = ServerToUserMessage::ApprovalPending { action, instance_id };
+    state.send_to_user(session_id, msg).await
+}
+
+/// Ask the agent to create an instance
+/// Also called once the agent's machine approved the creation
+pub(crate) async fn start_instance_creation(
+    state: &Arc<AppState>,
+    session_id: Uuid,
+    role: Role,
+    agent_id: Uuid,
+    instance_id: Uuid,
+    cwd: String,
+    client_ip: &str,
+) -> anyhow::Result<()> {
+    // Log create instance event
+    state.log_audit_event(
+        "create_instance",
+        session_id,
+        &format!("{:?}", role),
+        Some(agent_id),
+        Some(instance_id),
+        None,
+        client_ip,
+        true,
+        Some(&format!("cwd: {}", cwd)),
+    );
+
+    // Track the request so agent-side failures reach this user
+    state
+        .add_pending_instance(
+            instance_id,
+            PendingInstance {
+                agent_id,
+                session_id,
+                role,
+                client_ip: client_ip.to_string(),
+            },
+        )
+        .await;
+
+    // Send create command to agent
+    let cmd = ServerToAgentMessage::CreateInstance { instance_id, cwd };
+    state.send_to_agent(agent_id, cmd).await
+}
+
+/// Attach a session to an instance it is authorized for, and replay the terminal history
+/// Also called once the agent's machine approved the attach
+pub(crate) async fn attach_to_instance(
+    state: &Arc<AppState>,
+    session_id: Uuid,
+    role: Role,
+    agent_id: Uuid,
+    instance_id: Uuid,
+    client_ip: &str,
+) {
+    info!("User {} attaching to instance {}", session_id, instance_id);
+    state.attach_user_to_instance(session_id, instance_id).await;
+
+    // Log attach event
+    state.log_audit_event(
+        "attach",
+        session_id,
+        &format!("{:?}", role),
+        Some(agent_id),
+        Some(instance_id),
+        None,
+        client_ip,
+        true,
+        None,
+    );
+
+    // Send terminal history (if enabled and available)
+    if let Ok(history_msgs) = state.get_terminal_history(instance_id).await {
+        if !history_msgs.is_empty() {
+            debug!("Sending {} history messages to user {} for instance {}",
+                   history_msgs.len(), session_id, instance_id);
+            for msg in history_msgs {
+                let _ = state.send_to_user(session_id, msg).await;
+            }
+        }
+    }
+
+    // Notify instance of user count change
+    let user_count = state.get_instance_user_count(instance_id).await;
+    let msg = ServerToUserMessage::UserJoined {
+        instance_id,
+        user_count,
+    };
+    state.broadcast_to_instance(instance_id, msg).await;
+}
+
+/// Scope a user request needs (None for requests every session may send)
+/// Exhaustive on purpose: a new request type does not build until it is given a scope
+fn required_scope(msg: &UserMessage) -> Option<Scope> {
+    match msg {
+        UserMessage::Auth { .. }
+        | UserMessage::SessionAuth { .. }
+        | UserMessage::Resume { .. }
+        | UserMessage::TotpCode { .. }
+        | UserMessage::Heartbeat => None,
+        UserMessage::CreateInstance { .. } => Some(Scope::InstanceCreate),
+        UserMessage::CloseInstance { .. } => Some(Scope::InstanceClose),
+        UserMessage::Attach { .. } | UserMessage::Detach { .. } => Some(Scope::InstanceAttach),
+        UserMessage::PtyInput { .. } => Some(Scope::InstanceInput),
+        UserMessage::Resize { .. } => Some(Scope::InstanceResize),
+        UserMessage::ListInstances => Some(Scope::InstanceList),
+        UserMessage::CreateInvite { .. } | UserMessage::RevokeInvites { .. } => Some(Scope::InstanceInvite),
+        UserMessage::GetAdminStats => Some(Scope::AdminStats),
+        UserMessage::ForceDisconnectAgent { .. } => Some(Scope::AgentDisconnect),
+        UserMessage::ForceCloseInstance { .. } => Some(Scope::InstanceForceClose),
+        UserMessage::DeleteAgent { .. } => Some(Scope::AgentDelete),
+        UserMessage::RotateToken { .. } | UserMessage::RevokeToken { .. } => Some(Scope::TokenManage),
+        UserMessage::GetAllTags | UserMessage::GetAgentTags { .. } => Some(Scope::TagsRead),
+        UserMessage::AddAgentTag { .. } | UserMessage::RemoveAgentTag { .. } => Some(Scope::TagsWrite),
+        UserMessage::GetAuditLogs { .. } | UserMessage::VerifyAuditLog => Some(Scope::AuditRead),
+        UserMessage::ListSessions => Some(Scope::SessionList),
+        UserMessage::KickSession { .. } => Some(Scope::SessionKick),
+        UserMessage::SelectWorkingAgent { .. }
+        | UserMessage::ClearWorkingAgent
+        | UserMessage::ListAgentInstances { .. } => Some(Scope::AgentSelect),
+    }
 }
 
 /// Handle a message from a user
@@ -212,22 +764,55 @@ async fn handle_user_message(
     session_id: Uuid,
     role: Role,
     agent_id: Option<Uuid>,
+    scopes: &Scopes,
     state: &Arc<AppState>,
     client_ip: &str,
 ) -> anyhow::Result<()> {
     let msg: UserMessage = UserMessage::from_json(text)?;
     let role_str = format!("{:?}", role);
 
+    // Every request is checked against the session's scopes before it is handled
+    if let Some(scope) = required_scope(&msg).filter(|scope| !scopes.contains(*scope)) {
+        match msg {
+            // Read-only sessions can watch but never type; tell them why their input went nowhere
+            UserMessage::PtyInput { .. } => {
+                debug!("Dropping input from read-only user {}", session_id);
+                let msg = ServerToUserMessage::Error {
+                    message: "Read-only session: terminal input is not allowed".to_string(),
+                    code: Some(ErrorCode::ReadOnly),
+                };
+                state.send_to_user(session_id, msg).await?;
+            }
+            // They must not change the terminal size for everyone else either
+            UserMessage::Resize { .. } => debug!("Ignoring resize from read-only user {}", session_id),
+            _ => {
+                warn!("Denied request needing scope {} for session {}", scope.as_str(), session_id);
+                state.log_audit_event(
+                    "scope_denied",
+                    session_id,
+                    &role_str,
+                    agent_id,
+                    None,
+                    None,
+                    client_ip,
+                    false,
+                    Some(&format!("scope: {}", scope.as_str())),
+                );
+                return Err(anyhow::anyhow!("Permission denied: missing scope {}", scope.as_str()));
+            }
+        }
+        return Ok(());
+    }
+
     match msg {
-        UserMessage::Auth { .. } => {
+        UserMessage::Auth { .. }
+        | UserMessage::SessionAuth { .. }
+        | UserMessage::Resume { .. }
+        | UserMessage::TotpCode { .. } => {
             // Already authenticated, ignore
             debug!("Ignoring duplicate auth from user {}", session_id);
         }
         UserMessage::CreateInstance { cwd } => {
-            if !role.can_create_instance() {
-                return Err(anyhow::anyhow!("Permission denied: cannot create instance"));
-            }
-
             // Use effective agent ID (supports SuperAdmin working agent)
             let effective_agent_id = state.get_effective_agent_id(session_id).await
                 .ok_or_else(|| anyhow::anyhow!("No agent associated with session. SuperAdmin must select a working agent first."))?;
@@ -235,34 +820,29 @@ async fn handle_user_message(
             let instance_id = Uuid::new_v4();
             info!("User {} requesting instance creation in {} on agent {}", session_id, cwd, effective_agent_id);
 
-            // Log create instance event
-            state.log_audit_event(
-                "create_instance",
-                session_id,
-                &role_str,
-                Some(effective_agent_id),
-                Some(instance_id),
-                None,
-                client_ip,
-                true,
-                Some(&format!("cwd: {}", cwd)),
-            );
+            if state
+                .needs_approval(effective_agent_id, session_id, ApprovalAction::CreateInstance)
+                .await
+            {
+                let pending = PendingApproval {
+                    agent_id: effective_agent_id,
+                    action: ApprovalAction::CreateInstance,
+                    instance_id,
+                    cwd: Some(cwd),
+                    session_id,
+                    role,
+                    client_ip: client_ip.to_string(),
+                };
+                return request_approval(state, pending).await;
+            }
 
-            // Send create command to agent
-            let cmd = ServerToAgentMessage::CreateInstance {
-                instance_id,
-                cwd,
-            };
-            state.send_to_agent(effective_agent_id, cmd).await?;
+            start_instance_creation(state, session_id, role, effective_agent_id, instance_id, cwd, client_ip).await?;
         }
         UserMessage::CloseInstance { instance_id } => {
-            if !role.can_close_instance() {
-                return Err(anyhow::anyhow!("Permission denied: cannot close instance"));
-            }
-
-            // Use effective agent ID (supports SuperAdmin working agent)
-            let effective_agent_id = state.get_effective_agent_id(session_id).await
-                .ok_or_else(|| anyhow::anyhow!("No agent associated with session. SuperAdmin must select a working agent first."))?;
+            // The instance must belong to the session's effective agent
+            let effective_agent_id = state
+                .authorize_instance(session_id, instance_id, "close_instance", client_ip)
+                .await?;
 
             info!("User {} requesting to close instance {} on agent {}", session_id, instance_id, effective_agent_id);
 
@@ -284,40 +864,24 @@ async fn handle_user_message(
             state.send_to_agent(effective_agent_id, cmd).await?;
         }
         UserMessage::Attach { instance_id } => {
-            info!("User {} attaching to instance {}", session_id, instance_id);
-            state.attach_user_to_instance(session_id, instance_id).await;
-
-            // Log attach event
-            state.log_audit_event(
-                "attach",
-                session_id,
-                &role_str,
-                agent_id,
-                Some(instance_id),
-                None,
-                client_ip,
-                true,
-                None,
-            );
+            let owning_agent_id = state
+                .authorize_instance(session_id, instance_id, "attach", client_ip)
+                .await?;
 
-            // Send terminal history (if enabled and available)
-            if let Ok(history_msgs) = state.get_terminal_history(instance_id).await {
-                if !history_msgs.is_empty() {
-                    debug!("Sending {} history messages to user {} for instance {}",
-                           history_msgs.len(), session_id, instance_id);
-                    for msg in history_msgs {
-                        let _ = state.send_to_user(session_id, msg).await;
-                    }
-                }
+            if state.needs_approval(owning_agent_id, session_id, ApprovalAction::Attach).await {
+                let pending = PendingApproval {
+                    agent_id: owning_agent_id,
+                    action: ApprovalAction::Attach,
+                    instance_id,
+                    cwd: None,
+                    session_id,
+                    role,
+                    client_ip: client_ip.to_string(),
+                };
+                return request_approval(state, pending).await;
             }
 
-            // Notify instance of user count change
-            let user_count = state.get_instance_user_count(instance_id).await;
-            let msg = ServerToUserMessage::UserJoined {
-                instance_id,
-                user_count,
-            };
-            state.broadcast_to_instance(instance_id, msg).await;
+            attach_to_instance(state, session_id, role, owning_agent_id, instance_id, client_ip).await;
         }
         UserMessage::Detach { instance_id } => {
             info!("User {} detaching from instance {}", session_id, instance_id);
@@ -345,24 +909,44 @@ async fn handle_user_message(
             state.broadcast_to_instance(instance_id, msg).await;
         }
         UserMessage::PtyInput { instance_id, data } => {
-            // Find the agent for this instance and forward
-            // Use effective agent ID (supports SuperAdmin working agent)
-            if let Some(effective_agent_id) = state.get_effective_agent_id(session_id).await {
-                let cmd = ServerToAgentMessage::PtyInput { instance_id, data };
-                state.send_to_agent(effective_agent_id, cmd).await?;
+            // The instance must belong to the session's effective agent
+            let effective_agent_id = state
+                .authorize_instance(session_id, instance_id, "pty_input", client_ip)
+                .await?;
+
+            // Encrypted input cannot be inspected: refuse it rather than let it bypass a blocklist
+            if state.command_filter.is_active() && state.is_instance_encrypted(effective_agent_id, instance_id).await {
+                if state.command_filter.has_blocklist() {
+                    let msg = ServerToUserMessage::Error {
+                        message: "The command blocklist cannot inspect end-to-end encrypted input".to_string(),
+                        code: Some(ErrorCode::CommandBlocked),
+                    };
+                    state.send_to_user(session_id, msg).await?;
+                    return Ok(());
+                }
+            } else if state.command_filter.is_active()
+                && !check_command_input(state, session_id, &role_str, effective_agent_id, instance_id, &data, client_ip)
+                    .await?
+            {
+                return Ok(());
             }
+
+            let cmd = ServerToAgentMessage::PtyInput { instance_id, data };
+            state.send_to_agent(effective_agent_id, cmd).await?;
         }
         UserMessage::Resize { instance_id, size } => {
-            // Use effective agent ID (supports SuperAdmin working agent)
-            if let Some(effective_agent_id) = state.get_effective_agent_id(session_id).await {
-                let cmd = ServerToAgentMessage::Resize { instance_id, size };
-                state.send_to_agent(effective_agent_id, cmd).await?;
-            }
+            // The instance must belong to the session's effective agent
+            let effective_agent_id = state
+                .authorize_instance(session_id, instance_id, "resize", client_ip)
+                .await?;
+
+            let cmd = ServerToAgentMessage::Resize { instance_id, size };
+            state.send_to_agent(effective_agent_id, cmd).await?;
         }
         UserMessage::ListInstances => {
             // Use effective agent ID (supports SuperAdmin working agent)
             if let Some(effective_agent_id) = state.get_effective_agent_id(session_id).await {
-                let instances = state.get_instances(effective_agent_id).await;
+                let instances = state.get_session_instances(session_id, effective_agent_id).await;
                 debug!("User {} requested instance list: {} instances", session_id, instances.len());
 
                 // Send instance list back to user
@@ -375,6 +959,64 @@ async fn handle_user_message(
                 state.send_to_user(session_id, msg).await?;
             }
         }
+        UserMessage::CreateInvite { instance_id, expires_in_secs, max_uses, read_only } => {
+            let effective_agent_id = state
+                .authorize_instance(session_id, instance_id, "create_invite", client_ip)
+                .await?;
+            let created_by = state.session_account(session_id).unwrap_or_else(|| role_str.clone());
+            let (token, expires_at) = state
+                .create_invite(effective_agent_id, instance_id, expires_in_secs, max_uses, read_only, &created_by)
+                .await?;
+
+            info!("User {} created an invite to instance {}", session_id, instance_id);
+            state.log_audit_event(
+                "invite_created",
+                session_id,
+                &role_str,
+                Some(effective_agent_id),
+                Some(instance_id),
+                None,
+                client_ip,
+                true,
+                Some(&format!(
+                    "expires_at: {}, max_uses: {}, read_only: {}",
+                    expires_at.to_rfc3339(),
+                    max_uses,
+                    read_only
+                )),
+            );
+
+            let msg = ServerToUserMessage::InviteCreated {
+                instance_id,
+                token,
+                expires_at,
+                max_uses,
+                read_only,
+            };
+            state.send_to_user(session_id, msg).await?;
+        }
+        UserMessage::RevokeInvites { instance_id } => {
+            let effective_agent_id = state
+                .authorize_instance(session_id, instance_id, "revoke_invites", client_ip)
+                .await?;
+            let count = state.agent_repo.delete_instance_invites(instance_id).await?;
+
+            info!("User {} revoked {} invites to instance {}", session_id, count, instance_id);
+            state.log_audit_event(
+                "invites_revoked",
+                session_id,
+                &role_str,
+                Some(effective_agent_id),
+                Some(instance_id),
+                None,
+                client_ip,
+                true,
+                Some(&format!("count: {}", count)),
+            );
+
+            let msg = ServerToUserMessage::InvitesRevoked { instance_id, count };
+            state.send_to_user(session_id, msg).await?;
+        }
         UserMessage::Heartbeat => {
             debug!("Heartbeat from user {}", session_id);
             // Send pong response
@@ -385,19 +1027,11 @@ async fn handle_user_message(
         // Admin commands (SuperAdmin only)
         // ====================================================================
         UserMessage::GetAdminStats => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             let (agents, stats) = state.get_admin_stats().await;
             let msg = ServerToUserMessage::AdminStats { agents, stats };
             state.send_to_user(session_id, msg).await?;
         }
         UserMessage::ForceDisconnectAgent { agent_id: target_agent_id } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             info!("SuperAdmin {} force disconnecting agent {}", session_id, target_agent_id);
             state.force_disconnect_agent(target_agent_id).await?;
 
@@ -420,10 +1054,6 @@ async fn handle_user_message(
             state.broadcast_to_super_admins(msg).await;
         }
         UserMessage::ForceCloseInstance { instance_id } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             info!("SuperAdmin {} force closing instance {}", session_id, instance_id);
             let owning_agent_id = state.force_close_instance(instance_id).await?;
 
@@ -443,10 +1073,6 @@ async fn handle_user_message(
             // The instance closed notification will be sent when agent confirms
         }
         UserMessage::DeleteAgent { agent_id: target_agent_id } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             info!("SuperAdmin {} deleting agent {}", session_id, target_agent_id);
             state.delete_agent(target_agent_id).await?;
 
@@ -468,23 +1094,83 @@ async fn handle_user_message(
             state.send_to_user(session_id, msg.clone()).await?;
             state.broadcast_to_super_admins(msg).await;
         }
+        UserMessage::RotateToken { agent_id: target_agent_id, kind, expires_in_secs } => {
+            info!("SuperAdmin {} rotating {} token of agent {}", session_id, kind.as_str(), target_agent_id);
+            state
+                .start_token_rotation(target_agent_id, kind, expires_in_secs, session_id, role, client_ip)
+                .await?;
+
+            // The new token is sent back once the agent confirms it was saved
+        }
+        UserMessage::RevokeToken { agent_id: target_agent_id, kind } => {
+            info!("SuperAdmin {} revoking {} token of agent {}", session_id, kind.as_str(), target_agent_id);
+            state.revoke_token(target_agent_id, kind).await?;
+
+            // Log revoke token event
+            state.log_audit_event(
+                "revoke_token",
+                session_id,
+                &role_str,
+                None,
+                None,
+                Some(target_agent_id),
+                client_ip,
+                true,
+                Some(&format!("kind: {}", kind.as_str())),
+            );
+
+            // Broadcast to all super admins (including the requester)
+            let msg = ServerToUserMessage::TokenRevoked {
+                agent_id: target_agent_id,
+                kind,
+            };
+            state.broadcast_to_super_admins(msg).await;
+        }
         // ====================================================================
-        // Tag commands
+        // Session commands
         // ====================================================================
-        UserMessage::GetAllTags => {
-            if !role.can_create_instance() {
-                return Err(anyhow::anyhow!("Permission denied: not an admin"));
+        UserMessage::ListSessions => {
+            let sessions = state.list_user_sessions().await;
+            let msg = ServerToUserMessage::SessionList { sessions };
+            state.send_to_user(session_id, msg).await?;
+        }
+        UserMessage::KickSession { session_id: target_session_id } => {
+            if target_session_id == session_id {
+                return Err(anyhow::anyhow!("Cannot disconnect your own session"));
             }
+            info!("SuperAdmin {} disconnecting session {}", session_id, target_session_id);
+            let target_role = state
+                .kick_user_session(target_session_id)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Session not found: {}", target_session_id))?;
 
+            // Log kick event
+            state.log_audit_event(
+                "session_kicked",
+                session_id,
+                &role_str,
+                None,
+                None,
+                Some(target_session_id),
+                client_ip,
+                true,
+                Some(&format!("role: {:?}", target_role)),
+            );
+
+            let msg = ServerToUserMessage::SessionKicked {
+                session_id: target_session_id,
+            };
+            state.send_to_user(session_id, msg).await?;
+        }
+        // ====================================================================
+        // Tag commands
+        // ====================================================================
+        UserMessage::GetAllTags => {
             let tags = state.get_all_tags().await?;
             let msg = ServerToUserMessage::TagList { tags };
             state.send_to_user(session_id, msg).await?;
         }
         UserMessage::GetAgentTags { agent_id: target_agent_id } => {
-            if !role.can_create_instance() {
-                return Err(anyhow::anyhow!("Permission denied: not an admin"));
-            }
-
             let tags = state.get_agent_tags(target_agent_id).await?;
             let msg = ServerToUserMessage::AgentTags {
                 agent_id: target_agent_id,
@@ -493,10 +1179,6 @@ async fn handle_user_message(
             state.send_to_user(session_id, msg).await?;
         }
         UserMessage::AddAgentTag { agent_id: target_agent_id, tag } => {
-            if !role.can_create_instance() {
-                return Err(anyhow::anyhow!("Permission denied: not an admin"));
-            }
-
             info!("Admin {} adding tag '{}' to agent {}", session_id, tag, target_agent_id);
             state.add_agent_tag(target_agent_id, &tag).await?;
 
@@ -521,10 +1203,6 @@ async fn handle_user_message(
             state.broadcast_to_admins(msg).await;
         }
         UserMessage::RemoveAgentTag { agent_id: target_agent_id, tag } => {
-            if !role.can_create_instance() {
-                return Err(anyhow::anyhow!("Permission denied: not an admin"));
-            }
-
             info!("Admin {} removing tag '{}' from agent {}", session_id, tag, target_agent_id);
             state.remove_agent_tag(target_agent_id, &tag).await?;
 
@@ -551,32 +1229,68 @@ async fn handle_user_message(
         // ====================================================================
         // Audit log commands (SuperAdmin only)
         // ====================================================================
-        UserMessage::GetAuditLogs { limit, offset, event_type } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
+        UserMessage::GetAuditLogs {
+            limit,
+            offset,
+            event_type,
+            since,
+            until,
+            agent_id: filter_agent_id,
+            instance_id,
+            client_ip: filter_client_ip,
+            success,
+            session_id: filter_session_id,
+            search,
+        } => {
             let limit = limit.unwrap_or(100);
             let offset = offset.unwrap_or(0);
+            let filter = AuditLogFilter {
+                event_type,
+                since,
+                until,
+                agent_id: filter_agent_id.map(|id| id.to_string()),
+                instance_id: instance_id.map(|id| id.to_string()),
+                client_ip: filter_client_ip,
+                success,
+                session_id: filter_session_id.map(|id| id.to_string()),
+                search,
+            };
 
-            debug!("SuperAdmin {} requesting audit logs (limit={}, offset={}, event_type={:?})",
-                   session_id, limit, offset, event_type);
+            debug!("SuperAdmin {} requesting audit logs (limit={}, offset={}, filter={:?})",
+                   session_id, limit, offset, filter);
 
-            let (logs, total) = state
-                .get_audit_logs(event_type.as_deref(), limit, offset)
-                .await?;
+            let (logs, total) = state.get_audit_logs(&filter, limit, offset).await?;
 
             let msg = ServerToUserMessage::AuditLogList { logs, total };
             state.send_to_user(session_id, msg).await?;
         }
+        UserMessage::VerifyAuditLog => {
+            info!("SuperAdmin {} verifying the audit log chain", session_id);
+
+            let report = state.verify_audit_log().await?;
+            let details = match &report.broken {
+                Some(broken) => format!("checked: {}, broken at row {}: {}", report.checked, broken.id, broken.reason),
+                None => format!("checked: {}", report.checked),
+            };
+            state.log_audit_event(
+                "audit_verified",
+                session_id,
+                &role_str,
+                None,
+                None,
+                None,
+                client_ip,
+                report.broken.is_none(),
+                Some(&details),
+            );
+
+            let msg = ServerToUserMessage::AuditLogVerified { report };
+            state.send_to_user(session_id, msg).await?;
+        }
         // ====================================================================
         // Working Agent commands (SuperAdmin only)
         // ====================================================================
         UserMessage::SelectWorkingAgent { agent_id: target_agent_id } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             info!("SuperAdmin {} selecting working agent {}", session_id, target_agent_id);
 
             // Check if agent exists and is online
@@ -623,10 +1337,6 @@ async fn handle_user_message(
             }
         }
         UserMessage::ClearWorkingAgent => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             info!("SuperAdmin {} clearing working agent", session_id);
 
             state.clear_working_agent(session_id).await;
@@ -648,10 +1358,6 @@ async fn handle_user_message(
             state.send_to_user(session_id, msg).await?;
         }
         UserMessage::ListAgentInstances { agent_id: target_agent_id } => {
-            if !role.can_manage_all_agents() {
-                return Err(anyhow::anyhow!("Permission denied: not a super admin"));
-            }
-
             debug!("SuperAdmin {} requesting instances for agent {}", session_id, target_agent_id);
 
             let instances = state.get_instances(target_agent_id).await;
@@ -0,0 +1,97 @@
+// 终端端到端加密 - 与 crates/agent/src/e2e.rs 对应
+//
+// 口令只在浏览器和 Agent 之间使用，从不发送给服务器：
+//   key     = PBKDF2-HMAC-SHA256(口令, "claude-web-tunnel/e2e/<agent_id>", 210000)
+//   payload = base64(0x01 || nonce(12) || AES-256-GCM(key, nonce, aad, timestamp_ms(u64 BE) || data))
+//   aad     = "<instance_id>/input" 或 "<instance_id>/output"
+
+const VERSION = 1;
+const PBKDF2_ITERATIONS = 210000;
+const NONCE_LEN = 12;
+
+export type StreamDirection = 'input' | 'output';
+
+function toBase64(bytes: Uint8Array): string {
+  let binary = '';
+  for (let i = 0; i < bytes.length; i++) {
+    binary += String.fromCharCode(bytes[i]);
+  }
+  return btoa(binary);
+}
+
+function fromBase64(base64: string): Uint8Array {
+  const binary = atob(base64);
+  const bytes = new Uint8Array(binary.length);
+  for (let i = 0; i < binary.length; i++) {
+    bytes[i] = binary.charCodeAt(i);
+  }
+  return bytes;
+}
+
+function aad(instanceId: string, direction: StreamDirection): Uint8Array {
+  return new TextEncoder().encode(`${instanceId}/${direction}`);
+}
+
+// 由口令派生某个 Agent 的终端密钥
+export async function deriveStreamKey(passphrase: string, agentId: string): Promise<CryptoKey> {
+  const encoder = new TextEncoder();
+  const material = await crypto.subtle.importKey('raw', encoder.encode(passphrase), 'PBKDF2', false, ['deriveKey']);
+  return crypto.subtle.deriveKey(
+    {
+      name: 'PBKDF2',
+      hash: 'SHA-256',
+      salt: encoder.encode(`claude-web-tunnel/e2e/${agentId}`),
+      iterations: PBKDF2_ITERATIONS,
+    },
+    material,
+    { name: 'AES-GCM', length: 256 },
+    false,
+    ['encrypt', 'decrypt'],
+  );
+}
+
+// 加密终端数据（浏览器只发送 input）
+export async function sealStream(
+  key: CryptoKey,
+  instanceId: string,
+  direction: StreamDirection,
+  data: Uint8Array,
+  nonce: Uint8Array = crypto.getRandomValues(new Uint8Array(NONCE_LEN)),
+  timestampMs: number = Date.now(),
+): Promise<string> {
+  const plaintext = new Uint8Array(8 + data.length);
+  new DataView(plaintext.buffer).setBigUint64(0, BigInt(timestampMs));
+  plaintext.set(data, 8);
+
+  const ciphertext = new Uint8Array(
+    await crypto.subtle.encrypt({ name: 'AES-GCM', iv: nonce, additionalData: aad(instanceId, direction) }, key, plaintext),
+  );
+
+  const envelope = new Uint8Array(1 + NONCE_LEN + ciphertext.length);
+  envelope[0] = VERSION;
+  envelope.set(nonce, 1);
+  envelope.set(ciphertext, 1 + NONCE_LEN);
+  return toBase64(envelope);
+}
+
+// 解密终端数据，口令错误或数据被篡改时抛出异常
+export async function openStream(
+  key: CryptoKey,
+  instanceId: string,
+  direction: StreamDirection,
+  payload: string,
+): Promise<Uint8Array> {
+  const envelope = fromBase64(payload);
+  if (envelope.length < 1 + NONCE_LEN + 8 + 16 || envelope[0] !== VERSION) {
+    throw new Error('not an end-to-end encrypted message');
+  }
+
+  const plaintext = new Uint8Array(
+    await crypto.subtle.decrypt(
+      { name: 'AES-GCM', iv: envelope.slice(1, 1 + NONCE_LEN), additionalData: aad(instanceId, direction) },
+      key,
+      envelope.slice(1 + NONCE_LEN),
+    ),
+  );
+  return plaintext.slice(8);
+}
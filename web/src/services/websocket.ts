@@ -1,4 +1,6 @@
+import { get } from 'svelte/store';
 import { auth, app, wsStatus, admin } from '../stores';
+import { openStream, sealStream } from './e2e';
 import {
   setAllTags,
   setAgentTags,
@@ -8,29 +10,45 @@ import {
 import type {
   WsMessage,
   AuthResultMessage,
+  SessionTicketMessage,
   InstanceListMessage,
   InstanceCreatedMessage,
   InstanceClosedMessage,
   PtyOutputMessage,
   UserJoinedMessage,
   UserLeftMessage,
+  ApprovalPendingMessage,
   AgentStatusChangedMessage,
   ErrorMessage,
   AdminStatsMessage,
   AgentDisconnectedMessage,
   AgentDeletedMessage,
+  TokenRotatedMessage,
+  TokenRevokedMessage,
+  TokenKind,
+  SessionListMessage,
+  SessionKickedMessage,
+  InviteCreatedMessage,
+  InvitesRevokedMessage,
   TagListMessage,
   AgentTagsMessage,
   TagAddedMessage,
   TagRemovedMessage,
   AuditLogListMessage,
   AuditLogEntry,
+  AuditLogFilter,
+  AuditChainReport,
+  AuditLogVerifiedMessage,
   WorkingAgentSelectedMessage,
   WorkingAgentClearedMessage,
 } from '../types';
 
 type MessageHandler = (data: string) => void;
 type AuditLogHandler = (logs: AuditLogEntry[], total: number) => void;
+type AuditVerifyHandler = (report: AuditChainReport) => void;
+type TokenEventHandler = (message: TokenRotatedMessage | TokenRevokedMessage) => void;
+type SessionEventHandler = (message: SessionListMessage | SessionKickedMessage) => void;
+type InviteEventHandler = (message: InviteCreatedMessage | InvitesRevokedMessage) => void;
 
 // UTF-8 安全的 Base64 编码/解码
 function utf8ToBase64(str: string): string {
@@ -62,9 +80,20 @@ class WebSocketService {
   private heartbeatInterval: ReturnType<typeof setInterval> | null = null;
   private terminalOutputHandler: MessageHandler | null = null;
   private auditLogHandler: AuditLogHandler | null = null;
+  private auditVerifyHandler: AuditVerifyHandler | null = null;
+  private tokenEventHandler: TokenEventHandler | null = null;
+  private sessionEventHandler: SessionEventHandler | null = null;
+  private inviteEventHandler: InviteEventHandler | null = null;
   private currentInstanceId: string | null = null;
   private attachTimeout: ReturnType<typeof setTimeout> | null = null;
   private receivedOutput = false;
+  // 断线时连接着的实例，会话恢复后服务器仍保持 attach
+  private resumeInstanceId: string | null = null;
+  // 端到端加密密钥，按 Agent 缓存在内存中（从不发送给服务器）
+  private streamKeys = new Map<string, CryptoKey>();
+  // 加解密是异步的，用同一条队列保证输入输出顺序不乱
+  private streamQueue: Promise<void> = Promise.resolve();
+  private streamKeyWarned = false;
 
   constructor() {
     // 默认 URL，可通过 connect 方法覆盖
@@ -93,11 +122,24 @@ class WebSocketService {
         wsStatus.setStatus('connected');
         this.reconnectAttempts = 0;
 
-        // 发送认证消息 - 与后端 UserMessage::Auth 对应
-        this.send({
-          type: 'auth',
-          token: token,
-        });
+        // 发送认证消息 - 与后端 UserMessage::Auth / SessionAuth / Resume 对应
+        const authKind = localStorage.getItem('auth_kind');
+        if (authKind === 'ticket') {
+          this.send({
+            type: 'resume',
+            ticket: token,
+          });
+        } else if (authKind === 'session') {
+          this.send({
+            type: 'session_auth',
+            session: token,
+          });
+        } else {
+          this.send({
+            type: 'auth',
+            token: token,
+          });
+        }
 
         this.startHeartbeat();
         resolve();
@@ -110,9 +152,12 @@ class WebSocketService {
       this.ws.onclose = (event) => {
         console.log('WebSocket closed:', event.code, event.reason);
         this.stopHeartbeat();
+        if (this.currentInstanceId) {
+          this.resumeInstanceId = this.currentInstanceId;
+        }
         this.currentInstanceId = null;
 
-        // 尝试重连
+        // 尝试重连（登录后保存的是会话票据，不再重复发送原始令牌）
         if (this.reconnectAttempts < this.maxReconnectAttempts) {
           this.reconnectAttempts++;
           wsStatus.setReconnecting(this.reconnectAttempts, this.maxReconnectAttempts);
@@ -136,6 +181,14 @@ class WebSocketService {
     });
   }
 
+  // 发送 TOTP 验证码 - 与后端 UserMessage::TotpCode 对应
+  sendTotpCode(code: string): void {
+    this.send({
+      type: 'totp_code',
+      code,
+    });
+  }
+
   disconnect(): void {
     this.stopHeartbeat();
     if (this.ws) {
@@ -204,19 +257,60 @@ class WebSocketService {
     app.disconnectFromInstance();
   }
 
+  // 设置某个 Agent 的端到端加密密钥
+  setStreamKey(agentId: string, key: CryptoKey): void {
+    this.streamKeys.set(agentId, key);
+    this.streamKeyWarned = false;
+  }
+
+  hasStreamKey(agentId: string): boolean {
+    return this.streamKeys.has(agentId);
+  }
+
+  // 加密实例返回其 Agent 的密钥（缺失时为 null），未加密实例返回 undefined
+  private streamKeyFor(instanceId: string): CryptoKey | null | undefined {
+    const instance = get(app).instances.find(i => i.id === instanceId);
+    if (!instance?.encrypted) {
+      return undefined;
+    }
+    return this.streamKeys.get(instance.agent_id) ?? null;
+  }
+
+  // 在终端中提示一次缺少密钥或口令错误
+  private warnStreamKey(text: string): void {
+    if (!this.streamKeyWarned && this.terminalOutputHandler) {
+      this.streamKeyWarned = true;
+      this.terminalOutputHandler(`\r\n\x1b[31m${text}\x1b[0m\r\n`);
+    }
+  }
+
   // 发送终端输入 - 与后端 UserMessage::PtyInput 对应
   sendTerminalInput(data: string): void {
-    if (!this.currentInstanceId) {
+    const instanceId = this.currentInstanceId;
+    if (!instanceId) {
       console.warn('No instance connected');
       return;
     }
-    // 使用 UTF-8 安全的 base64 编码
-    const base64Data = utf8ToBase64(data);
-    this.send({
-      type: 'pty_input',
-      instance_id: this.currentInstanceId,
-      data: base64Data,
-    });
+
+    const key = this.streamKeyFor(instanceId);
+    if (key === undefined) {
+      // 使用 UTF-8 安全的 base64 编码
+      this.send({
+        type: 'pty_input',
+        instance_id: instanceId,
+        data: utf8ToBase64(data),
+      });
+      return;
+    }
+    if (key === null) {
+      this.warnStreamKey('该实例已启用端到端加密，请返回实例列表重新连接并输入口令');
+      return;
+    }
+
+    this.streamQueue = this.streamQueue.then(async () => {
+      const sealed = await sealStream(key, instanceId, 'input', new TextEncoder().encode(data));
+      this.send({ type: 'pty_input', instance_id: instanceId, data: sealed });
+    }).catch(error => console.error('Failed to encrypt input:', error));
   }
 
   // 发送终端调整大小 - 与后端 UserMessage::Resize 对应
@@ -248,6 +342,25 @@ class WebSocketService {
     });
   }
 
+  // 创建单实例邀请 (admin only) - 与后端 UserMessage::CreateInvite 对应
+  createInvite(instanceId: string, expiresInSecs: number, maxUses: number, readOnly: boolean): void {
+    this.send({
+      type: 'create_invite',
+      instance_id: instanceId,
+      expires_in_secs: expiresInSecs,
+      max_uses: maxUses,
+      read_only: readOnly,
+    });
+  }
+
+  // 吊销实例的全部邀请 (admin only) - 与后端 UserMessage::RevokeInvites 对应
+  revokeInvites(instanceId: string): void {
+    this.send({
+      type: 'revoke_invites',
+      instance_id: instanceId,
+    });
+  }
+
   // ========================================================================
   // Admin methods (SuperAdmin only)
   // ========================================================================
@@ -282,6 +395,38 @@ class WebSocketService {
     });
   }
 
+  // 轮换 Agent Token - 与后端 UserMessage::RotateToken 对应
+  rotateToken(agentId: string, kind: TokenKind, expiresInSecs?: number): void {
+    this.send({
+      type: 'rotate_token',
+      agent_id: agentId,
+      kind: kind,
+      expires_in_secs: expiresInSecs,
+    });
+  }
+
+  // 吊销 Agent Token - 与后端 UserMessage::RevokeToken 对应
+  revokeToken(agentId: string, kind: TokenKind): void {
+    this.send({
+      type: 'revoke_token',
+      agent_id: agentId,
+      kind: kind,
+    });
+  }
+
+  // 列出在线会话 - 与后端 UserMessage::ListSessions 对应
+  listSessions(): void {
+    this.send({ type: 'list_sessions' });
+  }
+
+  // 断开指定会话 - 与后端 UserMessage::KickSession 对应
+  kickSession(sessionId: string): void {
+    this.send({
+      type: 'kick_session',
+      session_id: sessionId,
+    });
+  }
+
   // ========================================================================
   // Tag methods (Admin only)
   // ========================================================================
@@ -322,15 +467,20 @@ class WebSocketService {
   // ========================================================================
 
   // 请求审计日志 - 与后端 UserMessage::GetAuditLogs 对应
-  requestAuditLogs(limit?: number, offset?: number, eventType?: string): void {
+  requestAuditLogs(limit?: number, offset?: number, filter: AuditLogFilter = {}): void {
     this.send({
       type: 'get_audit_logs',
       limit: limit,
       offset: offset,
-      event_type: eventType,
+      ...filter,
     });
   }
 
+  // 校验审计日志哈希链 - 与后端 UserMessage::VerifyAuditLog 对应
+  verifyAuditLog(): void {
+    this.send({ type: 'verify_audit_log' });
+  }
+
   // ========================================================================
   // Working Agent methods (SuperAdmin only)
   // ========================================================================
@@ -361,6 +511,26 @@ class WebSocketService {
     this.auditLogHandler = handler;
   }
 
+  // 设置审计日志校验结果处理器
+  setAuditVerifyHandler(handler: AuditVerifyHandler | null): void {
+    this.auditVerifyHandler = handler;
+  }
+
+  // 设置 Token 轮换/吊销结果处理器
+  setTokenEventHandler(handler: TokenEventHandler | null): void {
+    this.tokenEventHandler = handler;
+  }
+
+  // 设置在线会话列表/断开结果处理器
+  setSessionEventHandler(handler: SessionEventHandler | null): void {
+    this.sessionEventHandler = handler;
+  }
+
+  // 设置邀请创建/吊销结果处理器
+  setInviteEventHandler(handler: InviteEventHandler | null): void {
+    this.inviteEventHandler = handler;
+  }
+
   // 设置终端输出处理器
   setTerminalOutputHandler(handler: MessageHandler | null): void {
     this.terminalOutputHandler = handler;
@@ -379,6 +549,9 @@ class WebSocketService {
         case 'auth_result':
           this.handleAuthResult(message as AuthResultMessage);
           break;
+        case 'session_ticket':
+          this.handleSessionTicket(message as SessionTicketMessage);
+          break;
         case 'instance_list':
           this.handleInstanceList(message as InstanceListMessage);
           break;
@@ -397,6 +570,9 @@ class WebSocketService {
         case 'user_left':
           this.handleUserLeft(message as UserLeftMessage);
           break;
+        case 'approval_pending':
+          this.handleApprovalPending(message as ApprovalPendingMessage);
+          break;
         case 'agent_status_changed':
           this.handleAgentStatusChanged(message as AgentStatusChangedMessage);
           break;
@@ -416,6 +592,18 @@ class WebSocketService {
         case 'agent_deleted':
           this.handleAgentDeleted(message as AgentDeletedMessage);
           break;
+        case 'token_rotated':
+        case 'token_revoked':
+          this.handleTokenEvent(message as TokenRotatedMessage | TokenRevokedMessage);
+          break;
+        case 'session_list':
+        case 'session_kicked':
+          this.sessionEventHandler?.(message as SessionListMessage | SessionKickedMessage);
+          break;
+        case 'invite_created':
+        case 'invites_revoked':
+          this.inviteEventHandler?.(message as InviteCreatedMessage | InvitesRevokedMessage);
+          break;
         // Tag responses
         case 'tag_list':
           this.handleTagList(message as TagListMessage);
@@ -433,6 +621,9 @@ class WebSocketService {
         case 'audit_log_list':
           this.handleAuditLogList(message as AuditLogListMessage);
           break;
+        case 'audit_log_verified':
+          this.auditVerifyHandler?.((message as AuditLogVerifiedMessage).report);
+          break;
         // Working Agent responses
         case 'working_agent_selected':
           this.handleWorkingAgentSelected(message as WorkingAgentSelectedMessage);
@@ -449,14 +640,32 @@ class WebSocketService {
   }
 
   private handleAuthResult(message: AuthResultMessage): void {
-    const { success, role, agent_name, agent_id, error } = message;
+    const { success, role, agent_name, agent_id, error, second_factor_required, resumed } = message;
+    const resumeInstanceId = this.resumeInstanceId;
+    this.resumeInstanceId = null;
+
+    // Token 已通过，还需要输入 TOTP 验证码（重连时也会要求）
+    if (second_factor_required) {
+      app.setView('login');
+      app.setSecondFactorRequired(true);
+      if (error) {
+        app.setError(error);
+      }
+      return;
+    }
 
+    app.setSecondFactorRequired(false);
     if (success && role) {
       const token = localStorage.getItem('auth_token');
       if (token) {
-        auth.login(token, role, agent_name || null, agent_id || null);
-        // SuperAdmin goes to admin panel, others go to instances
-        if (role === 'super_admin') {
+        auth.login(token, role, agent_name || null, agent_id || null, message.scopes ?? []);
+        // 恢复的会话仍 attach 在断线前的实例上，直接回到终端
+        if (resumed && resumeInstanceId) {
+          this.currentInstanceId = resumeInstanceId;
+          this.receivedOutput = true;
+          app.connectToInstance(resumeInstanceId);
+        } else if (role === 'super_admin') {
+          // SuperAdmin goes to admin panel, others go to instances
           app.setView('admin');
         } else {
           app.setView('instances');
@@ -464,16 +673,24 @@ class WebSocketService {
         // 服务器认证成功后会自动发送实例列表，不需要再请求
       }
     } else {
-      app.setError(error || 'Authentication failed');
+      const expired = localStorage.getItem('auth_kind') === 'ticket';
+      app.setError(expired ? '会话已过期，请重新登录' : error || 'Authentication failed');
       auth.logout();
     }
   }
 
+  // 保存会话票据，之后的重连和页面刷新都用它恢复会话
+  private handleSessionTicket(message: SessionTicketMessage): void {
+    localStorage.setItem('auth_token', message.ticket);
+    localStorage.setItem('auth_kind', 'ticket');
+  }
+
   private handleInstanceList(message: InstanceListMessage): void {
     app.setInstances(message.instances);
   }
 
   private handleInstanceCreated(message: InstanceCreatedMessage): void {
+    app.setNotice(null);
     app.addInstance(message.instance);
   }
 
@@ -495,6 +712,12 @@ class WebSocketService {
         this.clearAttachTimeout();
       }
 
+      const key = this.streamKeyFor(message.instance_id);
+      if (key !== undefined) {
+        this.handleEncryptedOutput(message, key);
+        return;
+      }
+
       // 使用 UTF-8 安全的 base64 解码
       try {
         const decodedData = base64ToUtf8(message.data);
@@ -507,6 +730,30 @@ class WebSocketService {
     }
   }
 
+  // 解密端到端加密的终端输出，口令错误时丢弃密钥以便重新输入
+  private handleEncryptedOutput(message: PtyOutputMessage, key: CryptoKey | null): void {
+    if (!key) {
+      this.warnStreamKey('该实例已启用端到端加密，请返回实例列表重新连接并输入口令');
+      return;
+    }
+
+    this.streamQueue = this.streamQueue.then(async () => {
+      try {
+        const plaintext = await openStream(key, message.instance_id, 'output', message.data);
+        if (message.instance_id === this.currentInstanceId) {
+          this.terminalOutputHandler?.(new TextDecoder('utf-8').decode(plaintext));
+        }
+      } catch {
+        for (const [agentId, cached] of this.streamKeys) {
+          if (cached === key) {
+            this.streamKeys.delete(agentId);
+          }
+        }
+        this.warnStreamKey('终端输出解密失败：加密口令错误，请返回实例列表重新连接');
+      }
+    });
+  }
+
   private handleUserJoined(message: UserJoinedMessage): void {
     app.updateInstanceUsers(message.instance_id, message.user_count);
   }
@@ -515,7 +762,26 @@ class WebSocketService {
     app.updateInstanceUsers(message.instance_id, message.user_count);
   }
 
+  // 请求需要 Agent 所在机器确认：等待期间不按超时处理
+  private handleApprovalPending(message: ApprovalPendingMessage): void {
+    if (message.action === 'attach' && message.instance_id === this.currentInstanceId) {
+      this.clearAttachTimeout();
+      this.terminalOutputHandler?.('\x1b[33m等待 Agent 所在机器上的确认...\x1b[0m\r\n');
+      return;
+    }
+    app.setNotice('创建实例的请求正在等待 Agent 所在机器上的确认...');
+  }
+
   private handleError(message: ErrorMessage): void {
+    // 未获确认的 attach 回到实例列表
+    if (message.code === 'approval_denied' && this.currentInstanceId && !this.receivedOutput) {
+      this.detachFromInstance();
+    }
+    // 被服务器拦截的命令直接提示在终端中
+    if (message.code === 'command_blocked' && this.terminalOutputHandler) {
+      this.terminalOutputHandler(`\r\n\x1b[31m${message.message}\x1b[0m\r\n`);
+      return;
+    }
     app.setError(message.message);
   }
 
@@ -558,6 +824,13 @@ class WebSocketService {
     admin.removeAgent(message.agent_id);
   }
 
+  private handleTokenEvent(message: TokenRotatedMessage | TokenRevokedMessage): void {
+    console.log(`Agent ${message.agent_id} ${message.kind} token ${message.type === 'token_rotated' ? 'rotated' : 'revoked'}`);
+    if (this.tokenEventHandler) {
+      this.tokenEventHandler(message);
+    }
+  }
+
   // ========================================================================
   // Tag message handlers
   // ========================================================================
@@ -598,8 +871,10 @@ class WebSocketService {
 
     if (success) {
       admin.setWorkingAgent(agent_id, agent_name);
-      // Switch to instances view to show the agent's instances
-      app.setView('instances');
+      // Switch to instances view to show the agent's instances (unless a resumed terminal is open)
+      if (!this.currentInstanceId) {
+        app.setView('instances');
+      }
     } else {
       app.setError(error || 'Failed to select working agent');
     }
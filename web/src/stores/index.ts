@@ -1,5 +1,5 @@
 import { writable, derived } from 'svelte/store';
-import type { AuthState, AppState, AgentInstance, Role, AppView, InstanceStatus, AgentInfo, GlobalStats } from '../types';
+import type { AuthState, AppState, AgentInstance, Role, Scope, AppView, InstanceStatus, AgentInfo, GlobalStats } from '../types';
 
 // ============================================================================
 // Admin Store (SuperAdmin only)
@@ -114,19 +114,21 @@ function createAuthStore() {
     role: null,
     agentName: null,
     agentId: null,
+    scopes: [],
   };
 
   const { subscribe, set, update } = writable<AuthState>(initial);
 
   return {
     subscribe,
-    login: (token: string, role: Role, agentName: string | null, agentId: string | null) => {
+    login: (token: string, role: Role, agentName: string | null, agentId: string | null, scopes: Scope[]) => {
       set({
         isAuthenticated: true,
         token,
         role,
         agentName,
         agentId,
+        scopes,
       });
       // 保存到 localStorage
       localStorage.setItem('auth_token', token);
@@ -134,6 +136,7 @@ function createAuthStore() {
     logout: () => {
       set(initial);
       localStorage.removeItem('auth_token');
+      localStorage.removeItem('auth_kind');
     },
     restore: () => {
       const token = localStorage.getItem('auth_token');
@@ -153,6 +156,8 @@ function createAppStore() {
     connectedInstanceId: null,
     instances: [],
     error: null,
+    notice: null,
+    secondFactorRequired: false,
   };
 
   const { subscribe, set, update } = writable<AppState>(initial);
@@ -208,7 +213,13 @@ function createAppStore() {
       }));
     },
     setError: (error: string | null) => {
-      update(state => ({ ...state, error }));
+      update(state => ({ ...state, error, notice: null }));
+    },
+    setNotice: (notice: string | null) => {
+      update(state => ({ ...state, notice }));
+    },
+    setSecondFactorRequired: (secondFactorRequired: boolean) => {
+      update(state => ({ ...state, secondFactorRequired }));
     },
     reset: () => {
       set(initial);
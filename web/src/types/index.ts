@@ -1,5 +1,5 @@
 // 用户角色 - 与后端 snake_case 对应
-export type Role = 'super_admin' | 'admin' | 'user';
+export type Role = 'super_admin' | 'admin' | 'user' | 'viewer';
 
 // 认证状态
 export interface AuthState {
@@ -8,7 +8,44 @@ export interface AuthState {
   role: Role | null;
   agentName: string | null;
   agentId: string | null;
-}
+  scopes: Scope[];
+}
+
+// 权限范围 - 与后端 Scope 对应，每个请求都按会话的权限范围检查
+export type Scope =
+  | 'instance.list'
+  | 'instance.create'
+  | 'instance.close'
+  | 'instance.attach'
+  | 'instance.input'
+  | 'instance.resize'
+  | 'instance.invite'
+  | 'tags.read'
+  | 'tags.write'
+  | 'audit.read'
+  | 'admin.stats'
+  | 'agent.select'
+  | 'agent.disconnect'
+  | 'agent.delete'
+  | 'instance.force_close'
+  | 'token.manage'
+  | 'session.list'
+  | 'session.kick';
+
+// Agent Token 类型 - 与后端 TokenKind 对应
+export type TokenKind = 'admin' | 'share';
+
+// 错误码 - 与后端 ErrorCode 对应
+export type ErrorCode =
+  | 'directory_not_allowed'
+  | 'invalid_directory'
+  | 'instance_create_failed'
+  | 'read_only'
+  | 'command_blocked'
+  | 'approval_denied';
+
+// 需要 Agent 所在机器确认的操作 - 与后端 ApprovalAction 对应
+export type ApprovalAction = 'create_instance' | 'attach';
 
 // 实例状态 - 与后端对应
 export type InstanceStatus = 'running' | 'suspended' | 'stopped';
@@ -21,12 +58,17 @@ export interface AgentInstance {
   status: InstanceStatus;
   created_at: string;
   attached_users: number;
+  // 终端流经过端到端加密，需要输入 Agent 的加密口令
+  encrypted?: boolean;
 }
 
 // WebSocket 消息类型 - 与后端 snake_case 对应
 export type WsMessageType =
   // User -> Server
   | 'auth'
+  | 'session_auth'
+  | 'resume'
+  | 'totp_code'
   | 'create_instance'
   | 'close_instance'
   | 'attach'
@@ -47,18 +89,21 @@ export type WsMessageType =
   | 'remove_agent_tag'
   // Audit log commands (SuperAdmin only)
   | 'get_audit_logs'
+  | 'verify_audit_log'
   // Working Agent commands (SuperAdmin only)
   | 'select_working_agent'
   | 'clear_working_agent'
   | 'list_agent_instances'
   // Server -> User
   | 'auth_result'
+  | 'session_ticket'
   | 'instance_list'
   | 'instance_created'
   | 'instance_closed'
   | 'pty_output'
   | 'user_joined'
   | 'user_left'
+  | 'approval_pending'
   | 'agent_status_changed'
   | 'error'
   | 'pong'
@@ -73,6 +118,7 @@ export type WsMessageType =
   | 'tag_removed'
   // Audit log responses (SuperAdmin only)
   | 'audit_log_list'
+  | 'audit_log_verified'
   // Working Agent responses (SuperAdmin only)
   | 'working_agent_selected'
   | 'working_agent_cleared';
@@ -93,6 +139,24 @@ export interface AuthMessage extends WsMessage {
   token: string;
 }
 
+// 使用服务器签发的会话认证 (SSO 登录后)
+export interface SessionAuthMessage extends WsMessage {
+  type: 'session_auth';
+  session: string;
+}
+
+// 使用会话票据恢复断线前的会话
+export interface ResumeMessage extends WsMessage {
+  type: 'resume';
+  ticket: string;
+}
+
+// 二次验证码 (SuperAdmin 启用 TOTP 时)
+export interface TotpCodeMessage extends WsMessage {
+  type: 'totp_code';
+  code: string;
+}
+
 // 创建实例请求 (admin only)
 export interface CreateInstanceMessage extends WsMessage {
   type: 'create_instance';
@@ -154,6 +218,16 @@ export interface AuthResultMessage extends WsMessage {
   agent_name?: string;
   agent_id?: string;
   error?: string;
+  second_factor_required?: boolean;
+  resumed?: boolean;
+  scopes?: Scope[];
+}
+
+// 会话票据 (登录后签发并定期续期，重连时用于恢复会话)
+export interface SessionTicketMessage extends WsMessage {
+  type: 'session_ticket';
+  ticket: string;
+  expires_in_secs: number;
 }
 
 // 实例列表
@@ -195,6 +269,13 @@ export interface UserLeftMessage extends WsMessage {
   user_count: number;
 }
 
+// 请求正在等待 Agent 所在机器确认
+export interface ApprovalPendingMessage extends WsMessage {
+  type: 'approval_pending';
+  action: ApprovalAction;
+  instance_id: string;
+}
+
 // Agent 状态变更
 export interface AgentStatusChangedMessage extends WsMessage {
   type: 'agent_status_changed';
@@ -206,6 +287,7 @@ export interface AgentStatusChangedMessage extends WsMessage {
 export interface ErrorMessage extends WsMessage {
   type: 'error';
   message: string;
+  code?: ErrorCode;
 }
 
 // 心跳响应
@@ -262,6 +344,21 @@ export interface DeleteAgentMessage extends WsMessage {
   agent_id: string;
 }
 
+// 轮换 Agent Token
+export interface RotateTokenMessage extends WsMessage {
+  type: 'rotate_token';
+  agent_id: string;
+  kind: TokenKind;
+  expires_in_secs?: number;
+}
+
+// 吊销 Agent Token
+export interface RevokeTokenMessage extends WsMessage {
+  type: 'revoke_token';
+  agent_id: string;
+  kind: TokenKind;
+}
+
 // 管理统计响应
 export interface AdminStatsMessage extends WsMessage {
   type: 'admin_stats';
@@ -281,6 +378,69 @@ export interface AgentDeletedMessage extends WsMessage {
   agent_id: string;
 }
 
+// Token 轮换成功 (仅发送给发起请求的超级管理员)
+export interface TokenRotatedMessage extends WsMessage {
+  type: 'token_rotated';
+  agent_id: string;
+  kind: TokenKind;
+  token: string;
+  expires_at: string | null;
+}
+
+// Token 吊销通知
+export interface TokenRevokedMessage extends WsMessage {
+  type: 'token_revoked';
+  agent_id: string;
+  kind: TokenKind;
+}
+
+// 在线会话信息 - 与后端 SessionInfo 对应
+export interface SessionInfo {
+  session_id: string;
+  role: Role;
+  credential: 'config_token' | 'account' | 'agent_token' | 'sso' | 'invite';
+  account: string | null;
+  agent_id: string | null;
+  working_agent_id: string | null;
+  client_ip: string;
+  connected_at: string;
+  last_activity: string;
+  attached_instances: string[];
+}
+
+// 在线会话列表
+export interface SessionListMessage extends WsMessage {
+  type: 'session_list';
+  sessions: SessionInfo[];
+}
+
+// 会话已被断开 (仅发送给发起请求的超级管理员)
+export interface SessionKickedMessage extends WsMessage {
+  type: 'session_kicked';
+  session_id: string;
+}
+
+// ============================================================================
+// Invite Messages
+// ============================================================================
+
+// 单实例邀请已创建 (token 只返回这一次)
+export interface InviteCreatedMessage extends WsMessage {
+  type: 'invite_created';
+  instance_id: string;
+  token: string;
+  expires_at: string;
+  max_uses: number;
+  read_only: boolean;
+}
+
+// 实例的邀请已全部吊销
+export interface InvitesRevokedMessage extends WsMessage {
+  type: 'invites_revoked';
+  instance_id: string;
+  count: number;
+}
+
 // ============================================================================
 // Tag Messages
 // ============================================================================
@@ -346,6 +506,7 @@ export interface AuditLogEntry {
   id: number;
   timestamp: string;
   event_type: string;
+  session_id?: string;
   user_role: string;
   agent_id: string | null;
   instance_id: string | null;
@@ -353,14 +514,27 @@ export interface AuditLogEntry {
   client_ip: string;
   success: boolean;
   details: string | null;
+  actor?: string | null;
+}
+
+// 审计日志过滤条件 - 所有设置的条件同时满足
+export interface AuditLogFilter {
+  event_type?: string;
+  since?: string; // RFC 3339，包含
+  until?: string; // RFC 3339，不包含
+  agent_id?: string;
+  instance_id?: string;
+  client_ip?: string;
+  success?: boolean;
+  session_id?: string;
+  search?: string; // 详情中的子串
 }
 
 // 获取审计日志请求
-export interface GetAuditLogsMessage extends WsMessage {
+export interface GetAuditLogsMessage extends WsMessage, AuditLogFilter {
   type: 'get_audit_logs';
   limit?: number;
   offset?: number;
-  event_type?: string;
 }
 
 // 审计日志列表响应
@@ -370,6 +544,21 @@ export interface AuditLogListMessage extends WsMessage {
   total: number;
 }
 
+// 审计日志哈希链校验结果 - 与后端 AuditChainReport 对应
+export interface AuditChainReport {
+  checked: number;
+  unchained: number;
+  last_id: number | null;
+  last_hash: string | null;
+  broken: { id: number; reason: string } | null;
+}
+
+// 审计日志校验响应
+export interface AuditLogVerifiedMessage extends WsMessage {
+  type: 'audit_log_verified';
+  report: AuditChainReport;
+}
+
 // ============================================================================
 // Working Agent Messages (SuperAdmin only)
 // ============================================================================
@@ -416,4 +605,7 @@ export interface AppState {
   connectedInstanceId: string | null;
   instances: AgentInstance[];
   error: string | null;
+  // 提示信息（如等待 Agent 所在机器确认）
+  notice: string | null;
+  secondFactorRequired: boolean;
 }